@@ -0,0 +1,54 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rox::vm::VM;
+
+const COUNTING_LOOP: &str = "
+fun count(n) {
+  var sum = 0;
+  for (var i = 0; i < n; i++) sum += 3;
+  return sum;
+}
+return count(100000);
+";
+
+const NESTED_LOOPS: &str = "
+fun pairs(n) {
+  var found = 0;
+  var i = 0;
+  while (i < n) {
+    var j = 0;
+    while (j < i) {
+      if ((i + j) % 7 == 0) found = found + 1;
+      j = j + 1;
+    }
+    i = i + 1;
+  }
+  return found;
+}
+return pairs(400);
+";
+
+fn run(c: &mut Criterion, name: &str, program: &str) {
+    let mut group = c.benchmark_group(name);
+    for superinstructions in [false, true] {
+        let mut vm = VM::new();
+        vm.superinstructions = superinstructions;
+        let id = if superinstructions {
+            "superinstructions"
+        } else {
+            "plain"
+        };
+        group.bench_function(id, |b| b.iter(|| vm.interpret(program).unwrap()));
+    }
+    group.finish();
+}
+
+fn counting_loop(c: &mut Criterion) {
+    run(c, "counting_loop", COUNTING_LOOP);
+}
+
+fn nested_loops(c: &mut Criterion) {
+    run(c, "nested_loops", NESTED_LOOPS);
+}
+
+criterion_group!(benches, counting_loop, nested_loops);
+criterion_main!(benches);
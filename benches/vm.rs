@@ -0,0 +1,54 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rox::vm::VM;
+
+const PROGRAMS: [(&str, &str); 5] = [
+    (
+        "calls",
+        "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } return fib(20);",
+    ),
+    (
+        "int_arithmetic",
+        "fun f(n) { var x = 0; for (var i = 0; i < n; i++) x = x * 3 % 1000 + i - 7; return x; } return f(100000);",
+    ),
+    (
+        "float_arithmetic",
+        "fun f(n) { var x = 0.5; for (var i = 0; i < n; i++) x = x * 1.5 / 1.25 - 0.1; return x; } return f(100000);",
+    ),
+    (
+        "globals",
+        "var total = 0; for (var i = 0; i < 50000; i++) total = total + i; return total;",
+    ),
+    (
+        "list_iteration",
+        "fun list(...items) { return items; } var items = list(); for (var i = 0; i < 250; i++) items = list(...items, i); var s = 0; for (var n = 0; n < 200; n++) for (var x in items) s = s + x; return s;",
+    ),
+];
+
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vm");
+    for (name, program) in PROGRAMS {
+        let mut vm = VM::new();
+        group.bench_function(name, |b| b.iter(|| vm.interpret(program).unwrap()));
+    }
+    group.finish();
+}
+
+///
+/// The programs whose functions have no effects, on the stack VM and on the register machine.
+fn execution_models(c: &mut Criterion) {
+    let mut group = c.benchmark_group("execution_model");
+    for (name, program) in &PROGRAMS[..3] {
+        for register_vm in [false, true] {
+            let mut vm = VM::new();
+            vm.register_vm = register_vm;
+            let model = if register_vm { "register" } else { "stack" };
+            group.bench_function(format!("{}/{}", model, name), |b| {
+                b.iter(|| vm.interpret(program).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dispatch, execution_models);
+criterion_main!(benches);
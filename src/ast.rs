@@ -0,0 +1,375 @@
+use crate::chunk::ChunkArena;
+use crate::parser::Parser;
+use crate::token::Token;
+use crate::value::Value;
+
+pub use crate::parser::ParserError;
+
+///
+/// Parses `source` into its syntax tree, without resolving names or compiling it.
+pub fn parse(source: &str) -> Result<Program, ParserError> {
+    let mut chunks = ChunkArena::new();
+    Parser::new(source, &mut chunks).parse()
+}
+
+///
+/// Where a token is in the source. Every instruction compiled from the tree is attributed to
+/// one of these.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub line: isize,
+    pub start: usize,
+    pub len: usize,
+}
+
+impl From<&Token> for Span {
+    fn from(token: &Token) -> Self {
+        Span {
+            line: token.line,
+            start: token.start,
+            len: token.len,
+        }
+    }
+}
+
+///
+/// A whole script or module.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub statements: Vec<Stmt>,
+    pub eof: Span,
+}
+
+///
+/// How a name can be used. Reads of a constant initialized with a literal compile to the
+/// literal itself.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Binding {
+    #[default]
+    Variable,
+    Constant,
+    Literal(Value),
+}
+
+///
+/// Where a name lives at runtime, filled in by the resolver.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Target {
+    #[default]
+    Global,
+    /// stack slot in the frame of the enclosing function
+    Local(usize),
+}
+
+///
+/// A name being declared: a variable, constant, function, parameter or import.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub span: Span,
+    pub target: Target,
+}
+
+///
+/// A name being read or assigned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub span: Span,
+    pub target: Target,
+    pub binding: Binding,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    /// the last token of the statement
+    pub end: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Expression(Expr),
+    Print(Expr),
+    Var {
+        name: Name,
+        initializer: Option<Expr>,
+    },
+    Const {
+        name: Name,
+        initializer: Expr,
+    },
+    Function {
+        name: Name,
+        function: Box<Function>,
+    },
+    /// `import "path" as name;`
+    Import {
+        keyword: Span,
+        path: String,
+        path_span: Span,
+        name: Name,
+    },
+    /// `from "path" import a, b;`
+    ImportFrom {
+        keyword: Span,
+        path: String,
+        path_span: Span,
+        import: Span,
+        names: Vec<Name>,
+    },
+    Block(Block),
+    If {
+        condition: Expr,
+        paren: Span,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    While {
+        condition: Expr,
+        paren: Span,
+        body: Box<Stmt>,
+    },
+    For {
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        /// the ';' after the condition
+        semicolon: Span,
+        increment: Option<Expr>,
+        paren: Span,
+        body: Box<Stmt>,
+    },
+    ForIn {
+        item: Name,
+        iterable: Expr,
+        paren: Span,
+        body: Box<Stmt>,
+    },
+    Return {
+        keyword: Span,
+        value: Option<Expr>,
+    },
+    Throw(Expr),
+    Yield {
+        keyword: Span,
+        value: Option<Expr>,
+    },
+    Try {
+        keyword: Span,
+        body: Block,
+        catch: Option<Catch>,
+        finally: Option<Finally>,
+    },
+    Match(Box<Match>),
+}
+
+///
+/// Declarations between braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub statements: Vec<Stmt>,
+    pub close: Span,
+    /// locals declared in the block's scope, filled in by the resolver
+    pub locals: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Catch {
+    pub keyword: Span,
+    pub name: Name,
+    /// its locals include the exception variable
+    pub body: Block,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finally {
+    pub keyword: Span,
+    pub body: Block,
+}
+
+///
+/// A function declaration, a `fun (a) { ... }` expression or an arrow function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub doc: Option<String>,
+    pub params: Vec<Param>,
+    pub rest: Option<Name>,
+    pub body: FunctionBody,
+    /// the closing '}' of the body, or the last token of an arrow function's expression
+    pub end: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: Name,
+    /// the '=' and the default value
+    pub default: Option<(Span, Expr)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FunctionBody {
+    Block(Vec<Stmt>),
+    /// `(a) => a + 1`
+    Expression(Expr),
+}
+
+///
+/// `match (subject) { ... }`, both as a statement and as an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub subject: Expr,
+    /// the '{' before the arms
+    pub open: Span,
+    pub arms: Vec<Arm>,
+    pub close: Span,
+    /// slot of the hidden local holding the subject of a match statement, filled in by the
+    /// resolver
+    pub subject_slot: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arm {
+    /// the first token of the arm
+    pub start: Span,
+    pub patterns: Vec<Pattern>,
+    pub patterns_end: Span,
+    pub guard: Option<Expr>,
+    pub arrow: Span,
+    pub body: ArmBody,
+    /// the last token of the arm, including the ',' of an expression arm
+    pub end: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArmBody {
+    Statement(Box<Stmt>),
+    Expression(Expr),
+}
+
+///
+/// A `match` arm pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Wildcard,
+    Literal(Value),
+    /// lower bound, upper bound, whether the upper bound is included
+    Range(Value, Value, bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    /// the last token of the expression
+    pub end: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    /// numbers, strings, `true`, `false` and `nil`
+    Literal(Value),
+    Variable(Variable),
+    Assign {
+        target: Variable,
+        value: Box<Expr>,
+    },
+    /// `a += b`
+    CompoundAssign {
+        target: Variable,
+        op: BinaryOp,
+        op_span: Span,
+        value: Box<Expr>,
+    },
+    /// `++a` evaluates to the updated value, `a++` to the old one
+    Increment {
+        target: Variable,
+        op: BinaryOp,
+        prefix: bool,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Logical {
+        op: LogicalOp,
+        op_span: Span,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    /// `a ?? b`
+    Coalesce {
+        op_span: Span,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    /// `condition ? then : else`
+    Conditional {
+        condition: Box<Expr>,
+        question: Span,
+        then_branch: Box<Expr>,
+        colon: Span,
+        else_branch: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        arguments: Vec<Argument>,
+    },
+    /// `module.name`, or `module?.name` when `optional`
+    Get {
+        object: Box<Expr>,
+        name: String,
+        optional: bool,
+    },
+    Grouping(Box<Expr>),
+    Function(Box<Function>),
+    Match(Box<Match>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Argument {
+    Positional(Expr),
+    /// `name: value`
+    Named(String, Expr),
+    /// `...list`, with the span of the '...'
+    Spread(Span, Expr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Negate,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Power,
+    IntDivide,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
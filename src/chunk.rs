@@ -1,13 +1,103 @@
+use crate::function::{FunctionType, ObjectFunction};
+use crate::module::Module;
 use crate::opcode::Opcode;
 use crate::value::Value;
 use crate::vm::CallFrame;
+use num_bigint::BigInt;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct Chunk {
     pub op_codes: Vec<Opcode>,
     constants: Vec<Value>,
+    /// source line and (offset, length) span of each opcode, used to report runtime errors
+    lines: Vec<isize>,
+    spans: Vec<(usize, usize)>,
+    jump_tables: Vec<JumpTable>,
+}
+
+///
+/// Targets of a `match` over literal integers or strings, as forward offsets from the
+/// `OpJumpTable` that uses it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JumpTable {
+    /// the integer `dense[0]` stands for
+    pub min: i64,
+    pub dense: Vec<Option<u16>>,
+    pub strings: HashMap<String, u16>,
+    /// where values without an entry go
+    pub default: u16,
+}
+
+impl JumpTable {
+    pub fn target(&self, value: &Value) -> u16 {
+        let target = match value {
+            Value::String(s) => self.strings.get(s).copied(),
+            value => value
+                .to_i64()
+                .and_then(|n| n.checked_sub(self.min))
+                .and_then(|i| usize::try_from(i).ok())
+                .and_then(|i| self.dense.get(i).copied().flatten()),
+        };
+        target.unwrap_or(self.default)
+    }
+
+    fn to_bytes(&self, file: &mut File) {
+        file.write_all(&self.min.to_le_bytes()).unwrap();
+        file.write_all(&Chunk::size_to_bytes(self.dense.len()))
+            .unwrap();
+        self.dense.iter().for_each(|target| {
+            file.write_all(&[target.is_some() as u8]).unwrap();
+            file.write_all(&target.unwrap_or(0).to_le_bytes()).unwrap();
+        });
+        // sorted, so that a chunk is always written the same way
+        let mut strings: Vec<_> = self.strings.iter().collect();
+        strings.sort();
+        file.write_all(&Chunk::size_to_bytes(strings.len()))
+            .unwrap();
+        strings.into_iter().for_each(|(s, target)| {
+            file.write_all(&Chunk::size_to_bytes(s.len())).unwrap();
+            file.write_all(s.as_bytes()).unwrap();
+            file.write_all(&target.to_le_bytes()).unwrap();
+        });
+        file.write_all(&self.default.to_le_bytes()).unwrap();
+    }
+
+    fn from_bytes(file: &mut File) -> JumpTable {
+        let read_u16 = |file: &mut File| {
+            let mut buffer = [0u8; 2];
+            file.read_exact(&mut buffer).unwrap();
+            u16::from_le_bytes(buffer)
+        };
+        let mut buffer = [0u8; 8];
+        file.read_exact(&mut buffer).unwrap();
+        let min = i64::from_le_bytes(buffer);
+        let dense = (0..Chunk::bytes_to_usize(file))
+            .map(|_| {
+                let mut present = [0u8; 1];
+                file.read_exact(&mut present).unwrap();
+                let target = read_u16(file);
+                (present[0] == 1).then_some(target)
+            })
+            .collect();
+        let strings = (0..Chunk::bytes_to_usize(file))
+            .map(|_| {
+                let mut bytes = vec![0u8; Chunk::bytes_to_usize(file)];
+                file.read_exact(&mut bytes).unwrap();
+                (String::from_utf8(bytes).unwrap(), read_u16(file))
+            })
+            .collect();
+        JumpTable {
+            min,
+            dense,
+            strings,
+            default: read_u16(file),
+        }
+    }
 }
 
 impl Default for Chunk {
@@ -21,6 +111,9 @@ impl Chunk {
         Chunk {
             op_codes: Vec::new(),
             constants: Vec::new(),
+            lines: Vec::new(),
+            spans: Vec::new(),
+            jump_tables: Vec::new(),
         }
     }
 
@@ -38,102 +131,294 @@ impl Chunk {
         // write constant pool
 
         // size of pool
-        file.write(&[self.constants.len() as u8]).unwrap();
+        file.write_all(&Chunk::size_to_bytes(self.constants.len()))
+            .unwrap();
 
-        //
-        self.constants.iter().for_each(|c| {
-            // write value type (u8)
-            // write size of value (usize)
-            // write byte values
-            match c {
-                Value::Boolean(b) => {
-                    file.write(&[1]).unwrap(); // t
-                    file.write(&[if *b { 1 } else { 0 }]).unwrap(); // bytes
-                }
-                Value::Nil => {
-                    file.write(&[2]).unwrap(); // type
-                }
-                Value::Number(d) => {
-                    file.write(&[3]).unwrap(); // type
-                    file.write(&d.to_le_bytes()).unwrap();
-                }
-                Value::String(s) => {
-                    file.write(&[4]).unwrap();
-                    let str_bytes = s.as_bytes();
-                    file.write(&Chunk::size_to_bytes(str_bytes.len())).unwrap();
-                    file.write(str_bytes).unwrap();
-                }
-                Value::Function(_func) => {
-                    todo!("serialize funtcion to bytes");
-                }
-                Value::NativeFunction(_func) => {
-                    todo!("serialize funtcion to bytes");
-                }
-            }
-        });
+        self.constants
+            .iter()
+            .for_each(|c| Chunk::value_to_bytes(c, file));
+
+        // jump tables of `OpJumpTable`
+        file.write_all(&Chunk::size_to_bytes(self.jump_tables.len()))
+            .unwrap();
+        self.jump_tables
+            .iter()
+            .for_each(|table| table.to_bytes(file));
 
         file.flush().unwrap();
 
         // write chunks
+        file.write_all(&Chunk::size_to_bytes(self.op_codes.len()))
+            .unwrap();
         self.op_codes.iter().for_each(|opcode| {
             let v: Vec<u8> = opcode.into();
             let s = v.as_slice();
             let _n = file.write(s);
         });
+
+        // lines and spans, for runtime errors
+        file.write_all(&Chunk::size_to_bytes(self.lines.len()))
+            .unwrap();
+        self.lines
+            .iter()
+            .for_each(|line| file.write_all(&line.to_le_bytes()).unwrap());
+        self.spans.iter().for_each(|(start, len)| {
+            file.write_all(&Chunk::size_to_bytes(*start)).unwrap();
+            file.write_all(&Chunk::size_to_bytes(*len)).unwrap();
+        });
         Ok(())
     }
 
     pub fn from_bytes(file: &mut File) -> Chunk {
-        let mut buff = [0u8; 1];
-        file.read(&mut buff).unwrap();
-        let mut constant_pool_len = buff[0] as i8;
+        let mut constant_pool_len = Chunk::bytes_to_usize(file);
 
         let mut constants: Vec<Value> = Vec::new();
         while constant_pool_len > 0 {
-            // read type
-            file.read(&mut buff).unwrap();
-            let value = match buff[0] {
-                // Boolean
-                1 => {
-                    // read 0 or 1
-                    file.read(&mut buff).unwrap();
-                    Value::Boolean(buff[0] == 1)
-                }
-
-                // nil
-                2 => Value::Nil,
-                // number
-                3 => {
-                    let mut buff_f64 = [0u8; 8];
-                    file.read(&mut buff_f64).unwrap();
-                    Value::Number(f64::from_le_bytes(buff_f64))
-                }
-                // string
-                4 => {
-                    let len = Chunk::bytes_to_usize(file);
-                    let mut buff_f64 = Vec::with_capacity(len);
-                    unsafe {
-                        buff_f64.set_len(len);
-                    }
-                    file.read(buff_f64.as_mut_slice()).unwrap();
-                    let s = String::from_utf8(buff_f64).ok().unwrap();
-                    Value::String(s)
-                }
-                x => panic!("Unknown type {}", x),
-            };
+            let value = Chunk::value_from_bytes(file);
 
             constants.push(value);
 
             constant_pool_len -= 1;
         }
 
+        let jump_tables = (0..Chunk::bytes_to_usize(file))
+            .map(|_| JumpTable::from_bytes(file))
+            .collect();
+
+        let op_codes_len = Chunk::bytes_to_usize(file);
         let mut op_codes = Vec::new();
-        while let Some(opcode) = Opcode::from_file(file) {
-            op_codes.push(opcode);
+        while op_codes.len() < op_codes_len {
+            match Opcode::from_file(file) {
+                Some(opcode) => op_codes.push(opcode),
+                None => break,
+            }
         }
+
+        let lines_len = Chunk::bytes_to_usize(file);
+        let lines = (0..lines_len)
+            .map(|_| Chunk::bytes_to_usize(file) as isize)
+            .collect();
+        let spans = (0..lines_len)
+            .map(|_| (Chunk::bytes_to_usize(file), Chunk::bytes_to_usize(file)))
+            .collect();
         Chunk {
             op_codes,
             constants,
+            lines,
+            spans,
+            jump_tables,
+        }
+    }
+
+    fn value_to_bytes(value: &Value, file: &mut File) {
+        // write value type (u8)
+        // write size of value (usize)
+        // write byte values
+        match value {
+            Value::Boolean(b) => {
+                file.write(&[1]).unwrap(); // t
+                file.write(&[if *b { 1 } else { 0 }]).unwrap(); // bytes
+            }
+            Value::Nil => {
+                file.write(&[2]).unwrap(); // type
+            }
+            Value::Number(d) => {
+                file.write(&[3]).unwrap(); // type
+                file.write(&d.to_le_bytes()).unwrap();
+            }
+            Value::Int(i) => {
+                file.write_all(&[5]).unwrap(); // type
+                file.write_all(&i.to_le_bytes()).unwrap();
+            }
+            Value::BigInt(b) => {
+                file.write_all(&[6]).unwrap(); // type
+                let bytes = b.to_signed_bytes_le();
+                file.write_all(&Chunk::size_to_bytes(bytes.len())).unwrap();
+                file.write_all(&bytes).unwrap();
+            }
+            Value::Decimal(d) => {
+                file.write_all(&[7]).unwrap(); // type
+                file.write_all(&d.serialize()).unwrap();
+            }
+            Value::String(s) => {
+                file.write(&[4]).unwrap();
+                Chunk::string_to_bytes(s, file);
+            }
+            Value::Function(function) => {
+                file.write_all(&[9]).unwrap(); // type
+                Chunk::function_to_bytes(function, file);
+            }
+            // the argument names of `OpCallNamed`
+            Value::List(items) => {
+                file.write_all(&[8]).unwrap(); // type
+                file.write_all(&Chunk::size_to_bytes(items.len())).unwrap();
+                items
+                    .iter()
+                    .for_each(|item| Chunk::value_to_bytes(item, file));
+            }
+            Value::NativeFunction(_func) => {
+                unreachable!("native functions are never constants");
+            }
+            Value::Exception(_exception) => {
+                unreachable!("exceptions are never constants");
+            }
+            Value::Module(_module) => {
+                unreachable!("modules are never constants");
+            }
+            Value::Generator(_generator) => {
+                unreachable!("generators are never constants");
+            }
+            Value::Fiber(_fiber) => {
+                unreachable!("fibers are never constants");
+            }
+        }
+    }
+
+    fn value_from_bytes(file: &mut File) -> Value {
+        let mut buff = [0u8; 1];
+        // read type
+        file.read(&mut buff).unwrap();
+        match buff[0] {
+            // Boolean
+            1 => {
+                // read 0 or 1
+                file.read(&mut buff).unwrap();
+                Value::Boolean(buff[0] == 1)
+            }
+
+            // nil
+            2 => Value::Nil,
+            // number
+            3 => {
+                let mut buff_f64 = [0u8; 8];
+                file.read(&mut buff_f64).unwrap();
+                Value::Number(f64::from_le_bytes(buff_f64))
+            }
+            // integer
+            5 => {
+                let mut buff_i64 = [0u8; 8];
+                file.read_exact(&mut buff_i64).unwrap();
+                Value::Int(i64::from_le_bytes(buff_i64))
+            }
+            // big integer
+            6 => {
+                let len = Chunk::bytes_to_usize(file);
+                let mut bytes = vec![0u8; len];
+                file.read_exact(&mut bytes).unwrap();
+                Value::BigInt(BigInt::from_signed_bytes_le(&bytes))
+            }
+            // decimal
+            7 => {
+                let mut buff_decimal = [0u8; 16];
+                file.read_exact(&mut buff_decimal).unwrap();
+                Value::Decimal(Decimal::deserialize(buff_decimal))
+            }
+            // string
+            4 => Value::String(Chunk::string_from_bytes(file)),
+            // list
+            8 => {
+                let len = Chunk::bytes_to_usize(file);
+                Value::List((0..len).map(|_| Chunk::value_from_bytes(file)).collect())
+            }
+            // function
+            9 => Value::Function(Arc::new(Chunk::function_from_bytes(file))),
+            x => panic!("Unknown type {}", x),
+        }
+    }
+
+    fn string_to_bytes(s: &str, file: &mut File) {
+        file.write_all(&Chunk::size_to_bytes(s.len())).unwrap();
+        file.write_all(s.as_bytes()).unwrap();
+    }
+
+    fn string_from_bytes(file: &mut File) -> String {
+        let mut bytes = vec![0u8; Chunk::bytes_to_usize(file)];
+        file.read_exact(&mut bytes).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    ///
+    /// A function with the index of its chunk and of its module as they are, for
+    /// `module::write_compiled` to number within the file.
+    pub(crate) fn function_to_bytes(function: &ObjectFunction, file: &mut File) {
+        let ftype = match function.ftype {
+            FunctionType::Function => 0,
+            FunctionType::Script => 1,
+            FunctionType::Module => 2,
+        };
+        file.write_all(&[
+            ftype,
+            function.arity,
+            function.min_arity,
+            function.variadic as u8,
+            function.generator as u8,
+        ])
+        .unwrap();
+        file.write_all(&Chunk::size_to_bytes(function.chunk_index))
+            .unwrap();
+        file.write_all(&Chunk::size_to_bytes(function.module))
+            .unwrap();
+        Chunk::string_to_bytes(&function.name, file);
+        file.write_all(&Chunk::size_to_bytes(function.params.len()))
+            .unwrap();
+        function
+            .params
+            .iter()
+            .for_each(|param| Chunk::string_to_bytes(param, file));
+        file.write_all(&[function.doc.is_some() as u8]).unwrap();
+        if let Some(doc) = &function.doc {
+            Chunk::string_to_bytes(doc, file);
+        }
+    }
+
+    pub(crate) fn function_from_bytes(file: &mut File) -> ObjectFunction {
+        let mut header = [0u8; 5];
+        file.read_exact(&mut header).unwrap();
+        let ftype = match header[0] {
+            0 => FunctionType::Function,
+            1 => FunctionType::Script,
+            2 => FunctionType::Module,
+            x => panic!("Unknown function type {}", x),
+        };
+        let chunk_index = Chunk::bytes_to_usize(file);
+        let module = Chunk::bytes_to_usize(file);
+        let mut function = ObjectFunction::new(ftype, Chunk::string_from_bytes(file), chunk_index);
+        function.arity = header[1];
+        function.min_arity = header[2];
+        function.variadic = header[3] == 1;
+        function.generator = header[4] == 1;
+        function.module = module;
+        function.params = (0..Chunk::bytes_to_usize(file))
+            .map(|_| Chunk::string_from_bytes(file))
+            .collect();
+        let mut has_doc = [0u8; 1];
+        file.read_exact(&mut has_doc).unwrap();
+        if has_doc[0] == 1 {
+            function.doc = Some(Chunk::string_from_bytes(file));
+        }
+        function
+    }
+
+    ///
+    /// Renumbers the chunks and modules the chunk refers to: those of its function constants and
+    /// the modules of its imports.
+    pub(crate) fn relocate(
+        &mut self,
+        chunk: impl Fn(usize) -> usize,
+        module: impl Fn(usize) -> usize,
+    ) {
+        for constant in self.constants.iter_mut() {
+            if let Value::Function(function) = constant {
+                let mut relocated = ObjectFunction::clone(function);
+                relocated.chunk_index = chunk(function.chunk_index);
+                relocated.module = module(function.module);
+                *constant = Value::Function(Arc::new(relocated));
+            }
+        }
+        for opcode in self.op_codes.iter_mut() {
+            if let Opcode::OpImport(index) = opcode {
+                *index = module(*index);
+            }
         }
     }
 }
@@ -191,8 +476,7 @@ impl<'s> Iterator for ChunkOpCodeReader<'s> {
         let ip = self.ip;
         if ip < self.op_codes.len() {
             self.ip += 1;
-            // unsafe { Some((self.ip, self.op_codes.get_unchecked(ip))) }
-            Some((self.ip, self.op_codes.get(ip).unwrap()))
+            Some((self.ip, &self.op_codes[ip]))
         } else {
             None
         }
@@ -216,7 +500,34 @@ pub trait WritableChunk {
 
 impl Chunk {
     pub(crate) fn write_chunk(&mut self, bytes: Opcode) {
+        self.write_chunk_at(bytes, 0, (0, 0));
+    }
+
+    pub(crate) fn write_chunk_at(&mut self, bytes: Opcode, line: isize, span: (usize, usize)) {
         self.op_codes.push(bytes);
+        self.lines.push(line);
+        self.spans.push(span);
+    }
+
+    pub(crate) fn add_jump_table(&mut self, table: JumpTable) -> usize {
+        self.jump_tables.push(table);
+        self.jump_tables.len() - 1
+    }
+
+    pub fn jump_table(&self, index: usize) -> &JumpTable {
+        &self.jump_tables[index]
+    }
+
+    pub(crate) fn jump_table_mut(&mut self, index: usize) -> &mut JumpTable {
+        &mut self.jump_tables[index]
+    }
+
+    pub fn line_at(&self, index: usize) -> isize {
+        self.lines.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn span_at(&self, index: usize) -> (usize, usize) {
+        self.spans.get(index).copied().unwrap_or((0, 0))
     }
 
     pub(crate) fn replace_opcode(&mut self, index: usize, bytes: Opcode) {
@@ -232,6 +543,44 @@ impl Chunk {
         self.constants.get(index)
     }
 
+    ///
+    /// The value the instruction at `index` pushes, if it only loads a constant.
+    pub(crate) fn constant_value(&self, index: usize) -> Option<Value> {
+        match self.op_codes.get(index)? {
+            Opcode::OpConstant(idx) => self.read_constant(*idx).cloned(),
+            Opcode::OpTrue => Some(Value::Boolean(true)),
+            Opcode::OpFalse => Some(Value::Boolean(false)),
+            Opcode::OpNil => Some(Value::Nil),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Drops the instructions from `len` on, and the constants added last that only they load.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        let dropped: Vec<Opcode> = self.op_codes.drain(len..).collect();
+        self.lines.truncate(len);
+        self.spans.truncate(len);
+        for opcode in dropped.iter().rev() {
+            if let Opcode::OpConstant(idx) = opcode {
+                if *idx + 1 == self.constants.len() {
+                    self.constants.pop();
+                }
+            }
+        }
+    }
+
+    ///
+    /// Keeps only the instructions whose `keep` flag is set, with their lines and spans.
+    pub(crate) fn retain(&mut self, keep: &[bool]) {
+        let mut index = 0..;
+        self.op_codes.retain(|_| keep[index.next().unwrap()]);
+        let mut index = 0..;
+        self.lines.retain(|_| keep[index.next().unwrap()]);
+        let mut index = 0..;
+        self.spans.retain(|_| keep[index.next().unwrap()]);
+    }
+
     pub(crate) fn disassemble_chunk(&mut self, writer: &mut dyn Write) {
         let mut offset: usize = 0;
         while offset < self.op_codes.len() {
@@ -261,6 +610,9 @@ impl Chunk {
             Opcode::OpDefineGlobal(size) => {
                 self.constant_instruction("OP_DEFINE_GLOBAL", offset, *size, writer)
             }
+            Opcode::OpDefineGlobalConst(size) => {
+                self.constant_instruction("OP_DEFINE_GLOBAL_CONST", offset, *size, writer)
+            }
             Opcode::OpGetGlobal(size) => {
                 self.constant_instruction("OP_GET_GLOBAL", offset, *size, writer)
             }
@@ -278,6 +630,16 @@ impl Chunk {
             Opcode::OPSubtract => Chunk::simple_instruction("OP_SUBTRACT", offset, writer),
             Opcode::OPMultiply => Chunk::simple_instruction("OP_MULTIPLY", offset, writer),
             Opcode::OpDivide => Chunk::simple_instruction("OP_DIVIDE", offset, writer),
+            Opcode::OpModulo => Chunk::simple_instruction("OP_MODULO", offset, writer),
+            Opcode::OpPower => Chunk::simple_instruction("OP_POWER", offset, writer),
+            Opcode::OpIntDivide => Chunk::simple_instruction("OP_INT_DIVIDE", offset, writer),
+            Opcode::OpIncrement => Chunk::simple_instruction("OP_INCREMENT", offset, writer),
+            Opcode::OpBitAnd => Chunk::simple_instruction("OP_BIT_AND", offset, writer),
+            Opcode::OpBitOr => Chunk::simple_instruction("OP_BIT_OR", offset, writer),
+            Opcode::OpBitXor => Chunk::simple_instruction("OP_BIT_XOR", offset, writer),
+            Opcode::OpBitNot => Chunk::simple_instruction("OP_BIT_NOT", offset, writer),
+            Opcode::OpShiftLeft => Chunk::simple_instruction("OP_SHIFT_LEFT", offset, writer),
+            Opcode::OpShiftRight => Chunk::simple_instruction("OP_SHIFT_RIGHT", offset, writer),
             Opcode::OpFalse => Chunk::simple_instruction("OP_FALSE", offset, writer),
             Opcode::OpNil => Chunk::simple_instruction("OP_NIL", offset, writer),
             Opcode::OpTrue => Chunk::simple_instruction("OP_TRUE", offset, writer),
@@ -285,18 +647,104 @@ impl Chunk {
             Opcode::OpEqual => Chunk::simple_instruction("OP_EQUAL", offset, writer),
             Opcode::OpGreater => Chunk::simple_instruction("OP_GREATER", offset, writer),
             Opcode::OpLess => Chunk::simple_instruction("OP_LESS", offset, writer),
+            Opcode::OpGreaterEqual => Chunk::simple_instruction("OP_GREATER_EQUAL", offset, writer),
+            Opcode::OpLessEqual => Chunk::simple_instruction("OP_LESS_EQUAL", offset, writer),
+            Opcode::OpAddLocalConst(local, constant)
+            | Opcode::OpIncrementLocal(local, constant) => {
+                let name = match opcode {
+                    Opcode::OpIncrementLocal(_, _) => "OP_INCREMENT_LOCAL",
+                    _ => "OP_ADD_LOCAL_CONST",
+                };
+                let value = self.constants.get(*constant).unwrap();
+                writeln!(writer, "{: <20} {: <5} '{}'", name, local, value).unwrap();
+                offset + 1
+            }
+            Opcode::OpJumpIfNotLessLocals(a, b, jump) => {
+                writeln!(
+                    writer,
+                    "{: <20} {} {} {} -> {}",
+                    "OP_JUMP_IF_NOT_LESS_LOCALS",
+                    a,
+                    b,
+                    offset,
+                    offset + 1 + *jump as usize
+                )
+                .unwrap();
+                offset + 1
+            }
             Opcode::OpPrint => Chunk::simple_instruction("OP_PRINT", offset, writer),
             Opcode::OpPop => Chunk::simple_instruction("OP_POP", offset, writer),
+            Opcode::OpDup => Chunk::simple_instruction("OP_DUP", offset, writer),
+            Opcode::OpPopHandler => Chunk::simple_instruction("OP_POP_HANDLER", offset, writer),
+            Opcode::OpThrow => Chunk::simple_instruction("OP_THROW", offset, writer),
+            Opcode::OpEndFinally => Chunk::simple_instruction("OP_END_FINALLY", offset, writer),
+            Opcode::OpYield => Chunk::simple_instruction("OP_YIELD", offset, writer),
+            Opcode::OpExportModule => Chunk::simple_instruction("OP_EXPORT_MODULE", offset, writer),
+            Opcode::OpImport(index) => {
+                writeln!(writer, "{: <20} {: <5}", "OP_IMPORT", index).unwrap();
+                offset + 1
+            }
+            Opcode::OpGetProperty(idx) => {
+                self.constant_instruction("OP_GET_PROPERTY", offset, *idx, writer)
+            }
+            Opcode::OpTry(catch, finally) => {
+                writeln!(
+                    writer,
+                    "{: <20} {: <5} -> catch {}, finally {}",
+                    "OP_TRY",
+                    offset,
+                    offset + 1 + *catch as usize,
+                    offset + 1 + *finally as usize
+                )
+                .unwrap();
+                offset + 1
+            }
 
             Opcode::OpJumpIfFalse(jump) => {
                 Chunk::jump_instruction("OP_JUMP_IF_FALSE", offset, 1, jump, writer)
             }
             Opcode::OpJump(jump) => Chunk::jump_instruction("OP_JUMP", offset, 1, jump, writer),
             Opcode::OpLoop(jump) => Chunk::jump_instruction("OP_LOOP", offset, -1, jump, writer),
+            Opcode::OpForIter(jump) => {
+                Chunk::jump_instruction("OP_FOR_ITER", offset, 1, jump, writer)
+            }
+            Opcode::OpJumpTable(index) => {
+                writeln!(
+                    writer,
+                    "{: <20} {: <5} {:?}",
+                    "OP_JUMP_TABLE",
+                    index,
+                    self.jump_tables.get(*index)
+                )
+                .unwrap();
+                offset + 1
+            }
+            Opcode::OpInRange(inclusive) => {
+                let name = if *inclusive {
+                    "OP_IN_RANGE_INCLUSIVE"
+                } else {
+                    "OP_IN_RANGE"
+                };
+                Chunk::simple_instruction(name, offset, writer)
+            }
 
             Opcode::OpCall(args) => {
                 self.byte_instruction("OP_CALL", offset, (*args) as usize, writer)
             }
+            Opcode::OpTailCall(args) => {
+                self.byte_instruction("OP_TAIL_CALL", offset, (*args) as usize, writer)
+            }
+            Opcode::OpCallNamed(args, names) => {
+                let names = self.constants.get(*names).unwrap();
+                writeln!(writer, "{: <20} {: <5} '{}' ", "OP_CALL_NAMED", args, names).unwrap();
+                offset + 1
+            }
+            Opcode::OpCallSpread(groups) => {
+                self.byte_instruction("OP_CALL_SPREAD", offset, (*groups) as usize, writer)
+            }
+            Opcode::OpBuildList(count) => {
+                self.byte_instruction("OP_BUILD_LIST", offset, (*count) as usize, writer)
+            }
             _ => {
                 eprintln!("Unhandled opcode {:?}", opcode);
 
@@ -342,7 +790,10 @@ impl Chunk {
                 writeln!(writer, "{: <20} {: <5}  ", name, idx).unwrap();
             }
 
-            Opcode::OpCall(num_args) => {
+            Opcode::OpCall(num_args)
+            | Opcode::OpCallSpread(num_args)
+            | Opcode::OpTailCall(num_args)
+            | Opcode::OpBuildList(num_args) => {
                 writeln!(writer, "{: <20} {: <5}  ", name, num_args).unwrap();
             }
             _ => {
@@ -374,10 +825,13 @@ impl Chunk {
 
 #[cfg(test)]
 mod tests {
-    use crate::chunk::Chunk;
+    use crate::chunk::{Chunk, JumpTable};
     use crate::opcode::Opcode;
     use crate::value::Value;
+    use num_bigint::BigInt;
+    use rust_decimal::Decimal;
 
+    use std::collections::HashMap;
     use std::fs::File;
 
     use std::io::Write;
@@ -401,6 +855,36 @@ mod tests {
         idx = chunk.add_constant(Value::String("hello".to_string()));
         chunk.write_chunk(Opcode::OpConstant(idx));
 
+        idx = chunk.add_constant(Value::Int(-(1 << 60)));
+        chunk.write_chunk(Opcode::OpConstant(idx));
+
+        let big: BigInt = "-123456789012345678901234567890".parse().unwrap();
+        idx = chunk.add_constant(Value::BigInt(big.clone()));
+        chunk.write_chunk(Opcode::OpConstant(idx));
+
+        idx = chunk.add_constant(Value::Decimal(Decimal::new(110, 2)));
+        chunk.write_chunk(Opcode::OpConstant(idx));
+
+        chunk.add_constant(Value::List(vec![
+            Value::String("key".to_string()),
+            Value::List(vec![Value::Int(2)]),
+        ]));
+
+        chunk.write_chunk(Opcode::OpAddLocalConst(1, 4));
+        chunk.write_chunk(Opcode::OpJumpIfNotLessLocals(1, 2, 7));
+        chunk.write_chunk(Opcode::OpTailCall(3));
+        chunk.write_chunk(Opcode::OpIncrement);
+        chunk.write_chunk(Opcode::OpIncrementLocal(2, 4));
+
+        let table = JumpTable {
+            min: -1,
+            dense: vec![Some(3), None, Some(5)],
+            strings: HashMap::from([("a".to_string(), 7), ("b".to_string(), 8)]),
+            default: 9,
+        };
+        let table_index = chunk.add_jump_table(table.clone());
+        chunk.write_chunk(Opcode::OpJumpTable(table_index));
+
         let mut file = File::create("foo.txt").unwrap();
         chunk.to_bytes(&mut file).unwrap();
         file.flush().unwrap();
@@ -411,6 +895,24 @@ mod tests {
         // file1.read(&mut buff);
         // file1.read_to_end(&mut v);
         let _chunk1 = Chunk::from_bytes(&mut file1);
+        assert!(matches!(_chunk1.constants[4], Value::Int(n) if n == -(1 << 60)));
+        assert!(matches!(&_chunk1.constants[5], Value::BigInt(b) if *b == big));
+        assert_eq!(_chunk1.constants[6].to_string(), "1.10");
+        assert!(matches!(&_chunk1.constants[7], Value::List(items)
+            if matches!(&items[..], [Value::String(key), Value::List(inner)]
+                if key == "key" && matches!(inner[..], [Value::Int(2)]))));
+        assert_eq!(
+            _chunk1.op_codes[8..],
+            [
+                Opcode::OpAddLocalConst(1, 4),
+                Opcode::OpJumpIfNotLessLocals(1, 2, 7),
+                Opcode::OpTailCall(3),
+                Opcode::OpIncrement,
+                Opcode::OpIncrementLocal(2, 4),
+                Opcode::OpJumpTable(table_index)
+            ]
+        );
+        assert_eq!(_chunk1.jump_table(table_index), &table);
 
         let _a = 2;
     }
@@ -421,11 +923,19 @@ pub type ChunkIndex = usize;
 #[derive(Debug)]
 pub struct ChunkArena {
     pub chunks: Vec<Chunk>,
+    /// every module compiled into these chunks, the main script first
+    pub modules: Vec<Module>,
+    /// indexes of the modules being compiled, outermost first, to detect import cycles
+    pub loading: Vec<usize>,
 }
 
 impl ChunkArena {
     pub fn new() -> Self {
-        ChunkArena { chunks: Vec::new() }
+        ChunkArena {
+            chunks: Vec::new(),
+            modules: vec![Module::new(None, String::new())],
+            loading: vec![0],
+        }
     }
 
     pub fn allocate_chunk(&mut self) -> ChunkIndex {
@@ -1,239 +1,1153 @@
-use crate::chunk::{ChunkIndex, ChunkWriterTrait};
-use crate::function::ObjectFunction;
+use crate::ast::{
+    Argument, ArmBody, BinaryOp, Binding, Block, Expr, ExprKind, Function, FunctionBody, LogicalOp,
+    Match, Name, Pattern, Program, Span, Stmt, StmtKind, Target, UnaryOp, Variable,
+};
+use crate::chunk::{Chunk, ChunkArena, JumpTable};
+use crate::function::{FunctionType, ObjectFunction};
+use crate::module::{self, Module};
 use crate::opcode::Opcode;
-use crate::opcode::Opcode::OpJumpIfFalse;
-use crate::parser::Parser;
-use crate::precedence::{ParserRule, Precedence};
-use std::fmt::Write;
-
-use crate::token::{Token, TokenType};
+use crate::operators;
+use crate::parser::{Parser, ParserError};
 use crate::value::Value;
+use crate::vm::COMPLETION_NORMAL;
 
-use num_traits::FromPrimitive;
-
-use arrayvec::ArrayVec;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+///
+/// Generates the bytecode of a resolved program. Instructions are attributed to the tokens
+/// the tree records, and functions and imported modules get their chunks in source order.
 #[derive(Debug)]
-pub struct Compiler {
-    pub(crate) enclosing: Option<Box<Compiler>>,
-    // current_compilers: &'a mut CurrentCompiler<'a>,
-    pub(crate) function: Box<ObjectFunction>,
-    // scope
-    pub(crate) locals: ArrayVec<Local, 256>,
-
-    pub(crate) scope_depth: isize,
+pub struct Compiler<'c> {
+    chunks: &'c mut ChunkArena,
+    /// the function whose chunk is being written
+    function: ObjectFunction,
+    /// directories searched for imports not found next to the importing file
+    search_path: &'c [PathBuf],
+    /// evaluate operators on constant operands at compile time
+    fold_constants: bool,
+    /// compile `return f(x);` into a call that reuses the returning function's frame
+    tail_calls: bool,
+    /// read imported modules from their compiled files while up to date, and write them otherwise
+    cache_modules: bool,
+    /// code length right after the last expression that only loads a constant
+    constant_tail: Option<usize>,
+    /// the token of the last instruction emitted
+    last_span: Span,
+    result: Option<ParserError>,
+    panic_mode: bool,
 }
 
-///
-///
-///
-#[derive(Debug, Clone)]
-pub struct Local {
-    pub(crate) token: Option<Token>, // clone!!! noooo, just a ref..
-    pub(crate) depth: isize,
-}
+impl<'c> Compiler<'c> {
+    ///
+    /// A compiler writing the script or module `function` into `chunks`.
+    pub(crate) fn new(
+        chunks: &'c mut ChunkArena,
+        function: ObjectFunction,
+        search_path: &'c [PathBuf],
+        fold_constants: bool,
+        tail_calls: bool,
+        cache_modules: bool,
+    ) -> Self {
+        Compiler {
+            chunks,
+            function,
+            search_path,
+            fold_constants,
+            tail_calls,
+            cache_modules,
+            constant_tail: None,
+            last_span: Span::default(),
+            result: None,
+            panic_mode: false,
+        }
+    }
 
-///
+    ///
+    /// Compiles `program`, returning the function running it or the last error found.
+    pub(crate) fn compile(mut self, program: &Program) -> Result<ObjectFunction, ParserError> {
+        self.declarations(&program.statements);
+        if self.function.ftype == FunctionType::Module {
+            self.emit_bytes(Opcode::OpExportModule, Opcode::OpReturn, program.eof);
+        } else {
+            self.emit_return(program.eof);
+        }
 
-impl Compiler {
-    // pub fn new(name: &str, ftype: FunctionType) -> Box<Self> {
-    //     const INIT: Option<Local> = None;
-    //
-    //     Box::new(Compiler {
-    //         enclosing: None,
-    //         function: ObjectFunction::new(ftype, name.to_string()),
-    //         locals: Vec::with_capacity(256),
-    //         local_count: 0,
-    //         scope_depth: 0,
-    //     })
-    // }
-    pub fn new2(func: ObjectFunction) -> Box<Self> {
-        // Slot '0' is claimed by VM internal usage
-        let mut locals = ArrayVec::new();
-        locals.push(Local {
-            token: None,
-            depth: 0,
-        });
-
-        Box::new(Compiler {
-            enclosing: None,
-            function: Box::new(func),
-            locals,
-            scope_depth: 0,
-        })
-    }
-
-    pub(crate) fn add_local(&mut self, local: Local, errors: &mut Vec<&'static str>) {
-        if self.locals.len() == 256 {
-            errors.push("Too many local variables in function");
-            return;
+        match self.result {
+            None => Ok(self.function),
+            Some(err) => Err(err),
+        }
+    }
+
+    fn declarations(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            self.statement(statement);
+            self.panic_mode = false;
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        let end = statement.end;
+        match &statement.kind {
+            StmtKind::Expression(expression) => {
+                self.expression(expression);
+                self.emit_byte(Opcode::OpPop, end);
+            }
+            StmtKind::Print(expression) => {
+                self.expression(expression);
+                self.emit_byte(Opcode::OpPrint, end);
+            }
+            StmtKind::Var { name, initializer } => {
+                let global = self.declare(name);
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.emit_byte(Opcode::OpNil, name.span),
+                }
+                self.define_variable(global, end);
+            }
+            StmtKind::Const { name, initializer } => {
+                let global = self.declare(name);
+                self.expression(initializer);
+                if let Some(index) = global {
+                    self.emit_byte(Opcode::OpDefineGlobalConst(index), end);
+                }
+            }
+            StmtKind::Function { name, function } => {
+                let global = self.declare(name);
+                self.function(function);
+                self.define_variable(global, end);
+            }
+            StmtKind::Import {
+                path,
+                path_span,
+                name,
+                ..
+            } => {
+                let module = self.load_module(path, *path_span);
+                let global = self.declare(name);
+                self.emit_byte(Opcode::OpImport(module), name.span);
+                self.define_variable(global, end);
+            }
+            StmtKind::ImportFrom {
+                path,
+                path_span,
+                import,
+                names,
+                ..
+            } => {
+                let module = self.load_module(path, *path_span);
+                self.emit_byte(Opcode::OpImport(module), *import);
+                for name in names {
+                    let global = self.declare(name);
+                    self.emit_bytes(
+                        Opcode::OpDup,
+                        Opcode::OpGetProperty(global.unwrap_or(0)),
+                        name.span,
+                    );
+                    self.define_variable(global, name.span);
+                }
+                let last = names.last().map_or(*import, |name| name.span);
+                self.emit_byte(Opcode::OpPop, last);
+            }
+            StmtKind::Block(block) => self.block(block),
+            StmtKind::If {
+                condition,
+                paren,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                let then_jump = self.emit_jump(Opcode::OpJumpIfFalse(0), *paren);
+                self.emit_byte(Opcode::OpPop, *paren);
+                self.statement(then_branch);
+                let else_jump = self.emit_jump(Opcode::OpJump(0), then_branch.end);
+
+                self.patch_jump(then_jump, &Opcode::OpJumpIfFalse(0));
+                self.emit_byte(Opcode::OpPop, then_branch.end);
+
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump, &Opcode::OpJump(0));
+            }
+            StmtKind::While {
+                condition,
+                paren,
+                body,
+            } => {
+                let loop_start = self.length();
+                self.expression(condition);
+
+                let exit_jump = self.emit_jump(Opcode::OpJumpIfFalse(0), *paren);
+                self.emit_byte(Opcode::OpPop, *paren);
+                self.statement(body);
+                self.emit_loop(loop_start, body.end);
+
+                self.patch_jump(exit_jump, &Opcode::OpJumpIfFalse(0));
+                self.emit_byte(Opcode::OpPop, body.end);
+            }
+            StmtKind::For {
+                initializer,
+                condition,
+                semicolon,
+                increment,
+                paren,
+                body,
+            } => self.for_statement(
+                initializer.as_deref(),
+                condition.as_ref(),
+                *semicolon,
+                increment.as_ref(),
+                *paren,
+                body,
+            ),
+            StmtKind::ForIn {
+                iterable,
+                paren,
+                body,
+                ..
+            } => self.for_in_statement(iterable, *paren, body),
+            StmtKind::Return { value, .. } => match value {
+                None => self.emit_return(end),
+                Some(value) => {
+                    self.expression(value);
+                    // the called function's result is returned as is, so it can take over this frame
+                    if self.tail_calls {
+                        if let Some(Opcode::OpCall(args)) = self.chunk().op_codes.last().cloned() {
+                            let last = self.length() - 1;
+                            self.replace_opcode(last, Opcode::OpTailCall(args));
+                        }
+                    }
+                    self.emit_byte(Opcode::OpReturn, end);
+                }
+            },
+            StmtKind::Throw(value) => {
+                self.expression(value);
+                self.emit_byte(Opcode::OpThrow, value.end);
+            }
+            StmtKind::Yield { value, .. } => {
+                self.function.generator = true;
+                match value {
+                    Some(value) => self.expression(value),
+                    None => self.emit_byte(Opcode::OpNil, end),
+                }
+                self.emit_byte(Opcode::OpYield, end);
+            }
+            StmtKind::Try {
+                keyword,
+                body,
+                catch,
+                finally,
+            } => {
+                // the handler installed by `OpTry` is patched once the catch and finally
+                // targets are known
+                let handler = self.emit_jump(Opcode::OpTry(0, 0), *keyword);
+                self.block(body);
+                self.emit_byte(Opcode::OpPopHandler, body.close);
+
+                let mut catch_offset = 0;
+                if let Some(catch) = catch {
+                    let end_jump = self.emit_jump(Opcode::OpJump(0), catch.keyword);
+                    catch_offset = self.length() - handler;
+                    self.block(&catch.body);
+                    if finally.is_some() {
+                        self.emit_byte(Opcode::OpPopHandler, catch.body.close);
+                    }
+                    self.patch_jump(end_jump, &Opcode::OpJump(0));
+                }
+
+                let mut finally_offset = 0;
+                if let Some(finally) = finally {
+                    // the finally block starts with the pending completion (value and kind) in
+                    // two hidden locals, which `OpEndFinally` pops before resuming it
+                    let normal = self.make_constant(Value::Number(COMPLETION_NORMAL));
+                    self.emit_bytes(Opcode::OpNil, Opcode::OpConstant(normal), finally.keyword);
+                    finally_offset = self.length() - handler;
+                    self.block(&finally.body);
+                    self.emit_byte(Opcode::OpEndFinally, finally.body.close);
+                }
+
+                if catch_offset > u16::MAX as usize || finally_offset > u16::MAX as usize {
+                    self.error("Too much code in try statement", end);
+                }
+                self.replace_opcode(
+                    handler - 1,
+                    Opcode::OpTry(catch_offset as u16, finally_offset as u16),
+                );
+            }
+            StmtKind::Match(arms) => self.match_arms(arms, false),
         }
+    }
 
-        self.locals.push(local);
+    fn block(&mut self, block: &Block) {
+        self.declarations(&block.statements);
+        self.end_scope(block.locals, block.close);
     }
 
-    pub(crate) fn resolve_local(
+    fn for_statement(
         &mut self,
-        token: &Token,
-        errors: &mut Vec<&'static str>,
-    ) -> Option<usize> {
-        // let token = &self.parser.previous;
-        let local = self
-            .locals
-            .iter()
-            .enumerate()
-            .rev()
-            .find(|(_i, l)| Parser::identifiers_equal(token, &l.token));
+        initializer: Option<&Stmt>,
+        condition: Option<&Expr>,
+        semicolon: Span,
+        increment: Option<&Expr>,
+        paren: Span,
+        body: &Stmt,
+    ) {
+        if let Some(initializer) = initializer {
+            self.statement(initializer);
+        }
+        let mut loop_start = self.length();
+        let mut exit_jump: Option<usize> = None;
+        if let Some(condition) = condition {
+            self.expression(condition);
+            exit_jump = Some(self.emit_jump(Opcode::OpJumpIfFalse(0), semicolon));
+            self.emit_byte(Opcode::OpPop, semicolon);
+        }
+
+        if let Some(increment) = increment {
+            let body_jump = self.emit_jump(Opcode::OpJump(0), semicolon);
+            let incr_start = self.length();
+            self.expression(increment);
+            self.emit_byte(Opcode::OpPop, increment.end);
+
+            self.emit_loop(loop_start, paren);
+            loop_start = incr_start;
+            self.patch_jump(body_jump, &Opcode::OpJump(0))
+        }
+        self.statement(body);
+        self.emit_loop(loop_start, body.end);
+        if let Some(jump) = exit_jump {
+            self.patch_jump(jump, &Opcode::OpJumpIfFalse(0));
+            self.emit_byte(Opcode::OpPop, body.end);
+        }
+        let declares = matches!(
+            initializer.map(|initializer| &initializer.kind),
+            Some(StmtKind::Var { .. })
+        );
+        self.end_scope(declares as usize, body.end);
+    }
+
+    ///
+    /// The iterable and the iteration state live in two hidden locals, followed by the item
+    /// once `OpForIter` has pushed it.
+    fn for_in_statement(&mut self, iterable: &Expr, paren: Span, body: &Stmt) {
+        self.expression(iterable);
+        self.emit_constant(Value::Number(0.0), paren);
+
+        let loop_start = self.length();
+        let exit_jump = self.emit_jump(Opcode::OpForIter(0), paren);
+        self.statement(body);
+        self.end_scope(1, body.end);
+        self.emit_loop(loop_start, body.end);
+        self.patch_jump(exit_jump, &Opcode::OpForIter(0));
+        self.end_scope(2, body.end);
+    }
+
+    ///
+    /// In an expression the match evaluates to nil when no arm matches.
+    /// Arms with only literal patterns over dense integers or strings are dispatched through a
+    /// jump table instead of testing each pattern in turn.
+    fn match_arms(&mut self, arms: &Match, is_expression: bool) {
+        self.expression(&arms.subject);
+
+        let get_subject = if is_expression {
+            Opcode::OpDup
+        } else {
+            Opcode::OpGetLocal(arms.subject_slot)
+        };
+        self.emit_byte(get_subject.clone(), arms.open);
+        let table_jump = self.emit_jump(Opcode::OpJumpTable(0), arms.open);
+
+        let mut cases: Vec<(Value, usize)> = Vec::new();
+        let mut wildcard_body = None;
+        let mut dispatchable = true;
+        let mut end_jumps = Vec::new();
 
-        if let Some((i, l)) = local {
-            // println!("RESOLVE LOCAL AT {} LEN = {}", i, self.locals.len());
-            if l.depth == -1 {
-                errors.push("Can't read local variable in its own initializer")
+        for arm in &arms.arms {
+            let at = arm.patterns_end;
+            let guarded = arm.guard.is_some();
+            let mut body_jumps = Vec::new();
+            let mut fail_jumps = Vec::new();
+            let last = arm.patterns.len() - 1;
+            for (i, pattern) in arm.patterns.iter().enumerate() {
+                if self.pattern_test(pattern, &get_subject, at) {
+                    if i == last {
+                        fail_jumps.push(self.emit_jump(Opcode::OpJumpIfFalse(0), at));
+                        self.emit_byte(Opcode::OpPop, at);
+                    } else {
+                        let skip = self.emit_jump(Opcode::OpJumpIfFalse(0), at);
+                        self.emit_byte(Opcode::OpPop, at);
+                        body_jumps.push(self.emit_jump(Opcode::OpJump(0), at));
+                        self.patch_jump(skip, &Opcode::OpJumpIfFalse(0));
+                        self.emit_byte(Opcode::OpPop, at);
+                    }
+                } else if i != last {
+                    body_jumps.push(self.emit_jump(Opcode::OpJump(0), at));
+                }
             }
-            Some(i)
+            for jump in body_jumps {
+                self.patch_jump(jump, &Opcode::OpJump(0));
+            }
+
+            let body = self.length();
+            if let Some(guard) = &arm.guard {
+                self.expression(guard);
+                fail_jumps.push(self.emit_jump(Opcode::OpJumpIfFalse(0), guard.end));
+                self.emit_byte(Opcode::OpPop, guard.end);
+                dispatchable = false;
+            }
+            for pattern in &arm.patterns {
+                match pattern {
+                    Pattern::Wildcard if !guarded => {
+                        wildcard_body.get_or_insert(body);
+                    }
+                    Pattern::Literal(value) if !guarded => {
+                        if !cases.iter().any(|(seen, _)| seen == value) {
+                            cases.push((value.clone(), body));
+                        }
+                    }
+                    Pattern::Literal(_) | Pattern::Wildcard => {}
+                    Pattern::Range(..) => dispatchable = false,
+                }
+            }
+
+            match &arm.body {
+                ArmBody::Expression(value) => {
+                    self.emit_byte(Opcode::OpPop, arm.arrow);
+                    self.expression(value);
+                }
+                ArmBody::Statement(statement) => self.statement(statement),
+            }
+            end_jumps.push(self.emit_jump(Opcode::OpJump(0), arm.end));
+
+            for jump in &fail_jumps {
+                self.patch_jump(*jump, &Opcode::OpJumpIfFalse(0));
+            }
+            if !fail_jumps.is_empty() {
+                self.emit_byte(Opcode::OpPop, arm.end);
+            }
+        }
+
+        let no_match = self.length();
+        if is_expression {
+            self.emit_bytes(Opcode::OpPop, Opcode::OpNil, arms.close);
+        }
+        for jump in end_jumps {
+            self.patch_jump(jump, &Opcode::OpJump(0));
+        }
+
+        let default = wildcard_body.unwrap_or(no_match);
+        let dispatch = if dispatchable {
+            Compiler::jump_table(&cases, default, table_jump)
         } else {
             None
+        };
+        match dispatch {
+            Some(table) => {
+                let index = self.chunk().add_jump_table(table);
+                self.replace_opcode(table_jump - 1, Opcode::OpJumpTable(index));
+            }
+            // drop the subject pushed for the table
+            None => self.replace_opcode(table_jump - 1, Opcode::OpPop),
         }
 
-        // local.map(|a| a.0)
+        if !is_expression {
+            // the hidden local holding the subject
+            self.end_scope(1, arms.close);
+        }
     }
-}
 
-pub fn number(parser: &mut Parser, _can_assign: bool) {
-    match &parser.previous.token_type {
-        TokenType::Number(num) => parser.emit_constant(Value::Number(*num), parser.previous.line),
-        _ => panic!("unexpected token type"),
+    ///
+    /// Emits code leaving whether the subject matches `pattern` on the stack. Returns false,
+    /// emitting nothing, for the wildcard.
+    fn pattern_test(&mut self, pattern: &Pattern, get_subject: &Opcode, at: Span) -> bool {
+        match pattern {
+            Pattern::Wildcard => return false,
+            Pattern::Literal(value) => {
+                self.emit_byte(get_subject.clone(), at);
+                self.emit_constant(value.clone(), at);
+                self.emit_byte(Opcode::OpEqual, at);
+            }
+            Pattern::Range(lower, upper, inclusive) => {
+                self.emit_byte(get_subject.clone(), at);
+                self.emit_constant(lower.clone(), at);
+                self.emit_constant(upper.clone(), at);
+                self.emit_byte(Opcode::OpInRange(*inclusive), at);
+            }
+        }
+        true
     }
-}
 
-pub fn string(parser: &mut Parser, _can_assign: bool) {
-    match &parser.previous.token_type {
-        TokenType::String(str) => {
-            //dbg!(str);
-            parser.emit_constant(Value::new_string(str), parser.previous.line)
+    ///
+    /// A jump table for `cases` when they are at least three integers spread over no more than
+    /// twice as many values, or at least three strings.
+    fn jump_table(cases: &[(Value, usize)], default: usize, from: usize) -> Option<JumpTable> {
+        if cases.len() < 3 {
+            return None;
+        }
+        let offset = |target: usize| u16::try_from(target - from).ok();
+        let mut table = JumpTable {
+            default: offset(default)?,
+            ..JumpTable::default()
+        };
+
+        let integers: Option<Vec<i64>> = cases.iter().map(|(value, _)| value.to_i64()).collect();
+        if let Some(keys) = integers {
+            let min = *keys.iter().min().unwrap();
+            let span = keys
+                .iter()
+                .max()
+                .unwrap()
+                .checked_sub(min)?
+                .checked_add(1)?;
+            if span > 2 * cases.len() as i64 {
+                return None;
+            }
+            table.min = min;
+            table.dense = vec![None; span as usize];
+            for (key, (_, target)) in keys.iter().zip(cases) {
+                table.dense[(key - min) as usize] = Some(offset(*target)?);
+            }
+        } else if cases.iter().all(|(value, _)| value.is_string()) {
+            for (value, target) in cases {
+                let key = value.as_string().unwrap().clone();
+                table.strings.insert(key, offset(*target)?);
+            }
+        } else {
+            return None;
         }
-        _ => panic!("unexpected token type"),
+        Some(table)
     }
-}
 
-///
-///
-pub fn variable(parser: &mut Parser, can_assign: bool) {
-    named_variable(parser, can_assign);
-}
+    ///
+    /// Compiles `function` into a chunk of its own and loads it as a constant.
+    fn function(&mut self, function: &Function) {
+        let index = self.chunks.allocate_chunk();
+        let mut compiled =
+            ObjectFunction::new(FunctionType::Function, function.name.clone(), index);
+        compiled.module = self.function.module;
+        compiled.doc = function.doc.clone();
+        compiled.arity = function.params.len() as u8;
+        compiled.min_arity = function
+            .params
+            .iter()
+            .take_while(|param| param.default.is_none())
+            .count() as u8;
+        compiled.variadic = function.rest.is_some();
+        compiled.params = function
+            .params
+            .iter()
+            .map(|param| param.name.name.clone())
+            .collect();
 
-///
-///
-pub fn named_variable(parser: &mut Parser, can_assign: bool) {
-    let _index = parser.identifier_constant();
-
-    let (get_op, set_op) = if let Some(index) = parser.resolve_local() {
-        (Opcode::OpGetLocal(index), Opcode::OpSetLocal(index))
-    } else {
-        let index = parser.identifier_constant();
-        (Opcode::OpGetGlobal(index), Opcode::OpSetGlobal(index))
-    };
-
-    if can_assign && parser.match_token(TokenType::Equal) {
-        parser.expression();
-        parser.emit_byte(set_op, parser.previous.line)
-    } else {
-        parser.emit_byte(get_op, parser.previous.line)
+        let enclosing = mem::replace(&mut self.function, compiled);
+        for param in &function.params {
+            if let (Some((equal, default)), Target::Local(slot)) =
+                (&param.default, param.name.target)
+            {
+                self.default_value(slot, *equal, default);
+            }
+        }
+        match &function.body {
+            FunctionBody::Block(statements) => self.declarations(statements),
+            FunctionBody::Expression(value) => {
+                self.expression(value);
+                self.emit_byte(Opcode::OpReturn, value.end);
+            }
+        }
+        self.emit_return(function.end);
+        let compiled = mem::replace(&mut self.function, enclosing);
+
+        self.emit_constant(Value::Function(Arc::new(compiled)), function.end);
     }
-}
 
-///
-///
-pub fn grouping(compiler: &mut Parser, _can_assign: bool) {
-    compiler.expression();
-    compiler.consume(TokenType::RightParen, "Expected ')' after expression")
-}
+    ///
+    /// Emitted at the start of the function body: the caller passes nil for a missing
+    /// argument, in which case the default expression is evaluated into the parameter slot.
+    fn default_value(&mut self, slot: usize, equal: Span, default: &Expr) {
+        self.emit_bytes(Opcode::OpGetLocal(slot), Opcode::OpNil, equal);
+        self.emit_byte(Opcode::OpEqual, equal);
+        let skip_jump = self.emit_jump(Opcode::OpJumpIfFalse(0), equal);
+        self.emit_byte(Opcode::OpPop, equal);
+        self.expression(default);
+        self.emit_byte(Opcode::OpSetLocal(slot), default.end);
+        // both paths leave exactly one value behind: the comparison or the default
+        self.patch_jump(skip_jump, &Opcode::OpJumpIfFalse(0));
+        self.emit_byte(Opcode::OpPop, default.end);
+    }
 
-///
-///
-pub fn literal(parser: &mut Parser, _can_assign: bool) {
-    let token_type = &parser.previous.token_type.clone();
-    match token_type {
-        TokenType::False => parser.emit_byte(Opcode::OpFalse, parser.previous.line),
-        TokenType::Nil => parser.emit_byte(Opcode::OpNil, parser.previous.line),
-        TokenType::True => parser.emit_byte(Opcode::OpTrue, parser.previous.line),
-        _ => {}
+    fn expression(&mut self, expression: &Expr) {
+        let end = expression.end;
+        match &expression.kind {
+            ExprKind::Literal(value) => {
+                match value {
+                    Value::Boolean(true) => self.emit_byte(Opcode::OpTrue, end),
+                    Value::Boolean(false) => self.emit_byte(Opcode::OpFalse, end),
+                    Value::Nil => self.emit_byte(Opcode::OpNil, end),
+                    value => self.emit_constant(value.clone(), end),
+                }
+                self.mark_constant();
+            }
+            ExprKind::Variable(variable) => {
+                let (get_op, _) = self.variable_ops(variable);
+                self.emit_byte(get_op, end);
+            }
+            ExprKind::Assign { target, value } => {
+                let (_, set_op) = self.variable_ops(target);
+                self.expression(value);
+                self.emit_byte(set_op, end);
+            }
+            ExprKind::CompoundAssign {
+                target,
+                op,
+                op_span,
+                value,
+            } => {
+                let (get_op, set_op) = self.variable_ops(target);
+                self.emit_byte(get_op, *op_span);
+                self.expression(value);
+                self.emit_bytes(Compiler::binary_ops(*op)[0].clone(), set_op, end);
+            }
+            ExprKind::Increment { target, op, prefix } => {
+                let (get_op, set_op) = self.variable_ops(target);
+                let step = match op {
+                    BinaryOp::Subtract => Value::Int(-1),
+                    _ => Value::Int(1),
+                };
+                if *prefix {
+                    self.emit_byte(get_op, end);
+                    self.emit_constant(step, end);
+                    self.emit_bytes(Opcode::OpIncrement, set_op, end);
+                } else {
+                    // postfix: leave the old value on the stack
+                    self.emit_bytes(get_op, Opcode::OpDup, end);
+                    self.emit_constant(step, end);
+                    self.emit_bytes(Opcode::OpIncrement, set_op, end);
+                    self.emit_byte(Opcode::OpPop, end);
+                }
+            }
+            ExprKind::Unary { op, operand } => {
+                let operand_start = self.length();
+                self.expression(operand);
+
+                let op = match op {
+                    UnaryOp::Not => Opcode::OpNot,
+                    UnaryOp::Negate => Opcode::OpNegate,
+                    UnaryOp::BitNot => Opcode::OpBitNot,
+                };
+                if let Some(operand) = self.constant_operand(operand_start) {
+                    if self.fold(operand_start, operators::unary_op(&op, operand, false), end) {
+                        return;
+                    }
+                }
+                self.emit_byte(op, end)
+            }
+            ExprKind::Binary { op, left, right } => {
+                self.expression(left);
+                let left = self
+                    .length()
+                    .checked_sub(1)
+                    .and_then(|start| self.constant_operand(start));
+                let right_start = self.length();
+                self.expression(right);
+
+                let ops = Compiler::binary_ops(*op);
+                if let (Some(left), Some(right)) = (left, self.constant_operand(right_start)) {
+                    let result =
+                        operators::binary_op(&ops[0], left, right, false).and_then(|value| {
+                            ops[1..]
+                                .iter()
+                                .try_fold(value, |value, op| operators::unary_op(op, value, false))
+                        });
+                    if self.fold(right_start - 1, result, end) {
+                        return;
+                    }
+                }
+                for op in ops {
+                    self.emit_byte(op, end);
+                }
+            }
+            ExprKind::Logical {
+                op: LogicalOp::And,
+                op_span,
+                left,
+                right,
+            } => {
+                self.expression(left);
+                let end_jump = self.emit_jump(Opcode::OpJumpIfFalse(0), *op_span);
+                self.emit_byte(Opcode::OpPop, *op_span);
+                self.expression(right);
+                self.patch_jump(end_jump, &Opcode::OpJumpIfFalse(0))
+            }
+            ExprKind::Logical {
+                op: LogicalOp::Or,
+                op_span,
+                left,
+                right,
+            } => {
+                self.expression(left);
+                let else_jump = self.emit_jump(Opcode::OpJumpIfFalse(0), *op_span);
+                let end_jump = self.emit_jump(Opcode::OpJump(0), *op_span);
+
+                self.patch_jump(else_jump, &Opcode::OpJumpIfFalse(0));
+
+                self.emit_byte(Opcode::OpPop, *op_span);
+                self.expression(right);
+                self.patch_jump(end_jump, &Opcode::OpJump(0));
+            }
+            ExprKind::Coalesce {
+                op_span,
+                left,
+                right,
+            } => {
+                self.expression(left);
+                self.emit_bytes(Opcode::OpDup, Opcode::OpNil, *op_span);
+                self.emit_byte(Opcode::OpEqual, *op_span);
+                let not_nil_jump = self.emit_jump(Opcode::OpJumpIfFalse(0), *op_span);
+                // drop the comparison and the nil left operand
+                self.emit_bytes(Opcode::OpPop, Opcode::OpPop, *op_span);
+                self.expression(right);
+                let end_jump = self.emit_jump(Opcode::OpJump(0), right.end);
+
+                self.patch_jump(not_nil_jump, &Opcode::OpJumpIfFalse(0));
+                self.emit_byte(Opcode::OpPop, right.end);
+                self.patch_jump(end_jump, &Opcode::OpJump(0));
+            }
+            ExprKind::Conditional {
+                condition,
+                question,
+                then_branch,
+                colon,
+                else_branch,
+            } => {
+                self.expression(condition);
+                let then_jump = self.emit_jump(Opcode::OpJumpIfFalse(0), *question);
+                self.emit_byte(Opcode::OpPop, *question);
+                self.expression(then_branch);
+
+                let else_jump = self.emit_jump(Opcode::OpJump(0), *colon);
+                self.patch_jump(then_jump, &Opcode::OpJumpIfFalse(0));
+                self.emit_byte(Opcode::OpPop, *colon);
+                self.expression(else_branch);
+                self.patch_jump(else_jump, &Opcode::OpJump(0));
+            }
+            ExprKind::Call { callee, arguments } => {
+                self.expression(callee);
+                let call_op = self.arguments(arguments, end);
+                self.emit_byte(call_op, end);
+            }
+            ExprKind::Get {
+                object,
+                name,
+                optional,
+            } => {
+                self.expression(object);
+                let name = self.make_constant(Value::String(name.clone()));
+                if *optional {
+                    // a nil object is left on the stack as the result
+                    self.emit_bytes(Opcode::OpDup, Opcode::OpNil, end);
+                    self.emit_byte(Opcode::OpEqual, end);
+                    let not_nil_jump = self.emit_jump(Opcode::OpJumpIfFalse(0), end);
+                    self.emit_byte(Opcode::OpPop, end);
+                    let end_jump = self.emit_jump(Opcode::OpJump(0), end);
+
+                    self.patch_jump(not_nil_jump, &Opcode::OpJumpIfFalse(0));
+                    self.emit_byte(Opcode::OpPop, end);
+                    self.emit_byte(Opcode::OpGetProperty(name), end);
+                    self.patch_jump(end_jump, &Opcode::OpJump(0));
+                } else {
+                    self.emit_byte(Opcode::OpGetProperty(name), end);
+                }
+            }
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Function(function) => self.function(function),
+            ExprKind::Match(arms) => self.match_arms(arms, true),
+        }
     }
-}
 
-///
-///
-pub fn unary(parser: &mut Parser, _can_assign: bool) {
-    let token_type = &parser.previous.token_type.clone();
-    // compile the operand
-    parser.parse_precedence(&Precedence::Unary);
+    ///
+    /// The instructions applying `op`, the negated comparisons as their opposite and `OpNot`.
+    fn binary_ops(op: BinaryOp) -> Vec<Opcode> {
+        match op {
+            BinaryOp::Add => vec![Opcode::OpAdd],
+            BinaryOp::Subtract => vec![Opcode::OPSubtract],
+            BinaryOp::Multiply => vec![Opcode::OPMultiply],
+            BinaryOp::Divide => vec![Opcode::OpDivide],
+            BinaryOp::Modulo => vec![Opcode::OpModulo],
+            BinaryOp::Power => vec![Opcode::OpPower],
+            BinaryOp::IntDivide => vec![Opcode::OpIntDivide],
+            BinaryOp::BitAnd => vec![Opcode::OpBitAnd],
+            BinaryOp::BitOr => vec![Opcode::OpBitOr],
+            BinaryOp::BitXor => vec![Opcode::OpBitXor],
+            BinaryOp::ShiftLeft => vec![Opcode::OpShiftLeft],
+            BinaryOp::ShiftRight => vec![Opcode::OpShiftRight],
 
-    // Emit the operator instruction
-    match token_type {
-        TokenType::Bang => parser.emit_byte(Opcode::OpNot, parser.previous.line),
-        TokenType::Minus => parser.emit_byte(Opcode::OpNegate, parser.previous.line),
-        _ => {}
+            BinaryOp::NotEqual => vec![Opcode::OpEqual, Opcode::OpNot],
+            BinaryOp::Equal => vec![Opcode::OpEqual],
+            BinaryOp::Greater => vec![Opcode::OpGreater],
+            BinaryOp::GreaterEqual => vec![Opcode::OpLess, Opcode::OpNot],
+            BinaryOp::Less => vec![Opcode::OpLess],
+            BinaryOp::LessEqual => vec![Opcode::OpGreater, Opcode::OpNot],
+        }
     }
-}
-///
-///
-pub fn binary(parser: &mut Parser, _can_assign: bool) {
-    let token_type = &parser.previous.token_type.clone();
-    // compile the operand
-
-    let rule = ParserRule::get_rule(token_type);
-    let prec_u8 = (*rule.precedence) as u8;
-    let precedence: Precedence = FromPrimitive::from_u8(prec_u8 + 1).unwrap();
-    parser.parse_precedence(&precedence);
-    //  let rule = self.getRule(token_type);
-    // self.parse_precedence()
-
-    // Emit the operator instruction
-    match token_type {
-        TokenType::Plus => parser.emit_byte(Opcode::OpAdd, parser.previous.line),
-        TokenType::Minus => parser.emit_byte(Opcode::OPSubtract, parser.previous.line),
-        TokenType::Star => parser.emit_byte(Opcode::OPMultiply, parser.previous.line),
-        TokenType::Slash => parser.emit_byte(Opcode::OpDivide, parser.previous.line),
-
-        TokenType::BangEqual => parser.emit_byte(Opcode::OpEqual, parser.previous.line),
-        TokenType::EqualEqual => parser.emit_byte(Opcode::OpEqual, parser.previous.line),
-        TokenType::Greater => parser.emit_byte(Opcode::OpGreater, parser.previous.line),
-        TokenType::GreaterEqual => {
-            parser.emit_bytes(Opcode::OpLess, Opcode::OpNot, parser.previous.line)
-        }
-        TokenType::Less => parser.emit_byte(Opcode::OpLess, parser.previous.line),
-        TokenType::LessEqual => {
-            parser.emit_bytes(Opcode::OpGreater, Opcode::OpNot, parser.previous.line)
-        }
-        _ => {}
+
+    ///
+    /// Compiles the call arguments and returns the call instruction matching their shape.
+    /// Positional arguments before and between spreads are packed into lists.
+    fn arguments(&mut self, arguments: &[Argument], close: Span) -> Opcode {
+        let mut names: Vec<Value> = Vec::new();
+        // positional arguments since the last spread, packed into a list once a spread is seen
+        let mut pending = 0u8;
+        let mut spread_groups: Option<u8> = None;
+        for argument in arguments {
+            match argument {
+                Argument::Spread(ellipsis, value) => {
+                    let groups = spread_groups.unwrap_or(0);
+                    if pending > 0 {
+                        self.emit_byte(Opcode::OpBuildList(pending), *ellipsis);
+                    }
+                    self.expression(value);
+                    spread_groups = Some(groups + (pending > 0) as u8 + 1);
+                    pending = 0;
+                }
+                Argument::Named(name, value) => {
+                    names.push(Value::String(name.clone()));
+                    self.expression(value);
+                }
+                Argument::Positional(value) => {
+                    self.expression(value);
+                    pending += 1;
+                }
+            }
+        }
+
+        if let Some(groups) = spread_groups {
+            if pending > 0 {
+                self.emit_byte(Opcode::OpBuildList(pending), close);
+            }
+            Opcode::OpCallSpread(groups + (pending > 0) as u8)
+        } else if !names.is_empty() {
+            Opcode::OpCallNamed(
+                arguments.len() as u8,
+                self.make_constant(Value::List(names)),
+            )
+        } else {
+            Opcode::OpCall(arguments.len() as u8)
+        }
     }
-}
 
-///
-///
-pub fn and(parser: &mut Parser, _can_assign: bool) {
-    let end_jump = parser.emit_jump(OpJumpIfFalse(0));
-    parser.emit_byte(Opcode::OpPop, parser.previous.line);
-    parser.parse_precedence(&Precedence::And);
-    parser.patch_jump(end_jump, &Opcode::OpJumpIfFalse(0))
-}
+    ///
+    /// Get and set instructions for `variable`. Reads of a constant bound to a literal load
+    /// the literal instead.
+    fn variable_ops(&mut self, variable: &Variable) -> (Opcode, Opcode) {
+        let (get_op, set_op) = match variable.target {
+            Target::Local(slot) => (Opcode::OpGetLocal(slot), Opcode::OpSetLocal(slot)),
+            Target::Global => {
+                let index = self.make_constant(Value::String(variable.name.clone()));
+                (Opcode::OpGetGlobal(index), Opcode::OpSetGlobal(index))
+            }
+        };
+        match &variable.binding {
+            Binding::Literal(value) => {
+                let index = self.make_constant(value.clone());
+                (Opcode::OpConstant(index), set_op)
+            }
+            _ => (get_op, set_op),
+        }
+    }
 
-///
-///
-pub fn or(parser: &mut Parser, _can_assign: bool) {
-    let else_jump = parser.emit_jump(OpJumpIfFalse(0));
-    let end_jump = parser.emit_jump(Opcode::OpJump(0));
+    ///
+    /// The constant holding the name of a global declaration; locals need no instructions.
+    fn declare(&mut self, name: &Name) -> Option<usize> {
+        match name.target {
+            Target::Global => Some(self.make_constant(Value::String(name.name.clone()))),
+            Target::Local(_) => None,
+        }
+    }
 
-    parser.patch_jump(else_jump, &Opcode::OpJumpIfFalse(0));
+    fn define_variable(&mut self, global: Option<usize>, at: Span) {
+        if let Some(index) = global {
+            self.emit_byte(Opcode::OpDefineGlobal(index), at)
+        }
+    }
 
-    parser.emit_byte(Opcode::OpPop, parser.previous.line);
-    parser.parse_precedence(&Precedence::Or);
-    parser.patch_jump(end_jump, &Opcode::OpJump(0));
-}
-///
-///
-pub fn call(parser: &mut Parser, _can_assign: bool) {
-    let arg_count: u8 = parser.argument_list();
+    ///
+    /// Pops the `locals` going out of scope.
+    fn end_scope(&mut self, locals: usize, at: Span) {
+        for _ in 0..locals {
+            self.emit_byte(Opcode::OpPop, at);
+        }
+    }
+
+    ///
+    /// Compiles the module at `name`, unless it already was, and returns its index.
+    fn load_module(&mut self, name: &str, at: Span) -> usize {
+        let importer = self.chunks.modules[self.function.module].path.clone();
+        let path = match module::resolve(name, importer.as_deref(), self.search_path) {
+            Some(path) => path,
+            None => {
+                self.error(format!("Cannot find module '{}'", name).as_str(), at);
+                return 0;
+            }
+        };
+
+        if let Some(index) = self
+            .chunks
+            .modules
+            .iter()
+            .position(|m| m.path.as_ref() == Some(&path))
+        {
+            if self.chunks.loading.contains(&index) {
+                let cycle: Vec<String> = self.chunks.loading[self
+                    .chunks
+                    .loading
+                    .iter()
+                    .position(|i| *i == index)
+                    .unwrap()..]
+                    .iter()
+                    .map(|i| self.chunks.modules[*i].name())
+                    .chain(std::iter::once(self.chunks.modules[index].name()))
+                    .collect();
+                self.error(format!("Import cycle: {}", cycle.join(" -> ")).as_str(), at);
+            }
+            return index;
+        }
 
-    parser.emit_byte(Opcode::OpCall(arg_count), parser.previous.line);
+        self.load_path(&path, name, at)
+    }
+
+    ///
+    /// Compiles the module file at `path`, or reads it from its compiled file when caching
+    /// compiled modules, and returns its index.
+    fn load_path(&mut self, path: &Path, name: &str, at: Span) -> usize {
+        let stamp = module::CompiledStamp::of(path, self.fold_constants, self.tail_calls);
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(err) => {
+                self.error(
+                    format!("Cannot read module '{}': {}", name, err).as_str(),
+                    at,
+                );
+                return 0;
+            }
+        };
+        self.chunks
+            .modules
+            .push(Module::new(Some(path.to_path_buf()), source.clone()));
+        let index = self.chunks.modules.len() - 1;
+
+        self.chunks.loading.push(index);
+        let compiled = match &stamp {
+            Some(stamp) if self.cache_modules => self.load_compiled(path, index, stamp, at),
+            _ => None,
+        };
+        let cached = compiled.is_some();
+        let result = match compiled {
+            Some(function) => Ok(function),
+            None => {
+                let mut parser = Parser::new_module(
+                    &source,
+                    self.chunks,
+                    index,
+                    self.search_path.to_vec(),
+                    self.fold_constants,
+                    self.tail_calls,
+                );
+                parser.cache_modules = self.cache_modules;
+                parser.compile().map(|function| function.clone())
+            }
+        };
+        self.chunks.loading.pop();
+
+        match result {
+            Ok(function) => {
+                self.chunks.modules[index].function = Some(function);
+                if let Some(stamp) = stamp.filter(|_| self.cache_modules && !cached) {
+                    // a module that can't be cached is compiled from its source every time
+                    let output = module::compiled_path(path);
+                    let _ = module::write_compiled(self.chunks, index, &stamp, &output);
+                }
+            }
+            Err(err) => {
+                if !self.panic_mode {
+                    self.panic_mode = true;
+                    self.result = Some(err.imported(at, path));
+                }
+            }
+        }
+        index
+    }
+
+    ///
+    /// Adds the chunks of the module with this index from its compiled file, after loading the
+    /// modules it imports, and returns its function. None if there is no compiled file that is
+    /// up to date or one of its imports is gone.
+    fn load_compiled(
+        &mut self,
+        path: &Path,
+        index: usize,
+        stamp: &module::CompiledStamp,
+        at: Span,
+    ) -> Option<ObjectFunction> {
+        let compiled = module::read_compiled(&module::compiled_path(path), stamp)?;
+        if !compiled.imports.iter().all(|import| import.is_file()) {
+            return None;
+        }
+        let mut modules = vec![index];
+        for import in &compiled.imports {
+            let loaded = self
+                .chunks
+                .modules
+                .iter()
+                .position(|m| m.path.as_ref() == Some(import));
+            let module = match loaded {
+                // compiling it from its source reports the import cycle
+                Some(module) if self.chunks.loading.contains(&module) => return None,
+                Some(module) => module,
+                None => self.load_path(import, &import.display().to_string(), at),
+            };
+            modules.push(module);
+        }
+
+        let base = self.chunks.chunks.len();
+        for mut chunk in compiled.chunks {
+            chunk.relocate(|chunk| chunk + base, |module| modules[module]);
+            self.chunks.chunks.push(chunk);
+        }
+        let mut function = compiled.function;
+        function.chunk_index += base;
+        function.module = index;
+        Some(function)
+    }
+
+    ///
+    ///
+    ///
+    fn emit_jump(&mut self, opcode: Opcode, at: Span) -> usize {
+        self.emit_byte(opcode, at);
+        self.length()
+    }
+
+    ///
+    ///
+    fn emit_loop(&mut self, loop_start: usize, at: Span) {
+        self.emit_byte(Opcode::OpLoop(0), at);
+        let len = self.length();
+        let offset = len - loop_start;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large", at);
+        }
+        self.replace_opcode(len - 1, Opcode::OpLoop(offset as u16));
+    }
+
+    ///
+    ///
+    ///
+    fn patch_jump(&mut self, offset: usize, opcode: &Opcode) {
+        // the jump lands after the code so far, which can't be folded away anymore
+        self.constant_tail = None;
+        let jump = self.length() - offset;
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over", self.last_span);
+        }
+
+        let patched_opcode = match opcode {
+            Opcode::OpJumpIfFalse(_) => Opcode::OpJumpIfFalse(jump as u16),
+            Opcode::OpJump(_) => Opcode::OpJump(jump as u16),
+            Opcode::OpForIter(_) => Opcode::OpForIter(jump as u16),
+            _ => {
+                panic!("Not a jumpable opcode")
+            }
+        };
+
+        self.replace_opcode(offset - 1, patched_opcode);
+    }
+
+    fn error(&mut self, msg: &str, at: Span) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.result = Some(ParserError::report(at, msg));
+    }
+
+    ///
+    ///
+    fn emit_byte(&mut self, byte: Opcode, at: Span) {
+        self.last_span = at;
+        self.chunk()
+            .write_chunk_at(byte, at.line, (at.start, at.len));
+    }
+    ///
+    ///
+    fn emit_bytes(&mut self, byte1: Opcode, byte2: Opcode, at: Span) {
+        self.emit_byte(byte1, at);
+        self.emit_byte(byte2, at);
+    }
+    ///
+    ///
+    fn emit_return(&mut self, at: Span) {
+        self.emit_byte(Opcode::OpNil, at);
+        self.emit_byte(Opcode::OpReturn, at);
+    }
+    ///
+    ///
+    fn emit_constant(&mut self, value: Value, at: Span) {
+        let idx = self.make_constant(value);
+        self.emit_byte(Opcode::OpConstant(idx), at)
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        let index = self.function.chunk_index;
+        self.chunks.chunks.get_mut(index).unwrap()
+    }
+
+    fn make_constant(&mut self, value: Value) -> usize {
+        self.chunk().add_constant(value)
+    }
+
+    fn length(&mut self) -> usize {
+        self.chunk().op_codes.len()
+    }
+
+    fn replace_opcode(&mut self, index: usize, bytes: Opcode) {
+        self.chunk().replace_opcode(index, bytes);
+    }
+
+    ///
+    /// Records that the instruction just emitted is a whole expression loading a constant.
+    fn mark_constant(&mut self) {
+        self.constant_tail = Some(self.length());
+    }
+
+    ///
+    /// The value of the operand compiled from `start`, if it is a single constant instruction
+    /// that folding may replace.
+    fn constant_operand(&mut self, start: usize) -> Option<Value> {
+        let len = self.length();
+        if !self.fold_constants || self.constant_tail != Some(len) || len != start + 1 {
+            return None;
+        }
+        self.chunk().constant_value(start)
+    }
+
+    ///
+    /// Replaces the constant operands compiled from `start` with the result of their operator,
+    /// unless evaluating it raises an error, which is then left to happen at runtime.
+    fn fold(&mut self, start: usize, result: Result<Value, String>, at: Span) -> bool {
+        let value = match result {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        self.chunk().truncate(start);
+        match value {
+            Value::Boolean(true) => self.emit_byte(Opcode::OpTrue, at),
+            Value::Boolean(false) => self.emit_byte(Opcode::OpFalse, at),
+            Value::Nil => self.emit_byte(Opcode::OpNil, at),
+            value => self.emit_constant(value, at),
+        }
+        self.mark_constant();
+        true
+    }
 }
@@ -1,16 +1,14 @@
 use clap::{arg, command};
 
-use std::fs::File;
-use std::io;
-use std::io::{Read, Write};
-use std::path::Path;
+use rox::module;
+use std::path::{Path, PathBuf};
 
 fn main() {
     let matches = command!()
         .arg(arg!([file] " the file to compile").required(true))
         .arg(
             arg!(
-                -o --output <FILENAME> "Write output to <filename>"
+                -o --output <FILENAME> "Write output to <filename>, by default the file imports read it from"
             )
             // We don't have syntax yet for optional options, so manually calling `required`
             .required(false),
@@ -20,45 +18,23 @@ fn main() {
         ))
         .get_matches();
 
-    let mut out_writer = match matches.value_of("output") {
-        Some(x) => {
-            println!("{:?}", x);
-            let path = Path::new(x);
-            Box::new(File::create(&path).unwrap()) as Box<dyn Write>
-        }
-        None => Box::new(io::stdout()) as Box<dyn Write>,
-    };
-
     if let Some(name) = matches.value_of("file") {
-        compile_file(&name.to_string(), &mut out_writer)
+        compile_file(Path::new(name), matches.value_of("output").map(Path::new))
     }
 }
 
-fn compile_file(path: &String, _write: &mut Box<dyn Write>) {
-    match File::open(path) {
-        // The file is open (no error).
-        Ok(mut file) => {
-            let mut content = String::new();
-
-            // Read all the file content into a variable (ignoring the result of the operation).
-            file.read_to_string(&mut content).unwrap();
-
-            // let mut chunk = Chunk::new();
-            // let mut compiler = Compiler::new(content.as_str(), &mut chunk);
-            // if !compiler.compile() {
-            //     //return InterpretResult::CompileError;
-            // } else {
-            //     chunk.to_bytes()
-            //     chunk.disassemble_chunk(write);
-            //     file.flush();
-            //
-            // }
-
-            // The file is automatically closed when is goes out of scope.
-        }
-        // Error handling.
-        Err(error) => {
-            println!("Error opening file {}: {}", path, error);
+///
+/// Compiles the module at `path` and the modules it imports into the files `row --cache-modules`
+/// imports them from.
+fn compile_file(path: &Path, output: Option<&Path>) {
+    let search_path: Vec<PathBuf> = std::env::var_os("LOX_PATH")
+        .map(|paths| std::env::split_paths(&paths).collect())
+        .unwrap_or_default();
+    match module::compile_file(path, output, &search_path) {
+        Ok(output) => println!("{}", output.display()),
+        Err(err) => {
+            eprintln!("{:?}", err);
+            std::process::exit(65);
         }
     }
 }
@@ -3,6 +3,8 @@ use miette::{Diagnostic, SourceSpan};
 
 use thiserror::Error;
 
+use crate::value::Value;
+
 #[derive(Error, Debug, Diagnostic)]
 #[diagnostic(code(oops::lox::compileError))]
 #[error("{label}")]
@@ -18,15 +20,64 @@ pub struct LoxCompileError {
     pub(crate) label: String,
 }
 
+/// Raised while running a chunk, either by the VM itself or by a `throw` statement,
+/// in which case it carries the thrown value.
 #[derive(Error, Debug, Diagnostic)]
-#[error("Runtime Error")]
+#[error("{message}")]
 #[diagnostic(code(oops::lox::runtimeError), help("A runtime error occurred"))]
-pub struct LoxRuntimeError {}
+pub struct LoxRuntimeError {
+    pub(crate) message: String,
+    pub(crate) thrown: Option<Value>,
+    /// where an exception rethrown at the end of a finally block was first raised
+    pub(crate) site: Option<ThrowSite>,
+}
 
 impl LoxRuntimeError {
-    pub fn new(_msg: &str) -> Self {
-        LoxRuntimeError {}
+    pub fn new(msg: &str) -> Self {
+        LoxRuntimeError {
+            message: msg.to_string(),
+            thrown: None,
+            site: None,
+        }
+    }
+
+    pub fn thrown(value: Value) -> Self {
+        LoxRuntimeError {
+            message: value.to_string(),
+            thrown: Some(value),
+            site: None,
+        }
     }
+
+    pub(crate) fn rethrown(value: Value, site: Option<ThrowSite>) -> Self {
+        LoxRuntimeError {
+            site,
+            ..LoxRuntimeError::thrown(value)
+        }
+    }
+}
+
+/// The span and module of the instruction that raised an exception, and the stack trace there.
+#[derive(Debug, Clone)]
+pub(crate) struct ThrowSite {
+    pub(crate) span: (usize, usize),
+    pub(crate) module: usize,
+    pub(crate) trace: Vec<String>,
+}
+
+/// An exception no `catch` handled, reported at the instruction that raised it.
+#[derive(Error, Debug, Diagnostic)]
+#[diagnostic(code(oops::lox::uncaughtException))]
+#[error("Uncaught {message}")]
+pub struct LoxUncaughtError {
+    #[source_code]
+    pub(crate) src: NamedSource,
+    #[label("thrown here")]
+    pub(crate) bad_bit: SourceSpan,
+
+    pub(crate) message: String,
+    #[help]
+    pub(crate) trace: String,
 }
 
 // #[derive(Error, Debug, Diagnostic)]
@@ -0,0 +1,22 @@
+use std::fmt;
+
+///
+/// The value a `catch` receives for errors raised by the VM itself, such as type errors or
+/// undefined variables. `trace` lists the active calls, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectException {
+    pub message: String,
+    pub trace: Vec<String>,
+}
+
+impl ObjectException {
+    pub fn new(message: String, trace: Vec<String>) -> Self {
+        ObjectException { message, trace }
+    }
+}
+
+impl fmt::Display for ObjectException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error: {}", self.message)
+    }
+}
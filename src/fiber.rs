@@ -0,0 +1,110 @@
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::function::ObjectFunction;
+use crate::stack::{Stack, StackValue};
+use crate::vm::CallFrame;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FiberState {
+    /// created and not resumed yet
+    New,
+    /// stopped in a call to `suspend`
+    Suspended,
+    /// running, or waiting for a fiber it resumed
+    Running,
+    /// its function returned or threw
+    Done,
+}
+
+///
+/// A coroutine with its own value stack and call frames. The VM runs one fiber at a time: the
+/// running fiber's stack and frames are `VM::stack` and `VM::frames`, the others keep theirs
+/// here together with the position to continue at.
+pub struct ObjectFiber {
+    /// what the fiber runs, None for the fiber running the script itself
+    pub function: Option<Arc<ObjectFunction>>,
+    pub state: FiberState,
+    pub(crate) stack: Stack<StackValue>,
+    pub(crate) frames: Vec<CallFrame>,
+    pub(crate) ip: usize,
+    /// the fiber that resumed this one, which continues when this one suspends or finishes
+    pub(crate) caller: Option<FiberRef>,
+    /// started by the scheduler: suspending lets the next ready fiber run instead of the caller
+    pub(crate) scheduled: bool,
+}
+
+impl ObjectFiber {
+    pub fn new(function: Option<Arc<ObjectFunction>>) -> Self {
+        ObjectFiber {
+            function,
+            state: FiberState::New,
+            stack: Stack::new(),
+            frames: Vec::new(),
+            ip: 0,
+            caller: None,
+            scheduled: false,
+        }
+    }
+
+    fn name(&self) -> &str {
+        match &self.function {
+            Some(function) => function.name.as_str(),
+            None => "script",
+        }
+    }
+}
+
+///
+/// Shared handle to a fiber: every copy of the value resumes the same fiber.
+#[derive(Clone)]
+pub struct FiberRef(Arc<Mutex<ObjectFiber>>);
+
+impl FiberRef {
+    pub fn new(function: Option<Arc<ObjectFunction>>) -> Self {
+        FiberRef(Arc::new(Mutex::new(ObjectFiber::new(function))))
+    }
+
+    ///
+    /// The fiber running the script, which has no function and is never suspended.
+    pub fn main() -> Self {
+        let fiber = FiberRef::new(None);
+        fiber.lock().state = FiberState::Running;
+        fiber
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, ObjectFiber> {
+        self.0.lock().expect("fiber lock poisoned")
+    }
+}
+
+/// Fibers are equal only to themselves.
+impl PartialEq for FiberRef {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for FiberRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fiber = self.lock();
+        write!(f, "Object fiber '{}', {:?}", fiber.name(), fiber.state)
+    }
+}
+
+impl fmt::Display for FiberRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<fiber {}>", self.lock().name())
+    }
+}
+
+/// Built-ins that switch fibers, handled by the VM since natives only see their arguments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FiberOp {
+    /// `suspend(value)`: hands `value` back to whoever resumed the running fiber
+    Suspend,
+    /// `spawn(function)`: queues a new fiber for `run`
+    Spawn,
+    /// `run()`: runs the queued fibers round-robin until all have finished
+    Run,
+}
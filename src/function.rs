@@ -8,6 +8,7 @@ use std::io::Write;
 pub enum FunctionType {
     Function,
     Script,
+    Module,
 }
 
 impl fmt::Display for FunctionType {
@@ -18,10 +19,22 @@ impl fmt::Display for FunctionType {
 
 #[derive(Clone)]
 pub struct ObjectFunction {
-    ftype: FunctionType,
+    pub(crate) ftype: FunctionType,
     pub(crate) chunk_index: ChunkIndex,
+    /// number of declared parameters, not counting a `...rest` parameter
     pub(crate) arity: u8,
+    /// number of parameters without a default value
+    pub(crate) min_arity: u8,
+    /// whether extra arguments are collected into a trailing `...rest` list
+    pub(crate) variadic: bool,
+    /// whether the body contains `yield`, making calls return a generator
+    pub(crate) generator: bool,
+    /// parameter names, used to bind named arguments
+    pub(crate) params: Vec<String>,
     pub name: String,
+    pub doc: Option<String>,
+    /// index of the module whose globals the function uses, 0 for the main script
+    pub(crate) module: usize,
 }
 
 impl ObjectFunction {
@@ -34,6 +47,12 @@ impl ObjectFunction {
             ftype,
             chunk_index,
             arity: 0,
+            min_arity: 0,
+            variadic: false,
+            generator: false,
+            params: Vec::new(),
+            doc: None,
+            module: 0,
         }
     }
 }
@@ -0,0 +1,97 @@
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::error::ThrowSite;
+use crate::function::ObjectFunction;
+use crate::value::Value;
+use crate::vm::Handler;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeneratorState {
+    /// created, or stopped at a `yield`
+    Suspended,
+    /// its frame is on the VM's call stack
+    Running,
+    /// returned or threw; resuming it yields nil
+    Done,
+}
+
+///
+/// The value a generator function returns when called. While suspended it owns the stack slots
+/// of its frame (callee, parameters, locals and temporaries) and the position to resume at;
+/// `VM::resume` moves them back onto the VM stack.
+pub struct ObjectGenerator {
+    pub function: Arc<ObjectFunction>,
+    pub state: GeneratorState,
+    pub(crate) slots: Vec<Value>,
+    pub(crate) ip: usize,
+    /// `try` handlers active at the `yield`, with stack heights relative to the frame
+    pub(crate) handlers: Vec<Handler>,
+    /// where the exceptions pending in its finally blocks were raised, as in `CallFrame`
+    pub(crate) rethrows: Vec<(usize, ThrowSite)>,
+}
+
+impl ObjectGenerator {
+    pub fn new(function: Arc<ObjectFunction>, slots: Vec<Value>) -> Self {
+        ObjectGenerator {
+            function,
+            state: GeneratorState::Suspended,
+            slots,
+            ip: 0,
+            handlers: Vec::new(),
+            rethrows: Vec::new(),
+        }
+    }
+
+    pub fn new_value(function: Arc<ObjectFunction>, slots: Vec<Value>) -> Value {
+        Value::Generator(GeneratorRef(Arc::new(Mutex::new(ObjectGenerator::new(
+            function, slots,
+        )))))
+    }
+}
+
+///
+/// Shared handle to a generator: every copy of the value resumes the same generator.
+#[derive(Clone)]
+pub struct GeneratorRef(Arc<Mutex<ObjectGenerator>>);
+
+impl GeneratorRef {
+    pub fn lock(&self) -> MutexGuard<'_, ObjectGenerator> {
+        self.0.lock().expect("generator lock poisoned")
+    }
+}
+
+/// Generators are equal only to themselves.
+impl PartialEq for GeneratorRef {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for GeneratorRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.lock())
+    }
+}
+
+impl fmt::Display for GeneratorRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.lock())
+    }
+}
+
+impl fmt::Debug for ObjectGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Object generator '{}', {:?}",
+            self.function.name, self.state
+        )
+    }
+}
+
+impl fmt::Display for ObjectGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<generator {}>", self.function.name)
+    }
+}
@@ -1,7 +1,9 @@
 use clap::{arg, command, Command};
+use rox::vm::VM;
+use std::path::Path;
 
 fn main() {
-    let _matches = command!()
+    let matches = command!()
         .arg(arg!([file] " name to operate on").required(true))
         .arg(
             arg!(
@@ -15,10 +17,42 @@ fn main() {
         .arg(arg!(
             -d --debug ... "Turn debugging information on"
         ))
+        .arg(arg!(
+            --"no-fold" "Compile constant expressions as written instead of folding them"
+        ))
+        .arg(arg!(
+            --"no-optimize" "Run the bytecode as compiled, without the peephole optimizer"
+        ))
+        .arg(arg!(
+            --"no-superinstructions" "Optimize without fusing instructions into superinstructions"
+        ))
+        .arg(arg!(
+            --"no-tail-calls" "Give every call its own frame, including `return f(x);`"
+        ))
+        .arg(arg!(
+            --"register-vm" "Run calls of functions without effects on the register machine"
+        ))
+        .arg(arg!(
+            --"cache-modules" "Import modules from their compiled files, compiling those that are out of date"
+        ))
         .subcommand(
             Command::new("test")
                 .about("does testing things")
                 .arg(arg!(-l --list "lists test values")),
         )
         .get_matches();
+
+    if let Some(file) = matches.value_of("file") {
+        let mut vm = VM::new();
+        vm.fold_constants = !matches.is_present("no-fold");
+        vm.optimize = !matches.is_present("no-optimize");
+        vm.superinstructions = !matches.is_present("no-superinstructions");
+        vm.tail_calls = !matches.is_present("no-tail-calls");
+        vm.register_vm = matches.is_present("register-vm");
+        vm.cache_modules = matches.is_present("cache-modules");
+        if let Err(err) = vm.interpret_file(Path::new(file)) {
+            eprintln!("{:?}", err);
+            std::process::exit(70);
+        }
+    }
 }
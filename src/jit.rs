@@ -0,0 +1,792 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use cranelift_codegen::entity::EntityRef;
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, Value as Ssa};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Switch, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use crate::chunk::{Chunk, ChunkIndex};
+use crate::function::ObjectFunction;
+use crate::opcode::Opcode;
+use crate::value::Value;
+
+///
+/// Calls plus loop back-edges after which a function is compiled, unless `ROX_JIT_THRESHOLD`
+/// says otherwise.
+pub(crate) const HOT_THRESHOLD: u32 = 1000;
+///
+/// Deoptimizations after which a function goes back to being interpreted for good.
+const MAX_DEOPTS: u32 = 16;
+
+const NIL: u64 = 0;
+const BOOL: u64 = 1;
+const INT: u64 = 2;
+const NUMBER: u64 = 3;
+///
+/// Any other value, which compiled code only moves around: the payload is the frame slot it
+/// was in on entry, where the VM still holds it.
+const OTHER: u64 = 4;
+
+///
+/// A value in the frame of a compiled function.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Slot {
+    tag: u64,
+    bits: u64,
+}
+
+///
+/// Called with the frame and the position to start at, 0 or the start of a loop. Returns
+/// `-1 - ip` with the result in slot 0 after returning at `ip`, or else the position of the
+/// instruction to resume interpreting at in the low 32 bits and the frame's depth above them,
+/// with the frame written back to the slots.
+type Entry = extern "C" fn(*mut Slot, i64) -> i64;
+
+#[derive(Clone)]
+pub(crate) struct Compiled {
+    entry: Entry,
+    slots: usize,
+    /// the depth of the frame at each position the code can start at
+    entries: Rc<HashMap<usize, usize>>,
+}
+
+///
+/// How a run of compiled code ended.
+pub(crate) enum Exit {
+    /// the result, and the position of the `OpReturn` that returned it
+    Returned(Value, usize),
+    /// the instruction that needs the interpreter, and the frame to interpret it in
+    Deoptimized(usize, Vec<Value>),
+}
+
+enum Hotness {
+    Counting(u32),
+    Compiled(Compiled, u32),
+    Interpreted,
+}
+
+///
+/// Counts calls and loop back-edges of the functions of the running program, and compiles the
+/// hot ones into native code. Functions using anything but numbers, booleans, nil, locals and
+/// jumps stay interpreted.
+pub(crate) struct Jit {
+    module: Option<JITModule>,
+    /// indexed by chunk
+    functions: Vec<Hotness>,
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        Jit {
+            module: None,
+            functions: Vec::new(),
+        }
+    }
+
+    ///
+    /// Forgets the compiled code, whose chunks belong to the previous program.
+    pub fn reset(&mut self) {
+        self.functions.clear();
+        if let Some(module) = self.module.take() {
+            // nothing points into the module once the functions are forgotten
+            unsafe { module.free_memory() };
+        }
+    }
+
+    ///
+    /// Counts a call of `function` or a back-edge of one of its loops, and returns its compiled
+    /// code, compiling it once it is hot.
+    pub fn hot(
+        &mut self,
+        function: &ObjectFunction,
+        chunk: &Chunk,
+        threshold: u32,
+    ) -> Option<Compiled> {
+        let index = function.chunk_index;
+        if index >= self.functions.len() {
+            self.functions
+                .resize_with(index + 1, || Hotness::Counting(0));
+        }
+        match &mut self.functions[index] {
+            Hotness::Compiled(code, _) => return Some(code.clone()),
+            Hotness::Interpreted => return None,
+            Hotness::Counting(count) if *count < threshold => {
+                *count += 1;
+                return None;
+            }
+            Hotness::Counting(_) => {}
+        }
+        let compiled = self.compile(function, chunk);
+        self.functions[index] = match &compiled {
+            Some(code) => Hotness::Compiled(code.clone(), 0),
+            None => Hotness::Interpreted,
+        };
+        compiled
+    }
+
+    pub fn deoptimized(&mut self, chunk_index: ChunkIndex) {
+        if let Some(hotness) = self.functions.get_mut(chunk_index) {
+            if let Hotness::Compiled(_, deopts) = hotness {
+                *deopts += 1;
+                if *deopts == MAX_DEOPTS {
+                    *hotness = Hotness::Interpreted;
+                }
+            }
+        }
+    }
+
+    fn compile(&mut self, function: &ObjectFunction, chunk: &Chunk) -> Option<Compiled> {
+        let entry_depth = function.arity as usize + function.variadic as usize + 1;
+        let depths = depths(chunk, entry_depth)?;
+        // one more for the constant `OpAddLocalConst` adds
+        let slots = depths.iter().flatten().max().copied().unwrap_or(0) + 1;
+        let entries = loop_starts(chunk)
+            .into_iter()
+            .chain([0])
+            .filter_map(|ip| Some((ip, depths[ip]?)))
+            .collect::<HashMap<_, _>>();
+
+        let module = match &mut self.module {
+            Some(module) => module,
+            None => self.module.insert(new_module()?),
+        };
+        let mut ctx = module.make_context();
+        let pointer = module.target_config().pointer_type();
+        ctx.func.signature.params.push(AbiParam::new(pointer));
+        ctx.func.signature.params.push(AbiParam::new(types::I64));
+        ctx.func.signature.returns.push(AbiParam::new(types::I64));
+
+        let mut builder_context = FunctionBuilderContext::new();
+        let builder = FunctionBuilder::new(&mut ctx.func, &mut builder_context);
+        let mut codegen = Codegen::new(builder, chunk, &depths, slots);
+        codegen.translate(entries.keys());
+        codegen.builder.finalize();
+
+        let id = module
+            .declare_anonymous_function(&ctx.func.signature)
+            .ok()?;
+        module.define_function(id, &mut ctx).ok()?;
+        module.clear_context(&mut ctx);
+        module.finalize_definitions().ok()?;
+        let code = module.get_finalized_function(id);
+        // the signature declared above
+        let entry = unsafe { std::mem::transmute::<*const u8, Entry>(code) };
+        Some(Compiled {
+            entry,
+            slots,
+            entries: Rc::new(entries),
+        })
+    }
+}
+
+///
+/// Frees the compiled code, which `JITModule` leaks when dropped.
+impl Drop for Jit {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+impl Compiled {
+    ///
+    /// Whether the code can start at `ip` with a frame of `depth` values.
+    pub fn enters_at(&self, ip: usize, depth: usize) -> bool {
+        self.entries.get(&ip) == Some(&depth)
+    }
+
+    ///
+    /// Runs the function from `ip`, one of its entries, in `frame`: the callee and its
+    /// arguments and locals.
+    pub fn run(&self, frame: &[Value], ip: usize) -> Exit {
+        debug_assert!(self.enters_at(ip, frame.len()));
+        let mut slots = vec![Slot { tag: NIL, bits: 0 }; self.slots];
+        for (index, value) in frame.iter().enumerate() {
+            slots[index] = to_slot(value, index);
+        }
+        let exit = (self.entry)(slots.as_mut_ptr(), ip as i64);
+        if exit < 0 {
+            return Exit::Returned(from_slot(slots[0], frame), (-1 - exit) as usize);
+        }
+        let ip = exit as usize & 0xffff_ffff;
+        let depth = exit as usize >> 32;
+        let values = slots[..depth]
+            .iter()
+            .map(|slot| from_slot(*slot, frame))
+            .collect();
+        Exit::Deoptimized(ip, values)
+    }
+}
+
+fn new_module() -> Option<JITModule> {
+    let mut flags = settings::builder();
+    flags.set("use_colocated_libcalls", "false").ok()?;
+    flags.set("is_pic", "false").ok()?;
+    flags.set("opt_level", "speed").ok()?;
+    let isa = cranelift_native::builder()
+        .ok()?
+        .finish(settings::Flags::new(flags))
+        .ok()?;
+    Some(JITModule::new(JITBuilder::with_isa(
+        isa,
+        default_libcall_names(),
+    )))
+}
+
+fn to_slot(value: &Value, index: usize) -> Slot {
+    match value {
+        Value::Nil => Slot { tag: NIL, bits: 0 },
+        Value::Boolean(b) => Slot {
+            tag: BOOL,
+            bits: *b as u64,
+        },
+        Value::Int(i) => Slot {
+            tag: INT,
+            bits: *i as u64,
+        },
+        Value::Number(n) => Slot {
+            tag: NUMBER,
+            bits: n.to_bits(),
+        },
+        _ => Slot {
+            tag: OTHER,
+            bits: index as u64,
+        },
+    }
+}
+
+fn from_slot(slot: Slot, frame: &[Value]) -> Value {
+    match slot.tag {
+        NIL => Value::Nil,
+        BOOL => Value::Boolean(slot.bits != 0),
+        INT => Value::Int(slot.bits as i64),
+        NUMBER => Value::Number(f64::from_bits(slot.bits)),
+        _ => frame[slot.bits as usize].clone(),
+    }
+}
+
+fn constant_slot(value: &Value) -> Option<Slot> {
+    match value {
+        Value::Nil | Value::Boolean(_) | Value::Int(_) | Value::Number(_) => {
+            Some(to_slot(value, 0))
+        }
+        _ => None,
+    }
+}
+
+///
+/// The positions `OpLoop` instructions jump back to.
+fn loop_starts(chunk: &Chunk) -> Vec<usize> {
+    chunk
+        .op_codes
+        .iter()
+        .enumerate()
+        .filter_map(|(index, op)| match op {
+            Opcode::OpLoop(offset) => (index + 1).checked_sub(*offset as usize),
+            _ => None,
+        })
+        .collect()
+}
+
+///
+/// The stack depth of the frame before each instruction, `None` for unreachable ones.
+/// Returns `None` when a reachable instruction can't be compiled.
+fn depths(chunk: &Chunk, entry_depth: usize) -> Option<Vec<Option<usize>>> {
+    let code = &chunk.op_codes;
+    let mut depths = vec![None; code.len()];
+    let mut pending = vec![(0, entry_depth)];
+    while let Some((index, depth)) = pending.pop() {
+        match depths.get(index)? {
+            Some(known) if *known == depth => continue,
+            Some(_) => return None,
+            None => depths[index] = Some(depth),
+        }
+        let next = index + 1;
+        let pops = |count: usize| depth.checked_sub(count);
+        let successors = match &code[index] {
+            Opcode::OpConstant(constant) => {
+                constant_slot(chunk.read_constant(*constant)?)?;
+                vec![(next, depth + 1)]
+            }
+            Opcode::OpNil | Opcode::OpTrue | Opcode::OpFalse => vec![(next, depth + 1)],
+            Opcode::OpGetLocal(local) if *local < depth => vec![(next, depth + 1)],
+            Opcode::OpSetLocal(local) if *local < depth => vec![(next, depth)],
+            Opcode::OpDup => vec![(next, pops(1)? + 2)],
+            Opcode::OpPop => vec![(next, pops(1)?)],
+            Opcode::OpNegate | Opcode::OpNot => vec![(next, pops(1)? + 1)],
+            Opcode::OpAdd
+            | Opcode::OpIncrement
+            | Opcode::OPSubtract
+            | Opcode::OPMultiply
+            | Opcode::OpDivide
+            | Opcode::OpEqual
+            | Opcode::OpGreater
+            | Opcode::OpLess
+            | Opcode::OpGreaterEqual
+            | Opcode::OpLessEqual => vec![(next, pops(2)? + 1)],
+            Opcode::OpJumpIfFalse(jump) => {
+                pops(1)?;
+                vec![(next, depth), (next + *jump as usize, depth)]
+            }
+            Opcode::OpJump(jump) => vec![(next + *jump as usize, depth)],
+            Opcode::OpLoop(offset) => vec![(next.checked_sub(*offset as usize)?, depth)],
+            Opcode::OpReturn => {
+                pops(1)?;
+                vec![]
+            }
+            Opcode::OpAddLocalConst(local, constant)
+            | Opcode::OpIncrementLocal(local, constant)
+                if *local < depth =>
+            {
+                constant_slot(chunk.read_constant(*constant)?)?;
+                vec![(next, depth)]
+            }
+            Opcode::OpJumpIfNotLessLocals(a, b, jump) if *a < depth && *b < depth => {
+                vec![(next, depth + 1), (next + *jump as usize, depth + 1)]
+            }
+            _ => return None,
+        };
+        pending.extend(successors);
+    }
+    Some(depths)
+}
+
+///
+/// Translates a chunk whose depths are known, keeping each slot of the frame in a pair of
+/// variables: its tag and its payload.
+struct Codegen<'a> {
+    builder: FunctionBuilder<'a>,
+    chunk: &'a Chunk,
+    depths: &'a [Option<usize>],
+    slots: usize,
+    blocks: Vec<Block>,
+    /// where each instruction hands over to the interpreter, made on first use
+    deopts: HashMap<usize, Block>,
+    frame: Ssa,
+}
+
+impl<'a> Codegen<'a> {
+    fn new(
+        mut builder: FunctionBuilder<'a>,
+        chunk: &'a Chunk,
+        depths: &'a [Option<usize>],
+        slots: usize,
+    ) -> Self {
+        let blocks = depths.iter().map(|_| builder.create_block()).collect();
+        for var in 0..slots * 2 {
+            builder.declare_var(Variable::new(var), types::I64);
+        }
+        Codegen {
+            builder,
+            chunk,
+            depths,
+            slots,
+            blocks,
+            deopts: HashMap::new(),
+            frame: Ssa::new(0),
+        }
+    }
+
+    fn tag(&mut self, slot: usize) -> Ssa {
+        self.builder.use_var(Variable::new(slot * 2))
+    }
+
+    fn bits(&mut self, slot: usize) -> Ssa {
+        self.builder.use_var(Variable::new(slot * 2 + 1))
+    }
+
+    fn set(&mut self, slot: usize, tag: Ssa, bits: Ssa) {
+        self.builder.def_var(Variable::new(slot * 2), tag);
+        self.builder.def_var(Variable::new(slot * 2 + 1), bits);
+    }
+
+    fn set_const(&mut self, slot: usize, value: Slot) {
+        let tag = self.builder.ins().iconst(types::I64, value.tag as i64);
+        let bits = self.builder.ins().iconst(types::I64, value.bits as i64);
+        self.set(slot, tag, bits);
+    }
+
+    fn copy(&mut self, from: usize, to: usize) {
+        let tag = self.tag(from);
+        let bits = self.bits(from);
+        self.set(to, tag, bits);
+    }
+
+    fn is_tag(&mut self, slot: usize, tag: u64) -> Ssa {
+        let actual = self.tag(slot);
+        self.builder
+            .ins()
+            .icmp_imm(IntCC::Equal, actual, tag as i64)
+    }
+
+    fn both_tagged(&mut self, a: usize, b: usize, tag: u64) -> Ssa {
+        let a = self.is_tag(a, tag);
+        let b = self.is_tag(b, tag);
+        self.builder.ins().band(a, b)
+    }
+
+    fn float(&mut self, slot: usize) -> Ssa {
+        let bits = self.bits(slot);
+        self.builder
+            .ins()
+            .bitcast(types::F64, MemFlags::new(), bits)
+    }
+
+    fn set_bool(&mut self, slot: usize, flag: Ssa) {
+        let bits = self.builder.ins().uextend(types::I64, flag);
+        let tag = self.builder.ins().iconst(types::I64, BOOL as i64);
+        self.set(slot, tag, bits);
+    }
+
+    fn set_float(&mut self, slot: usize, n: Ssa) {
+        let bits = self.builder.ins().bitcast(types::I64, MemFlags::new(), n);
+        let tag = self.builder.ins().iconst(types::I64, NUMBER as i64);
+        self.set(slot, tag, bits);
+    }
+
+    fn set_int(&mut self, slot: usize, i: Ssa) {
+        let tag = self.builder.ins().iconst(types::I64, INT as i64);
+        self.set(slot, tag, i);
+    }
+
+    fn deopt_block(&mut self, index: usize) -> Block {
+        let builder = &mut self.builder;
+        *self
+            .deopts
+            .entry(index)
+            .or_insert_with(|| builder.create_block())
+    }
+
+    ///
+    /// Continues in a new block when `condition` holds, and deoptimizes at `index` otherwise.
+    fn guard(&mut self, condition: Ssa, index: usize) {
+        let deopt = self.deopt_block(index);
+        let ok = self.builder.create_block();
+        self.builder.ins().brif(condition, ok, &[], deopt, &[]);
+        self.builder.switch_to_block(ok);
+    }
+
+    ///
+    /// Branches to a new block when both slots are integers, returning it and the block
+    /// that follows otherwise.
+    fn branch_on_ints(&mut self, a: usize, b: usize) -> (Block, Block) {
+        let ints = self.both_tagged(a, b, INT);
+        let int_block = self.builder.create_block();
+        let other = self.builder.create_block();
+        self.builder.ins().brif(ints, int_block, &[], other, &[]);
+        (int_block, other)
+    }
+
+    fn translate<'e>(&mut self, entries: impl Iterator<Item = &'e usize>) {
+        let entry = self.builder.create_block();
+        self.builder.append_block_params_for_function_params(entry);
+        self.builder.switch_to_block(entry);
+        self.frame = self.builder.block_params(entry)[0];
+        let start = self.builder.block_params(entry)[1];
+        // slots past the frame hold nil
+        for slot in 0..self.slots {
+            let tag = self.load(slot, 0);
+            let bits = self.load(slot, 8);
+            self.set(slot, tag, bits);
+        }
+        let mut switch = Switch::new();
+        for ip in entries.filter(|ip| **ip != 0) {
+            switch.set_entry(*ip as u128, self.blocks[*ip]);
+        }
+        switch.emit(&mut self.builder, start, self.blocks[0]);
+
+        for index in 0..self.chunk.op_codes.len() {
+            if let Some(depth) = self.depths[index] {
+                self.builder.switch_to_block(self.blocks[index]);
+                self.instruction(index, depth);
+            }
+        }
+
+        let mut deopts: Vec<(usize, Block)> = self.deopts.iter().map(|(i, b)| (*i, *b)).collect();
+        deopts.sort_by_key(|(index, _)| *index);
+        for (index, block) in deopts {
+            self.builder.switch_to_block(block);
+            let depth = self.depths[index].expect("deoptimized at a reachable instruction");
+            for slot in 0..depth {
+                let tag = self.tag(slot);
+                let bits = self.bits(slot);
+                self.store(slot, 0, tag);
+                self.store(slot, 8, bits);
+            }
+            let exit = self
+                .builder
+                .ins()
+                .iconst(types::I64, (depth << 32 | index) as i64);
+            self.builder.ins().return_(&[exit]);
+        }
+        self.builder.seal_all_blocks();
+    }
+
+    fn load(&mut self, slot: usize, offset: usize) -> Ssa {
+        self.builder.ins().load(
+            types::I64,
+            MemFlags::trusted(),
+            self.frame,
+            (slot * 16 + offset) as i32,
+        )
+    }
+
+    fn store(&mut self, slot: usize, offset: usize, value: Ssa) {
+        self.builder.ins().store(
+            MemFlags::trusted(),
+            value,
+            self.frame,
+            (slot * 16 + offset) as i32,
+        );
+    }
+
+    fn constant(&self, index: usize) -> Slot {
+        self.chunk
+            .read_constant(index)
+            .and_then(constant_slot)
+            .expect("checked by `depths`")
+    }
+
+    fn instruction(&mut self, index: usize, depth: usize) {
+        let next = index + 1;
+        let top = depth.wrapping_sub(1);
+        match &self.chunk.op_codes[index] {
+            Opcode::OpConstant(constant) => {
+                let value = self.constant(*constant);
+                self.set_const(depth, value);
+            }
+            Opcode::OpNil => self.set_const(depth, Slot { tag: NIL, bits: 0 }),
+            Opcode::OpTrue => self.set_const(depth, Slot { tag: BOOL, bits: 1 }),
+            Opcode::OpFalse => self.set_const(depth, Slot { tag: BOOL, bits: 0 }),
+            Opcode::OpGetLocal(local) => self.copy(*local, depth),
+            Opcode::OpSetLocal(local) => self.copy(top, *local),
+            Opcode::OpDup => self.copy(top, depth),
+            Opcode::OpPop => {}
+            Opcode::OpNegate => {
+                let (int_block, other) = self.branch_on_ints(top, top);
+                self.builder.switch_to_block(int_block);
+                let i = self.bits(top);
+                let fits = self.builder.ins().icmp_imm(IntCC::NotEqual, i, i64::MIN);
+                self.guard(fits, index);
+                let negated = self.builder.ins().ineg(i);
+                self.set_int(top, negated);
+                self.builder.ins().jump(self.blocks[next], &[]);
+
+                self.builder.switch_to_block(other);
+                let number = self.is_tag(top, NUMBER);
+                self.guard(number, index);
+                let n = self.float(top);
+                let negated = self.builder.ins().fneg(n);
+                self.set_float(top, negated);
+            }
+            Opcode::OpNot => {
+                let boolean = self.is_tag(top, BOOL);
+                self.guard(boolean, index);
+                let bits = self.bits(top);
+                let not = self.builder.ins().bxor_imm(bits, 1);
+                let tag = self.tag(top);
+                self.set(top, tag, not);
+            }
+            op @ (Opcode::OpAdd
+            | Opcode::OpIncrement
+            | Opcode::OPSubtract
+            | Opcode::OPMultiply
+            | Opcode::OpDivide
+            | Opcode::OpEqual
+            | Opcode::OpGreater
+            | Opcode::OpLess
+            | Opcode::OpGreaterEqual
+            | Opcode::OpLessEqual) => {
+                let op = op.clone();
+                self.binary(&op, index, depth - 2, depth - 1, depth - 2);
+            }
+            Opcode::OpAddLocalConst(local, constant)
+            | Opcode::OpIncrementLocal(local, constant) => {
+                let value = self.constant(*constant);
+                self.set_const(depth, value);
+                self.binary(&Opcode::OpAdd, index, *local, depth, *local);
+            }
+            Opcode::OpJumpIfNotLessLocals(a, b, jump) => {
+                self.binary(&Opcode::OpLess, index, *a, *b, depth);
+                let less = self.bits(depth);
+                let target = self.blocks[next + *jump as usize];
+                self.builder
+                    .ins()
+                    .brif(less, self.blocks[next], &[], target, &[]);
+                return;
+            }
+            Opcode::OpJumpIfFalse(jump) => {
+                let nil = self.is_tag(top, NIL);
+                let boolean = self.is_tag(top, BOOL);
+                let bits = self.bits(top);
+                let zero = self.builder.ins().icmp_imm(IntCC::Equal, bits, 0);
+                let false_ = self.builder.ins().band(boolean, zero);
+                let falsey = self.builder.ins().bor(nil, false_);
+                let target = self.blocks[next + *jump as usize];
+                self.builder
+                    .ins()
+                    .brif(falsey, target, &[], self.blocks[next], &[]);
+                return;
+            }
+            Opcode::OpJump(jump) => {
+                self.builder
+                    .ins()
+                    .jump(self.blocks[next + *jump as usize], &[]);
+                return;
+            }
+            Opcode::OpLoop(offset) => {
+                self.builder
+                    .ins()
+                    .jump(self.blocks[next - *offset as usize], &[]);
+                return;
+            }
+            Opcode::OpReturn => {
+                let tag = self.tag(top);
+                let bits = self.bits(top);
+                self.store(0, 0, tag);
+                self.store(0, 8, bits);
+                let returned = self.builder.ins().iconst(types::I64, -1 - index as i64);
+                self.builder.ins().return_(&[returned]);
+                return;
+            }
+            other => unreachable!("{:?} is rejected by `depths`", other),
+        }
+        self.builder.ins().jump(self.blocks[next], &[]);
+    }
+
+    ///
+    /// `a op b` into slot `to`, with the result `operators::number_op` gives for two integers or
+    /// two floats; other operands deoptimize.
+    fn binary(&mut self, op: &Opcode, index: usize, a: usize, b: usize, to: usize) {
+        let done = self.builder.create_block();
+        let (int_block, other) = self.branch_on_ints(a, b);
+
+        self.builder.switch_to_block(int_block);
+        let (x, y) = (self.bits(a), self.bits(b));
+        let int_cc = match op {
+            Opcode::OpEqual => Some(IntCC::Equal),
+            Opcode::OpGreater => Some(IntCC::SignedGreaterThan),
+            Opcode::OpLess => Some(IntCC::SignedLessThan),
+            Opcode::OpGreaterEqual => Some(IntCC::SignedGreaterThanOrEqual),
+            Opcode::OpLessEqual => Some(IntCC::SignedLessThanOrEqual),
+            _ => None,
+        };
+        if let Some(cc) = int_cc {
+            let flag = self.builder.ins().icmp(cc, x, y);
+            self.set_bool(to, flag);
+            self.builder.ins().jump(done, &[]);
+        } else if op == &Opcode::OpDivide {
+            let deopt = self.deopt_block(index);
+            self.builder.ins().jump(deopt, &[]);
+        } else {
+            let (result, overflow) = match op {
+                Opcode::OpAdd | Opcode::OpIncrement => self.builder.ins().sadd_overflow(x, y),
+                Opcode::OPSubtract => self.builder.ins().ssub_overflow(x, y),
+                _ => self.builder.ins().smul_overflow(x, y),
+            };
+            let fits = self.builder.ins().icmp_imm(IntCC::Equal, overflow, 0);
+            self.guard(fits, index);
+            self.set_int(to, result);
+            self.builder.ins().jump(done, &[]);
+        }
+
+        self.builder.switch_to_block(other);
+        let numbers = self.both_tagged(a, b, NUMBER);
+        self.guard(numbers, index);
+        let (x, y) = (self.float(a), self.float(b));
+        let float_cc = match op {
+            Opcode::OpEqual => Some(FloatCC::Equal),
+            Opcode::OpGreater => Some(FloatCC::GreaterThan),
+            Opcode::OpLess => Some(FloatCC::LessThan),
+            // the negations of OpLess and OpGreater, true for NaN
+            Opcode::OpGreaterEqual => Some(FloatCC::UnorderedOrGreaterThanOrEqual),
+            Opcode::OpLessEqual => Some(FloatCC::UnorderedOrLessThanOrEqual),
+            _ => None,
+        };
+        if let Some(cc) = float_cc {
+            let flag = self.builder.ins().fcmp(cc, x, y);
+            self.set_bool(to, flag);
+        } else {
+            let result = match op {
+                Opcode::OpAdd | Opcode::OpIncrement => self.builder.ins().fadd(x, y),
+                Opcode::OPSubtract => self.builder.ins().fsub(x, y),
+                Opcode::OPMultiply => self.builder.ins().fmul(x, y),
+                _ => self.builder.ins().fdiv(x, y),
+            };
+            self.set_float(to, result);
+        }
+        self.builder.ins().jump(done, &[]);
+        self.builder.switch_to_block(done);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::ChunkArena;
+    use crate::jit::{Exit, Jit};
+    use crate::parser::Parser;
+    use crate::value::Value;
+    use std::sync::Arc;
+
+    ///
+    /// Compiles the first function declared in `source`, and runs it with `args`.
+    fn run(source: &str, args: Vec<Value>) -> Option<Exit> {
+        let mut chunks = ChunkArena::new();
+        let mut parser = Parser::new(source, &mut chunks);
+        parser.compile().expect("compiles");
+        let function = (0..)
+            .map_while(|index| parser.chunk_at(0).read_constant(index))
+            .find_map(|value| value.as_function().ok())
+            .expect("a function");
+        let mut jit = Jit::new();
+        let code = jit.hot(&function, parser.chunk_at(function.chunk_index), 0)?;
+        let mut frame = vec![Value::Function(Arc::clone(&function))];
+        frame.extend(args);
+        Some(code.run(&frame, 0))
+    }
+
+    #[test]
+    fn compiles_numeric_functions() {
+        let sum = "fun f(n) { var s = 0; for (var i = 0; i < n; i++) s = s + i; return s; }";
+        match run(sum, vec![Value::Int(10)]) {
+            Some(Exit::Returned(Value::Int(45), _)) => {}
+            _ => panic!("Expected 45"),
+        }
+
+        let halve = "fun f(x) { while (x >= 1.0) x = x / 2.0; return -x; }";
+        match run(halve, vec![Value::Number(10.0)]) {
+            Some(Exit::Returned(Value::Number(n), _)) => assert_eq!(n, -0.625),
+            _ => panic!("Expected -0.625"),
+        }
+    }
+
+    #[test]
+    fn deoptimizes_at_the_failing_instruction() {
+        let sum = "fun f(n) { var s = 0; for (var i = 0; i < n; i++) s = s + i; return s; }";
+        match run(sum, vec![Value::new_string("x")]) {
+            Some(Exit::Deoptimized(_, frame)) => {
+                assert_eq!(frame.len(), 4);
+                assert_eq!(frame[1], Value::new_string("x"));
+                assert_eq!(&frame[2..], [Value::Int(0), Value::Int(0)]);
+            }
+            _ => panic!("Expected a deoptimization"),
+        }
+
+        let overflow = "fun f(n) { return n * n; }";
+        assert!(matches!(
+            run(overflow, vec![Value::Int(i64::MAX)]),
+            Some(Exit::Deoptimized(_, _))
+        ));
+    }
+
+    #[test]
+    fn leaves_other_functions_to_the_interpreter() {
+        assert!(run("fun f(n) { print n; return n; }", vec![Value::Int(1)]).is_none());
+        assert!(run("fun f(n) { return f(n); }", vec![Value::Int(1)]).is_none());
+        assert!(run("fun f(n) { return \"\" + n; }", vec![Value::Int(1)]).is_none());
+    }
+}
@@ -1,15 +1,29 @@
 extern crate core;
 
+pub mod ast;
 mod token;
 // mod full_scanner;
 pub mod chunk;
 pub mod compiler;
 mod error;
+mod exception;
+mod fiber;
 mod function;
+mod generator;
+#[cfg(feature = "jit")]
+mod jit;
+pub mod module;
+#[cfg(feature = "nan-boxing")]
+pub mod nanbox;
 mod native;
+mod numeric;
 mod opcode;
+mod operators;
+mod optimizer;
 mod parser;
 mod precedence;
+mod register;
+mod resolver;
 mod scanner;
 mod stack;
 pub mod value;
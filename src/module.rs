@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use miette::{IntoDiagnostic, NamedSource, Result};
+
+use crate::chunk::{Chunk, ChunkArena};
+use crate::error::LoxCompileError;
+use crate::function::ObjectFunction;
+use crate::opcode::Opcode;
+use crate::parser::Parser;
+
+///
+/// A source file compiled into the current `ChunkArena`. Index 0 is the main script, which has
+/// no `function` since the VM calls it directly.
+#[derive(Debug)]
+pub struct Module {
+    pub path: Option<PathBuf>,
+    pub source: String,
+    pub function: Option<ObjectFunction>,
+}
+
+impl Module {
+    pub fn new(path: Option<PathBuf>, source: String) -> Self {
+        Module {
+            path,
+            source,
+            function: None,
+        }
+    }
+
+    pub fn source_name(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => "bad_file.rs".to_string(),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match &self.path {
+            Some(path) => path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            None => "script".to_string(),
+        }
+    }
+}
+
+///
+/// Finds the file an `import` refers to: first relative to the importing file (or the working
+/// directory for scripts without a path), then in each directory of the search path.
+pub fn resolve(name: &str, importer: Option<&Path>, search_path: &[PathBuf]) -> Option<PathBuf> {
+    let base = importer
+        .and_then(|path| path.parent())
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    std::iter::once(base)
+        .chain(search_path.iter().cloned())
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+        .map(|path| path.canonicalize().unwrap_or(path))
+}
+
+/// Starts every compiled module file, followed by the version of the format.
+const COMPILED_MAGIC: &[u8; 4] = b"ROXC";
+const COMPILED_VERSION: u8 = 1;
+
+///
+/// Where the compiled form of the module at `path` is kept: next to it, with the extension
+/// `loxc`. Imports read it instead of compiling the source while it is up to date.
+pub fn compiled_path(path: &Path) -> PathBuf {
+    path.with_extension("loxc")
+}
+
+///
+/// What a compiled module was compiled from: the size and modification time of its source file,
+/// and the options that change the code generated. A compiled file is only used when they match.
+#[derive(Debug, PartialEq)]
+pub(crate) struct CompiledStamp {
+    size: u64,
+    modified: (u64, u32),
+    fold_constants: bool,
+    tail_calls: bool,
+}
+
+impl CompiledStamp {
+    pub(crate) fn of(path: &Path, fold_constants: bool, tail_calls: bool) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(CompiledStamp {
+            size: metadata.len(),
+            modified: (modified.as_secs(), modified.subsec_nanos()),
+            fold_constants,
+            tail_calls,
+        })
+    }
+
+    fn to_bytes(&self, file: &mut File) -> std::io::Result<()> {
+        file.write_all(&self.size.to_le_bytes())?;
+        file.write_all(&self.modified.0.to_le_bytes())?;
+        file.write_all(&self.modified.1.to_le_bytes())?;
+        file.write_all(&[self.fold_constants as u8, self.tail_calls as u8])
+    }
+
+    fn from_bytes(file: &mut File) -> std::io::Result<Self> {
+        let mut buffer = [0u8; 22];
+        file.read_exact(&mut buffer)?;
+        let u64_at = |at: usize| u64::from_le_bytes(buffer[at..at + 8].try_into().unwrap());
+        Ok(CompiledStamp {
+            size: u64_at(0),
+            modified: (
+                u64_at(8),
+                u32::from_le_bytes(buffer[16..20].try_into().unwrap()),
+            ),
+            fold_constants: buffer[20] == 1,
+            tail_calls: buffer[21] == 1,
+        })
+    }
+}
+
+///
+/// A module read back from its compiled file. Its chunks and modules are numbered within the
+/// file: chunk `i` is `chunks[i]`, module 0 is the module itself and module `i + 1` is the one
+/// at `imports[i]`.
+pub(crate) struct CompiledModule {
+    pub(crate) imports: Vec<PathBuf>,
+    pub(crate) chunks: Vec<Chunk>,
+    pub(crate) function: ObjectFunction,
+}
+
+///
+/// Writes the module with this index in `chunks`, which must have compiled without errors, to
+/// `output`: the chunks of its functions, unoptimized, and the paths of the modules it imports.
+/// The file is written next to `output` first and then moved over it, so that an import never
+/// reads a partly written file.
+pub(crate) fn write_compiled(
+    chunks: &ChunkArena,
+    index: usize,
+    stamp: &CompiledStamp,
+    output: &Path,
+) -> std::io::Result<()> {
+    let function = chunks.modules[index]
+        .function
+        .as_ref()
+        .expect("a compiled module");
+
+    // the module's chunks are interleaved with those of the modules it imports
+    let mut own = vec![function.chunk_index];
+    let mut imports = vec![index];
+    let mut next = 0;
+    while next < own.len() {
+        let chunk = &chunks.chunks[own[next]];
+        for constant in (0..).map_while(|i| chunk.read_constant(i)) {
+            if let Ok(function) = constant.as_function() {
+                own.push(function.chunk_index);
+            }
+        }
+        for opcode in chunk.op_codes.iter() {
+            if let Opcode::OpImport(module) = opcode {
+                if !imports.contains(module) {
+                    imports.push(*module);
+                }
+            }
+        }
+        next += 1;
+    }
+    let local_chunks: HashMap<usize, usize> = own
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| (*chunk, i))
+        .collect();
+    let local_module = |module: usize| imports.iter().position(|m| *m == module).unwrap();
+
+    let mut temp = output.as_os_str().to_owned();
+    temp.push(".tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(COMPILED_MAGIC)?;
+    file.write_all(&[COMPILED_VERSION])?;
+    stamp.to_bytes(&mut file)?;
+    file.write_all(&Chunk::size_to_bytes(imports.len() - 1))?;
+    for module in &imports[1..] {
+        let path = chunks.modules[*module]
+            .path
+            .as_ref()
+            .expect("a module file");
+        let path = path.to_string_lossy();
+        file.write_all(&Chunk::size_to_bytes(path.len()))?;
+        file.write_all(path.as_bytes())?;
+    }
+    file.write_all(&Chunk::size_to_bytes(own.len()))?;
+    for chunk_index in &own {
+        let mut chunk = chunks.chunks[*chunk_index].clone();
+        chunk.relocate(|chunk| local_chunks[&chunk], local_module);
+        chunk.to_bytes(&mut file)?;
+    }
+    let mut function = function.clone();
+    function.chunk_index = 0;
+    function.module = 0;
+    Chunk::function_to_bytes(&function, &mut file);
+    file.flush()?;
+    std::fs::rename(&temp, output)
+}
+
+///
+/// The module compiled into `input`, unless the file is missing or was compiled from something
+/// other than `stamp` describes.
+pub(crate) fn read_compiled(input: &Path, stamp: &CompiledStamp) -> Option<CompiledModule> {
+    let mut file = File::open(input).ok()?;
+    let mut magic = [0u8; 5];
+    file.read_exact(&mut magic).ok()?;
+    if &magic[..4] != COMPILED_MAGIC
+        || magic[4] != COMPILED_VERSION
+        || CompiledStamp::from_bytes(&mut file).ok()? != *stamp
+    {
+        return None;
+    }
+    let imports = (0..Chunk::bytes_to_usize(&mut file))
+        .map(|_| {
+            let mut path = vec![0u8; Chunk::bytes_to_usize(&mut file)];
+            file.read_exact(&mut path).ok()?;
+            String::from_utf8(path).ok().map(PathBuf::from)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let chunks = (0..Chunk::bytes_to_usize(&mut file))
+        .map(|_| Chunk::from_bytes(&mut file))
+        .collect();
+    let function = Chunk::function_from_bytes(&mut file);
+    Some(CompiledModule {
+        imports,
+        chunks,
+        function,
+    })
+}
+
+///
+/// Compiles the module at `path` the way an `import` with compiled modules enabled does, which
+/// also writes the compiled files of its imports, and writes it to `output`, by default its
+/// `compiled_path`. Returns where it was written.
+pub fn compile_file(
+    path: &Path,
+    output: Option<&Path>,
+    search_path: &[PathBuf],
+) -> Result<PathBuf> {
+    let path = path.canonicalize().into_diagnostic()?;
+    let stamp = CompiledStamp::of(&path, true, true);
+    let source = std::fs::read_to_string(&path).into_diagnostic()?;
+
+    let mut chunks = ChunkArena::new();
+    chunks
+        .modules
+        .push(Module::new(Some(path.clone()), source.clone()));
+    chunks.loading.push(1);
+    let mut parser = Parser::new_module(&source, &mut chunks, 1, search_path.to_vec(), true, true);
+    parser.cache_modules = true;
+    let function = match parser.compile() {
+        Ok(function) => function.clone(),
+        Err(err) => {
+            return Err(LoxCompileError {
+                src: NamedSource::new(path.display().to_string(), source.clone()),
+                bad_bit: (err.line as usize, err.start).into(),
+                label: err.msg,
+            })
+            .into_diagnostic()
+        }
+    };
+    chunks.modules[1].function = Some(function);
+
+    let output = output.map_or_else(|| compiled_path(&path), Path::to_path_buf);
+    let stamp = stamp.ok_or_else(|| miette::miette!("Cannot read {}", path.display()))?;
+    write_compiled(&chunks, 1, &stamp, &output).into_diagnostic()?;
+    Ok(output)
+}
+
+///
+/// The value an `import` evaluates to. Its properties are the module's globals as they are
+/// when read, so they follow assignments made by the module's own functions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectModule {
+    pub name: String,
+    /// index of the module in the `ChunkArena` it was compiled into
+    pub index: usize,
+}
+
+impl ObjectModule {
+    pub fn new(name: String, index: usize) -> Self {
+        ObjectModule { name, index }
+    }
+}
+
+impl fmt::Display for ObjectModule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<module {}>", self.name)
+    }
+}
@@ -0,0 +1,421 @@
+use std::borrow::Cow;
+use std::fmt::{Debug, Display, Formatter};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use num_bigint::BigInt;
+use rust_decimal::Decimal;
+
+use crate::function::ObjectFunction;
+use crate::native::ObjectNative;
+use crate::opcode::Opcode;
+use crate::operators;
+use crate::stack::Slot;
+use crate::value::Value;
+
+///
+/// All bits of a quiet NaN plus one more, so no NaN produced by arithmetic looks like a boxed value.
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+///
+/// The NaN every float NaN is stored as.
+const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+///
+/// Set together with `QNAN` on object handles.
+const SIGN: u64 = 0x8000_0000_0000_0000;
+///
+/// Set together with `QNAN` on inline integers.
+const INT: u64 = 0x0001_0000_0000_0000;
+const PAYLOAD: u64 = 0x0000_ffff_ffff_ffff;
+
+const NIL: u64 = QNAN | 1;
+const FALSE: u64 = QNAN | 2;
+const TRUE: u64 = QNAN | 3;
+
+const INT_MIN: i64 = -(1 << 47);
+const INT_MAX: i64 = (1 << 47) - 1;
+
+///
+/// An 8 byte `Value`, NaN-boxed: floats are stored as their own bits, while nil, booleans,
+/// integers of up to 48 bits and handles to every other value live in the payload of a NaN.
+///
+/// Converts to and from `Value` and offers the same accessors, so embedders can switch between
+/// the two without changing their code. With the `nan-boxing` feature the VM stack holds these.
+pub struct NanBoxed {
+    bits: u64,
+    // Send and Sync only when a shared `Value` is
+    handle: PhantomData<Arc<Value>>,
+}
+
+impl NanBoxed {
+    fn from_bits(bits: u64) -> NanBoxed {
+        NanBoxed {
+            bits,
+            handle: PhantomData,
+        }
+    }
+
+    fn from_f64(n: f64) -> NanBoxed {
+        NanBoxed::from_bits(if n.is_nan() {
+            CANONICAL_NAN
+        } else {
+            n.to_bits()
+        })
+    }
+
+    fn is_float(&self) -> bool {
+        self.bits & QNAN != QNAN
+    }
+
+    fn is_int(&self) -> bool {
+        self.bits & (SIGN | QNAN | INT) == QNAN | INT
+    }
+
+    fn is_handle(&self) -> bool {
+        self.bits & (SIGN | QNAN) == SIGN | QNAN
+    }
+
+    fn int(&self) -> i64 {
+        // sign-extend the 48 bit payload
+        (((self.bits & PAYLOAD) << 16) as i64) >> 16
+    }
+
+    fn handle(&self) -> Option<&Value> {
+        if self.is_handle() {
+            // handles are only made by `From<Value>` out of `Arc::into_raw`, and kept alive until drop
+            Some(unsafe { &*((self.bits & PAYLOAD) as *const Value) })
+        } else {
+            None
+        }
+    }
+
+    ///
+    /// Calls `f` with the `Value` this stands for, without cloning a boxed one.
+    fn with_value<R>(&self, f: impl FnOnce(&Value) -> R) -> R {
+        match self.handle() {
+            Some(value) => f(value),
+            None => f(&self.to_value()),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self.bits {
+            NIL => Value::Nil,
+            FALSE => Value::Boolean(false),
+            TRUE => Value::Boolean(true),
+            _ if self.is_float() => Value::Number(f64::from_bits(self.bits)),
+            _ if self.is_int() => Value::Int(self.int()),
+            _ => self.handle().expect("a NaN-boxed handle").clone(),
+        }
+    }
+
+    pub fn new_string(str: &str) -> NanBoxed {
+        NanBoxed::from(Value::new_string(str))
+    }
+
+    pub fn nil() -> NanBoxed {
+        NanBoxed::from_bits(NIL)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.bits == NIL
+    }
+
+    /// true for both floats and integers
+    pub fn is_number(&self) -> bool {
+        self.is_float() || self.is_int() || self.with_value(Value::is_number)
+    }
+
+    pub fn is_integral(&self) -> bool {
+        self.with_value(Value::is_integral)
+    }
+
+    pub fn is_bool(&self) -> bool {
+        self.bits == TRUE || self.bits == FALSE
+    }
+
+    pub fn is_string(&self) -> bool {
+        self.handle().is_some_and(Value::is_string)
+    }
+
+    pub fn is_object(&self) -> bool {
+        self.handle().is_some_and(Value::is_object)
+    }
+
+    pub fn is_list(&self) -> bool {
+        self.handle().is_some_and(Value::is_list)
+    }
+
+    pub fn is_function(&self) -> bool {
+        self.handle().is_some_and(Value::is_function)
+    }
+
+    pub fn is_native(&self) -> bool {
+        self.handle().is_some_and(Value::is_native)
+    }
+
+    pub fn as_number(&self) -> Result<&f64, &str> {
+        if self.is_float() {
+            // f64 and u64 share size and alignment, and every bit pattern is a valid f64
+            Ok(unsafe { &*(&self.bits as *const u64 as *const f64) })
+        } else {
+            Err("Must be a number")
+        }
+    }
+
+    ///
+    /// The value of a float or an integer as a float.
+    pub fn to_f64(&self) -> Option<f64> {
+        self.with_value(Value::to_f64)
+    }
+
+    ///
+    /// The value of an integer, or of a float without a fractional part in the `i64` range.
+    pub fn to_i64(&self) -> Option<i64> {
+        self.with_value(Value::to_i64)
+    }
+
+    ///
+    /// The value of an integer of either size.
+    pub fn to_bigint(&self) -> Option<BigInt> {
+        self.with_value(Value::to_bigint)
+    }
+
+    ///
+    /// The exact value of an integer or a decimal, if it fits in a decimal.
+    pub fn to_decimal(&self) -> Option<Decimal> {
+        self.with_value(Value::to_decimal)
+    }
+
+    pub fn as_bool(&self) -> Result<&bool, &str> {
+        match self.bits {
+            TRUE => Ok(&true),
+            FALSE => Ok(&false),
+            _ => Err("Must be a boolean"),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<&String, &str> {
+        self.handle().ok_or("Must be a obj string")?.as_string()
+    }
+
+    pub fn as_function(&self) -> Result<Arc<ObjectFunction>, &str> {
+        self.handle().ok_or("Must be a obj string")?.as_function()
+    }
+
+    pub fn as_native(&self) -> Result<ObjectNative, &str> {
+        self.handle().ok_or("Must be a obj string")?.as_native()
+    }
+
+    pub fn as_list(&self) -> Result<&Vec<Value>, &str> {
+        self.handle().ok_or("Must be a list")?.as_list()
+    }
+}
+
+impl From<Value> for NanBoxed {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Nil => NanBoxed::nil(),
+            Value::Boolean(true) => NanBoxed::from_bits(TRUE),
+            Value::Boolean(false) => NanBoxed::from_bits(FALSE),
+            Value::Number(n) => NanBoxed::from_f64(n),
+            Value::Int(i) if (INT_MIN..=INT_MAX).contains(&i) => {
+                NanBoxed::from_bits(QNAN | INT | (i as u64 & PAYLOAD))
+            }
+            value => {
+                let ptr = Arc::into_raw(Arc::new(value));
+                // checked in release builds too, a wider pointer would be truncated into a
+                // handle to some other memory
+                assert_eq!(
+                    ptr as u64 & !PAYLOAD,
+                    0,
+                    "NaN-boxing needs pointers that fit in 48 bits, found {:p}",
+                    ptr
+                );
+                NanBoxed::from_bits(SIGN | QNAN | ptr as u64)
+            }
+        }
+    }
+}
+
+impl From<NanBoxed> for Value {
+    fn from(value: NanBoxed) -> Self {
+        value.into_value()
+    }
+}
+
+impl Slot for NanBoxed {
+    ///
+    /// Moves a boxed value out of its box when this is the only handle to it, instead of cloning.
+    fn into_value(self) -> Value {
+        if !self.is_handle() {
+            return self.to_value();
+        }
+        let ptr = (self.bits & PAYLOAD) as *const Value;
+        std::mem::forget(self);
+        // the handle's count, which `forget` kept from being released by `drop`
+        let boxed = unsafe { Arc::from_raw(ptr) };
+        Arc::try_unwrap(boxed).unwrap_or_else(|shared| (*shared).clone())
+    }
+
+    fn value(&self) -> Cow<'_, Value> {
+        match self.handle() {
+            Some(value) => Cow::Borrowed(value),
+            None => Cow::Owned(self.to_value()),
+        }
+    }
+
+    fn values(slots: &[Self]) -> Cow<'_, [Value]> {
+        Cow::Owned(slots.iter().map(NanBoxed::to_value).collect())
+    }
+
+    fn number_op(op: &Opcode, a: &Self, b: &Self, wrapping: bool) -> Option<Self> {
+        // floats are operated on in place, without going through a `Value`
+        if a.is_float() && b.is_float() {
+            let (a, b) = (f64::from_bits(a.bits), f64::from_bits(b.bits));
+            return operators::number_op(op, &Value::Number(a), &Value::Number(b), wrapping)
+                .map(NanBoxed::from);
+        }
+        if a.is_handle() || b.is_handle() {
+            return None;
+        }
+        operators::number_op(op, &a.to_value(), &b.to_value(), wrapping).map(NanBoxed::from)
+    }
+}
+
+impl Clone for NanBoxed {
+    fn clone(&self) -> Self {
+        if self.is_handle() {
+            unsafe { Arc::increment_strong_count((self.bits & PAYLOAD) as *const Value) };
+        }
+        NanBoxed::from_bits(self.bits)
+    }
+}
+
+impl Drop for NanBoxed {
+    fn drop(&mut self) {
+        if self.is_handle() {
+            unsafe { drop(Arc::from_raw((self.bits & PAYLOAD) as *const Value)) };
+        }
+    }
+}
+
+/// Equal exactly when the `Value`s they stand for are.
+impl PartialEq for NanBoxed {
+    fn eq(&self, other: &Self) -> bool {
+        if self.is_float() && other.is_float() {
+            return f64::from_bits(self.bits) == f64::from_bits(other.bits);
+        }
+        self.with_value(|a| other.with_value(|b| a == b))
+    }
+}
+
+impl Display for NanBoxed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.with_value(|value| write!(f, "{}", value))
+    }
+}
+
+impl Debug for NanBoxed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.with_value(|value| write!(f, "{:?}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nanbox::NanBoxed;
+    use crate::stack::Slot;
+    use crate::value::Value;
+    use num_bigint::BigInt;
+    use rust_decimal::Decimal;
+    use std::mem::size_of;
+
+    #[test]
+    fn is_eight_bytes() {
+        assert_eq!(size_of::<NanBoxed>(), 8);
+    }
+
+    #[test]
+    fn round_trips() {
+        let values = vec![
+            Value::Nil,
+            Value::Boolean(true),
+            Value::Boolean(false),
+            Value::Number(0.0),
+            Value::Number(-2.5),
+            Value::Number(f64::INFINITY),
+            Value::Number(f64::NEG_INFINITY),
+            Value::Number(f64::MIN_POSITIVE),
+            Value::Int(0),
+            Value::Int(-1),
+            Value::Int((1 << 47) - 1),
+            Value::Int(-(1 << 47)),
+            Value::Int(1 << 47),
+            Value::Int(i64::MIN),
+            Value::BigInt(BigInt::from(7)),
+            Value::Decimal(Decimal::new(125, 2)),
+            Value::new_string("boxed"),
+            Value::List(vec![Value::Int(1), Value::new_string("a")]),
+        ];
+        for value in values {
+            let boxed = NanBoxed::from(value.clone());
+            assert_eq!(boxed.to_value(), value);
+            assert_eq!(boxed.to_string(), value.to_string());
+            assert_eq!(Value::from(boxed.clone()), value);
+        }
+    }
+
+    #[test]
+    fn keeps_nan() {
+        let boxed = NanBoxed::from(Value::Number(-f64::NAN));
+        assert!(boxed.as_number().unwrap().is_nan());
+        assert_ne!(boxed, boxed.clone());
+    }
+
+    #[test]
+    fn has_the_value_accessors() {
+        let number = NanBoxed::from(Value::Number(1.5));
+        assert_eq!(number.as_number(), Ok(&1.5));
+        assert!(number.is_number() && !number.is_integral());
+
+        let int = NanBoxed::from(Value::Int(-3));
+        assert!(int.is_number() && int.is_integral());
+        assert_eq!(int.to_i64(), Some(-3));
+        assert_eq!(int, NanBoxed::from(Value::Number(-3.0)));
+        assert!(int.as_number().is_err());
+
+        assert_eq!(NanBoxed::from(Value::Boolean(true)).as_bool(), Ok(&true));
+        assert!(NanBoxed::nil().is_nil() && !NanBoxed::nil().is_bool());
+
+        let string = NanBoxed::new_string("s");
+        assert!(string.is_string() && !string.is_object());
+        assert_eq!(string.as_string().unwrap(), "s");
+        assert_eq!(string, NanBoxed::new_string("s"));
+
+        let list = NanBoxed::from(Value::List(vec![Value::Nil]));
+        assert!(list.is_list() && list.is_object());
+        assert_eq!(list.as_list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn moves_out_unshared_values() {
+        let list = Value::List(vec![Value::Int(1)]);
+        let boxed = NanBoxed::from(list.clone());
+        let shared = boxed.clone();
+        assert_eq!(boxed.into_value(), list);
+        assert_eq!(shared.into_value(), list);
+        assert_eq!(
+            NanBoxed::from(Value::Number(2.5)).into_value(),
+            Value::Number(2.5)
+        );
+        assert_eq!(*NanBoxed::nil().value(), Value::Nil);
+    }
+
+    #[test]
+    fn clones_share_the_boxed_value() {
+        let string = NanBoxed::new_string("shared");
+        let copy = string.clone();
+        drop(string);
+        assert_eq!(copy.as_string().unwrap(), "shared");
+    }
+}
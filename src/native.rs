@@ -1,16 +1,24 @@
+use crate::fiber::FiberOp;
 use crate::value::Value;
 use std::fmt;
 
-pub type NativeFn = fn(arg_count: u8, args: *const Value) -> Value;
+/// Natives report errors as a message, raised as a catchable runtime error.
+pub type NativeFn = fn(arg_count: u8, args: *const Value) -> Result<Value, String>;
 
 #[derive(Clone)]
 pub struct ObjectNative {
     pub name: String,
     pub function: NativeFn,
+    /// set for the built-ins that switch fibers, whose `function` is never called
+    pub(crate) fiber_op: Option<FiberOp>,
 }
 impl ObjectNative {
     pub fn new(name: String, function: NativeFn) -> Self {
-        ObjectNative { name, function }
+        ObjectNative {
+            name,
+            function,
+            fiber_op: None,
+        }
     }
 }
 impl PartialEq for ObjectNative {
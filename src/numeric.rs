@@ -0,0 +1,135 @@
+use std::str::FromStr;
+
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::value::Value;
+
+///
+/// The rounding modes accepted by `round` and `format`, by name.
+fn rounding_strategy(mode: &Value) -> Result<RoundingStrategy, String> {
+    let name = mode
+        .as_string()
+        .map_err(|_| format!("Rounding mode must be a string, found {}", mode))?;
+    match name.as_str() {
+        "half_even" => Ok(RoundingStrategy::MidpointNearestEven),
+        "half_up" => Ok(RoundingStrategy::MidpointAwayFromZero),
+        "half_down" => Ok(RoundingStrategy::MidpointTowardZero),
+        "up" => Ok(RoundingStrategy::AwayFromZero),
+        "down" => Ok(RoundingStrategy::ToZero),
+        "ceiling" => Ok(RoundingStrategy::ToPositiveInfinity),
+        "floor" => Ok(RoundingStrategy::ToNegativeInfinity),
+        other => Err(format!("Unknown rounding mode {}", other)),
+    }
+}
+
+///
+/// The decimal places and rounding mode following the number in `round` and `format`.
+fn rounding_args(name: &str, args: &[Value]) -> Result<(u32, RoundingStrategy), String> {
+    if args.is_empty() || args.len() > 3 {
+        return Err(format!(
+            "{} expects 1 to 3 arguments, but got {}",
+            name,
+            args.len()
+        ));
+    }
+    let places = match args.get(1) {
+        Some(places) => places
+            .to_i64()
+            .and_then(|places| u32::try_from(places).ok())
+            .ok_or_else(|| {
+                format!(
+                    "Decimal places must be a non-negative integer, found {}",
+                    places
+                )
+            })?,
+        None => 0,
+    };
+    let strategy = match args.get(2) {
+        Some(mode) => rounding_strategy(mode)?,
+        None => RoundingStrategy::MidpointNearestEven,
+    };
+    Ok((places, strategy))
+}
+
+///
+/// The exact decimal a float prints as, so that `decimal(0.1)` is `0.1`.
+pub fn float_to_decimal(n: f64) -> Option<Decimal> {
+    Decimal::from_str(&n.to_string()).ok()
+}
+
+pub fn to_bigint(value: &Value) -> Result<Value, String> {
+    match value {
+        Value::Int(i) => Ok(Value::BigInt(BigInt::from(*i))),
+        Value::BigInt(b) => Ok(Value::BigInt(b.clone())),
+        Value::Number(n) if n.is_finite() => Ok(Value::BigInt(
+            BigInt::from_str(&format!("{:.0}", n.trunc())).unwrap(),
+        )),
+        Value::Decimal(d) => Ok(Value::BigInt(
+            BigInt::from_str(&d.trunc().to_string()).unwrap(),
+        )),
+        Value::String(s) => BigInt::from_str(s.trim())
+            .map(Value::BigInt)
+            .map_err(|_| format!("Can't convert \"{}\" to a big integer", s)),
+        other => Err(format!("Can't convert {} to a big integer", other)),
+    }
+}
+
+pub fn to_decimal(value: &Value) -> Result<Value, String> {
+    let decimal = match value {
+        Value::Number(n) => float_to_decimal(*n),
+        Value::String(s) => Decimal::from_str(s.trim()).ok(),
+        other => other.to_decimal(),
+    };
+    decimal
+        .map(Value::Decimal)
+        .ok_or_else(|| format!("Can't convert {} to a decimal", value))
+}
+
+///
+/// `round(x, places, mode)`: decimals and floats rounded to `places` digits after the point,
+/// half to even unless another mode is given. Integers are already round.
+pub fn round(args: &[Value]) -> Result<Value, String> {
+    let (places, strategy) = rounding_args("round", args)?;
+    match &args[0] {
+        Value::Int(_) | Value::BigInt(_) => Ok(args[0].clone()),
+        Value::Decimal(d) => Ok(Value::Decimal(d.round_dp_with_strategy(places, strategy))),
+        Value::Number(n) if !n.is_finite() => Ok(Value::Number(*n)),
+        Value::Number(n) => match float_to_decimal(*n) {
+            Some(d) => Ok(Value::Number(
+                d.round_dp_with_strategy(places, strategy).to_f64().unwrap(),
+            )),
+            None => Ok(Value::Number(*n)),
+        },
+        other => Err(format!("round expects a number, found {}", other)),
+    }
+}
+
+///
+/// `format(x, places, mode)`: the number as a string with exactly `places` digits after the
+/// point, rounded like `round`.
+pub fn format(args: &[Value]) -> Result<Value, String> {
+    let (places, strategy) = rounding_args("format", args)?;
+    let decimal = match &args[0] {
+        Value::Number(n) => float_to_decimal(*n),
+        Value::BigInt(b) if places == 0 => return Ok(Value::String(b.to_string())),
+        Value::BigInt(b) => {
+            return Ok(Value::String(format!(
+                "{}.{}",
+                b,
+                "0".repeat(places as usize)
+            )))
+        }
+        other if other.is_number() => other.to_decimal(),
+        other => return Err(format!("format expects a number, found {}", other)),
+    };
+    match decimal {
+        Some(d) => {
+            let mut rounded = d.round_dp_with_strategy(places, strategy);
+            rounded.rescale(places);
+            Ok(Value::String(rounded.to_string()))
+        }
+        None => Ok(Value::String(args[0].to_string())),
+    }
+}
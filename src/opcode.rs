@@ -6,6 +6,7 @@ pub enum Opcode {
     OpConstant(usize),
 
     OpDefineGlobal(usize),
+    OpDefineGlobalConst(usize),
 
     OpGetGlobal(usize),
     OpSetGlobal(usize),
@@ -14,10 +15,41 @@ pub enum Opcode {
     OpSetLocal(usize),
 
     OpCall(u8),
+    /// argument count, constant index of the list of argument names
+    OpCallNamed(u8, usize),
+    /// number of argument lists on the stack to flatten before calling
+    OpCallSpread(u8),
+    /// `OpCall` in `return f(x);`, which reuses the frame of the returning function when it can
+    OpTailCall(u8),
+    OpBuildList(u8),
 
     OpJumpIfFalse(u16),
     OpJump(u16),
     OpLoop(u16),
+    /// pops a value and jumps to its target in the chunk's jump table with this index
+    OpJumpTable(usize),
+    /// pops a value and two bounds, pushes whether the value is a number in the range;
+    /// true when the upper bound is included
+    OpInRange(bool),
+    /// advances the loop over the iterable and state below the top of the stack: pushes the
+    /// next item, or jumps forward this far once there is none
+    OpForIter(u16),
+
+    /// installs an exception handler: forward offsets to the catch and finally code, 0 if absent
+    OpTry(u16, u16),
+    OpPopHandler,
+    OpThrow,
+    /// resumes the completion (normal, throw or return) that entered a finally block
+    OpEndFinally,
+    /// suspends the running generator, handing the popped value to whoever resumed it
+    OpYield,
+
+    /// runs the module with this index on first use, then pushes its cached module object
+    OpImport(usize),
+    /// ends a module's script by capturing its namespace as the module object
+    OpExportModule,
+    /// constant index of the property name
+    OpGetProperty(usize),
 
     OpNil,
     OpTrue,
@@ -34,13 +66,40 @@ pub enum Opcode {
     OPSubtract,
     OPMultiply,
     OpDivide,
+    OpModulo,
+    OpPower,
+    OpIntDivide,
+    /// `OpAdd` for `++` and `--`, which only apply to numbers
+    OpIncrement,
+
+    /// bitwise, integral operands only
+    OpBitAnd,
+    OpBitOr,
+    OpBitXor,
+    OpBitNot,
+    OpShiftLeft,
+    OpShiftRight,
 
     OpEqual,
     OpGreater,
     OpLess,
+    /// not less, replacing `OpLess; OpNot`
+    OpGreaterEqual,
+    /// not greater, replacing `OpGreater; OpNot`
+    OpLessEqual,
 
     OpPrint,
     OpPop,
+    OpDup,
+
+    // superinstructions, fused by the optimizer from the instruction sequences they replace
+    /// `OpGetLocal, OpConstant, OpAdd, OpSetLocal, OpPop`: adds the constant to the local in place
+    OpAddLocalConst(usize, usize),
+    /// `OpAddLocalConst` fused from `OpIncrement`, for `i++` and `i--` on a local
+    OpIncrementLocal(usize, usize),
+    /// `OpGetLocal, OpGetLocal, OpLess, OpJumpIfFalse`: pushes whether the first local is less
+    /// than the second, and jumps forward this far when it isn't
+    OpJumpIfNotLessLocals(usize, usize, u16),
 }
 
 impl Into<Vec<u8>> for &Opcode {
@@ -73,27 +132,100 @@ impl Into<Vec<u8>> for &Opcode {
             Opcode::OpPrint => v.push(23),
             Opcode::OpPop => v.push(24),
             Opcode::OpCall(_) => v.push(25),
+            Opcode::OpModulo => v.push(26),
+            Opcode::OpPower => v.push(27),
+            Opcode::OpIntDivide => v.push(28),
+            Opcode::OpBitAnd => v.push(29),
+            Opcode::OpBitOr => v.push(30),
+            Opcode::OpBitXor => v.push(31),
+            Opcode::OpBitNot => v.push(32),
+            Opcode::OpShiftLeft => v.push(33),
+            Opcode::OpShiftRight => v.push(34),
+            Opcode::OpDup => v.push(35),
+            Opcode::OpCallNamed(_, _) => v.push(36),
+            Opcode::OpCallSpread(_) => v.push(37),
+            Opcode::OpBuildList(_) => v.push(38),
+            Opcode::OpTry(_, _) => v.push(39),
+            Opcode::OpPopHandler => v.push(40),
+            Opcode::OpThrow => v.push(41),
+            Opcode::OpEndFinally => v.push(42),
+            Opcode::OpImport(_) => v.push(43),
+            Opcode::OpExportModule => v.push(44),
+            Opcode::OpGetProperty(_) => v.push(45),
+            Opcode::OpDefineGlobalConst(_) => v.push(46),
+            Opcode::OpJumpTable(_) => v.push(47),
+            Opcode::OpInRange(_) => v.push(48),
+            Opcode::OpYield => v.push(49),
+            Opcode::OpForIter(_) => v.push(50),
+            Opcode::OpGreaterEqual => v.push(51),
+            Opcode::OpLessEqual => v.push(52),
+            Opcode::OpAddLocalConst(_, _) => v.push(53),
+            Opcode::OpJumpIfNotLessLocals(_, _, _) => v.push(54),
+            Opcode::OpTailCall(_) => v.push(55),
+            Opcode::OpIncrement => v.push(56),
+            Opcode::OpIncrementLocal(_, _) => v.push(57),
         };
 
         match &self {
             // usize
             Opcode::OpConstant(idx)
             | Opcode::OpDefineGlobal(idx)
+            | Opcode::OpDefineGlobalConst(idx)
             | Opcode::OpGetGlobal(idx)
             | Opcode::OpSetGlobal(idx)
             | Opcode::OpGetLocal(idx)
-            | Opcode::OpSetLocal(idx) => {
+            | Opcode::OpSetLocal(idx)
+            | Opcode::OpImport(idx)
+            | Opcode::OpGetProperty(idx)
+            | Opcode::OpJumpTable(idx) => {
                 v.extend_from_slice(&idx.to_le_bytes());
                 v
             }
 
-            Opcode::OpCall(args) => {
+            Opcode::OpCall(args)
+            | Opcode::OpCallSpread(args)
+            | Opcode::OpTailCall(args)
+            | Opcode::OpBuildList(args) => {
                 v.push(*args);
                 v
             }
 
+            Opcode::OpCallNamed(args, names) => {
+                v.push(*args);
+                v.extend_from_slice(&names.to_le_bytes());
+                v
+            }
+
             // u16
-            Opcode::OpJumpIfFalse(jump) | Opcode::OpJump(jump) | Opcode::OpLoop(jump) => {
+            Opcode::OpJumpIfFalse(jump)
+            | Opcode::OpJump(jump)
+            | Opcode::OpLoop(jump)
+            | Opcode::OpForIter(jump) => {
+                v.extend_from_slice(&jump.to_le_bytes());
+                v
+            }
+
+            Opcode::OpInRange(inclusive) => {
+                v.push(*inclusive as u8);
+                v
+            }
+
+            Opcode::OpTry(catch, finally) => {
+                v.extend_from_slice(&catch.to_le_bytes());
+                v.extend_from_slice(&finally.to_le_bytes());
+                v
+            }
+
+            Opcode::OpAddLocalConst(local, constant)
+            | Opcode::OpIncrementLocal(local, constant) => {
+                v.extend_from_slice(&local.to_le_bytes());
+                v.extend_from_slice(&constant.to_le_bytes());
+                v
+            }
+
+            Opcode::OpJumpIfNotLessLocals(a, b, jump) => {
+                v.extend_from_slice(&a.to_le_bytes());
+                v.extend_from_slice(&b.to_le_bytes());
                 v.extend_from_slice(&jump.to_le_bytes());
                 v
             }
@@ -111,7 +243,7 @@ fn usize_from_bytes(bytes: &[u8]) -> usize {
 }
 fn usize_from_reader(reader: &mut File) -> usize {
     let mut buffer = [0_u8; std::mem::size_of::<usize>()];
-    reader.read(&mut buffer).unwrap();
+    reader.read_exact(&mut buffer).unwrap();
     usize::from_le_bytes(buffer)
 }
 fn u16_from_bytes(bytes: &[u8]) -> u16 {
@@ -119,9 +251,14 @@ fn u16_from_bytes(bytes: &[u8]) -> u16 {
     dst.clone_from_slice(&bytes[1..]);
     u16::from_le_bytes(dst)
 }
+fn u8_from_reader(reader: &mut File) -> u8 {
+    let mut buffer = [0_u8; 1];
+    reader.read_exact(&mut buffer).unwrap();
+    buffer[0]
+}
 fn u16_from_reader(reader: &mut File) -> u16 {
     let mut buffer = [0_u8; std::mem::size_of::<u16>()];
-    reader.read(&mut buffer).unwrap();
+    reader.read_exact(&mut buffer).unwrap();
     u16::from_le_bytes(buffer)
 }
 impl Opcode {
@@ -168,6 +305,55 @@ impl Opcode {
                     22 => Opcode::OpLess,
                     23 => Opcode::OpPrint,
                     24 => Opcode::OpPop,
+                    25 => Opcode::OpCall(u8_from_reader(reader)),
+                    26 => Opcode::OpModulo,
+                    27 => Opcode::OpPower,
+                    28 => Opcode::OpIntDivide,
+                    29 => Opcode::OpBitAnd,
+                    30 => Opcode::OpBitOr,
+                    31 => Opcode::OpBitXor,
+                    32 => Opcode::OpBitNot,
+                    33 => Opcode::OpShiftLeft,
+                    34 => Opcode::OpShiftRight,
+                    35 => Opcode::OpDup,
+                    36 => {
+                        let args = u8_from_reader(reader);
+                        Opcode::OpCallNamed(args, usize_from_reader(reader))
+                    }
+                    37 => Opcode::OpCallSpread(u8_from_reader(reader)),
+                    38 => Opcode::OpBuildList(u8_from_reader(reader)),
+                    39 => {
+                        let catch = u16_from_reader(reader);
+                        Opcode::OpTry(catch, u16_from_reader(reader))
+                    }
+                    40 => Opcode::OpPopHandler,
+                    41 => Opcode::OpThrow,
+                    42 => Opcode::OpEndFinally,
+                    43 => Opcode::OpImport(usize_from_reader(reader)),
+                    44 => Opcode::OpExportModule,
+                    45 => Opcode::OpGetProperty(usize_from_reader(reader)),
+                    46 => Opcode::OpDefineGlobalConst(usize_from_reader(reader)),
+                    47 => Opcode::OpJumpTable(usize_from_reader(reader)),
+                    48 => Opcode::OpInRange(u8_from_reader(reader) == 1),
+                    49 => Opcode::OpYield,
+                    50 => Opcode::OpForIter(u16_from_reader(reader)),
+                    51 => Opcode::OpGreaterEqual,
+                    52 => Opcode::OpLessEqual,
+                    53 => {
+                        let local = usize_from_reader(reader);
+                        Opcode::OpAddLocalConst(local, usize_from_reader(reader))
+                    }
+                    54 => {
+                        let a = usize_from_reader(reader);
+                        let b = usize_from_reader(reader);
+                        Opcode::OpJumpIfNotLessLocals(a, b, u16_from_reader(reader))
+                    }
+                    55 => Opcode::OpTailCall(u8_from_reader(reader)),
+                    56 => Opcode::OpIncrement,
+                    57 => {
+                        let local = usize_from_reader(reader);
+                        Opcode::OpIncrementLocal(local, usize_from_reader(reader))
+                    }
 
                     _ => panic!("Unknwon opcode {}", buff[0]),
                 };
@@ -0,0 +1,322 @@
+use num_bigint::{BigInt, Sign};
+use num_traits::{ToPrimitive, Zero};
+use rust_decimal::{Decimal, MathematicalOps};
+
+use crate::opcode::Opcode;
+use crate::value::Value;
+
+/// The operands of a numeric binary operator, promoted to their common type.
+enum Operands {
+    Ints(i64, i64),
+    Floats(f64, f64),
+    Bigs(BigInt, BigInt),
+    Decimals(Decimal, Decimal),
+}
+
+fn numeric_operands(a: Value, b: Value) -> Result<Operands, String> {
+    if !a.is_number() || !b.is_number() {
+        return Err(format!(
+            "Operand must be numbers, found operand #1 = {:?}, #2 = {:?}",
+            b.to_string(),
+            a.to_string()
+        ));
+    }
+    Ok(match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Operands::Ints(a, b),
+        (Value::Number(a), Value::Number(b)) => Operands::Floats(a, b),
+        (Value::Int(a), Value::Number(b)) => Operands::Floats(a as f64, b),
+        (Value::Number(a), Value::Int(b)) => Operands::Floats(a, b as f64),
+        (a, b) => return promote_operands(a, b),
+    })
+}
+
+///
+/// Operands involving a big integer or a decimal: a decimal makes both decimals and a big
+/// integer makes both big integers. Neither mixes with floats, which would silently drop digits;
+/// scripts convert explicitly with `float()`, `bigint()` or `decimal()`.
+fn promote_operands(a: Value, b: Value) -> Result<Operands, String> {
+    if matches!(a, Value::Decimal(_)) || matches!(b, Value::Decimal(_)) {
+        return match (a.to_decimal(), b.to_decimal()) {
+            (Some(a), Some(b)) => Ok(Operands::Decimals(a, b)),
+            _ => Err(format!(
+                "Decimals only combine with integers and decimals, found operand #1 = {}, #2 = {}",
+                a, b
+            )),
+        };
+    }
+    match (a.to_bigint(), b.to_bigint()) {
+        (Some(a), Some(b)) => Ok(Operands::Bigs(a, b)),
+        _ => Err(format!(
+            "Big integers only combine with integers and decimals, found operand #1 = {}, #2 = {}",
+            a, b
+        )),
+    }
+}
+
+///
+/// `a / b` for big integers, which is only defined when the quotient is exact. Scripts use the
+/// integer division `~/` otherwise, or `decimal()` for a fractional quotient.
+fn big_divide(a: BigInt, b: BigInt) -> Result<Value, String> {
+    if b.is_zero() {
+        return Err("Division by zero".to_string());
+    }
+    let quotient = &a / &b;
+    if &quotient * &b != a {
+        return Err(format!(
+            "Quotient of {} / {} is not an integer, use ~/ for integer division",
+            a, b
+        ));
+    }
+    Ok(Value::BigInt(quotient))
+}
+
+fn integer_operands(a: &Value, b: &Value) -> Result<(i64, i64), String> {
+    match (a.to_i64(), b.to_i64()) {
+        (Some(a), Some(b)) => Ok((a, b)),
+        _ => Err(format!(
+            "Operands must be integers, found operand #1 = {}, #2 = {}",
+            a, b
+        )),
+    }
+}
+
+///
+/// The result of a checked integer operation, wrapped around instead when `wrapping` is set.
+fn integer_result(checked: Option<i64>, wrapped: i64, wrapping: bool) -> Result<Value, String> {
+    match checked {
+        Some(i) => Ok(Value::Int(i)),
+        None if wrapping => Ok(Value::Int(wrapped)),
+        None => Err("Integer overflow".to_string()),
+    }
+}
+
+fn decimal_result(result: Option<Decimal>) -> Result<Value, String> {
+    result
+        .map(Value::Decimal)
+        .ok_or_else(|| "Decimal overflow".to_string())
+}
+
+fn add(a: Value, b: Value, wrapping: bool) -> Result<Value, String> {
+    if a.is_number() && b.is_number() {
+        return Ok(match numeric_operands(a, b)? {
+            Operands::Ints(a, b) => integer_result(a.checked_add(b), a.wrapping_add(b), wrapping)?,
+            Operands::Floats(a, b) => Value::Number(a + b),
+            Operands::Bigs(a, b) => Value::BigInt(a + b),
+            Operands::Decimals(a, b) => decimal_result(a.checked_add(b))?,
+        });
+    }
+    // strings concatenate with numbers and with caught errors, which show as "Error: message"
+    let concatenable =
+        |v: &Value| v.is_string() || v.is_number() || matches!(v, Value::Exception(_));
+    if concatenable(&a) && concatenable(&b) && (a.is_string() || b.is_string()) {
+        return Ok(Value::String(format!("{}{}", a, b)));
+    }
+    Err(format!("Addition operation requires operands must be of same type, either number or string. Found operand #1 = {}, operand #2 = {}", b, a))
+}
+
+///
+/// Applies a binary operator to its operands the way `VM::run` does, so that the compiler can
+/// fold constant expressions. Errors are the messages of the runtime errors raised.
+pub fn binary_op(op: &Opcode, a: Value, b: Value, wrapping: bool) -> Result<Value, String> {
+    let value = match op {
+        Opcode::OpAdd => add(a, b, wrapping)?,
+        Opcode::OpIncrement if !a.is_number() => {
+            return Err(format!(
+                "Operand of '++' or '--' must be a number, found {}",
+                a
+            ))
+        }
+        Opcode::OpIncrement => add(a, b, wrapping)?,
+        Opcode::OPSubtract => match numeric_operands(a, b)? {
+            Operands::Ints(a, b) => integer_result(a.checked_sub(b), a.wrapping_sub(b), wrapping)?,
+            Operands::Floats(a, b) => Value::Number(a - b),
+            Operands::Bigs(a, b) => Value::BigInt(a - b),
+            Operands::Decimals(a, b) => decimal_result(a.checked_sub(b))?,
+        },
+        Opcode::OPMultiply => match numeric_operands(a, b)? {
+            Operands::Ints(a, b) => integer_result(a.checked_mul(b), a.wrapping_mul(b), wrapping)?,
+            Operands::Floats(a, b) => Value::Number(a * b),
+            Operands::Bigs(a, b) => Value::BigInt(a * b),
+            Operands::Decimals(a, b) => decimal_result(a.checked_mul(b))?,
+        },
+        Opcode::OpDivide => match numeric_operands(a, b)? {
+            Operands::Ints(a, b) => Value::Number(a as f64 / b as f64),
+            Operands::Floats(a, b) => Value::Number(a / b),
+            Operands::Bigs(a, b) => big_divide(a, b)?,
+            Operands::Decimals(_, b) if b.is_zero() => return Err("Division by zero".to_string()),
+            Operands::Decimals(a, b) => decimal_result(a.checked_div(b))?,
+        },
+        Opcode::OpModulo => match numeric_operands(a, b)? {
+            Operands::Ints(_, 0) => return Err("Modulo by zero".to_string()),
+            Operands::Ints(a, b) => integer_result(a.checked_rem(b), a.wrapping_rem(b), wrapping)?,
+            Operands::Floats(a, b) => Value::Number(a % b),
+            Operands::Bigs(_, b) if b.is_zero() => return Err("Modulo by zero".to_string()),
+            Operands::Bigs(a, b) => Value::BigInt(a % b),
+            Operands::Decimals(_, b) if b.is_zero() => return Err("Modulo by zero".to_string()),
+            Operands::Decimals(a, b) => decimal_result(a.checked_rem(b))?,
+        },
+        Opcode::OpPower => match numeric_operands(a, b)? {
+            Operands::Ints(a, b) if (0..=u32::MAX as i64).contains(&b) => {
+                integer_result(a.checked_pow(b as u32), a.wrapping_pow(b as u32), wrapping)?
+            }
+            Operands::Ints(a, b) => Value::Number((a as f64).powf(b as f64)),
+            Operands::Floats(a, b) => Value::Number(a.powf(b)),
+            Operands::Bigs(a, b) => match b.to_u32() {
+                Some(exponent) => Value::BigInt(a.pow(exponent)),
+                None => {
+                    return Err(format!(
+                        "Big integer exponent must be between 0 and {}, found {}",
+                        u32::MAX,
+                        b
+                    ))
+                }
+            },
+            Operands::Decimals(a, b) => match b.fract().is_zero().then(|| b.to_i64()) {
+                Some(Some(exponent)) => decimal_result(a.checked_powi(exponent))?,
+                _ => return Err(format!("Decimal exponent must be an integer, found {}", b)),
+            },
+        },
+        Opcode::OpIntDivide => match numeric_operands(a, b)? {
+            Operands::Ints(_, 0) => return Err("Integer division by zero".to_string()),
+            Operands::Ints(a, b) => {
+                let floored = a.checked_div(b).map(|q| {
+                    if a % b != 0 && (a < 0) != (b < 0) {
+                        q - 1
+                    } else {
+                        q
+                    }
+                });
+                integer_result(floored, a.wrapping_div(b), wrapping)?
+            }
+            Operands::Floats(a, b) => Value::Number((a / b).floor()),
+            Operands::Bigs(_, b) if b.is_zero() => {
+                return Err("Integer division by zero".to_string())
+            }
+            Operands::Bigs(a, b) => {
+                let remainder = &a % &b;
+                let quotient = &a / &b;
+                if !remainder.is_zero() && (a.sign() == Sign::Minus) != (b.sign() == Sign::Minus) {
+                    Value::BigInt(quotient - 1)
+                } else {
+                    Value::BigInt(quotient)
+                }
+            }
+            Operands::Decimals(_, b) if b.is_zero() => {
+                return Err("Integer division by zero".to_string())
+            }
+            Operands::Decimals(a, b) => decimal_result(a.checked_div(b).map(|q| q.floor()))?,
+        },
+        Opcode::OpBitAnd => {
+            let (a, b) = integer_operands(&a, &b)?;
+            Value::Int(a & b)
+        }
+        Opcode::OpBitOr => {
+            let (a, b) = integer_operands(&a, &b)?;
+            Value::Int(a | b)
+        }
+        Opcode::OpBitXor => {
+            let (a, b) = integer_operands(&a, &b)?;
+            Value::Int(a ^ b)
+        }
+        Opcode::OpShiftLeft | Opcode::OpShiftRight => match integer_operands(&a, &b)? {
+            (_, b) if !(0..64).contains(&b) => {
+                return Err(format!(
+                    "Shift amount must be between 0 and 63, found {}",
+                    b
+                ))
+            }
+            (a, b) if *op == Opcode::OpShiftLeft => Value::Int(a << b),
+            (a, b) => Value::Int(a >> b),
+        },
+        Opcode::OpEqual => Value::Boolean(a == b),
+        Opcode::OpGreater => match numeric_operands(a, b)? {
+            Operands::Ints(a, b) => Value::Boolean(a > b),
+            Operands::Floats(a, b) => Value::Boolean(a > b),
+            Operands::Bigs(a, b) => Value::Boolean(a > b),
+            Operands::Decimals(a, b) => Value::Boolean(a > b),
+        },
+        Opcode::OpLess => match numeric_operands(a, b)? {
+            Operands::Ints(a, b) => Value::Boolean(a < b),
+            Operands::Floats(a, b) => Value::Boolean(a < b),
+            Operands::Bigs(a, b) => Value::Boolean(a < b),
+            Operands::Decimals(a, b) => Value::Boolean(a < b),
+        },
+        // the negations, so that NaN compares like the two instructions they replace
+        Opcode::OpGreaterEqual => unary_op(
+            &Opcode::OpNot,
+            binary_op(&Opcode::OpLess, a, b, wrapping)?,
+            wrapping,
+        )?,
+        Opcode::OpLessEqual => unary_op(
+            &Opcode::OpNot,
+            binary_op(&Opcode::OpGreater, a, b, wrapping)?,
+            wrapping,
+        )?,
+        other => panic!("{:?} is not a binary operator", other),
+    };
+    Ok(value)
+}
+
+///
+/// The common case of `binary_op`, two ints or two floats, computed from references to the
+/// operands on the stack. None leaves everything else, including overflow errors, to
+/// `binary_op`.
+#[inline]
+pub fn number_op(op: &Opcode, a: &Value, b: &Value, wrapping: bool) -> Option<Value> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => {
+            let (a, b) = (*a, *b);
+            let checked = match op {
+                Opcode::OpAdd | Opcode::OpIncrement => (a.checked_add(b), a.wrapping_add(b)),
+                Opcode::OPSubtract => (a.checked_sub(b), a.wrapping_sub(b)),
+                Opcode::OPMultiply => (a.checked_mul(b), a.wrapping_mul(b)),
+                Opcode::OpEqual => return Some(Value::Boolean(a == b)),
+                Opcode::OpGreater => return Some(Value::Boolean(a > b)),
+                Opcode::OpLess => return Some(Value::Boolean(a < b)),
+                Opcode::OpGreaterEqual => return Some(Value::Boolean(a >= b)),
+                Opcode::OpLessEqual => return Some(Value::Boolean(a <= b)),
+                _ => return None,
+            };
+            match checked {
+                (Some(i), _) => Some(Value::Int(i)),
+                (None, wrapped) if wrapping => Some(Value::Int(wrapped)),
+                (None, _) => None,
+            }
+        }
+        (Value::Number(a), Value::Number(b)) => Some(match op {
+            Opcode::OpAdd | Opcode::OpIncrement => Value::Number(a + b),
+            Opcode::OPSubtract => Value::Number(a - b),
+            Opcode::OPMultiply => Value::Number(a * b),
+            Opcode::OpDivide => Value::Number(a / b),
+            Opcode::OpEqual => Value::Boolean(a == b),
+            Opcode::OpGreater => Value::Boolean(a > b),
+            Opcode::OpLess => Value::Boolean(a < b),
+            // negations of OpLess and OpGreater, so NaN compares as the slow path does
+            Opcode::OpGreaterEqual => Value::Boolean(a >= b || a.is_nan() || b.is_nan()),
+            Opcode::OpLessEqual => Value::Boolean(a <= b || a.is_nan() || b.is_nan()),
+            _ => return None,
+        }),
+        _ => None,
+    }
+}
+
+///
+/// Applies a unary operator to its operand the way `VM::run` does.
+pub fn unary_op(op: &Opcode, a: Value, wrapping: bool) -> Result<Value, String> {
+    match (op, a) {
+        (Opcode::OpNegate, Value::Int(i)) => {
+            integer_result(i.checked_neg(), i.wrapping_neg(), wrapping)
+        }
+        (Opcode::OpNegate, Value::BigInt(b)) => Ok(Value::BigInt(-b)),
+        (Opcode::OpNegate, Value::Decimal(d)) => Ok(Value::Decimal(-d)),
+        (Opcode::OpNegate, Value::Number(n)) => Ok(Value::Number(-n)),
+        (Opcode::OpNegate, _) => Err("Operand must be numbers".to_string()),
+        (Opcode::OpNot, Value::Boolean(b)) => Ok(Value::Boolean(!b)),
+        (Opcode::OpNot, _) => Err("Operands must be boolean".to_string()),
+        (Opcode::OpBitNot, a) => match a.to_i64() {
+            Some(i) => Ok(Value::Int(!i)),
+            None => Err(format!("Operand must be an integer, found {}", a)),
+        },
+        (other, _) => panic!("{:?} is not a unary operator", other),
+    }
+}
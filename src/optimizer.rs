@@ -0,0 +1,494 @@
+use crate::chunk::Chunk;
+use crate::opcode::Opcode;
+
+///
+/// Rewrites the code of a compiled chunk into shorter code that behaves the same: jumps to
+/// jumps go straight to the final target, code that can't be reached is dropped, values pushed
+/// only to be popped again are not pushed, and `OpLess; OpNot` becomes `OpGreaterEqual`.
+pub fn optimize(chunk: &mut Chunk) {
+    let len = chunk.op_codes.len();
+    for index in 0..len {
+        thread_jump(chunk, index);
+    }
+
+    let mut keep = reachable(chunk);
+    let mut is_target = vec![false; len + 1];
+    for index in (0..len).filter(|index| keep[*index]) {
+        for target in targets(chunk, index) {
+            is_target[target] = true;
+        }
+    }
+
+    // the last instruction kept before the one being looked at
+    let mut previous: Option<usize> = None;
+    for index in 0..len {
+        if !keep[index] {
+            continue;
+        }
+        let fused = match (previous.map(|p| &chunk.op_codes[p]), &chunk.op_codes[index]) {
+            (Some(Opcode::OpLess), Opcode::OpNot) => Some(Opcode::OpGreaterEqual),
+            (Some(Opcode::OpGreater), Opcode::OpNot) => Some(Opcode::OpLessEqual),
+            (Some(pushed), Opcode::OpPop) if is_pure_push(pushed) => None,
+            _ => {
+                previous = Some(index);
+                continue;
+            }
+        };
+        // the pair can only be rewritten when no jump lands in between
+        let p = previous.unwrap();
+        if is_target[p + 1..=index].iter().any(|t| *t) {
+            previous = Some(index);
+            continue;
+        }
+        keep[index] = false;
+        match fused {
+            Some(opcode) => chunk.op_codes[p] = opcode,
+            None => {
+                keep[p] = false;
+                previous = (0..p).rev().find(|i| keep[*i]);
+            }
+        }
+    }
+
+    // a jump over nothing but dropped code goes on with the next instruction anyway
+    for index in (0..len).rev() {
+        if keep[index]
+            && matches!(
+                chunk.op_codes[index],
+                Opcode::OpJump(_) | Opcode::OpJumpIfFalse(_)
+            )
+        {
+            let target = targets(chunk, index)[0];
+            if target > index && !keep[index + 1..target].iter().any(|k| *k) {
+                keep[index] = false;
+            }
+        }
+    }
+
+    relocate(chunk, &keep);
+    chunk.retain(&keep);
+}
+
+///
+/// Replaces the instruction sequences dominating loops with superinstructions: `i = i + 1;`,
+/// `i += 1;` and `i++;` on a local, and a loop condition comparing two locals.
+pub fn fuse_superinstructions(chunk: &mut Chunk) {
+    let len = chunk.op_codes.len();
+    let mut is_target = vec![false; len + 1];
+    for index in 0..len {
+        for target in targets(chunk, index) {
+            is_target[target] = true;
+        }
+    }
+
+    let mut keep = vec![true; len];
+    let mut index = 0;
+    while index < len {
+        match superinstruction(&chunk.op_codes[index..]) {
+            // jumps may land on the first instruction replaced, which leads to the fused one
+            Some((at, fused, count)) if !is_target[index + 1..index + count].iter().any(|t| *t) => {
+                keep[index..index + count]
+                    .iter_mut()
+                    .for_each(|k| *k = false);
+                keep[index + at] = true;
+                chunk.op_codes[index + at] = fused;
+                index += count;
+            }
+            _ => index += 1,
+        }
+    }
+
+    relocate(chunk, &keep);
+    chunk.retain(&keep);
+}
+
+///
+/// The superinstruction adding a constant to a local in place with `op`.
+fn add_local_const(op: &Opcode, local: usize, constant: usize) -> Opcode {
+    match op {
+        Opcode::OpIncrement => Opcode::OpIncrementLocal(local, constant),
+        _ => Opcode::OpAddLocalConst(local, constant),
+    }
+}
+
+///
+/// The superinstruction for the code starting at `code[0]`: its position among the instructions
+/// it replaces, which keeps the line and span errors are reported at, itself, and their number.
+fn superinstruction(code: &[Opcode]) -> Option<(usize, Opcode, usize)> {
+    match code {
+        [Opcode::OpGetLocal(local), Opcode::OpConstant(constant), op @ (Opcode::OpAdd | Opcode::OpIncrement), Opcode::OpSetLocal(set), Opcode::OpPop, ..]
+            if local == set =>
+        {
+            Some((2, add_local_const(op, *local, *constant), 5))
+        }
+        [Opcode::OpGetLocal(local), Opcode::OpDup, Opcode::OpConstant(constant), op @ (Opcode::OpAdd | Opcode::OpIncrement), Opcode::OpSetLocal(set), Opcode::OpPop, Opcode::OpPop, ..]
+            if local == set =>
+        {
+            Some((3, add_local_const(op, *local, *constant), 7))
+        }
+        // the jump moves back to the comparison, one instruction further from its target
+        [Opcode::OpGetLocal(a), Opcode::OpGetLocal(b), Opcode::OpLess, Opcode::OpJumpIfFalse(jump), ..] => {
+            jump.checked_add(1)
+                .map(|jump| (2, Opcode::OpJumpIfNotLessLocals(*a, *b, jump), 4))
+        }
+        _ => None,
+    }
+}
+
+///
+/// Instructions that only push a value, which an `OpPop` right after undoes.
+fn is_pure_push(opcode: &Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::OpGetLocal(_)
+            | Opcode::OpConstant(_)
+            | Opcode::OpNil
+            | Opcode::OpTrue
+            | Opcode::OpFalse
+            | Opcode::OpDup
+    )
+}
+
+///
+/// The instructions the one at `index` can transfer control to other than the next one.
+fn targets(chunk: &Chunk, index: usize) -> Vec<usize> {
+    let next = index + 1;
+    match &chunk.op_codes[index] {
+        Opcode::OpJump(jump)
+        | Opcode::OpJumpIfFalse(jump)
+        | Opcode::OpForIter(jump)
+        | Opcode::OpJumpIfNotLessLocals(_, _, jump) => vec![next + *jump as usize],
+        Opcode::OpLoop(offset) => vec![next - *offset as usize],
+        Opcode::OpTry(catch, finally) => [catch, finally]
+            .iter()
+            .filter(|offset| ***offset > 0)
+            .map(|offset| next + **offset as usize)
+            .collect(),
+        Opcode::OpJumpTable(table) => {
+            let table = chunk.jump_table(*table);
+            table
+                .dense
+                .iter()
+                .flatten()
+                .chain(table.strings.values())
+                .chain(std::iter::once(&table.default))
+                .map(|offset| next + *offset as usize)
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+///
+/// Whether execution never goes on with the instruction after this one.
+fn is_terminator(opcode: &Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::OpReturn
+            | Opcode::OpJump(_)
+            | Opcode::OpLoop(_)
+            | Opcode::OpThrow
+            | Opcode::OpJumpTable(_)
+    )
+}
+
+///
+/// Which instructions can run, following every path from the start of the chunk.
+fn reachable(chunk: &Chunk) -> Vec<bool> {
+    let len = chunk.op_codes.len();
+    let mut reached = vec![false; len];
+    let mut pending = vec![0];
+    while let Some(index) = pending.pop() {
+        if index >= len || reached[index] {
+            continue;
+        }
+        reached[index] = true;
+        pending.extend(targets(chunk, index));
+        if !is_terminator(&chunk.op_codes[index]) {
+            pending.push(index + 1);
+        }
+    }
+    reached
+}
+
+///
+/// Points a jump landing on an unconditional jump at where that one goes, and a conditional
+/// jump landing on the same test at where the test jumps, as the value tested is still there.
+fn thread_jump(chunk: &mut Chunk, index: usize) {
+    let conditional = match chunk.op_codes[index] {
+        Opcode::OpJump(_) | Opcode::OpLoop(_) => false,
+        Opcode::OpJumpIfFalse(_) => true,
+        _ => return,
+    };
+    let mut target = targets(chunk, index)[0];
+    let mut visited = vec![index];
+    while let Some(opcode) = chunk.op_codes.get(target) {
+        let threads = match opcode {
+            Opcode::OpJump(_) | Opcode::OpLoop(_) => true,
+            Opcode::OpJumpIfFalse(_) => conditional,
+            _ => false,
+        };
+        if !threads || visited.contains(&target) {
+            break;
+        }
+        visited.push(target);
+        target = targets(chunk, target)[0];
+    }
+    if let Some(threaded) = encode_jump(&chunk.op_codes[index], index, target) {
+        chunk.op_codes[index] = threaded;
+    }
+}
+
+///
+/// The jump from `index` to `target` of the same kind as `opcode`, if its offset fits.
+fn encode_jump(opcode: &Opcode, index: usize, target: usize) -> Option<Opcode> {
+    let next = index + 1;
+    let forward = target
+        .checked_sub(next)
+        .and_then(|offset| u16::try_from(offset).ok());
+    let backward = next
+        .checked_sub(target)
+        .and_then(|offset| u16::try_from(offset).ok());
+    match opcode {
+        Opcode::OpJump(_) | Opcode::OpLoop(_) => forward
+            .map(Opcode::OpJump)
+            .or_else(|| backward.map(Opcode::OpLoop)),
+        Opcode::OpJumpIfFalse(_) => forward.map(Opcode::OpJumpIfFalse),
+        Opcode::OpForIter(_) => forward.map(Opcode::OpForIter),
+        _ => None,
+    }
+}
+
+///
+/// Rewrites the offsets of the kept jumps for the positions they have once the others are
+/// gone. A jump to a dropped instruction lands on the next one kept, which does the same.
+fn relocate(chunk: &mut Chunk, keep: &[bool]) {
+    let len = chunk.op_codes.len();
+    let mut position = Vec::with_capacity(len + 1);
+    let mut kept = 0;
+    for flag in keep.iter().chain(std::iter::once(&true)) {
+        position.push(kept);
+        kept += *flag as usize;
+    }
+    let offset = |from: usize, to: usize| (position[to] - position[from] - 1) as u16;
+
+    for index in (0..len).filter(|index| keep[*index]) {
+        let targets = targets(chunk, index);
+        let relocated = match &chunk.op_codes[index] {
+            Opcode::OpJump(_) => Opcode::OpJump(offset(index, targets[0])),
+            Opcode::OpJumpIfFalse(_) => Opcode::OpJumpIfFalse(offset(index, targets[0])),
+            Opcode::OpForIter(_) => Opcode::OpForIter(offset(index, targets[0])),
+            Opcode::OpJumpIfNotLessLocals(a, b, _) => {
+                Opcode::OpJumpIfNotLessLocals(*a, *b, offset(index, targets[0]))
+            }
+            Opcode::OpLoop(_) => {
+                Opcode::OpLoop((position[index] + 1 - position[targets[0]]) as u16)
+            }
+            Opcode::OpTry(catch, finally) => {
+                let next = index + 1;
+                let relocate = |o: u16| match o {
+                    0 => 0,
+                    o => offset(index, next + o as usize),
+                };
+                Opcode::OpTry(relocate(*catch), relocate(*finally))
+            }
+            Opcode::OpJumpTable(table) => {
+                let next = index + 1;
+                let table = chunk.jump_table_mut(*table);
+                for o in table
+                    .dense
+                    .iter_mut()
+                    .flatten()
+                    .chain(table.strings.values_mut())
+                    .chain(std::iter::once(&mut table.default))
+                {
+                    *o = offset(index, next + *o as usize);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        chunk.op_codes[index] = relocated;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{Chunk, ChunkArena};
+    use crate::opcode::Opcode;
+    use crate::optimizer::{fuse_superinstructions, optimize};
+    use crate::parser::Parser;
+
+    fn optimized(op_codes: Vec<Opcode>) -> Vec<Opcode> {
+        let mut chunk = Chunk::new();
+        for opcode in op_codes {
+            chunk.write_chunk(opcode);
+        }
+        optimize(&mut chunk);
+        chunk.op_codes
+    }
+
+    #[test]
+    fn removes_dead_stores_and_unreachable_code() {
+        assert_eq!(
+            optimized(vec![
+                Opcode::OpGetLocal(1),
+                Opcode::OpPop,
+                Opcode::OpGetLocal(1),
+                Opcode::OpReturn,
+                Opcode::OpNil,
+                Opcode::OpReturn,
+            ]),
+            vec![Opcode::OpGetLocal(1), Opcode::OpReturn]
+        );
+    }
+
+    #[test]
+    fn fuses_comparisons_unless_a_jump_lands_between() {
+        assert_eq!(
+            optimized(vec![
+                Opcode::OpGetLocal(1),
+                Opcode::OpGetLocal(2),
+                Opcode::OpLess,
+                Opcode::OpNot,
+                Opcode::OpReturn,
+            ]),
+            vec![
+                Opcode::OpGetLocal(1),
+                Opcode::OpGetLocal(2),
+                Opcode::OpGreaterEqual,
+                Opcode::OpReturn,
+            ]
+        );
+        let code = vec![
+            Opcode::OpGetLocal(1),
+            Opcode::OpJumpIfFalse(3),
+            Opcode::OpGetLocal(1),
+            Opcode::OpGetLocal(2),
+            Opcode::OpLess,
+            Opcode::OpNot,
+            Opcode::OpReturn,
+        ];
+        assert_eq!(optimized(code.clone()), code);
+    }
+
+    #[test]
+    fn threads_and_repatches_jumps() {
+        assert_eq!(
+            optimized(vec![
+                Opcode::OpGetLocal(1),
+                Opcode::OpJumpIfFalse(1),
+                Opcode::OpReturn,
+                Opcode::OpJump(1),
+                Opcode::OpReturn,
+                Opcode::OpNil,
+                Opcode::OpReturn,
+            ]),
+            vec![
+                Opcode::OpGetLocal(1),
+                Opcode::OpJumpIfFalse(1),
+                Opcode::OpReturn,
+                Opcode::OpNil,
+                Opcode::OpReturn,
+            ]
+        );
+        assert_eq!(
+            optimized(vec![
+                Opcode::OpTrue,
+                Opcode::OpJumpIfFalse(4),
+                Opcode::OpPop,
+                Opcode::OpGetLocal(1),
+                Opcode::OpPop,
+                Opcode::OpLoop(6),
+                Opcode::OpPop,
+                Opcode::OpNil,
+                Opcode::OpReturn,
+            ]),
+            vec![
+                Opcode::OpTrue,
+                Opcode::OpJumpIfFalse(2),
+                Opcode::OpPop,
+                Opcode::OpLoop(4),
+                Opcode::OpPop,
+                Opcode::OpNil,
+                Opcode::OpReturn,
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_jumps_to_the_next_instruction() {
+        assert_eq!(
+            optimized(vec![
+                Opcode::OpGetLocal(1),
+                Opcode::OpJump(1),
+                Opcode::OpNil,
+                Opcode::OpReturn,
+            ]),
+            vec![Opcode::OpGetLocal(1), Opcode::OpReturn]
+        );
+    }
+
+    #[test]
+    fn compiled_functions_are_optimized() {
+        let source = "fun f(a) { a; return a >= 1; }";
+        let mut chunks = ChunkArena::new();
+        let mut parser = Parser::new(source, &mut chunks);
+        parser.compile().expect("compiles");
+        assert_eq!(
+            parser.chunk_at(1).op_codes,
+            vec![
+                Opcode::OpGetLocal(1),
+                Opcode::OpConstant(0),
+                Opcode::OpGreaterEqual,
+                Opcode::OpReturn,
+            ]
+        );
+
+        let mut chunks = ChunkArena::new();
+        let mut parser = Parser::new(source, &mut chunks);
+        parser.optimize = false;
+        parser.compile().expect("compiles");
+        assert_eq!(parser.chunk_at(1).op_codes.len(), 9);
+    }
+
+    #[test]
+    fn fuses_loop_instructions() {
+        let mut chunks = ChunkArena::new();
+        let mut parser = Parser::new(
+            "fun f(n) { var s = 0; for (var i = 0; i < n; i++) s += 2; return s; }",
+            &mut chunks,
+        );
+        parser.compile().expect("compiles");
+        let ops = &parser.chunk_at(1).op_codes;
+        assert!(ops.contains(&Opcode::OpJumpIfNotLessLocals(3, 1, 6)));
+        assert_eq!(
+            ops.iter()
+                .filter(|op| matches!(op, Opcode::OpAddLocalConst(_, _)))
+                .count(),
+            1
+        );
+        assert_eq!(
+            ops.iter()
+                .filter(|op| matches!(op, Opcode::OpIncrementLocal(_, _)))
+                .count(),
+            1
+        );
+
+        let mut chunk = Chunk::new();
+        for opcode in [
+            Opcode::OpGetLocal(1),
+            Opcode::OpConstant(0),
+            Opcode::OpAdd,
+            Opcode::OpSetLocal(2),
+            Opcode::OpPop,
+            Opcode::OpNil,
+            Opcode::OpReturn,
+        ] {
+            chunk.write_chunk(opcode);
+        }
+        let unfused = chunk.op_codes.clone();
+        fuse_superinstructions(&mut chunk);
+        assert_eq!(chunk.op_codes, unfused);
+    }
+}
@@ -1,9 +1,14 @@
-use crate::chunk::{Chunk, ChunkIndex, ChunkWriterTrait};
-use crate::compiler::{Compiler, Local};
-use std::io::Write;
+use crate::ast::{
+    Argument, Arm, ArmBody, BinaryOp, Block, Catch, Expr, ExprKind, Finally, Function,
+    FunctionBody, LogicalOp, Match, Name, Param, Pattern, Program, Span, Stmt, StmtKind, Target,
+    UnaryOp, Variable,
+};
+use crate::chunk::{Chunk, ChunkIndex};
+use crate::compiler::Compiler;
+use crate::resolver::Resolver;
 
 use crate::function::{FunctionType, ObjectFunction};
-use crate::opcode::Opcode;
+use crate::optimizer;
 use crate::precedence::{ParserRule, Precedence};
 use crate::scanner::Scanner;
 use crate::token::TokenType::Comma;
@@ -11,7 +16,10 @@ use crate::token::{Token, TokenType};
 use crate::value::Value;
 
 use crate::chunk::ChunkArena;
-use std::mem;
+use crate::module::Module;
+use num_traits::FromPrimitive;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct ParserError {
@@ -19,18 +27,81 @@ pub struct ParserError {
     pub start: usize,
     len: usize,
     pub msg: String,
+    /// the error is in an imported module, and was printed when it was found there
+    imported: bool,
 }
 
+impl ParserError {
+    ///
+    /// Prints an error found in a parsed program, in the format of parse errors.
+    pub(crate) fn report(span: Span, msg: &str) -> Self {
+        eprintln!(
+            " [line {}] Error at {} {}: {}",
+            span.line, span.len, span.start, msg
+        );
+        ParserError {
+            line: span.line,
+            start: span.start,
+            len: span.len,
+            msg: msg.to_string(),
+            imported: false,
+        }
+    }
+
+    ///
+    /// The error of a module that failed to compile, at the import of it. Only the innermost
+    /// import names the file and line of the error; imports of the importing modules keep
+    /// that message.
+    pub(crate) fn imported(self, at: Span, path: &Path) -> Self {
+        let msg = if self.imported {
+            self.msg
+        } else {
+            format!(
+                "{} (in {}, line {})",
+                self.msg,
+                path.display(),
+                self.line + 1
+            )
+        };
+        ParserError {
+            line: at.line,
+            start: at.start,
+            len: at.len,
+            msg,
+            imported: true,
+        }
+    }
+}
+
+///
+/// Compiles a script or module in three passes: parsing into a syntax tree, resolving the
+/// names in the tree, and generating the bytecode from it.
 #[derive(Debug)]
 pub struct Parser<'a> {
     pub scanner: Scanner<'a>,
-    pub compiler: Box<Compiler>,
     pub current: Token,
     pub previous: Token,
     pub result: Option<ParserError>,
     pub panic_mode: bool,
-    resolver_errors: Vec<&'static str>,
     chunks: &'a mut ChunkArena,
+    /// the script or module being compiled
+    function: ObjectFunction,
+    /// index of the module being compiled, 0 for the main script
+    module: usize,
+    /// directories searched for imports not found next to the importing file
+    pub search_path: Vec<PathBuf>,
+    /// messages about code that compiles but is probably wrong
+    pub warnings: Vec<String>,
+    /// evaluate operators on constant operands at compile time
+    pub fold_constants: bool,
+    /// run the peephole optimizer over every chunk once the main script is compiled
+    pub optimize: bool,
+    /// fuse common instruction sequences into superinstructions along with optimizing
+    pub superinstructions: bool,
+    /// compile `return f(x);` into a call that reuses the returning function's frame
+    pub tail_calls: bool,
+    /// read imported modules from their compiled files while up to date, and write them otherwise
+    pub cache_modules: bool,
 }
 
 impl<'a> Parser<'a> {
@@ -42,20 +113,50 @@ impl<'a> Parser<'a> {
 
         Parser {
             scanner: Scanner::new(source),
-            resolver_errors: Vec::new(),
-            compiler: Compiler::new2(ObjectFunction::new(
+            function: ObjectFunction::new(
                 FunctionType::Script,
                 "script".to_string(),
                 initial_chunk,
-            )),
+            ),
             chunks: chunks_array,
             current: Token::dummy(),
             previous: Token::dummy(),
             result: None,
             panic_mode: false,
+            module: 0,
+            search_path: Vec::new(),
+            warnings: Vec::new(),
+            fold_constants: true,
+            optimize: true,
+            superinstructions: true,
+            tail_calls: true,
+            cache_modules: false,
         }
     }
 
+    ///
+    /// A parser for an imported module, compiled into the importer's chunks.
+    pub(crate) fn new_module(
+        source: &'a str,
+        chunks_array: &'a mut ChunkArena,
+        module: usize,
+        search_path: Vec<PathBuf>,
+        fold_constants: bool,
+        tail_calls: bool,
+    ) -> Self {
+        let name = chunks_array.modules[module].name();
+        let mut parser = Parser::new(source, chunks_array);
+        let chunk_index = parser.function.chunk_index;
+        let mut function = ObjectFunction::new(FunctionType::Module, name, chunk_index);
+        function.module = module;
+        parser.function = function;
+        parser.module = module;
+        parser.search_path = search_path;
+        parser.fold_constants = fold_constants;
+        parser.tail_calls = tail_calls;
+        parser
+    }
+
     ///
     ///
     ///
@@ -64,9 +165,10 @@ impl<'a> Parser<'a> {
         loop {
             self.current = self.scanner.scan_token();
             // dbg!(format!("CURRENT {:?} ", self.current.token_type));
-            match self.current.token_type {
-                TokenType::Error => {
-                    println!("ERROR")
+            match &self.current.token_type {
+                TokenType::Error(msg) => {
+                    let msg = msg.clone();
+                    self.error_at_current(msg.as_str());
                 }
                 _ => {
                     break;
@@ -76,184 +178,216 @@ impl<'a> Parser<'a> {
     }
 
     ///
-    ///
+    /// Parses, resolves and compiles the source. Each pass only runs when the one before
+    /// found no errors, and the last error found is returned.
     pub fn compile(&mut self) -> Result<&mut ObjectFunction, ParserError> {
+        let mut program = self.parse()?;
+
+        let mut resolver = Resolver::new(self.function.ftype.clone());
+        let resolved = resolver.resolve(&mut program);
+        self.warnings.append(&mut resolver.warnings);
+        resolved?;
+
+        self.function = Compiler::new(
+            self.chunks,
+            self.function.clone(),
+            &self.search_path,
+            self.fold_constants,
+            self.tail_calls,
+            self.cache_modules,
+        )
+        .compile(&program)?;
+
+        // imported modules are compiled into the same chunks, so the main script does them all
+        if self.optimize && self.module == 0 {
+            for chunk in self.chunks.chunks.iter_mut() {
+                optimizer::optimize(chunk);
+                if self.superinstructions {
+                    optimizer::fuse_superinstructions(chunk);
+                }
+            }
+        }
+        Ok(&mut self.function)
+    }
+
+    ///
+    /// The syntax tree of the source, or the last syntax error in it.
+    pub fn parse(&mut self) -> Result<Program, ParserError> {
         self.result = None;
         self.panic_mode = false;
         self.advance();
-        // self.expression();
-        // self.consume(TokenType::EOF, "Expect end of expression");
 
+        let mut statements = Vec::new();
         while !self.match_token(TokenType::EOF) {
-            self.declaration();
+            statements.extend(self.declaration());
         }
 
-        self.end_compiler()
+        match &self.result {
+            None => Ok(Program {
+                statements,
+                eof: Span::from(&self.previous),
+            }),
+            Some(err) => Err(err.clone()),
+        }
     }
 
-    fn push_compiler(&mut self, kind: FunctionType) {
-        // let function_name = self.gc.intern(self.previous..to_owned());
-        match &self.previous.token_type {
-            TokenType::Identifier(function_name) => {
-                let index = self.chunks.allocate_chunk();
-                let new_compiler =
-                    Compiler::new2(ObjectFunction::new(kind, function_name.to_string(), index));
-
-                // let new_compiler = Compiler::new(function_name, kind);
-                let old_compiler = mem::replace(&mut self.compiler, new_compiler);
-                self.compiler.enclosing = Some(old_compiler);
-            }
-            _ => {
-                panic!("push_compiler ????")
-            }
+    ///
+    /// A statement ending at the token just consumed.
+    fn stmt(&self, kind: StmtKind) -> Stmt {
+        Stmt {
+            kind,
+            end: Span::from(&self.previous),
         }
     }
 
-    fn pop_compiler(&mut self) -> Box<Compiler> {
-        self.emit_return(self.previous.line);
-        match self.compiler.enclosing.take() {
-            Some(enclosing) => mem::replace(&mut self.compiler, enclosing),
-            None => panic!("Didn't find an enclosing compiler"),
+    ///
+    /// An expression ending at the token just consumed.
+    pub(crate) fn expr(&self, kind: ExprKind) -> Expr {
+        Expr {
+            kind,
+            end: Span::from(&self.previous),
         }
     }
 
     ///
     ///
     ///
-    fn declaration(&mut self) {
-        if self.match_token(TokenType::Fun) {
-            self.fun_declaration()
-        } else if self.match_token(TokenType::Var) {
-            self.var_declaration()
-        } else {
-            self.statement();
-        }
+    fn declaration(&mut self) -> Option<Stmt> {
+        // `fun (` starts an anonymous function expression, not a declaration
+        let statement =
+            if self.check(TokenType::Fun) && self.peek_token().token_type == TokenType::LeftParen {
+                Some(self.statement())
+            } else if self.match_token(TokenType::Fun) {
+                self.fun_declaration()
+            } else if self.match_token(TokenType::Var) {
+                Some(self.var_declaration())
+            } else if self.match_token(TokenType::Const) {
+                Some(self.const_declaration())
+            } else if self.match_token(TokenType::Import) {
+                Some(self.import_declaration())
+            } else if self.match_token(TokenType::From) {
+                Some(self.selective_import_declaration())
+            } else {
+                Some(self.statement())
+            };
         if self.panic_mode {
             self.synchronize();
         }
+        statement
     }
 
     ///
     ///
     ///
-    fn fun_declaration(&mut self) {
-        let global = self.parse_variable("Expect function name");
-        self.mark_initialized();
-        self.function(FunctionType::Function);
-        self.define_variable(global, self.previous.line);
+    fn fun_declaration(&mut self) -> Option<Stmt> {
+        let doc = self.previous.doc.clone();
+        let name = self.parse_variable("Expect function name");
+        if !matches!(self.previous.token_type, TokenType::Identifier(_)) {
+            return None;
+        }
+        let function = self.function(name.name.clone(), doc);
+        Some(self.stmt(StmtKind::Function {
+            name,
+            function: Box::new(function),
+        }))
     }
 
     ///
     ///
-    fn var_declaration(&mut self) {
-        let index = self.parse_variable("Expect variable name");
-        if self.match_token(TokenType::Equal) {
-            self.expression()
+    fn var_declaration(&mut self) -> Stmt {
+        let name = self.parse_variable("Expect variable name");
+        let initializer = if self.match_token(TokenType::Equal) {
+            Some(self.expression())
         } else {
-            self.emit_byte(Opcode::OpNil, self.previous.line);
-        }
+            None
+        };
 
         self.consume(TokenType::SemiColon, "Expect ';' after value");
 
-        self.define_variable(index, self.previous.line);
+        self.stmt(StmtKind::Var { name, initializer })
     }
 
     ///
-    ///
-    fn parse_variable(&mut self, msg: &'a str) -> usize {
-        self.consume(TokenType::Identifier("".to_string()), msg);
-
-        self.declare_variable();
-        if self.compiler.scope_depth > 0 {
-            return 0;
-        }
+    /// `const NAME = expr;`
+    fn const_declaration(&mut self) -> Stmt {
+        let name = self.parse_variable("Expect constant name");
+        self.consume(TokenType::Equal, "Expect '=' after constant name");
+        let initializer = self.expression();
+        self.consume(TokenType::SemiColon, "Expect ';' after constant value");
 
-        self.identifier_constant()
+        self.stmt(StmtKind::Const { name, initializer })
     }
 
     ///
-    ///
-    ///
-    pub(crate) fn identifier_constant(&mut self) -> usize {
-        match &self.previous.token_type {
-            TokenType::Identifier(name) => self.make_constant(Value::String(name.to_string())),
-            _ => panic!("should not happen"),
-        }
+    /// `import "path" as name;`
+    fn import_declaration(&mut self) -> Stmt {
+        let keyword = Span::from(&self.previous);
+        let (path, path_span) = self.module_path("Expect module path after 'import'");
+        self.consume(TokenType::As, "Expect 'as' after module path");
+        let name = self.parse_variable("Expect module name");
+        self.consume(TokenType::SemiColon, "Expect ';' after import");
+        self.stmt(StmtKind::Import {
+            keyword,
+            path,
+            path_span,
+            name,
+        })
     }
 
     ///
-    ///
-    fn define_variable(&mut self, index: usize, line: isize) {
-        // self.previous.line
-        if self.compiler.scope_depth > 0 {
-            self.mark_initialized();
-            return;
-        }
-        self.emit_byte(Opcode::OpDefineGlobal(index), line)
-    }
-
-    ///
-    ///
-    fn declare_variable(&mut self) {
-        if self.compiler.scope_depth == 0 {
-            return;
-        }
-        let token = self.previous.clone();
-        // it's an error to have two variables with the same name in the same local scope
-
-        if self
-            .compiler
-            .locals
-            .iter_mut()
-            .rev()
-            .take_while(|l| !(l.depth != -1 && l.depth < self.compiler.scope_depth))
-            .find(|l| Parser::identifiers_equal(&token, &l.token))
-            .is_some()
-        {
-            self.error("Already a variable with this name in this scope")
-        }
-        /*
-        for l in self.locals.iter_mut().rev() {
-            if l.depth != -1 && l.depth < self.scope_depth {
+    /// `from "path" import a, b;`
+    fn selective_import_declaration(&mut self) -> Stmt {
+        let keyword = Span::from(&self.previous);
+        let (path, path_span) = self.module_path("Expect module path after 'from'");
+        self.consume(TokenType::Import, "Expect 'import' after module path");
+        let import = Span::from(&self.previous);
+        let mut names = Vec::new();
+        loop {
+            names.push(self.parse_variable("Expect name to import"));
+            if self.panic_mode || !self.match_token(Comma) {
                 break;
             }
-
-            if Compiler::identifiers_equal(&token, &l.token) {
-                self.error("Already a variable with this name in this scope")
-            }
         }
-
-         */
-
-        self.add_local(token);
-        //  self.previous.
-        //  self.writer.emit_byte(Opcode::OpDefineGlobal(index),   self.previous.line)
+        self.consume(TokenType::SemiColon, "Expect ';' after import");
+        self.stmt(StmtKind::ImportFrom {
+            keyword,
+            path,
+            path_span,
+            import,
+            names,
+        })
     }
 
     ///
+    /// Consumes the path of an import.
+    fn module_path(&mut self, msg: &str) -> (String, Span) {
+        self.consume(TokenType::String("".to_string()), msg);
+        let path = match &self.previous.token_type {
+            TokenType::String(name) => name.clone(),
+            _ => String::new(),
+        };
+        (path, Span::from(&self.previous))
+    }
+
     ///
-    pub(crate) fn identifiers_equal(token1: &Token, token_opt2: &Option<Token>) -> bool {
-        if let Some(token2) = token_opt2 {
-            if token1.len != token2.len {
-                false
-            } else {
-                match &token1.token_type {
-                    TokenType::Identifier(name1) => match &token2.token_type {
-                        TokenType::Identifier(name2) => name1 == name2,
-                        _ => false,
-                    },
-                    _ => false,
-                }
-            }
-        } else {
-            false
+    /// Consumes the name of a declaration.
+    fn parse_variable(&mut self, msg: &'a str) -> Name {
+        self.consume(TokenType::Identifier("".to_string()), msg);
+        let name = match &self.previous.token_type {
+            TokenType::Identifier(name) => name.clone(),
+            _ => String::new(),
+        };
+        Name {
+            name,
+            span: Span::from(&self.previous),
+            target: Target::Global,
         }
     }
 
     ///
     ///
     ///
-    fn statement(&mut self) {
+    fn statement(&mut self) -> Stmt {
         if self.match_token(TokenType::Print) {
             self.print_statement()
         } else if self.match_token(TokenType::For) {
@@ -264,23 +398,37 @@ impl<'a> Parser<'a> {
             self.while_statement()
         } else if self.match_token(TokenType::Return) {
             self.return_statement()
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement()
+        } else if self.match_token(TokenType::Yield) {
+            self.yield_statement()
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement()
+        } else if self.match_token(TokenType::Match) {
+            let arms = self.match_arms(false);
+            self.stmt(StmtKind::Match(Box::new(arms)))
         } else if self.match_token(TokenType::LeftBrace) {
-            self.begin_scope();
-            self.block();
-            self.end_scope();
+            let block = self.block();
+            self.stmt(StmtKind::Block(block))
         } else {
             self.expression_statement()
         }
     }
 
-    fn block(&mut self) {
+    fn block(&mut self) -> Block {
+        let mut statements = Vec::new();
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
-            self.declaration();
+            statements.extend(self.declaration());
         }
         self.consume(
             TokenType::RightBrace,
             "Expect '}' after block. Maybe a ';' missing?",
         );
+        Block {
+            statements,
+            close: Span::from(&self.previous),
+            locals: 0,
+        }
     }
 
     ///
@@ -297,157 +445,324 @@ impl<'a> Parser<'a> {
     ///
     ///
     ///
-    fn check(&mut self, token_type: TokenType) -> bool {
+    pub(crate) fn check(&mut self, token_type: TokenType) -> bool {
         self.current.token_type == token_type
     }
 
     ///
     ///
     ///
-    fn print_statement(&mut self) {
-        self.expression();
+    fn print_statement(&mut self) -> Stmt {
+        let value = self.expression();
         self.consume(TokenType::SemiColon, "Expect ';' after value");
-        self.emit_byte(Opcode::OpPrint, self.previous.line);
+        self.stmt(StmtKind::Print(value))
     }
 
     ///
     ///
     ///
-    fn if_statement(&mut self) {
+    fn if_statement(&mut self) -> Stmt {
         self.consume(TokenType::LeftParen, "Expect '(' after if");
-        self.expression();
+        let condition = self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after if condition");
+        let paren = Span::from(&self.previous);
 
-        let then_jump = self.emit_jump(Opcode::OpJumpIfFalse(0));
-        self.emit_byte(Opcode::OpPop, self.previous.line);
-        self.statement();
-        let else_jump = self.emit_jump(Opcode::OpJump(0));
-
-        self.patch_jump(then_jump, &Opcode::OpJumpIfFalse(0));
-        self.emit_byte(Opcode::OpPop, self.previous.line);
-
-        if self.match_token(TokenType::Else) {
-            self.statement();
-        }
-        self.patch_jump(else_jump, &Opcode::OpJump(0));
+        let then_branch = Box::new(self.statement());
+        let else_branch = if self.match_token(TokenType::Else) {
+            Some(Box::new(self.statement()))
+        } else {
+            None
+        };
+        self.stmt(StmtKind::If {
+            condition,
+            paren,
+            then_branch,
+            else_branch,
+        })
     }
 
     ///
     ///
-    fn return_statement(&mut self) {
-        if self.match_token(TokenType::SemiColon) {
-            self.emit_return(self.previous.line);
+    fn return_statement(&mut self) -> Stmt {
+        let keyword = Span::from(&self.previous);
+        let value = if self.match_token(TokenType::SemiColon) {
+            None
         } else {
-            self.expression();
+            let value = self.expression();
             self.consume(TokenType::SemiColon, "Expect ';' after return value");
-            self.emit_byte(Opcode::OpReturn, self.previous.line);
-        }
+            Some(value)
+        };
+        self.stmt(StmtKind::Return { keyword, value })
     }
-    fn while_statement(&mut self) {
-        let loop_start = self.length();
-        self.consume(TokenType::LeftParen, "Expect '(' after while");
-        self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after while condition");
 
-        let exit_jump = self.emit_jump(Opcode::OpJumpIfFalse(0));
-        self.emit_byte(Opcode::OpPop, self.previous.line);
-        self.statement();
-        self.emit_loop(loop_start);
-
-        self.patch_jump(exit_jump, &Opcode::OpJumpIfFalse(0));
-        self.emit_byte(Opcode::OpPop, self.previous.line);
+    ///
+    ///
+    fn throw_statement(&mut self) -> Stmt {
+        let value = self.expression();
+        self.consume(TokenType::SemiColon, "Expect ';' after thrown value");
+        self.stmt(StmtKind::Throw(value))
     }
 
     ///
+    /// `yield value;` or `yield;`, which makes the enclosing function a generator.
+    fn yield_statement(&mut self) -> Stmt {
+        let keyword = Span::from(&self.previous);
+        let value = if self.match_token(TokenType::SemiColon) {
+            None
+        } else {
+            let value = self.expression();
+            self.consume(TokenType::SemiColon, "Expect ';' after yielded value");
+            Some(value)
+        };
+        self.stmt(StmtKind::Yield { keyword, value })
+    }
+
     ///
-    ///
-    fn for_statement(&mut self) {
-        self.begin_scope();
-        self.consume(TokenType::LeftParen, "Expect '(' after 'for'");
-        if self.match_token(TokenType::SemiColon) {
-            // no initializer
-        } else if self.match_token(TokenType::Var) {
-            self.var_declaration();
+    /// `try { ... } catch (e) { ... } finally { ... }` with a catch clause, a finally clause
+    /// or both.
+    fn try_statement(&mut self) -> Stmt {
+        let keyword = Span::from(&self.previous);
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'");
+        let body = self.block();
+
+        let catch = if self.match_token(TokenType::Catch) {
+            let keyword = Span::from(&self.previous);
+            self.consume(TokenType::LeftParen, "Expect '(' after 'catch'");
+            let name = self.parse_variable("Expect exception variable name");
+            self.consume(TokenType::RightParen, "Expect ')' after exception variable");
+            self.consume(TokenType::LeftBrace, "Expect '{' before catch body");
+            let body = self.block();
+            Some(Catch {
+                keyword,
+                name,
+                body,
+            })
         } else {
-            self.expression_statement();
-        }
-        let mut loop_start = self.length();
-        //condition clause
-        let mut exit_jump: Option<usize> = None;
-        if !self.match_token(TokenType::SemiColon) {
-            self.expression();
-            self.consume(TokenType::SemiColon, "Expect ';' after loop condition");
-            exit_jump = Some(self.emit_jump(Opcode::OpJumpIfFalse(0)));
-            self.emit_byte(Opcode::OpPop, self.previous.line);
-        }
+            None
+        };
 
-        // self.consume(TokenType::RightParen, "Expect ')' after 'for' clauses");
+        let finally = if self.match_token(TokenType::Finally) {
+            let keyword = Span::from(&self.previous);
+            self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'");
+            let body = self.block();
+            Some(Finally { keyword, body })
+        } else {
+            if catch.is_none() {
+                self.error_at_current("Expect 'catch' or 'finally' after try block");
+            }
+            None
+        };
 
-        // increment clause
-        if !self.match_token(TokenType::RightParen) {
-            let body_jump = self.emit_jump(Opcode::OpJump(0));
-            let incr_start = self.length();
-            self.expression();
-            self.emit_byte(Opcode::OpPop, self.previous.line);
-            self.consume(TokenType::RightParen, "Expect ')' after 'for' clauses");
+        self.stmt(StmtKind::Try {
+            keyword,
+            body,
+            catch,
+            finally,
+        })
+    }
 
-            self.emit_loop(loop_start);
-            loop_start = incr_start;
-            self.patch_jump(body_jump, &Opcode::OpJump(0))
+    ///
+    /// `match (subject) { pattern | pattern if guard => arm ... }`. In a statement each arm is a
+    /// statement; in an expression each arm is an expression followed by a comma.
+    pub(crate) fn match_arms(&mut self, is_expression: bool) -> Match {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'match'");
+        let subject = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after match subject");
+        self.consume(TokenType::LeftBrace, "Expect '{' before match arms");
+        let open = Span::from(&self.previous);
+
+        let mut arms = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            let start = Span::from(&self.current);
+            let mut patterns = vec![self.pattern()];
+            while self.match_token(TokenType::Pipe) {
+                patterns.push(self.pattern());
+            }
+            let patterns_end = Span::from(&self.previous);
+
+            let guard = if self.match_token(TokenType::If) {
+                Some(self.expression())
+            } else {
+                None
+            };
+
+            self.consume(TokenType::FatArrow, "Expect '=>' after match pattern");
+            let arrow = Span::from(&self.previous);
+            let body = if is_expression {
+                let value = self.expression();
+                if !self.check(TokenType::RightBrace) {
+                    self.consume(TokenType::Comma, "Expect ',' after match arm");
+                }
+                ArmBody::Expression(value)
+            } else {
+                ArmBody::Statement(Box::new(self.statement()))
+            };
+
+            arms.push(Arm {
+                start,
+                patterns,
+                patterns_end,
+                guard,
+                arrow,
+                body,
+                end: Span::from(&self.previous),
+            });
         }
-        self.statement();
-        self.emit_loop(loop_start);
-        if let Some(jump) = exit_jump {
-            self.patch_jump(jump, &Opcode::OpJumpIfFalse(0));
-            self.emit_byte(Opcode::OpPop, self.previous.line);
+        self.consume(TokenType::RightBrace, "Expect '}' after match arms");
+
+        Match {
+            subject,
+            open,
+            arms,
+            close: Span::from(&self.previous),
+            subject_slot: 0,
         }
-        self.end_scope();
     }
+
     ///
     ///
-    ///
-    pub(crate) fn emit_jump(&mut self, opcode: Opcode) -> usize {
-        self.emit_byte(opcode, self.previous.line);
-        self.length()
+    fn pattern(&mut self) -> Pattern {
+        if let TokenType::Identifier(name) = &self.current.token_type {
+            if name == "_" {
+                self.advance();
+                return Pattern::Wildcard;
+            }
+        }
+        let value = self.pattern_literal();
+        let inclusive = self.check(TokenType::DotDotEqual);
+        if self.match_token(TokenType::DotDot) || self.match_token(TokenType::DotDotEqual) {
+            let upper = self.pattern_literal();
+            if !value.is_number() || !upper.is_number() {
+                self.error("Range patterns must have number bounds");
+            }
+            return Pattern::Range(value, upper, inclusive);
+        }
+        Pattern::Literal(value)
     }
 
     ///
     ///
-    fn emit_loop(&mut self, loop_start: usize) {
-        self.emit_byte(Opcode::OpLoop(0), self.previous.line);
-        let len = self.length();
-        let offset = len - loop_start;
-        if offset > u16::MAX as usize {
-            self.error("Loop body too large");
+    fn pattern_literal(&mut self) -> Value {
+        let negative = self.match_token(TokenType::Minus);
+        self.advance();
+        match &self.previous.token_type {
+            TokenType::Number(n) if negative => Value::Number(-n),
+            TokenType::Number(n) => Value::Number(*n),
+            TokenType::Integer(n) if negative => Value::Int(-n),
+            TokenType::Integer(n) => Value::Int(*n),
+            TokenType::BigInteger(n) if negative => Value::BigInt(-n),
+            TokenType::BigInteger(n) => Value::BigInt(n.clone()),
+            TokenType::Decimal(d) if negative => Value::Decimal(-d),
+            TokenType::Decimal(d) => Value::Decimal(*d),
+            TokenType::String(s) if !negative => Value::String(s.clone()),
+            TokenType::True if !negative => Value::Boolean(true),
+            TokenType::False if !negative => Value::Boolean(false),
+            TokenType::Nil if !negative => Value::Nil,
+            _ => {
+                self.error("Expect a literal, range or '_' pattern");
+                Value::Nil
+            }
         }
-        self.replace_opcode(len - 1, Opcode::OpLoop(offset as u16));
     }
+
+    fn while_statement(&mut self) -> Stmt {
+        self.consume(TokenType::LeftParen, "Expect '(' after while");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after while condition");
+        let paren = Span::from(&self.previous);
+
+        let body = Box::new(self.statement());
+        self.stmt(StmtKind::While {
+            condition,
+            paren,
+            body,
+        })
+    }
+
     ///
     ///
     ///
-    pub(crate) fn patch_jump(&mut self, offset: usize, opcode: &Opcode) {
-        let jump = self.length() - offset;
-        if jump > u16::MAX as usize {
-            self.error("Too much code to jump over");
-        }
-
-        let patched_opcode = match opcode {
-            Opcode::OpJumpIfFalse(_) => Opcode::OpJumpIfFalse(jump as u16),
-            Opcode::OpJump(_) => Opcode::OpJump(jump as u16),
-            _ => {
-                panic!("Not a jumpable opcode")
+    fn for_statement(&mut self) -> Stmt {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'");
+        let initializer = if self.match_token(TokenType::SemiColon) {
+            // no initializer
+            None
+        } else if self.match_token(TokenType::Var) {
+            if self.peek_token().token_type == TokenType::In {
+                return self.for_in_statement();
             }
+            Some(Box::new(self.var_declaration()))
+        } else {
+            Some(Box::new(self.expression_statement()))
+        };
+
+        //condition clause
+        let condition = if !self.match_token(TokenType::SemiColon) {
+            let condition = self.expression();
+            self.consume(TokenType::SemiColon, "Expect ';' after loop condition");
+            Some(condition)
+        } else {
+            None
         };
+        let semicolon = Span::from(&self.previous);
 
-        self.replace_opcode(offset - 1, patched_opcode);
+        // increment clause
+        let increment = if !self.match_token(TokenType::RightParen) {
+            let increment = self.expression();
+            self.consume(TokenType::RightParen, "Expect ')' after 'for' clauses");
+            Some(increment)
+        } else {
+            None
+        };
+        let paren = Span::from(&self.previous);
+
+        let body = Box::new(self.statement());
+        self.stmt(StmtKind::For {
+            initializer,
+            condition,
+            semicolon,
+            increment,
+            paren,
+            body,
+        })
     }
+
+    ///
+    /// `for (var item in iterable) body` over a list or a generator.
+    fn for_in_statement(&mut self) -> Stmt {
+        self.consume(
+            TokenType::Identifier("".to_string()),
+            "Expect loop variable name",
+        );
+        let item = Name {
+            name: match &self.previous.token_type {
+                TokenType::Identifier(name) => name.clone(),
+                _ => String::new(),
+            },
+            span: Span::from(&self.previous),
+            target: Target::Global,
+        };
+        self.consume(TokenType::In, "Expect 'in' after loop variable");
+        let iterable = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after 'for' clauses");
+        let paren = Span::from(&self.previous);
+
+        let body = Box::new(self.statement());
+        self.stmt(StmtKind::ForIn {
+            item,
+            iterable,
+            paren,
+            body,
+        })
+    }
+
     ///
     ///
     ///
-    fn expression_statement(&mut self) {
-        self.expression();
+    fn expression_statement(&mut self) -> Stmt {
+        let expression = self.expression();
         self.consume(TokenType::SemiColon, "Expect ';' after value");
-        self.emit_byte(Opcode::OpPop, self.previous.line);
+        self.stmt(StmtKind::Expression(expression))
     }
 
     ///
@@ -466,6 +781,10 @@ impl<'a> Parser<'a> {
             | TokenType::If
             | TokenType::While
             | TokenType::Print
+            | TokenType::Throw
+            | TokenType::Try
+            | TokenType::Match
+            | TokenType::Yield
             | TokenType::Return => (),
             _ => self.advance(),
         }
@@ -473,7 +792,7 @@ impl<'a> Parser<'a> {
     ///
     ///
     ///
-    pub fn consume(&mut self, token_type: TokenType, message: &'a str) {
+    pub fn consume(&mut self, token_type: TokenType, message: &str) {
         if self.current.token_type == token_type {
             self.advance();
             return;
@@ -484,144 +803,240 @@ impl<'a> Parser<'a> {
 
     ///
     ///
-    fn end_compiler(&mut self) -> Result<&mut ObjectFunction, ParserError> {
-        self.emit_return(self.previous.line);
-
-        // if let None = self.result {
-        //     self.compiler
-        //         .function
-        //         .disassemble_chunk(&mut (Box::new(io::stdout()) as Box<dyn Write>));
-        //
-        //     return Ok(&mut self.compiler.function);
-        // }
-        let res = &self.result;
-
-        // write!(io::stdout(), "{}\n", "CHUNKS");
-
-        // self.compiler
-        //     .function
-        //     .disassemble_chunk(&mut (Box::new(io::stdout()) as Box<dyn Write>));
-
-        match res {
-            None => Ok(&mut self.compiler.function),
-            Some(err) => Err(err.clone()),
-        }
-    }
-
-    ///
-    ///
-    pub(crate) fn expression(&mut self) {
+    pub(crate) fn expression(&mut self) -> Expr {
         self.parse_precedence(&Precedence::Assigment)
     }
 
-    pub(crate) fn parse_precedence(&mut self, precedence: &Precedence) {
+    pub(crate) fn parse_precedence(&mut self, precedence: &Precedence) -> Expr {
         self.advance();
         let prefix_rule = ParserRule::get_rule(&self.previous.token_type).prefix;
 
         let can_assign = *precedence <= Precedence::Assigment;
-        if prefix_rule.is_none() {
-            self.error("Expect expression");
-            return;
-        } else {
-            prefix_rule.unwrap()(self, can_assign);
-        }
+        let mut expression = match prefix_rule {
+            Some(prefix_rule) => prefix_rule(self, can_assign),
+            None => {
+                self.error("Expect expression");
+                return self.invalid();
+            }
+        };
 
         while precedence <= ParserRule::get_rule(&self.current.token_type).precedence {
             self.advance();
             let infix_rule = ParserRule::get_rule(&self.previous.token_type).infix;
-            if infix_rule.is_some() {
-                infix_rule.unwrap()(self, can_assign);
+            if let Some(infix_rule) = infix_rule {
+                expression = infix_rule(self, expression, can_assign);
             }
         }
 
-        if can_assign && self.match_token(TokenType::Equal) {
+        if can_assign
+            && (self.match_token(TokenType::Equal) || self.match_compound_assignment().is_some())
+        {
             self.error("Invalid assignment target")
+        } else if can_assign && self.match_increment().is_some() {
+            self.error("Invalid increment target")
         }
+        expression
     }
 
     ///
+    /// Stands in for an expression that failed to parse; the program is never compiled.
+    pub(crate) fn invalid(&self) -> Expr {
+        self.expr(ExprKind::Literal(Value::Nil))
+    }
+
     ///
-    pub(crate) fn resolve_local(&mut self) -> Option<usize> {
-        let result = self
-            .compiler
-            .resolve_local(&self.previous, &mut self.resolver_errors);
-        while let Some(e) = self.resolver_errors.pop() {
-            self.error(e);
-        }
-        result
+    /// Consumes a `+=`-style operator and returns the arithmetic it applies.
+    pub(crate) fn match_compound_assignment(&mut self) -> Option<BinaryOp> {
+        let op = match self.current.token_type {
+            TokenType::PlusEqual => BinaryOp::Add,
+            TokenType::MinusEqual => BinaryOp::Subtract,
+            TokenType::StarEqual => BinaryOp::Multiply,
+            TokenType::SlashEqual => BinaryOp::Divide,
+            TokenType::PercentEqual => BinaryOp::Modulo,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
     }
 
     ///
+    /// Consumes a postfix `++` or `--` and returns the arithmetic it applies.
+    pub(crate) fn match_increment(&mut self) -> Option<BinaryOp> {
+        let op = match self.current.token_type {
+            TokenType::PlusPlus => BinaryOp::Add,
+            TokenType::MinusMinus => BinaryOp::Subtract,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
     ///
-    pub(crate) fn add_local(&mut self, token: Token) {
-        let _result = self.compiler.add_local(
-            Local {
-                token: Some(token),
-                depth: -1, //self.scope_depth
+    /// The variable named by the identifier just consumed, before it is resolved.
+    pub(crate) fn variable(&self) -> Variable {
+        Variable {
+            name: match &self.previous.token_type {
+                TokenType::Identifier(name) => name.clone(),
+                _ => String::new(),
             },
-            &mut self.resolver_errors,
-        );
-
-        while let Some(e) = self.resolver_errors.pop() {
-            self.error(e);
+            span: Span::from(&self.previous),
+            target: Target::Global,
+            binding: Default::default(),
         }
     }
 
     ///
-    ///
-    pub(crate) fn mark_initialized(&mut self) {
-        if self.compiler.scope_depth == 0 {
-            return;
+    /// Parameter list, body and closing '}' of a function whose name was consumed.
+    pub(crate) fn function(&mut self, name: String, doc: Option<String>) -> Function {
+        self.consume(TokenType::LeftParen, "Expect '(' after function name");
+        let (params, rest) = self.parameters();
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body");
+        let body = self.block();
+        Function {
+            name,
+            doc,
+            params,
+            rest,
+            body: FunctionBody::Block(body.statements),
+            end: body.close,
         }
-        self.compiler
-            .locals
-            .last_mut()
-            // .get_mut(self.compiler.local_count - 1)
-            .unwrap()
-            .depth = self.compiler.scope_depth;
     }
 
     ///
+    /// `(a, b) => expr` or `(a, b) => { ... }`, the opening '(' has already been consumed.
+    pub(crate) fn arrow_function(&mut self) -> Expr {
+        let name = self.lambda_name();
+        let (params, rest) = self.parameters();
+        self.consume(TokenType::FatArrow, "Expect '=>' after lambda parameters");
+        let body = if self.match_token(TokenType::LeftBrace) {
+            FunctionBody::Block(self.block().statements)
+        } else {
+            FunctionBody::Expression(self.expression())
+        };
+        let function = Function {
+            name,
+            doc: None,
+            params,
+            rest,
+            body,
+            end: Span::from(&self.previous),
+        };
+        self.expr(ExprKind::Function(Box::new(function)))
+    }
+
+    ///
+    /// Synthetic name for anonymous functions so they can be told apart in stack traces.
+    pub(crate) fn lambda_name(&self) -> String {
+        format!("lambda:{}", self.previous.line)
+    }
+
     ///
-    pub(crate) fn function(&mut self, kind: FunctionType) {
-        self.push_compiler(kind);
-        self.begin_scope();
-        self.consume(TokenType::LeftParen, "Expect '(' after function name");
+    /// Parameter list up to and including the closing ')'. Parameters may have a default
+    /// value, `b = 10`, and the last one may be a `...rest` list of the extra arguments.
+    fn parameters(&mut self) -> (Vec<Param>, Option<Name>) {
+        let mut params: Vec<Param> = Vec::new();
+        let mut rest = None;
         if !self.check(TokenType::RightParen) {
             loop {
-                self.compiler.function.arity += 1;
-                if self.compiler.function.arity == 254 {
+                if self.match_token(TokenType::Ellipsis) {
+                    rest = Some(self.parse_variable("Expect rest parameter name"));
+                    if self.check(Comma) {
+                        self.error_at_current("Rest parameter must be the last parameter");
+                    }
+                    break;
+                }
+                if params.len() == 253 {
                     self.error_at_current("Can't have more than 255 parameters")
                 }
-                let index = self.parse_variable("Expect parameter name");
-                self.define_variable(index, self.previous.line);
+                let name = self.parse_variable("Expect parameter name");
+                let default = if self.match_token(TokenType::Equal) {
+                    let equal = Span::from(&self.previous);
+                    Some((equal, self.expression()))
+                } else {
+                    if params.iter().any(|param| param.default.is_some()) {
+                        self.error("Parameter without a default can't follow one with a default");
+                    }
+                    None
+                };
+                params.push(Param { name, default });
                 if !self.match_token(Comma) {
                     break;
                 }
             }
         }
         self.consume(TokenType::RightParen, "Expect ')' after function name");
-        self.consume(TokenType::LeftBrace, "Expect '{' before function body");
-        self.block();
-
-        let compiler = self.pop_compiler();
-        let function = compiler.function;
-        let v = Value::Function(*function);
-        let _ = &self.emit_constant(v, self.previous.line);
+        (params, rest)
     }
 
     ///
+    /// Looks one token past `current` without consuming anything.
+    pub(crate) fn peek_token(&mut self) -> Token {
+        let state = self.scanner.save();
+        let token = self.scanner.scan_token();
+        self.scanner.restore(state);
+        token
+    }
+
     ///
+    /// Called right after a '(': true when the matching ')' is followed by `=>`, i.e. the
+    /// parenthesis opens an arrow function parameter list rather than a grouping.
+    pub(crate) fn is_arrow_parameters(&mut self) -> bool {
+        let state = self.scanner.save();
+        let mut token = self.current.clone();
+        let mut depth = 1;
+        let is_arrow = loop {
+            match token.token_type {
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen if depth == 1 => {
+                    break self.scanner.scan_token().token_type == TokenType::FatArrow;
+                }
+                TokenType::RightParen => depth -= 1,
+                TokenType::EOF | TokenType::Error(_) => break false,
+                _ => {}
+            }
+            token = self.scanner.scan_token();
+        };
+        self.scanner.restore(state);
+        is_arrow
+    }
+
     ///
-    pub fn argument_list(&mut self) -> u8 {
-        let mut count = 0u8;
+    /// Call arguments up to and including the closing ')': plain positional arguments,
+    /// `name: value` arguments, or `...list` spreads.
+    pub fn argument_list(&mut self) -> Vec<Argument> {
+        let mut arguments = Vec::new();
+        let mut named = false;
+        let mut spread = false;
         if !self.check(TokenType::RightParen) {
             loop {
-                self.expression();
-                if count == 255 {
-                    self.error("Can't have more than 255 arguments")
+                if arguments.len() == 255 {
+                    self.error("Can't have more than 255 arguments");
+                    break;
+                }
+                if self.match_token(TokenType::Ellipsis) {
+                    if named {
+                        self.error("Can't mix spread and named arguments");
+                    }
+                    let ellipsis = Span::from(&self.previous);
+                    arguments.push(Argument::Spread(ellipsis, self.expression()));
+                    spread = true;
+                } else if matches!(self.current.token_type, TokenType::Identifier(_))
+                    && self.peek_token().token_type == TokenType::Colon
+                {
+                    if spread {
+                        self.error_at_current("Can't mix spread and named arguments");
+                    }
+                    self.advance();
+                    let name = self.variable().name;
+                    self.advance();
+                    arguments.push(Argument::Named(name, self.expression()));
+                    named = true;
+                } else {
+                    if named {
+                        self.error_at_current("Positional argument can't follow named arguments");
+                    }
+                    arguments.push(Argument::Positional(self.expression()));
                 }
-                count += 1;
                 if !self.match_token(TokenType::Comma) {
                     // dbg!(format!("NOT A COMMA {:?}", self.current.token_type));
                     break;
@@ -629,62 +1044,24 @@ impl<'a> Parser<'a> {
             }
         }
         self.consume(TokenType::RightParen, "Expect ')' after arguments");
-        //  dbg!(count);
-        count
+        arguments
     }
 
     ///
     ///
-    pub(crate) fn begin_scope(&mut self) {
-        self.compiler.scope_depth += 1;
-    }
-
-    ///
-    ///
-    pub(crate) fn end_scope(&mut self) {
-        self.compiler.scope_depth -= 1;
-
-        while let Some(local) = self.compiler.locals.last() {
-            if local.depth > self.compiler.scope_depth {
-                self.emit_byte(Opcode::OpPop, self.previous.line);
-
-                self.compiler.locals.pop();
-            } else {
-                break;
-            }
-        }
-
-        // while self.compiler.local_count > 0
-        //     && self
-        //         .compiler
-        //         .locals
-        //         .get(self.compiler.local_count - 1)
-        //         .unwrap()
-        //         .depth
-        //         > self.compiler.scope_depth
-        // {
-        //     self.compiler
-        //         .function
-        //         .emit_byte(Opcode::OpPop, self.previous.line);
-        //     self.compiler.local_count.sub_assign(1);
-        // }
-    }
-
-    ///
-    ///
-    fn error_at_current(&mut self, msg: &'a str) {
+    fn error_at_current(&mut self, msg: &str) {
         self.error_at(&self.current.clone(), msg);
     }
 
     ///
     ///
-    fn error(&mut self, msg: &'a str) {
+    pub(crate) fn error(&mut self, msg: &str) {
         self.error_at(&mut self.previous.clone(), msg);
     }
 
     ///
     ///
-    fn error_at(&mut self, token: &Token, msg: &'a str) {
+    pub(crate) fn error_at(&mut self, token: &Token, msg: &str) {
         if self.panic_mode {
             return;
         }
@@ -692,7 +1069,7 @@ impl<'a> Parser<'a> {
         eprint!(" [line {}] Error", token.line);
         match token.token_type {
             TokenType::EOF => eprint!(" at end"),
-            TokenType::Error => {}
+            TokenType::Error(_) => {}
             _ => {
                 eprint!(" at {} {}", token.len, token.start);
             }
@@ -704,6 +1081,7 @@ impl<'a> Parser<'a> {
             start: token.start,
             len: token.len,
             msg: msg.to_string(),
+            imported: false,
         });
         // self.result = Some(Box::new(LoxCompileError {
         //     src: NamedSource::new("bad_file.rs", self.scanner.get_input()),
@@ -711,60 +1089,456 @@ impl<'a> Parser<'a> {
         // }));
     }
 
-    fn chunk_index(&self) -> ChunkIndex {
-        self.compiler.function.chunk_index
+    pub fn module_count(&self) -> usize {
+        self.chunks.modules.len()
     }
 
-    ///
-    ///
-    pub fn emit_byte(&mut self, byte: Opcode, line: isize) {
-        self.write_chunk(byte, line);
+    pub fn module_at(&self, index: usize) -> &Module {
+        &self.chunks.modules[index]
     }
-    ///
-    ///
-    pub(crate) fn emit_bytes(&mut self, byte1: Opcode, byte2: Opcode, line: isize) {
-        self.emit_byte(byte1, line);
-        self.emit_byte(byte2, line);
+
+    pub fn chunk_at(&self, index: ChunkIndex) -> &Chunk {
+        self.chunks.chunks.get(index).unwrap()
     }
-    ///
-    ///
-    pub(crate) fn emit_return(&mut self, line: isize) {
-        self.emit_byte(Opcode::OpNil, line);
-        self.emit_byte(Opcode::OpReturn, line);
+
+    pub fn disassemble_chunk(&mut self, writer: &mut Box<dyn Write>) {
+        let chunk = &mut self.chunks.chunks[self.function.chunk_index];
+        chunk.disassemble_chunk(writer);
+        chunk.disassemble_chunk_constants(writer);
     }
-    ///
-    ///
-    pub fn emit_constant(&mut self, value: Value, line: isize) {
-        let idx = self.make_constant(value);
-        self.emit_byte(Opcode::OpConstant(idx), line)
+}
+
+pub fn number(parser: &mut Parser, _can_assign: bool) -> Expr {
+    let value = match &parser.previous.token_type {
+        TokenType::Number(num) => Value::Number(*num),
+        TokenType::Integer(num) => Value::Int(*num),
+        TokenType::BigInteger(num) => Value::BigInt(num.clone()),
+        TokenType::Decimal(num) => Value::Decimal(*num),
+        _ => panic!("unexpected token type"),
+    };
+    parser.expr(ExprKind::Literal(value))
+}
+
+pub fn string(parser: &mut Parser, _can_assign: bool) -> Expr {
+    match &parser.previous.token_type {
+        TokenType::String(str) => parser.expr(ExprKind::Literal(Value::new_string(str))),
+        _ => panic!("unexpected token type"),
     }
-    pub fn chunk(&mut self) -> &mut Chunk {
-        let index = self.chunk_index();
-        self.chunks.chunks.get_mut(index).unwrap()
+}
+
+///
+/// A variable read, or assigned when followed by `=`, `+=`-style operators, `++` or `--`.
+pub fn variable(parser: &mut Parser, can_assign: bool) -> Expr {
+    let target = parser.variable();
+    let compound_op = if can_assign {
+        parser
+            .match_compound_assignment()
+            .map(|op| (op, Span::from(&parser.previous)))
+    } else {
+        None
+    };
+
+    if can_assign && parser.match_token(TokenType::Equal) {
+        let value = Box::new(parser.expression());
+        parser.expr(ExprKind::Assign { target, value })
+    } else if let Some((op, op_span)) = compound_op {
+        let value = Box::new(parser.expression());
+        parser.expr(ExprKind::CompoundAssign {
+            target,
+            op,
+            op_span,
+            value,
+        })
+    } else if let Some(op) = parser.match_increment() {
+        parser.expr(ExprKind::Increment {
+            target,
+            op,
+            prefix: false,
+        })
+    } else {
+        parser.expr(ExprKind::Variable(target))
     }
+}
 
-    pub fn chunk_at(&self, index: ChunkIndex) -> &Chunk {
-        self.chunks.chunks.get(index).unwrap()
+///
+/// Prefix `++x` / `--x`, evaluates to the updated value.
+pub fn increment(parser: &mut Parser, _can_assign: bool) -> Expr {
+    let op = match parser.previous.token_type {
+        TokenType::PlusPlus => BinaryOp::Add,
+        _ => BinaryOp::Subtract,
+    };
+    parser.consume(
+        TokenType::Identifier("".to_string()),
+        "Expect variable after increment operator",
+    );
+    if !matches!(parser.previous.token_type, TokenType::Identifier(_)) {
+        return parser.invalid();
+    }
+    let target = parser.variable();
+    parser.expr(ExprKind::Increment {
+        target,
+        op,
+        prefix: true,
+    })
+}
+
+///
+///
+pub fn grouping(parser: &mut Parser, _can_assign: bool) -> Expr {
+    if parser.is_arrow_parameters() {
+        return parser.arrow_function();
+    }
+    let expression = parser.expression();
+    parser.consume(TokenType::RightParen, "Expected ')' after expression");
+    parser.expr(ExprKind::Grouping(Box::new(expression)))
+}
+
+///
+/// `fun (a, b) { ... }` used as an expression.
+pub fn lambda(parser: &mut Parser, _can_assign: bool) -> Expr {
+    let name = parser.lambda_name();
+    let function = parser.function(name, None);
+    parser.expr(ExprKind::Function(Box::new(function)))
+}
+
+///
+///
+pub fn literal(parser: &mut Parser, _can_assign: bool) -> Expr {
+    let value = match parser.previous.token_type {
+        TokenType::False => Value::Boolean(false),
+        TokenType::True => Value::Boolean(true),
+        _ => Value::Nil,
+    };
+    parser.expr(ExprKind::Literal(value))
+}
+
+///
+///
+pub fn unary(parser: &mut Parser, _can_assign: bool) -> Expr {
+    let op = match parser.previous.token_type {
+        TokenType::Bang => UnaryOp::Not,
+        TokenType::Tilde => UnaryOp::BitNot,
+        _ => UnaryOp::Negate,
+    };
+    let operand = Box::new(parser.parse_precedence(&Precedence::Unary));
+    parser.expr(ExprKind::Unary { op, operand })
+}
+
+///
+///
+pub fn binary(parser: &mut Parser, left: Expr, _can_assign: bool) -> Expr {
+    let token_type = &parser.previous.token_type.clone();
+
+    let rule = ParserRule::get_rule(token_type);
+    let prec_u8 = (*rule.precedence) as u8;
+    // '**' is right-associative: the right operand may itself contain '**'
+    let precedence: Precedence = match token_type {
+        TokenType::StarStar => Precedence::Exponent,
+        _ => FromPrimitive::from_u8(prec_u8 + 1).unwrap(),
+    };
+    let right = Box::new(parser.parse_precedence(&precedence));
+
+    let op = match token_type {
+        TokenType::Plus => BinaryOp::Add,
+        TokenType::Minus => BinaryOp::Subtract,
+        TokenType::Star => BinaryOp::Multiply,
+        TokenType::Slash => BinaryOp::Divide,
+        TokenType::Percent => BinaryOp::Modulo,
+        TokenType::StarStar => BinaryOp::Power,
+        TokenType::TildeSlash => BinaryOp::IntDivide,
+        TokenType::Ampersand => BinaryOp::BitAnd,
+        TokenType::Pipe => BinaryOp::BitOr,
+        TokenType::Caret => BinaryOp::BitXor,
+        TokenType::LessLess => BinaryOp::ShiftLeft,
+        TokenType::GreaterGreater => BinaryOp::ShiftRight,
+
+        TokenType::BangEqual => BinaryOp::NotEqual,
+        TokenType::EqualEqual => BinaryOp::Equal,
+        TokenType::Greater => BinaryOp::Greater,
+        TokenType::GreaterEqual => BinaryOp::GreaterEqual,
+        TokenType::Less => BinaryOp::Less,
+        TokenType::LessEqual => BinaryOp::LessEqual,
+        _ => return left,
+    };
+
+    parser.expr(ExprKind::Binary {
+        op,
+        left: Box::new(left),
+        right,
+    })
+}
+
+///
+///
+pub fn and(parser: &mut Parser, left: Expr, _can_assign: bool) -> Expr {
+    logical(parser, left, LogicalOp::And, &Precedence::And)
+}
+
+///
+///
+pub fn or(parser: &mut Parser, left: Expr, _can_assign: bool) -> Expr {
+    logical(parser, left, LogicalOp::Or, &Precedence::Or)
+}
+
+fn logical(parser: &mut Parser, left: Expr, op: LogicalOp, precedence: &Precedence) -> Expr {
+    let op_span = Span::from(&parser.previous);
+    let right = Box::new(parser.parse_precedence(precedence));
+    parser.expr(ExprKind::Logical {
+        op,
+        op_span,
+        left: Box::new(left),
+        right,
+    })
+}
+
+///
+/// `cond ? a : b`, right-associative so `a ? b : c ? d : e` nests in the else branch.
+pub fn conditional(parser: &mut Parser, condition: Expr, _can_assign: bool) -> Expr {
+    let question = Span::from(&parser.previous);
+    let then_branch = Box::new(parser.expression());
+    parser.consume(
+        TokenType::Colon,
+        "Expect ':' after then branch of conditional",
+    );
+    let colon = Span::from(&parser.previous);
+    let else_branch = Box::new(parser.parse_precedence(&Precedence::Conditional));
+    parser.expr(ExprKind::Conditional {
+        condition: Box::new(condition),
+        question,
+        then_branch,
+        colon,
+        else_branch,
+    })
+}
+
+///
+/// `a ?? b` evaluates `b` only when `a` is nil, unlike `or` which also skips on `false`.
+pub fn coalesce(parser: &mut Parser, left: Expr, _can_assign: bool) -> Expr {
+    let op_span = Span::from(&parser.previous);
+    let right = Box::new(parser.parse_precedence(&Precedence::Coalesce));
+    parser.expr(ExprKind::Coalesce {
+        op_span,
+        left: Box::new(left),
+        right,
+    })
+}
+
+///
+///
+pub fn call(parser: &mut Parser, callee: Expr, _can_assign: bool) -> Expr {
+    let arguments = parser.argument_list();
+    parser.expr(ExprKind::Call {
+        callee: Box::new(callee),
+        arguments,
+    })
+}
+
+///
+/// `module.name`; modules are the only values with properties. `object?.name` is nil instead
+/// when the object is.
+pub fn dot(parser: &mut Parser, object: Expr, _can_assign: bool) -> Expr {
+    let optional = parser.previous.token_type == TokenType::QuestionDot;
+    parser.consume(
+        TokenType::Identifier("".to_string()),
+        "Expect property name after '.'",
+    );
+    if !matches!(parser.previous.token_type, TokenType::Identifier(_)) {
+        return object;
     }
+    let name = parser.variable().name;
+    parser.expr(ExprKind::Get {
+        object: Box::new(object),
+        name,
+        optional,
+    })
+}
 
-    fn write_chunk(&mut self, byte: Opcode, _line: isize) {
-        let index = self.chunk_index();
-        self.chunk().write_chunk(byte);
+///
+/// `match` used as an expression.
+pub fn match_expression(parser: &mut Parser, _can_assign: bool) -> Expr {
+    let arms = parser.match_arms(true);
+    parser.expr(ExprKind::Match(Box::new(arms)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{BinaryOp, ExprKind, Span, StmtKind};
+    use crate::chunk::ChunkArena;
+    use crate::opcode::Opcode;
+    use crate::parser::Parser;
+    use crate::value::Value;
+
+    #[test]
+    fn operators_nest_by_precedence() {
+        let mut chunks = ChunkArena::new();
+        let program = Parser::new("print 1 + 2 * 3;", &mut chunks)
+            .parse()
+            .expect("parses");
+        let value = match &program.statements[..] {
+            [statement] => match &statement.kind {
+                StmtKind::Print(value) => value,
+                kind => panic!("not a print statement: {:?}", kind),
+            },
+            statements => panic!("expected one statement: {:?}", statements),
+        };
+        match &value.kind {
+            ExprKind::Binary {
+                op: BinaryOp::Add,
+                left,
+                right,
+            } => {
+                assert_eq!(left.kind, ExprKind::Literal(Value::Int(1)));
+                assert!(matches!(
+                    right.kind,
+                    ExprKind::Binary {
+                        op: BinaryOp::Multiply,
+                        ..
+                    }
+                ));
+            }
+            kind => panic!("not an addition: {:?}", kind),
+        }
     }
-    pub fn make_constant(&mut self, value: Value) -> usize {
-        self.chunk().add_constant(value)
+
+    #[test]
+    fn nodes_end_at_their_last_token() {
+        let mut chunks = ChunkArena::new();
+        let program = Parser::new("var a = 1;\nprint a +\n 22;", &mut chunks)
+            .parse()
+            .expect("parses");
+        let print = &program.statements[1];
+        assert_eq!(
+            print.end,
+            Span {
+                line: 2,
+                start: 24,
+                len: 1
+            }
+        );
+        match &print.kind {
+            StmtKind::Print(value) => assert_eq!(
+                value.end,
+                Span {
+                    line: 2,
+                    start: 22,
+                    len: 2
+                }
+            ),
+            kind => panic!("not a print statement: {:?}", kind),
+        }
     }
 
-    pub fn disassemble_chunk(&mut self, writer: &mut Box<dyn Write>) {
-        self.chunk().disassemble_chunk(writer);
-        self.chunk().disassemble_chunk_constants(writer);
+    #[test]
+    fn syntax_errors_stop_before_compiling() {
+        let mut chunks = ChunkArena::new();
+        let mut parser = Parser::new("print 1 +;", &mut chunks);
+        let err = parser.compile().unwrap_err();
+        assert_eq!(err.msg, "Expect expression");
+        assert!(parser.chunk_at(0).op_codes.is_empty());
+    }
+
+    #[test]
+    fn literal_constants_are_inlined() {
+        let mut chunks = ChunkArena::new();
+        let mut parser = Parser::new("const A = 42; const B = A + 1; print A + B;", &mut chunks);
+        parser.compile().expect("compiles");
+        let chunk = parser.chunk_at(0);
+
+        let globals_read: Vec<_> = chunk
+            .op_codes
+            .iter()
+            .filter_map(|op| match op {
+                Opcode::OpGetGlobal(idx) => chunk.read_constant(*idx),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(globals_read, vec![&Value::String("B".to_string())]);
+    }
+
+    #[test]
+    fn dense_matches_use_a_jump_table() {
+        let mut chunks = ChunkArena::new();
+        let mut parser = Parser::new(
+            "match (2) { 1 => print 1; 2 => print 2; 4 => print 4; }",
+            &mut chunks,
+        );
+        parser.compile().expect("compiles");
+        let chunk = parser.chunk_at(0);
+        assert!(chunk
+            .op_codes
+            .iter()
+            .any(|op| matches!(op, Opcode::OpJumpTable(_))));
+
+        let mut chunks = ChunkArena::new();
+        let mut parser = Parser::new(
+            "match (2) { 1 => print 1; 50 => print 2; 99 => print 4; }",
+            &mut chunks,
+        );
+        parser.compile().expect("compiles");
+        let chunk = parser.chunk_at(0);
+        assert!(!chunk
+            .op_codes
+            .iter()
+            .any(|op| matches!(op, Opcode::OpJumpTable(_))));
+    }
+
+    #[test]
+    fn unreachable_arms_warn() {
+        let mut chunks = ChunkArena::new();
+        let mut parser = Parser::new(
+            "match (1) {\n 1 => print 1;\n _ => print 2;\n 3 => print 3;\n 1 => print 4;\n}",
+            &mut chunks,
+        );
+        parser.compile().expect("compiles");
+        assert_eq!(
+            parser.warnings,
+            vec![
+                "[line 3] Unreachable match arm",
+                "[line 4] Unreachable match arm"
+            ]
+        );
+    }
+
+    fn main_ops(source: &str, fold_constants: bool) -> Vec<Opcode> {
+        let mut chunks = ChunkArena::new();
+        let mut parser = Parser::new(source, &mut chunks);
+        parser.fold_constants = fold_constants;
+        parser.compile().expect("compiles");
+        parser.chunk_at(0).op_codes.clone()
     }
 
-    pub fn length(&mut self) -> usize {
-        self.chunk().op_codes.len()
+    #[test]
+    fn constant_expressions_fold() {
+        let ops = main_ops("return 1 + 2 * 3;", true);
+        assert!(matches!(
+            ops[..],
+            [Opcode::OpConstant(_), Opcode::OpReturn, ..]
+        ));
+
+        let ops = main_ops("return 1 >= 2 and -3 != 4;", true);
+        assert!(!ops
+            .iter()
+            .any(|op| matches!(op, Opcode::OpLess | Opcode::OpNot | Opcode::OpEqual)));
+
+        let ops = main_ops("return 1 + 2 * 3;", false);
+        assert!(ops.contains(&Opcode::OPMultiply) && ops.contains(&Opcode::OpAdd));
     }
 
-    pub fn replace_opcode(&mut self, index: usize, bytes: Opcode) {
-        self.chunk().replace_opcode(index, bytes);
+    #[test]
+    fn failing_and_jumped_over_operands_do_not_fold() {
+        let ops = main_ops("return 1 ~/ 0;", true);
+        assert!(ops.contains(&Opcode::OpIntDivide));
+
+        let ops = main_ops("var x = false; return (x and 1) + 2;", true);
+        assert!(ops.contains(&Opcode::OpAdd));
+    }
+
+    #[test]
+    fn returned_calls_are_tail_calls() {
+        let ops = main_ops("fun f(n) { return n; } return f(1);", true);
+        assert!(ops.ends_with(&[Opcode::OpTailCall(1), Opcode::OpReturn]));
+
+        let ops = main_ops("fun f(n) { return n; } return f(1) + 1;", true);
+        assert!(!ops.iter().any(|op| matches!(op, Opcode::OpTailCall(_))));
     }
 }
@@ -1,8 +1,13 @@
-use crate::compiler::{and, binary, call, grouping, literal, number, or, string, unary, variable};
-use crate::parser::Parser;
+use crate::ast::Expr;
+use crate::parser::{
+    and, binary, call, coalesce, conditional, dot, grouping, increment, lambda, literal,
+    match_expression, number, or, string, unary, variable, Parser,
+};
 use crate::token::TokenType;
 use lazy_static::lazy_static;
+use num_bigint::BigInt;
 use num_derive::FromPrimitive;
+use rust_decimal::Decimal;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 lazy_static! {
@@ -16,12 +21,36 @@ lazy_static! {
         m.insert(TokenType::LeftBrace ,                     ParserRule::new(None,           None,           &Precedence::None));
         m.insert(TokenType::RightBrace ,                    ParserRule::new(None,           None,           &Precedence::None));
         m.insert(TokenType::Comma ,                         ParserRule::new(None,           None,           &Precedence::None));
-        m.insert(TokenType::Dot ,                           ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::Dot ,                           ParserRule::new(None,           Some(dot),      &Precedence::Call));
         m.insert(TokenType::Minus ,                         ParserRule::new(Some(unary),    Some(binary),   &Precedence::Term));
         m.insert(TokenType::Plus ,                          ParserRule::new(None,           Some(binary),   &Precedence::Term));
         m.insert(TokenType::SemiColon ,                     ParserRule::new(None,           None,           &Precedence::None));
         m.insert(TokenType::Slash ,                         ParserRule::new(None,           Some(binary),   &Precedence::Factor));
         m.insert(TokenType::Star ,                          ParserRule::new(None,           Some(binary),   &Precedence::Factor));
+        m.insert(TokenType::StarStar ,                      ParserRule::new(None,           Some(binary),   &Precedence::Exponent));
+        m.insert(TokenType::Percent ,                       ParserRule::new(None,           Some(binary),   &Precedence::Factor));
+        m.insert(TokenType::TildeSlash ,                    ParserRule::new(None,           Some(binary),   &Precedence::Factor));
+        m.insert(TokenType::Tilde ,                         ParserRule::new(Some(unary),    None,           &Precedence::None));
+        m.insert(TokenType::Ampersand ,                     ParserRule::new(None,           Some(binary),   &Precedence::BitAnd));
+        m.insert(TokenType::Pipe ,                          ParserRule::new(None,           Some(binary),   &Precedence::BitOr));
+        m.insert(TokenType::Caret ,                         ParserRule::new(None,           Some(binary),   &Precedence::BitXor));
+        m.insert(TokenType::LessLess ,                      ParserRule::new(None,           Some(binary),   &Precedence::Shift));
+        m.insert(TokenType::GreaterGreater ,                ParserRule::new(None,           Some(binary),   &Precedence::Shift));
+        m.insert(TokenType::PlusEqual ,                     ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::MinusEqual ,                    ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::StarEqual ,                     ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::SlashEqual ,                    ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::PercentEqual ,                  ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::PlusPlus ,                      ParserRule::new(Some(increment), None,          &Precedence::None));
+        m.insert(TokenType::MinusMinus ,                    ParserRule::new(Some(increment), None,          &Precedence::None));
+        m.insert(TokenType::Question ,                      ParserRule::new(None,           Some(conditional), &Precedence::Conditional));
+        m.insert(TokenType::QuestionQuestion ,              ParserRule::new(None,           Some(coalesce), &Precedence::Coalesce));
+        m.insert(TokenType::QuestionDot ,                   ParserRule::new(None,           Some(dot),      &Precedence::Call));
+        m.insert(TokenType::Colon ,                         ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::FatArrow ,                      ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::Ellipsis ,                      ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::DotDot ,                        ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::DotDotEqual ,                   ParserRule::new(None,           None,           &Precedence::None));
         m.insert(TokenType::Bang ,                          ParserRule::new(Some(unary),    None,           &Precedence::None));
         m.insert(TokenType::BangEqual ,                     ParserRule::new(None,           Some(binary),   &Precedence::Equality));
         m.insert(TokenType::Equal ,                         ParserRule::new(None,           None,           &Precedence::None));
@@ -33,11 +62,14 @@ lazy_static! {
         m.insert(TokenType::Identifier("".to_string()),     ParserRule::new(Some(variable), None,           &Precedence::None));
         m.insert(TokenType::String("".to_string()) ,        ParserRule::new(Some(string),   None,           &Precedence::None));
         m.insert(TokenType::Number(0.) ,                    ParserRule::new(Some(number),   None,           &Precedence::None));
+        m.insert(TokenType::Integer(0) ,                    ParserRule::new(Some(number),   None,           &Precedence::None));
+        m.insert(TokenType::BigInteger(BigInt::default()) , ParserRule::new(Some(number),   None,           &Precedence::None));
+        m.insert(TokenType::Decimal(Decimal::ZERO) ,        ParserRule::new(Some(number),   None,           &Precedence::None));
         m.insert(TokenType::And ,                           ParserRule::new(None,           Some(and),      &Precedence::And));
         m.insert(TokenType::Class ,                         ParserRule::new(None,           None,           &Precedence::None));
         m.insert(TokenType::Else ,                          ParserRule::new(None,           None,           &Precedence::None));
         m.insert(TokenType::False ,                         ParserRule::new(Some(literal),  None,           &Precedence::None));
-        m.insert(TokenType::Fun ,                           ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::Fun ,                           ParserRule::new(Some(lambda),   None,           &Precedence::None));
         m.insert(TokenType::For ,                           ParserRule::new(None,           None,           &Precedence::None));
         m.insert(TokenType::If ,                            ParserRule::new(None,           None,           &Precedence::None));
         m.insert(TokenType::Nil ,                           ParserRule::new(Some(literal),  None,           &Precedence::None));
@@ -49,7 +81,18 @@ lazy_static! {
         m.insert(TokenType::True ,                          ParserRule::new(Some(literal),  None,           &Precedence::None));
         m.insert(TokenType::Var ,                           ParserRule::new(None,           None,           &Precedence::None));
         m.insert(TokenType::While ,                         ParserRule::new(None,           None,           &Precedence::None));
-        m.insert(TokenType::Error ,                         ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::Throw ,                         ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::Try ,                           ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::Catch ,                         ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::Finally ,                       ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::Import ,                        ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::From ,                          ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::As ,                            ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::Const ,                         ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::Match ,                         ParserRule::new(Some(match_expression), None,     &Precedence::None));
+        m.insert(TokenType::Yield ,                         ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::In ,                            ParserRule::new(None,           None,           &Precedence::None));
+        m.insert(TokenType::Error("".to_string()) ,         ParserRule::new(None,           None,           &Precedence::None));
         m.insert(TokenType::EOF ,                           ParserRule::new(None,           None,           &Precedence::None));
         // @formatter:on
         m
@@ -60,20 +103,27 @@ lazy_static! {
 pub enum Precedence {
     None,
     Assigment,
+    Conditional,
+    Coalesce,
     Or,
     And,
     Equality,
     Comparison,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Term,
     Factor,
     Unary,
+    Exponent,
     Call,
     Primary,
 }
 
 pub struct ParserRule<'a> {
-    pub(crate) prefix: Option<ParseFn>,
-    pub(crate) infix: Option<ParseFn>,
+    pub(crate) prefix: Option<PrefixFn>,
+    pub(crate) infix: Option<InfixFn>,
     pub(crate) precedence: &'a Precedence,
 }
 
@@ -84,7 +134,7 @@ impl<'a> Debug for ParserRule<'a> {
 }
 
 impl<'a> ParserRule<'a> {
-    fn new(prefix: Option<ParseFn>, infix: Option<ParseFn>, precedence: &'a Precedence) -> Self {
+    fn new(prefix: Option<PrefixFn>, infix: Option<InfixFn>, precedence: &'a Precedence) -> Self {
         ParserRule {
             prefix,
             infix,
@@ -97,7 +147,11 @@ impl<'a> ParserRule<'a> {
     }
 }
 
-pub type ParseFn = fn(compiler: &mut Parser, can_assign: bool);
+pub type PrefixFn = fn(parser: &mut Parser, can_assign: bool) -> Expr;
+
+///
+/// Parses the rest of an expression whose left operand was already parsed.
+pub type InfixFn = fn(parser: &mut Parser, left: Expr, can_assign: bool) -> Expr;
 
 #[cfg(test)]
 mod tests {
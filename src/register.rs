@@ -0,0 +1,627 @@
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::function::ObjectFunction;
+use crate::opcode::Opcode;
+use crate::operators;
+use crate::parser::Parser;
+use crate::value::Value;
+
+///
+/// Where an instruction reads a value from: a register of the frame, or a constant of the
+/// function.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Operand {
+    Reg(usize),
+    Const(usize),
+}
+
+///
+/// A three-address instruction. Register `n` is slot `n` of the stack frame the function would
+/// have in the stack VM: the callee, then the parameters, the locals and the temporaries.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Instr {
+    Move(usize, Operand),
+    /// a unary operator, result register first
+    Unary(Opcode, usize, Operand),
+    /// a binary operator, result register first
+    Binary(Opcode, usize, Operand, Operand),
+    GetGlobal(usize, String),
+    /// calls the function in the register with the arguments in the registers after it, and
+    /// leaves the result in its place
+    Call(usize, u8),
+    Jump(usize),
+    JumpIfFalse(Operand, usize),
+    Return(Operand),
+}
+
+impl Instr {
+    fn result_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            Instr::Move(dst, _)
+            | Instr::Unary(_, dst, _)
+            | Instr::Binary(_, dst, _, _)
+            | Instr::GetGlobal(dst, _) => Some(dst),
+            _ => None,
+        }
+    }
+}
+
+///
+/// A function lowered to register code.
+#[derive(Debug)]
+pub(crate) struct RegisterFunction {
+    pub code: Vec<Instr>,
+    pub constants: Vec<Value>,
+    pub registers: usize,
+}
+
+///
+/// Lowers the stack code of a function with `slots` parameters plus the callee into register
+/// code. The values of the stack are tracked while lowering, so the pushes of locals and
+/// constants are folded into the instructions that use them, and a result stored in a local is
+/// computed straight into it. Returns `None` for functions that use more than locals, globals,
+/// operators, jumps and calls, whose effects the stack VM has to see.
+pub(crate) fn lower(chunk: &Chunk, slots: usize) -> Option<RegisterFunction> {
+    let code = &chunk.op_codes;
+    let depths = depths(chunk, slots)?;
+    let mut is_target = vec![false; code.len() + 1];
+    for (index, op) in code.iter().enumerate() {
+        if let Some(target) = target(op, index) {
+            is_target[target?] = true;
+        }
+    }
+
+    let mut lowering = Lowering {
+        chunk,
+        code: Vec::new(),
+        constants: Vec::new(),
+        stack: None,
+        fresh: false,
+        registers: slots,
+    };
+    let mut starts = vec![0; code.len() + 1];
+    for (index, op) in code.iter().enumerate() {
+        let Some(depth) = depths[index] else {
+            starts[index] = lowering.code.len();
+            lowering.stack = None;
+            continue;
+        };
+        if is_target[index] || index == 0 {
+            // every jump leaves the values of the stack in their own registers
+            if lowering.stack.is_some() {
+                lowering.materialize(0..depth);
+            }
+            lowering.stack = Some((0..depth).map(Operand::Reg).collect());
+        }
+        starts[index] = lowering.code.len();
+        lowering.lower(op, index)?;
+    }
+    starts[code.len()] = lowering.code.len();
+
+    for instr in lowering.code.iter_mut() {
+        if let Instr::Jump(target) | Instr::JumpIfFalse(_, target) = instr {
+            *target = starts[*target];
+        }
+    }
+    Some(RegisterFunction {
+        code: lowering.code,
+        constants: lowering.constants,
+        registers: lowering.registers,
+    })
+}
+
+///
+/// The depth of the stack before each instruction, `None` for unreachable ones. Returns `None`
+/// when a reachable instruction can't be lowered, or the depth at an instruction depends on the
+/// way there.
+fn depths(chunk: &Chunk, slots: usize) -> Option<Vec<Option<usize>>> {
+    let code = &chunk.op_codes;
+    let mut depths = vec![None; code.len()];
+    let mut pending = vec![(0, slots)];
+    while let Some((index, depth)) = pending.pop() {
+        match depths.get(index)? {
+            Some(known) if *known == depth => continue,
+            Some(_) => return None,
+            None => depths[index] = Some(depth),
+        }
+        let op = &code[index];
+        let (pops, pushes) = match op {
+            Opcode::OpConstant(_)
+            | Opcode::OpNil
+            | Opcode::OpTrue
+            | Opcode::OpFalse
+            | Opcode::OpGetLocal(_)
+            | Opcode::OpGetGlobal(_)
+            | Opcode::OpJumpIfNotLessLocals(_, _, _) => (0, 1),
+            Opcode::OpSetLocal(_)
+            | Opcode::OpNegate
+            | Opcode::OpNot
+            | Opcode::OpBitNot
+            | Opcode::OpJumpIfFalse(_) => (1, 1),
+            Opcode::OpDup => (1, 2),
+            Opcode::OpPop | Opcode::OpReturn => (1, 0),
+            Opcode::OpAdd
+            | Opcode::OPSubtract
+            | Opcode::OPMultiply
+            | Opcode::OpDivide
+            | Opcode::OpModulo
+            | Opcode::OpPower
+            | Opcode::OpIntDivide
+            | Opcode::OpIncrement
+            | Opcode::OpBitAnd
+            | Opcode::OpBitOr
+            | Opcode::OpBitXor
+            | Opcode::OpShiftLeft
+            | Opcode::OpShiftRight
+            | Opcode::OpEqual
+            | Opcode::OpGreater
+            | Opcode::OpLess
+            | Opcode::OpGreaterEqual
+            | Opcode::OpLessEqual => (2, 1),
+            Opcode::OpAddLocalConst(_, _)
+            | Opcode::OpIncrementLocal(_, _)
+            | Opcode::OpJump(_)
+            | Opcode::OpLoop(_) => (0, 0),
+            Opcode::OpCall(arg_count) | Opcode::OpTailCall(arg_count) => {
+                (*arg_count as usize + 1, 1)
+            }
+            _ => return None,
+        };
+        let depth = depth.checked_sub(pops)? + pushes;
+        if !matches!(op, Opcode::OpJump(_) | Opcode::OpLoop(_) | Opcode::OpReturn) {
+            pending.push((index + 1, depth));
+        }
+        if let Some(target) = target(op, index) {
+            pending.push((target?, depth));
+        }
+    }
+    Some(depths)
+}
+
+///
+/// The position an instruction may jump to, `Some(None)` for jumps the lowering can't follow.
+fn target(op: &Opcode, index: usize) -> Option<Option<usize>> {
+    let next = index + 1;
+    match op {
+        Opcode::OpJump(jump)
+        | Opcode::OpJumpIfFalse(jump)
+        | Opcode::OpJumpIfNotLessLocals(_, _, jump) => Some(Some(next + *jump as usize)),
+        Opcode::OpLoop(offset) => Some(next.checked_sub(*offset as usize)),
+        Opcode::OpForIter(_) | Opcode::OpTry(_, _) | Opcode::OpJumpTable(_) => Some(None),
+        _ => None,
+    }
+}
+
+struct Lowering<'a> {
+    chunk: &'a Chunk,
+    code: Vec<Instr>,
+    constants: Vec<Value>,
+    /// where each value on the stack is, `None` after a jump or a return
+    stack: Option<Vec<Operand>>,
+    /// whether the last instruction computed the top of the stack into its own register
+    fresh: bool,
+    registers: usize,
+}
+
+impl Lowering<'_> {
+    fn stack(&mut self) -> &mut Vec<Operand> {
+        self.stack.as_mut().expect("reachable code")
+    }
+
+    fn depth(&self) -> usize {
+        self.stack.as_ref().map_or(0, Vec::len)
+    }
+
+    fn emit(&mut self, instr: Instr) {
+        self.code.push(instr);
+        self.fresh = false;
+    }
+
+    fn constant(&mut self, value: Value) -> Operand {
+        self.constants.push(value);
+        Operand::Const(self.constants.len() - 1)
+    }
+
+    fn pop(&mut self) -> Option<Operand> {
+        self.stack().pop()
+    }
+
+    ///
+    /// Pushes the result of `instr`, computed into the register of its stack slot.
+    fn push_result(&mut self, instr: impl FnOnce(usize) -> Instr) {
+        let dst = self.depth();
+        self.emit(instr(dst));
+        self.stack().push(Operand::Reg(dst));
+        self.registers = self.registers.max(dst + 1);
+        self.fresh = true;
+    }
+
+    ///
+    /// Moves the values of the stack slots in `range` into their own registers.
+    fn materialize(&mut self, range: std::ops::Range<usize>) {
+        for slot in range {
+            let operand = self.stack()[slot].clone();
+            if operand != Operand::Reg(slot) {
+                self.emit(Instr::Move(slot, operand));
+                self.stack()[slot] = Operand::Reg(slot);
+                self.registers = self.registers.max(slot + 1);
+            }
+        }
+    }
+
+    ///
+    /// Moves the values that still refer to register `local` into their own registers, before
+    /// `local` is written. Only slots above a register refer to it.
+    fn detach(&mut self, local: usize) {
+        for slot in local + 1..self.depth() {
+            if self.stack()[slot] == Operand::Reg(local) {
+                self.emit(Instr::Move(slot, Operand::Reg(local)));
+                self.stack()[slot] = Operand::Reg(slot);
+            }
+        }
+    }
+
+    fn local(&mut self, local: usize) -> Option<Operand> {
+        self.stack().get(local).cloned()
+    }
+
+    fn lower(&mut self, op: &Opcode, index: usize) -> Option<()> {
+        match op {
+            Opcode::OpConstant(constant) => {
+                let value = self.chunk.read_constant(*constant)?.clone();
+                let constant = self.constant(value);
+                self.stack().push(constant);
+            }
+            Opcode::OpNil | Opcode::OpTrue | Opcode::OpFalse => {
+                let constant = self.constant(match op {
+                    Opcode::OpTrue => Value::Boolean(true),
+                    Opcode::OpFalse => Value::Boolean(false),
+                    _ => Value::Nil,
+                });
+                self.stack().push(constant);
+            }
+            Opcode::OpGetLocal(local) => {
+                let operand = self.local(*local)?;
+                self.stack().push(operand);
+            }
+            Opcode::OpSetLocal(local) => {
+                let local = *local;
+                let value = self.stack().last()?.clone();
+                self.local(local)?;
+                if value != Operand::Reg(local) {
+                    self.detach(local);
+                    let top = self.depth() - 1;
+                    match self.code.last_mut().and_then(Instr::result_mut) {
+                        Some(dst) if self.fresh && *dst == top => *dst = local,
+                        _ => self.emit(Instr::Move(local, value)),
+                    }
+                    self.stack()[local] = Operand::Reg(local);
+                    self.stack()[top] = Operand::Reg(local);
+                }
+                self.fresh = false;
+            }
+            Opcode::OpGetGlobal(constant) => {
+                let name = self
+                    .chunk
+                    .read_constant(*constant)?
+                    .as_string()
+                    .ok()?
+                    .clone();
+                self.push_result(|dst| Instr::GetGlobal(dst, name));
+            }
+            Opcode::OpPop => {
+                self.pop()?;
+            }
+            Opcode::OpDup => {
+                let top = self.stack().last()?.clone();
+                self.stack().push(top);
+            }
+            Opcode::OpNegate | Opcode::OpNot | Opcode::OpBitNot => {
+                let a = self.pop()?;
+                self.push_result(|dst| Instr::Unary(op.clone(), dst, a));
+            }
+            Opcode::OpAdd
+            | Opcode::OPSubtract
+            | Opcode::OPMultiply
+            | Opcode::OpDivide
+            | Opcode::OpModulo
+            | Opcode::OpPower
+            | Opcode::OpIntDivide
+            | Opcode::OpIncrement
+            | Opcode::OpBitAnd
+            | Opcode::OpBitOr
+            | Opcode::OpBitXor
+            | Opcode::OpShiftLeft
+            | Opcode::OpShiftRight
+            | Opcode::OpEqual
+            | Opcode::OpGreater
+            | Opcode::OpLess
+            | Opcode::OpGreaterEqual
+            | Opcode::OpLessEqual => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push_result(|dst| Instr::Binary(op.clone(), dst, a, b));
+            }
+            Opcode::OpAddLocalConst(local, constant)
+            | Opcode::OpIncrementLocal(local, constant) => {
+                let binary = match op {
+                    Opcode::OpIncrementLocal(_, _) => Opcode::OpIncrement,
+                    _ => Opcode::OpAdd,
+                };
+                let a = self.local(*local)?;
+                let value = self.chunk.read_constant(*constant)?.clone();
+                let b = self.constant(value);
+                self.detach(*local);
+                self.emit(Instr::Binary(binary, *local, a, b));
+                self.stack()[*local] = Operand::Reg(*local);
+            }
+            Opcode::OpJumpIfNotLessLocals(a, b, _) => {
+                let (a, b) = (self.local(*a)?, self.local(*b)?);
+                self.push_result(|dst| Instr::Binary(Opcode::OpLess, dst, a, b));
+                self.materialize(0..self.depth());
+                let condition = self.stack().last()?.clone();
+                self.emit(Instr::JumpIfFalse(condition, target(op, index)??));
+            }
+            Opcode::OpJumpIfFalse(_) | Opcode::OpJump(_) | Opcode::OpLoop(_) => {
+                // jumps hold positions in the stack code until all of it is lowered
+                let target = target(op, index)??;
+                self.materialize(0..self.depth());
+                if let Opcode::OpJumpIfFalse(_) = op {
+                    let condition = self.stack().last()?.clone();
+                    self.emit(Instr::JumpIfFalse(condition, target));
+                } else {
+                    self.emit(Instr::Jump(target));
+                    self.stack = None;
+                }
+            }
+            Opcode::OpCall(arg_count) | Opcode::OpTailCall(arg_count) => {
+                let base = self.depth().checked_sub(*arg_count as usize + 1)?;
+                self.materialize(base..self.depth());
+                self.emit(Instr::Call(base, *arg_count));
+                self.stack().truncate(base);
+                self.stack().push(Operand::Reg(base));
+            }
+            Opcode::OpReturn => {
+                let result = self.pop()?;
+                self.emit(Instr::Return(result));
+                self.stack = None;
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+enum Lowered {
+    Pending,
+    Code(Rc<RegisterFunction>),
+    Interpreted,
+}
+
+///
+/// What register code reads from the VM running it.
+pub(crate) struct Env<'a> {
+    /// the global with a name in the module with an index
+    pub globals: &'a dyn Fn(usize, &str) -> Option<Value>,
+    pub wrapping: bool,
+    /// calls nested deeper than this are left to the stack VM, which reports the overflow
+    pub frames: usize,
+}
+
+///
+/// Runs functions lowered to register code. Register code has no effects but its result, so
+/// a call that can't finish in it, on an error, a native or a function that can't be lowered,
+/// is abandoned and made again by the stack VM. The called function then stays there.
+#[derive(Default)]
+pub(crate) struct RegisterMachine {
+    /// indexed by chunk
+    functions: Vec<Lowered>,
+    registers: Vec<Value>,
+}
+
+impl RegisterMachine {
+    ///
+    /// Forgets the lowered code, whose chunks belong to the previous program.
+    pub fn reset(&mut self) {
+        self.functions.clear();
+    }
+
+    fn lowered(
+        &mut self,
+        parser: &Parser,
+        function: &ObjectFunction,
+    ) -> Option<Rc<RegisterFunction>> {
+        let index = function.chunk_index;
+        if index >= self.functions.len() {
+            self.functions.resize_with(index + 1, || Lowered::Pending);
+        }
+        if let Lowered::Pending = self.functions[index] {
+            let slots = function.arity as usize + 1;
+            self.functions[index] = match lower(parser.chunk_at(index), slots) {
+                Some(code) => Lowered::Code(Rc::new(code)),
+                None => Lowered::Interpreted,
+            };
+        }
+        match &self.functions[index] {
+            Lowered::Code(code) => Some(code.clone()),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Calls the function in the first of the `frame` values with the arguments after it, and
+    /// returns its result, or `None` when the stack VM has to make the call.
+    pub fn call(&mut self, parser: &Parser, frame: &[Value], env: &Env) -> Option<Value> {
+        let function = callee(&frame[0], frame.len() - 1)?;
+        let code = self.lowered(parser, &function)?;
+        self.registers.extend_from_slice(frame);
+        let result = self.run(parser, &code, 0, function.module, env, 1);
+        self.registers.clear();
+        if result.is_none() {
+            self.functions[function.chunk_index] = Lowered::Interpreted;
+        }
+        result
+    }
+
+    fn run(
+        &mut self,
+        parser: &Parser,
+        function: &RegisterFunction,
+        base: usize,
+        module: usize,
+        env: &Env,
+        depth: usize,
+    ) -> Option<Value> {
+        if self.registers.len() < base + function.registers {
+            self.registers.resize(base + function.registers, Value::Nil);
+        }
+        let mut pc = 0;
+        loop {
+            match &function.code[pc] {
+                Instr::Move(dst, a) => {
+                    self.registers[base + dst] = self.read(function, base, a).clone();
+                }
+                Instr::Unary(op, dst, a) => {
+                    let a = self.read(function, base, a).clone();
+                    self.registers[base + dst] = operators::unary_op(op, a, env.wrapping).ok()?;
+                }
+                Instr::Binary(op, dst, a, b) => {
+                    let (a, b) = (self.read(function, base, a), self.read(function, base, b));
+                    let value = match operators::number_op(op, a, b, env.wrapping) {
+                        Some(value) => value,
+                        None => {
+                            operators::binary_op(op, a.clone(), b.clone(), env.wrapping).ok()?
+                        }
+                    };
+                    self.registers[base + dst] = value;
+                }
+                Instr::GetGlobal(dst, name) => {
+                    self.registers[base + dst] = (env.globals)(module, name)?;
+                }
+                Instr::Call(callee_reg, arg_count) => {
+                    if depth >= env.frames {
+                        return None;
+                    }
+                    let callee_base = base + callee_reg;
+                    let callee = callee(&self.registers[callee_base], *arg_count as usize)?;
+                    let code = self.lowered(parser, &callee)?;
+                    let result =
+                        self.run(parser, &code, callee_base, callee.module, env, depth + 1)?;
+                    self.registers[callee_base] = result;
+                }
+                Instr::Jump(target) => {
+                    pc = *target;
+                    continue;
+                }
+                Instr::JumpIfFalse(condition, target) => {
+                    if let Value::Nil | Value::Boolean(false) = self.read(function, base, condition)
+                    {
+                        pc = *target;
+                        continue;
+                    }
+                }
+                Instr::Return(a) => return Some(self.read(function, base, a).clone()),
+            }
+            pc += 1;
+        }
+    }
+
+    fn read<'a>(
+        &'a self,
+        function: &'a RegisterFunction,
+        base: usize,
+        operand: &Operand,
+    ) -> &'a Value {
+        match operand {
+            Operand::Reg(reg) => &self.registers[base + reg],
+            Operand::Const(index) => &function.constants[*index],
+        }
+    }
+}
+
+///
+/// The function in `value` when it can run as register code with `arg_count` arguments: a
+/// plain function taking exactly that many.
+fn callee(value: &Value, arg_count: usize) -> Option<std::sync::Arc<ObjectFunction>> {
+    match value {
+        Value::Function(function)
+            if !function.generator
+                && !function.variadic
+                && function.arity as usize == arg_count =>
+        {
+            Some(function.clone())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::ChunkArena;
+    use crate::opcode::Opcode;
+    use crate::parser::Parser;
+    use crate::register::Instr::{Binary, Jump, JumpIfFalse, Move, Return};
+    use crate::register::Operand::{Const, Reg};
+    use crate::register::{lower, RegisterFunction};
+
+    ///
+    /// Lowers the first function declared in `source`.
+    fn lowered(source: &str) -> Option<RegisterFunction> {
+        let mut chunks = ChunkArena::new();
+        let mut parser = Parser::new(source, &mut chunks);
+        parser.compile().expect("compiles");
+        let function = (0..)
+            .map_while(|index| parser.chunk_at(0).read_constant(index))
+            .find_map(|value| value.as_function().ok())
+            .expect("a function");
+        lower(
+            parser.chunk_at(function.chunk_index),
+            function.arity as usize + 1,
+        )
+    }
+
+    #[test]
+    fn computes_into_locals() {
+        let code =
+            lowered("fun f(n) { var s = 0; for (var i = 0; i < n; i++) s = s + i; return s; }")
+                .expect("lowers");
+        assert_eq!(
+            code.code,
+            vec![
+                Move(2, Const(0)),
+                Move(3, Const(1)),
+                Binary(Opcode::OpLess, 4, Reg(3), Reg(1)),
+                JumpIfFalse(Reg(4), 9),
+                Jump(7),
+                Binary(Opcode::OpIncrement, 3, Reg(3), Const(2)),
+                Jump(2),
+                Binary(Opcode::OpAdd, 2, Reg(2), Reg(3)),
+                Jump(5),
+                Return(Reg(2)),
+            ]
+        );
+        assert_eq!(code.registers, 5);
+    }
+
+    #[test]
+    fn keeps_values_read_before_a_local_is_written() {
+        let code = lowered("fun f(a) { return a + (a = 2); }").expect("lowers");
+        assert_eq!(
+            code.code,
+            vec![
+                Move(2, Reg(1)),
+                Move(1, Const(0)),
+                Binary(Opcode::OpAdd, 2, Reg(2), Reg(1)),
+                Return(Reg(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_effects_to_the_stack_vm() {
+        assert!(lowered("fun f(n) { print n; return n; }").is_none());
+        assert!(lowered("fun f(n) { g = n; }").is_none());
+        assert!(lowered("fun f(n) { try { return n; } catch (e) { return e; } }").is_none());
+    }
+}
@@ -0,0 +1,621 @@
+use crate::ast::{
+    Argument, ArmBody, Binding, Block, Expr, ExprKind, Function, FunctionBody, Match, Name,
+    Pattern, Program, Span, Stmt, StmtKind, Target, Variable,
+};
+use crate::function::FunctionType;
+use crate::parser::ParserError;
+use crate::value::Value;
+
+use arrayvec::ArrayVec;
+use std::collections::HashMap;
+
+///
+///
+///
+#[derive(Debug, Clone)]
+struct Local {
+    /// None for the hidden locals of match statements, for-in loops and finally blocks
+    name: Option<String>,
+    /// -1 until the variable is initialized
+    depth: isize,
+    binding: Binding,
+}
+
+///
+/// The locals of a function being resolved. Slot 0 is claimed by VM internal usage.
+#[derive(Debug)]
+struct FunctionScope {
+    ftype: FunctionType,
+    locals: ArrayVec<Local, 256>,
+    scope_depth: isize,
+}
+
+impl FunctionScope {
+    fn new(ftype: FunctionType) -> Self {
+        let mut locals = ArrayVec::new();
+        locals.push(Local {
+            name: None,
+            depth: 0,
+            binding: Binding::Variable,
+        });
+        FunctionScope {
+            ftype,
+            locals,
+            scope_depth: 0,
+        }
+    }
+}
+
+///
+/// Binds every name in a parsed program to a local slot or a global, counts the locals each
+/// block pops, and reports the misuses of names the grammar allows. Like the parser, it
+/// reports one error per declaration.
+#[derive(Debug)]
+pub(crate) struct Resolver {
+    functions: Vec<FunctionScope>,
+    /// `const` declarations of the module being resolved, by name
+    global_constants: HashMap<String, Binding>,
+    /// messages about code that compiles but is probably wrong
+    pub(crate) warnings: Vec<String>,
+    result: Option<ParserError>,
+    panic_mode: bool,
+}
+
+impl Resolver {
+    ///
+    /// A resolver for a script or a module.
+    pub(crate) fn new(ftype: FunctionType) -> Self {
+        Resolver {
+            functions: vec![FunctionScope::new(ftype)],
+            global_constants: HashMap::new(),
+            warnings: Vec::new(),
+            result: None,
+            panic_mode: false,
+        }
+    }
+
+    ///
+    /// Fills in the resolution fields of `program`, returning the last error found.
+    pub(crate) fn resolve(&mut self, program: &mut Program) -> Result<(), ParserError> {
+        self.declarations(&mut program.statements);
+        match self.result.take() {
+            None => Ok(()),
+            Some(err) => Err(err),
+        }
+    }
+
+    fn scope(&mut self) -> &mut FunctionScope {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn declarations(&mut self, statements: &mut [Stmt]) {
+        for statement in statements {
+            self.statement(statement);
+            self.panic_mode = false;
+        }
+    }
+
+    fn statement(&mut self, statement: &mut Stmt) {
+        match &mut statement.kind {
+            StmtKind::Expression(expression)
+            | StmtKind::Print(expression)
+            | StmtKind::Throw(expression) => self.expression(expression),
+            StmtKind::Var { name, initializer } => {
+                self.declare(name);
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.mark_initialized();
+            }
+            StmtKind::Const { name, initializer } => {
+                self.declare(name);
+                self.expression(initializer);
+                // the binding is set once the initializer is resolved, so it can't refer to it
+                let binding = match &initializer.kind {
+                    ExprKind::Literal(value) => Binding::Literal(value.clone()),
+                    _ => Binding::Constant,
+                };
+                if self.scope().scope_depth > 0 {
+                    if let Some(local) = self.scope().locals.last_mut() {
+                        local.binding = binding;
+                    }
+                    self.mark_initialized();
+                } else {
+                    self.global_constants.insert(name.name.clone(), binding);
+                }
+            }
+            StmtKind::Function { name, function } => {
+                self.declare(name);
+                self.mark_initialized();
+                self.function(function);
+            }
+            StmtKind::Import { keyword, name, .. } => {
+                self.top_level(*keyword);
+                self.declare(name);
+                self.mark_initialized();
+            }
+            StmtKind::ImportFrom { keyword, names, .. } => {
+                self.top_level(*keyword);
+                for name in names {
+                    self.declare(name);
+                    self.mark_initialized();
+                }
+            }
+            StmtKind::Block(block) => self.block(block),
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            StmtKind::While {
+                condition, body, ..
+            } => {
+                self.expression(condition);
+                self.statement(body);
+            }
+            StmtKind::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                if let Some(condition) = condition {
+                    self.expression(condition);
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment);
+                }
+                self.statement(body);
+                self.end_scope();
+            }
+            StmtKind::ForIn {
+                item,
+                iterable,
+                paren,
+                body,
+            } => {
+                // the iterable and the iteration state, then the item in a scope of its own
+                self.begin_scope();
+                self.expression(iterable);
+                self.add_hidden_locals(2, Binding::Variable, *paren);
+                self.begin_scope();
+                self.add_local(Some(item.name.clone()), *paren);
+                self.mark_initialized();
+                item.target = Target::Local(self.scope().locals.len() - 1);
+                self.statement(body);
+                self.end_scope();
+                self.end_scope();
+            }
+            StmtKind::Return { keyword, value } => {
+                if self.scope().ftype == FunctionType::Module {
+                    self.error("Can't return from a module", *keyword);
+                }
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            StmtKind::Yield { keyword, value } => {
+                if self.scope().ftype != FunctionType::Function {
+                    self.error("Can't yield outside a function", *keyword);
+                }
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            StmtKind::Try {
+                body,
+                catch,
+                finally,
+                ..
+            } => {
+                self.block(body);
+                if let Some(catch) = catch {
+                    self.begin_scope();
+                    self.declare(&mut catch.name);
+                    self.mark_initialized();
+                    self.declarations(&mut catch.body.statements);
+                    catch.body.locals = self.end_scope();
+                }
+                if let Some(finally) = finally {
+                    // the pending completion, popped by OpEndFinally rather than the scope
+                    self.begin_scope();
+                    self.add_hidden_locals(2, Binding::Variable, finally.keyword);
+                    self.block(&mut finally.body);
+                    let scope = self.scope();
+                    scope.scope_depth -= 1;
+                    let len = scope.locals.len();
+                    scope.locals.truncate(len.saturating_sub(2));
+                }
+            }
+            StmtKind::Match(arms) => self.match_arms(arms, false),
+        }
+    }
+
+    fn block(&mut self, block: &mut Block) {
+        self.begin_scope();
+        self.declarations(&mut block.statements);
+        block.locals = self.end_scope();
+    }
+
+    ///
+    /// Statements keep the subject in a hidden local so arms can declare locals.
+    fn match_arms(&mut self, arms: &mut Match, is_expression: bool) {
+        self.expression(&mut arms.subject);
+        if !is_expression {
+            self.begin_scope();
+            self.add_hidden_locals(1, Binding::Constant, arms.open);
+            arms.subject_slot = self.scope().locals.len() - 1;
+        }
+
+        let mut seen: Vec<Value> = Vec::new();
+        let mut exhaustive = false;
+        for arm in arms.arms.iter_mut() {
+            if exhaustive {
+                self.warning(arm.start, "Unreachable match arm");
+            }
+            let guarded = arm.guard.is_some();
+            if !guarded && !exhaustive {
+                for pattern in &arm.patterns {
+                    if let Pattern::Literal(value) = pattern {
+                        if seen.contains(value) {
+                            self.warning(arm.start, "Unreachable match pattern");
+                        }
+                    }
+                }
+            }
+            if let Some(guard) = &mut arm.guard {
+                self.expression(guard);
+            }
+            for pattern in &arm.patterns {
+                match pattern {
+                    Pattern::Wildcard if !guarded => exhaustive = true,
+                    Pattern::Literal(value) if !guarded && !seen.contains(value) => {
+                        seen.push(value.clone())
+                    }
+                    _ => {}
+                }
+            }
+            match &mut arm.body {
+                ArmBody::Statement(statement) => self.statement(statement),
+                ArmBody::Expression(expression) => self.expression(expression),
+            }
+        }
+
+        if !is_expression {
+            self.end_scope();
+        }
+    }
+
+    ///
+    /// Parameters are locals of the function, initialized in order so a default value can
+    /// use the parameters before it.
+    fn function(&mut self, function: &mut Function) {
+        self.functions
+            .push(FunctionScope::new(FunctionType::Function));
+        self.begin_scope();
+        for param in function.params.iter_mut() {
+            self.declare(&mut param.name);
+            self.mark_initialized();
+            if let Some((_, default)) = &mut param.default {
+                self.expression(default);
+            }
+        }
+        if let Some(rest) = &mut function.rest {
+            self.declare(rest);
+            self.mark_initialized();
+        }
+        match &mut function.body {
+            FunctionBody::Block(statements) => self.declarations(statements),
+            FunctionBody::Expression(expression) => self.expression(expression),
+        }
+        self.functions.pop();
+    }
+
+    fn expression(&mut self, expression: &mut Expr) {
+        match &mut expression.kind {
+            ExprKind::Literal(_) => {}
+            ExprKind::Variable(variable) => self.variable(variable),
+            ExprKind::Assign { target, value } | ExprKind::CompoundAssign { target, value, .. } => {
+                self.variable(target);
+                self.assignable(target);
+                self.expression(value);
+            }
+            ExprKind::Increment { target, .. } => {
+                self.variable(target);
+                self.assignable(target);
+            }
+            ExprKind::Unary { operand, .. } => self.expression(operand),
+            ExprKind::Binary { left, right, .. }
+            | ExprKind::Logical { left, right, .. }
+            | ExprKind::Coalesce { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            ExprKind::Conditional {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expression(condition);
+                self.expression(then_branch);
+                self.expression(else_branch);
+            }
+            ExprKind::Call { callee, arguments } => {
+                self.expression(callee);
+                for argument in arguments {
+                    match argument {
+                        Argument::Positional(value)
+                        | Argument::Named(_, value)
+                        | Argument::Spread(_, value) => self.expression(value),
+                    }
+                }
+            }
+            ExprKind::Get { object, .. } => self.expression(object),
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Function(function) => self.function(function),
+            ExprKind::Match(arms) => self.match_arms(arms, true),
+        }
+    }
+
+    ///
+    /// Locals of the function being resolved shadow globals. There are no closures, so using a
+    /// local of an enclosing function is an error rather than a read of a global by that name.
+    fn variable(&mut self, variable: &mut Variable) {
+        let local = self
+            .scope()
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.as_ref() == Some(&variable.name))
+            .map(|(slot, local)| (slot, local.depth, local.binding.clone()));
+
+        match local {
+            Some((slot, depth, binding)) => {
+                if depth == -1 {
+                    self.error(
+                        "Can't read local variable in its own initializer",
+                        variable.span,
+                    );
+                }
+                variable.target = Target::Local(slot);
+                variable.binding = binding;
+            }
+            None => {
+                let enclosing = &self.functions[..self.functions.len() - 1];
+                if enclosing.iter().any(|function| {
+                    function
+                        .locals
+                        .iter()
+                        .any(|local| local.name.as_ref() == Some(&variable.name))
+                }) {
+                    self.error(
+                        &format!(
+                            "Can't capture local variable '{}' in a function",
+                            variable.name
+                        ),
+                        variable.span,
+                    );
+                }
+                variable.target = Target::Global;
+                variable.binding = self
+                    .global_constants
+                    .get(&variable.name)
+                    .cloned()
+                    .unwrap_or_default();
+            }
+        }
+    }
+
+    fn assignable(&mut self, target: &Variable) {
+        if target.binding != Binding::Variable {
+            self.error("Can't assign to a constant", target.span);
+        }
+    }
+
+    ///
+    /// Declares a local in a scope, or checks a global doesn't redefine a constant.
+    fn declare(&mut self, name: &mut Name) {
+        let depth = self.scope().scope_depth;
+        if depth == 0 {
+            if self.global_constants.contains_key(&name.name) {
+                self.error("Already a constant with this name", name.span);
+            }
+            name.target = Target::Global;
+            return;
+        }
+
+        // it's an error to have two variables with the same name in the same local scope
+        if self
+            .scope()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|l| !(l.depth != -1 && l.depth < depth))
+            .any(|l| l.name.as_ref() == Some(&name.name))
+        {
+            self.error("Already a variable with this name in this scope", name.span)
+        }
+        self.add_local(Some(name.name.clone()), name.span);
+        name.target = Target::Local(self.scope().locals.len() - 1);
+    }
+
+    fn add_local(&mut self, name: Option<String>, span: Span) {
+        let scope = self.scope();
+        if scope.locals.len() == 256 {
+            self.error("Too many local variables in function", span);
+            return;
+        }
+        scope.locals.push(Local {
+            name,
+            depth: -1,
+            binding: Binding::Variable,
+        });
+    }
+
+    fn add_hidden_locals(&mut self, count: usize, binding: Binding, span: Span) {
+        for _ in 0..count {
+            let depth = self.scope().scope_depth;
+            let scope = self.scope();
+            if scope.locals.len() == 256 {
+                self.error("Too many local variables in function", span);
+                return;
+            }
+            scope.locals.push(Local {
+                name: None,
+                depth,
+                binding: binding.clone(),
+            });
+        }
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope = self.scope();
+        if scope.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = scope.locals.last_mut() {
+            local.depth = scope.scope_depth;
+        }
+    }
+
+    fn top_level(&mut self, keyword: Span) {
+        if self.scope().scope_depth > 0 || self.functions.len() > 1 {
+            self.error("Can only import at the top level", keyword);
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope().scope_depth += 1;
+    }
+
+    ///
+    /// Returns how many locals went out of scope.
+    fn end_scope(&mut self) -> usize {
+        let scope = self.scope();
+        scope.scope_depth -= 1;
+
+        let mut count = 0;
+        while let Some(local) = scope.locals.last() {
+            if local.depth > scope.scope_depth {
+                scope.locals.pop();
+                count += 1;
+            } else {
+                break;
+            }
+        }
+        count
+    }
+
+    fn error(&mut self, msg: &str, span: Span) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.result = Some(ParserError::report(span, msg));
+    }
+
+    fn warning(&mut self, span: Span, msg: &str) {
+        eprintln!(
+            " [line {}] Warning at {} {}: {}",
+            span.line, span.len, span.start, msg
+        );
+        self.warnings.push(format!("[line {}] {}", span.line, msg));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{self, ExprKind, Program, StmtKind, Target};
+    use crate::function::FunctionType;
+    use crate::resolver::Resolver;
+
+    fn resolved(source: &str) -> Program {
+        let mut program = ast::parse(source).expect("parses");
+        Resolver::new(FunctionType::Script)
+            .resolve(&mut program)
+            .expect("resolves");
+        program
+    }
+
+    fn resolve_error(source: &str) -> String {
+        let mut program = ast::parse(source).expect("parses");
+        Resolver::new(FunctionType::Script)
+            .resolve(&mut program)
+            .unwrap_err()
+            .msg
+    }
+
+    #[test]
+    fn locals_get_slots_and_blocks_pop_them() {
+        let program = resolved("var g = 1; { var a = 1; { var b = a; } print g; }");
+        let block = match &program.statements[1].kind {
+            StmtKind::Block(block) => block,
+            kind => panic!("not a block: {:?}", kind),
+        };
+        assert_eq!(block.locals, 1);
+        let inner = match &block.statements[1].kind {
+            StmtKind::Block(inner) => inner,
+            kind => panic!("not a block: {:?}", kind),
+        };
+        assert_eq!(inner.locals, 1);
+        match &inner.statements[0].kind {
+            StmtKind::Var {
+                name,
+                initializer: Some(initializer),
+            } => {
+                assert_eq!(name.target, Target::Local(2));
+                match &initializer.kind {
+                    ExprKind::Variable(a) => assert_eq!(a.target, Target::Local(1)),
+                    kind => panic!("not a variable: {:?}", kind),
+                }
+            }
+            kind => panic!("not a var declaration: {:?}", kind),
+        }
+        match &block.statements[2].kind {
+            StmtKind::Print(value) => match &value.kind {
+                ExprKind::Variable(g) => assert_eq!(g.target, Target::Global),
+                kind => panic!("not a variable: {:?}", kind),
+            },
+            kind => panic!("not a print statement: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn misused_names_are_errors() {
+        assert_eq!(
+            resolve_error("{ var a = a; }"),
+            "Can't read local variable in its own initializer"
+        );
+        assert_eq!(
+            resolve_error("{ var a = 1; var a = 2; }"),
+            "Already a variable with this name in this scope"
+        );
+        assert_eq!(
+            resolve_error("const c = 1; c = 2;"),
+            "Can't assign to a constant"
+        );
+        assert_eq!(
+            resolve_error("fun f() { import \"m\" as m; }"),
+            "Can only import at the top level"
+        );
+        assert_eq!(resolve_error("yield 1;"), "Can't yield outside a function");
+        assert_eq!(
+            resolve_error("fun f() { var a = 1; var g = (x) => x + a; }"),
+            "Can't capture local variable 'a' in a function"
+        );
+    }
+}
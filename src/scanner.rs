@@ -1,4 +1,17 @@
 use crate::token::{Token, TokenType};
+use num_bigint::BigInt;
+use rust_decimal::Decimal;
+use std::str::FromStr;
+///
+/// Saved scanner position, lets the parser look ahead and rewind.
+#[derive(Debug, Clone)]
+pub struct ScannerState {
+    start: usize,
+    current: usize,
+    line: isize,
+    pending_doc: Vec<String>,
+}
+
 #[derive(Debug)]
 pub struct Scanner<'a> {
     pub input: &'a str,
@@ -7,6 +20,8 @@ pub struct Scanner<'a> {
     start: usize,
     current: usize,
     pub line: isize,
+    // `///` lines seen since the last token, waiting for a `fun` or `class` to attach to
+    pending_doc: Vec<String>,
 }
 
 impl<'a> Scanner<'a> {
@@ -18,9 +33,26 @@ impl<'a> Scanner<'a> {
             start: 0,
             current: 0,
             line: 0,
+            pending_doc: Vec::new(),
         }
     }
 
+    pub fn save(&self) -> ScannerState {
+        ScannerState {
+            start: self.start,
+            current: self.current,
+            line: self.line,
+            pending_doc: self.pending_doc.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, state: ScannerState) {
+        self.start = state.start;
+        self.current = state.current;
+        self.line = state.line;
+        self.pending_doc = state.pending_doc;
+    }
+
     pub fn get_input(&self) -> String {
         self.input.clone().to_string()
     }
@@ -49,13 +81,13 @@ impl<'a> Scanner<'a> {
     ///
     ///
     fn peek(&mut self) -> char {
-        unsafe { *self.source.get_unchecked(self.current) }
+        *self.source.get(self.current).unwrap_or(&'\0')
     }
 
     ///
     ///
     fn peek_next(&mut self) -> char {
-        unsafe { *self.source.get_unchecked(self.current + 1) }
+        *self.source.get(self.current + 1).unwrap_or(&'\0')
     }
 
     fn advance(&mut self) -> char {
@@ -73,27 +105,102 @@ impl<'a> Scanner<'a> {
     ///
     ///
     pub fn scan_token(&mut self) -> Token {
-        self.skip_whitespace();
+        if let Err(msg) = self.skip_whitespace() {
+            return self.error_token(msg);
+        }
 
         self.start = self.current;
 
         if self.is_at_end() {
+            self.pending_doc.clear();
             return self.make_token(TokenType::EOF);
         }
         let c = self.advance();
 
-        match c {
+        let token = match c {
             '\0' => self.make_token(TokenType::EOF),
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
             '{' => self.make_token(TokenType::LeftBrace),
             '}' => self.make_token(TokenType::RightBrace),
             ',' => self.make_token(TokenType::Comma),
-            '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
+            '.' => {
+                if self.peek() == '.' && self.peek_next() == '.' {
+                    self.advance();
+                    self.advance();
+                    self.make_token(TokenType::Ellipsis)
+                } else if self.match_char('.') {
+                    if self.match_char('=') {
+                        self.make_token(TokenType::DotDotEqual)
+                    } else {
+                        self.make_token(TokenType::DotDot)
+                    }
+                } else {
+                    self.make_token(TokenType::Dot)
+                }
+            }
+            '-' => {
+                if self.match_char('=') {
+                    self.make_token(TokenType::MinusEqual)
+                } else if self.match_char('-') {
+                    self.make_token(TokenType::MinusMinus)
+                } else {
+                    self.make_token(TokenType::Minus)
+                }
+            }
+            '+' => {
+                if self.match_char('=') {
+                    self.make_token(TokenType::PlusEqual)
+                } else if self.match_char('+') {
+                    self.make_token(TokenType::PlusPlus)
+                } else {
+                    self.make_token(TokenType::Plus)
+                }
+            }
             ';' => self.make_token(TokenType::SemiColon),
-            '*' => self.make_token(TokenType::Star),
+            ':' => self.make_token(TokenType::Colon),
+            '?' => {
+                if self.match_char('?') {
+                    self.make_token(TokenType::QuestionQuestion)
+                } else if self.match_char('.') {
+                    self.make_token(TokenType::QuestionDot)
+                } else {
+                    self.make_token(TokenType::Question)
+                }
+            }
+            '%' => {
+                if self.match_char('=') {
+                    self.make_token(TokenType::PercentEqual)
+                } else {
+                    self.make_token(TokenType::Percent)
+                }
+            }
+            '&' => self.make_token(TokenType::Ampersand),
+            '|' => self.make_token(TokenType::Pipe),
+            '^' => self.make_token(TokenType::Caret),
+            '/' => {
+                if self.match_char('=') {
+                    self.make_token(TokenType::SlashEqual)
+                } else {
+                    self.make_token(TokenType::Slash)
+                }
+            }
+            '*' => {
+                if self.match_char('*') {
+                    self.make_token(TokenType::StarStar)
+                } else if self.match_char('=') {
+                    self.make_token(TokenType::StarEqual)
+                } else {
+                    self.make_token(TokenType::Star)
+                }
+            }
+            '~' => {
+                if self.match_char('/') {
+                    self.make_token(TokenType::TildeSlash)
+                } else {
+                    self.make_token(TokenType::Tilde)
+                }
+            }
 
             // operators
             '!' => {
@@ -106,6 +213,8 @@ impl<'a> Scanner<'a> {
             '=' => {
                 if self.match_char('=') {
                     self.make_token(TokenType::EqualEqual)
+                } else if self.match_char('>') {
+                    self.make_token(TokenType::FatArrow)
                 } else {
                     self.make_token(TokenType::Equal)
                 }
@@ -113,6 +222,8 @@ impl<'a> Scanner<'a> {
             '<' => {
                 if self.match_char('=') {
                     self.make_token(TokenType::LessEqual)
+                } else if self.match_char('<') {
+                    self.make_token(TokenType::LessLess)
                 } else {
                     self.make_token(TokenType::Less)
                 }
@@ -121,6 +232,8 @@ impl<'a> Scanner<'a> {
             '>' => {
                 if self.match_char('=') {
                     self.make_token(TokenType::GreaterEqual)
+                } else if self.match_char('>') {
+                    self.make_token(TokenType::GreaterGreater)
                 } else {
                     self.make_token(TokenType::Greater)
                 }
@@ -137,7 +250,33 @@ impl<'a> Scanner<'a> {
                     panic!("Line: {}  Unexpected character [{}]", self.line, c);
                 }
             }
+        };
+        self.attach_doc(token)
+    }
+
+    ///
+    /// Doc comments only document declarations, anything else in between drops them.
+    fn attach_doc(&mut self, mut token: Token) -> Token {
+        if self.pending_doc.is_empty() {
+            return token;
         }
+        match token.token_type {
+            TokenType::Fun | TokenType::Class => {
+                token.doc = Some(self.pending_doc.join("\n"));
+            }
+            _ => {}
+        }
+        self.pending_doc.clear();
+        token
+    }
+
+    fn error_token(&self, msg: &str) -> Token {
+        Token::new(
+            TokenType::Error(msg.to_string()),
+            self.start,
+            self.current - self.start,
+            self.line,
+        )
     }
 
     fn is_alpha(c: char) -> bool {
@@ -151,8 +290,94 @@ impl<'a> Scanner<'a> {
         TokenType::String(s)
     }
 
+    ///
+    /// Literals with a fractional part or an exponent are floats, the others integers. An `n`
+    /// suffix makes a big integer and a `d` suffix an exact decimal.
     fn make_number_token_type(&self) -> TokenType {
-        TokenType::Number(self.get_token_text().parse::<f64>().ok().unwrap())
+        let text = self.get_token_text();
+        if !Scanner::valid_separators(&text, 10) {
+            return TokenType::Error("Misplaced '_' separator in number literal".to_string());
+        }
+        let text = text.replace('_', "");
+        let is_float = text.contains(['.', 'e', 'E']);
+        if let Some(digits) = text.strip_suffix('n') {
+            if is_float {
+                return TokenType::Error(
+                    "Big integer literal can't have a fraction or an exponent".to_string(),
+                );
+            }
+            return TokenType::BigInteger(digits.parse::<BigInt>().unwrap());
+        }
+        if let Some(digits) = text.strip_suffix('d') {
+            let decimal = if digits.contains(['e', 'E']) {
+                Decimal::from_scientific(digits)
+            } else {
+                Decimal::from_str(digits)
+            };
+            return match decimal {
+                Ok(d) => TokenType::Decimal(d),
+                Err(_) => TokenType::Error("Decimal literal out of range".to_string()),
+            };
+        }
+        if is_float {
+            return match text.parse::<f64>() {
+                Ok(n) if n.is_finite() => TokenType::Number(n),
+                _ => TokenType::Error("Float literal out of range".to_string()),
+            };
+        }
+        match text.parse::<i64>() {
+            Ok(n) => TokenType::Integer(n),
+            Err(_) => TokenType::Error(
+                "Integer literal too large, use an 'n' suffix for a big integer".to_string(),
+            ),
+        }
+    }
+
+    ///
+    /// `0x`, `0b` and `0o` literals, optionally big integers with an `n` suffix.
+    fn make_radix_token_type(&self) -> TokenType {
+        let text = self.get_token_text();
+        let (radix, name) = match &text[..2] {
+            "0x" | "0X" => (16, "hexadecimal"),
+            "0b" | "0B" => (2, "binary"),
+            _ => (8, "octal"),
+        };
+        let (digits, big) = match text[2..].strip_suffix('n') {
+            Some(digits) => (digits, true),
+            None => (&text[2..], false),
+        };
+        if digits.is_empty() {
+            return TokenType::Error(format!("Expect digits in {} literal", name));
+        }
+        if let Some(c) = digits.chars().find(|c| *c != '_' && !c.is_digit(radix)) {
+            return TokenType::Error(format!("Invalid digit '{}' in {} literal", c, name));
+        }
+        if !Scanner::valid_separators(digits, radix) {
+            return TokenType::Error("Misplaced '_' separator in number literal".to_string());
+        }
+        let digits = digits.replace('_', "");
+        if big {
+            return TokenType::BigInteger(BigInt::parse_bytes(digits.as_bytes(), radix).unwrap());
+        }
+        match i64::from_str_radix(&digits, radix) {
+            Ok(n) => TokenType::Integer(n),
+            Err(_) => TokenType::Error(
+                "Integer literal too large, use an 'n' suffix for a big integer".to_string(),
+            ),
+        }
+    }
+
+    ///
+    /// Digit separators may only appear between two digits.
+    fn valid_separators(text: &str, radix: u32) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        chars.iter().enumerate().all(|(i, c)| {
+            *c != '_'
+                || (i > 0
+                    && i + 1 < chars.len()
+                    && chars[i - 1].is_digit(radix)
+                    && chars[i + 1].is_digit(radix))
+        })
     }
 
     fn make_identifier_token_type(&self) -> TokenType {
@@ -187,7 +412,7 @@ impl<'a> Scanner<'a> {
     ///
     ///
     ///
-    fn skip_whitespace(&mut self) {
+    fn skip_whitespace(&mut self) -> Result<(), &'static str> {
         loop {
             let c = self.peek();
 
@@ -199,18 +424,61 @@ impl<'a> Scanner<'a> {
                     self.line += 1;
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
-                    }
+                '/' => match self.peek_next() {
+                    '/' => self.line_comment(),
+                    '*' => self.block_comment()?,
+                    _ => return Ok(()),
+                },
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    ///
+    /// `//` comment up to the end of the line. `///` (but not `////`) is kept as doc trivia.
+    fn line_comment(&mut self) {
+        let comment_start = self.current;
+        while self.peek() != '\n' && !self.is_at_end() {
+            self.advance();
+        }
+        let text = self.source[comment_start..self.current]
+            .iter()
+            .collect::<String>();
+        if let Some(doc) = text.strip_prefix("///") {
+            if !doc.starts_with('/') {
+                self.pending_doc
+                    .push(doc.strip_prefix(' ').unwrap_or(doc).to_string());
+            }
+        }
+    }
+
+    ///
+    /// `/* ... */` comment. Block comments nest, so `/* a /* b */ c */` is a single comment.
+    fn block_comment(&mut self) -> Result<(), &'static str> {
+        self.start = self.current;
+        // The opening "/*".
+        self.advance();
+        self.advance();
+
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return Err("Unterminated block comment.");
+            }
+            match self.advance() {
+                '\n' => self.line += 1,
+                '/' if self.peek() == '*' => {
+                    self.advance();
+                    depth += 1;
                 }
-                _ => return,
+                '*' if self.peek() == '/' => {
+                    self.advance();
+                    depth -= 1;
+                }
+                _ => {}
             }
         }
+        Ok(())
     }
 
     ///
@@ -240,23 +508,67 @@ impl<'a> Scanner<'a> {
     ///
     ///
     fn number(&mut self) -> Token {
-        while self.peek().is_digit(10) {
+        // Look for a radix prefix.
+        if self.source[self.start] == '0'
+            && matches!(self.peek(), 'x' | 'X' | 'b' | 'B' | 'o' | 'O')
+        {
             self.advance();
+            self.literal_rest();
+            return self.make_token(self.make_radix_token_type());
         }
 
+        self.digits();
+
         // Look for a fractional part.
-        if self.peek() == '.' && self.peek_next().is_digit(10) {
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
             // Consume the "."
             self.advance();
+            self.digits();
+        }
 
-            while self.peek().is_digit(10) {
+        // Look for an exponent.
+        if matches!(self.peek(), 'e' | 'E') {
+            let next = self.peek_next();
+            let signed = matches!(next, '+' | '-')
+                && self
+                    .source
+                    .get(self.current + 2)
+                    .is_some_and(|c| c.is_ascii_digit());
+            if next.is_ascii_digit() || signed {
                 self.advance();
+                self.advance();
+                self.digits();
             }
         }
 
+        // Look for a type suffix.
+        if matches!(self.peek(), 'n' | 'd') {
+            self.advance();
+        }
+
+        // Letters or digits glued to the literal, like `12abc` or `1.5e`, make it malformed.
+        if Scanner::is_alpha(self.peek()) || self.peek().is_ascii_digit() {
+            self.literal_rest();
+            return self.error_token(
+                format!("Malformed number literal '{}'", self.get_token_text()).as_str(),
+            );
+        }
+
         self.make_token(self.make_number_token_type())
     }
 
+    fn digits(&mut self) {
+        while self.peek().is_ascii_digit() || self.peek() == '_' {
+            self.advance();
+        }
+    }
+
+    fn literal_rest(&mut self) {
+        while Scanner::is_alpha(self.peek()) || self.peek().is_ascii_digit() {
+            self.advance();
+        }
+    }
+
     ///
     ///
     ///
@@ -307,10 +619,43 @@ impl<'a> Scanner<'a> {
 
         let token_type = match c {
             '\0' => TokenType::EOF,
-            'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
+            'a' => {
+                if self.current - self.start > 1 {
+                    match self.source[self.start + 1] {
+                        'n' => self.check_keyword(2, 1, "d", TokenType::And),
+                        's' => self.check_keyword(2, 0, "", TokenType::As),
+                        _ => self.make_identifier_token_type(),
+                    }
+                } else {
+                    self.make_identifier_token_type()
+                }
+            }
+            'c' => {
+                if self.current - self.start > 1 {
+                    match self.source[self.start + 1] {
+                        'a' => self.check_keyword(2, 3, "tch", TokenType::Catch),
+                        'l' => self.check_keyword(2, 3, "ass", TokenType::Class),
+                        'o' => self.check_keyword(2, 3, "nst", TokenType::Const),
+                        _ => self.make_identifier_token_type(),
+                    }
+                } else {
+                    self.make_identifier_token_type()
+                }
+            }
             'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
-            'i' => self.check_keyword(1, 1, "f", TokenType::If),
+            'i' => {
+                if self.current - self.start > 1 {
+                    match self.source[self.start + 1] {
+                        'f' => self.check_keyword(2, 0, "", TokenType::If),
+                        'm' => self.check_keyword(2, 4, "port", TokenType::Import),
+                        'n' => self.check_keyword(2, 0, "", TokenType::In),
+                        _ => self.make_identifier_token_type(),
+                    }
+                } else {
+                    self.make_identifier_token_type()
+                }
+            }
+            'm' => self.check_keyword(1, 4, "atch", TokenType::Match),
             'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
             'o' => self.check_keyword(1, 1, "r", TokenType::Or),
             'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
@@ -318,12 +663,15 @@ impl<'a> Scanner<'a> {
             's' => self.check_keyword(1, 4, "super", TokenType::Super),
             'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
             'w' => self.check_keyword(1, 4, "hile", TokenType::While),
+            'y' => self.check_keyword(1, 4, "ield", TokenType::Yield),
             'f' => {
                 //dbg!("{} {} {}", self.current, self.start, self.source[self.start]);
                 if self.current - self.start > 1 {
                     match self.source[self.start + 1] {
                         'a' => self.check_keyword(2, 3, "lse", TokenType::False),
+                        'i' => self.check_keyword(2, 5, "nally", TokenType::Finally),
                         'o' => self.check_keyword(2, 1, "r", TokenType::For),
+                        'r' => self.check_keyword(2, 2, "om", TokenType::From),
                         'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
                         _ => self.make_identifier_token_type(),
                     }
@@ -334,7 +682,13 @@ impl<'a> Scanner<'a> {
             't' => {
                 if self.current - self.start > 1 {
                     match self.source[self.start + 1] {
+                        'h' if self.current - self.start == 5 => {
+                            self.check_keyword(2, 3, "row", TokenType::Throw)
+                        }
                         'h' => self.check_keyword(2, 2, "is", TokenType::This),
+                        'r' if self.current - self.start == 3 => {
+                            self.check_keyword(2, 1, "y", TokenType::Try)
+                        }
                         'r' => self.check_keyword(2, 2, "ue", TokenType::True),
                         _ => self.make_identifier_token_type(),
                     }
@@ -367,6 +721,7 @@ impl<'a> Scanner<'a> {
 #[cfg(test)]
 mod tests {
     use crate::scanner::Scanner;
+    use crate::token::TokenType;
     use std::str;
 
     #[test]
@@ -404,4 +759,111 @@ mod tests {
         let mut scanner = Scanner::new("var a = \"hello world\"");
         scanner.start();
     }
+
+    #[test]
+    fn nested_block_comment() {
+        let mut scanner = Scanner::new("/* a /* b\n */ c\n */ var");
+        let token = scanner.scan_token();
+        assert_eq!(token.token_type, TokenType::Var);
+        assert_eq!(token.line, 2);
+    }
+
+    #[test]
+    fn unterminated_block_comment() {
+        let mut scanner = Scanner::new("/* a /* b */");
+        let token = scanner.scan_token();
+        assert_eq!(token.token_type, TokenType::Error("".to_string()));
+    }
+
+    #[test]
+    fn doc_comment() {
+        let mut scanner = Scanner::new("/// Adds.\n/// Twice.\nfun add() {}");
+        let token = scanner.scan_token();
+        assert_eq!(token.token_type, TokenType::Fun);
+        assert_eq!(token.doc, Some("Adds.\nTwice.".to_string()));
+    }
+
+    #[test]
+    fn doc_comment_dropped() {
+        let mut scanner = Scanner::new("/// Not a declaration.\nvar a;\n//// plain\nfun f() {}");
+        let mut token = scanner.scan_token();
+        while token.token_type != TokenType::Fun {
+            assert_eq!(token.doc, None);
+            token = scanner.scan_token();
+        }
+        assert_eq!(token.doc, None);
+    }
+
+    #[test]
+    fn number_literals() {
+        let mut scanner =
+            Scanner::new("0xFF 0b1010 0o17 0x_ff 1_000_000 1.5e-3 2E+2 1e3 0xFFn 1_0n 1.5e2d 1..5");
+        let mut next = || scanner.scan_token().token_type;
+        assert!(matches!(next(), TokenType::Integer(255)));
+        assert!(matches!(next(), TokenType::Integer(10)));
+        assert!(matches!(next(), TokenType::Integer(15)));
+        assert!(matches!(next(), TokenType::Error(_)));
+        assert!(matches!(next(), TokenType::Integer(1_000_000)));
+        assert!(matches!(next(), TokenType::Number(n) if n == 1.5e-3));
+        assert!(matches!(next(), TokenType::Number(n) if n == 200.0));
+        assert!(matches!(next(), TokenType::Number(n) if n == 1000.0));
+        assert!(matches!(next(), TokenType::BigInteger(n) if n == 255.into()));
+        assert!(matches!(next(), TokenType::BigInteger(n) if n == 10.into()));
+        assert!(matches!(next(), TokenType::Decimal(d) if d.to_string() == "150"));
+        assert!(matches!(next(), TokenType::Integer(1)));
+        assert_eq!(next(), TokenType::DotDot);
+        assert!(matches!(next(), TokenType::Integer(5)));
+    }
+
+    #[test]
+    fn malformed_number_literals() {
+        for (source, message) in [
+            ("12abc", "Malformed number literal '12abc'"),
+            ("1.5e", "Malformed number literal '1.5e'"),
+            ("0x", "Expect digits in hexadecimal literal"),
+            ("0b102", "Invalid digit '2' in binary literal"),
+            ("0o8", "Invalid digit '8' in octal literal"),
+            ("1__000", "Misplaced '_' separator in number literal"),
+            ("1_", "Misplaced '_' separator in number literal"),
+            ("1_.5", "Misplaced '_' separator in number literal"),
+            (
+                "1.5n",
+                "Big integer literal can't have a fraction or an exponent",
+            ),
+            ("1e999", "Float literal out of range"),
+            (
+                "0x8000000000000000",
+                "Integer literal too large, use an 'n' suffix for a big integer",
+            ),
+        ] {
+            let token = Scanner::new(source).scan_token();
+            match token.token_type {
+                TokenType::Error(msg) => assert_eq!(msg, message, "{}", source),
+                other => panic!("Expected an error for {}, got {:?}", source, other),
+            }
+            assert_eq!(token.len, source.len(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn operators() {
+        let mut scanner = Scanner::new("% ** * ~/ ~ & | ^ << <= >> /");
+        for expected in [
+            TokenType::Percent,
+            TokenType::StarStar,
+            TokenType::Star,
+            TokenType::TildeSlash,
+            TokenType::Tilde,
+            TokenType::Ampersand,
+            TokenType::Pipe,
+            TokenType::Caret,
+            TokenType::LessLess,
+            TokenType::LessEqual,
+            TokenType::GreaterGreater,
+            TokenType::Slash,
+            TokenType::EOF,
+        ] {
+            assert_eq!(scanner.scan_token().token_type, expected);
+        }
+    }
 }
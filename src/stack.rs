@@ -1,5 +1,56 @@
+use std::borrow::Cow;
 use std::fmt;
 
+#[cfg(feature = "nan-boxing")]
+use crate::nanbox::NanBoxed;
+use crate::opcode::Opcode;
+use crate::operators;
+use crate::value::Value;
+
+///
+/// How the VM stack stores values. The VM pushes and reads `Value`s, which a slot converts from
+/// and to.
+pub trait Slot: From<Value> + Clone + fmt::Debug {
+    fn into_value(self) -> Value;
+
+    ///
+    /// The value in the slot, borrowed when the slot holds one.
+    fn value(&self) -> Cow<'_, Value>;
+
+    ///
+    /// The values in `slots`, borrowed when they are stored as `Value`s.
+    fn values(slots: &[Self]) -> Cow<'_, [Value]>;
+
+    ///
+    /// `operators::number_op` on two slots, for the fast paths of the VM.
+    fn number_op(op: &Opcode, a: &Self, b: &Self, wrapping: bool) -> Option<Self>;
+}
+
+impl Slot for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+
+    fn value(&self) -> Cow<'_, Value> {
+        Cow::Borrowed(self)
+    }
+
+    fn values(slots: &[Self]) -> Cow<'_, [Value]> {
+        Cow::Borrowed(slots)
+    }
+
+    fn number_op(op: &Opcode, a: &Self, b: &Self, wrapping: bool) -> Option<Self> {
+        operators::number_op(op, a, b, wrapping)
+    }
+}
+
+///
+/// The slots of the VM stack, 8 byte `NanBoxed` values with the `nan-boxing` feature.
+#[cfg(feature = "nan-boxing")]
+pub type StackValue = NanBoxed;
+#[cfg(not(feature = "nan-boxing"))]
+pub type StackValue = Value;
+
 /// A stack.
 ///
 /// Supports only the most basic stack operations needed for the machine.
@@ -7,19 +58,19 @@ use std::fmt;
 ///
 /// ```
 /// use stack_vm::Stack;
-/// let mut stack: Stack<usize> = Stack::new();
+/// let mut stack: Stack<Value> = Stack::new();
 /// assert!(stack.is_empty());
 ///
-/// stack.push(13);
+/// stack.push(Value::Int(13));
 /// assert!(!stack.is_empty());
 ///
 /// let value = stack.pop();
-/// assert_eq!(value, 13);
+/// assert_eq!(value, Value::Int(13));
 /// ```
 #[derive(Debug, Default)]
 pub struct Stack<T>(Vec<T>);
 
-impl<T: fmt::Debug> Stack<T> {
+impl<T: Slot> Stack<T> {
     /// Create a new empty `Stack` and return it.
     pub fn new() -> Stack<T> {
         Stack(vec![])
@@ -35,23 +86,25 @@ impl<T: fmt::Debug> Stack<T> {
     }
 
     /// Push an element onto the top of the stack.
-    pub fn push(&mut self, value: T) {
-        self.0.push(value);
+    pub fn push(&mut self, value: Value) {
+        self.0.push(T::from(value));
     }
 
     /// Pop the top element off the stack and return it.
-    pub fn pop(&mut self) -> T {
-        self.0.pop().expect("Unable to pop from empty stack!")
+    pub fn pop(&mut self) -> Value {
+        self.0
+            .pop()
+            .expect("Unable to pop from empty stack!")
+            .into_value()
     }
 
     pub fn pop_n(&mut self, n: u8) {
-        for _i in 0..n {
-            self.safe_pop();
-        }
+        let len = self.0.len();
+        self.0.truncate(len.saturating_sub(n as usize));
     }
 
-    pub fn safe_pop(&mut self) -> Option<T> {
-        self.0.pop()
+    pub fn safe_pop(&mut self) -> Option<Value> {
+        self.0.pop().map(Slot::into_value)
     }
 
     pub fn len(&self) -> usize {
@@ -59,29 +112,59 @@ impl<T: fmt::Debug> Stack<T> {
     }
 
     /// Pop the top element off the stack and return it.
-    pub fn get(&mut self, idx: usize) -> &T {
-        self.0.get(idx).expect("Unable to get index from stack")
+    pub fn get(&self, idx: usize) -> Cow<'_, Value> {
+        self.0
+            .get(idx)
+            .expect("Unable to get index from stack")
+            .value()
     }
-    pub fn replace(&mut self, idx: usize, value: T) {
-        let _ = std::mem::replace(&mut self.0[idx], value);
+    pub fn replace(&mut self, idx: usize, value: Value) {
+        let _ = std::mem::replace(&mut self.0[idx], T::from(value));
     }
 
-    /// Take a sneaky look at the top element on the stack.
-    pub fn peek(&self, dist: usize) -> &T {
+    /// Push a copy of the element at `idx`, without converting it to a `Value`.
+    pub fn push_copy(&mut self, idx: usize) {
+        let value = self.0[idx].clone();
+        self.0.push(value);
+    }
+
+    /// Copy the top element into `idx`.
+    pub fn copy_top(&mut self, idx: usize) {
         let len = self.0.len();
-        if len == 0 {
-            panic!("Cannot peek into empty stack!")
+        self.0[idx] = self.0[len - 1].clone();
+    }
+
+    /// Replace the top two elements with the result of `Slot::number_op` on them, if it has one.
+    pub fn number_op(&mut self, op: &Opcode, wrapping: bool) -> bool {
+        let len = self.0.len();
+        match T::number_op(op, &self.0[len - 2], &self.0[len - 1], wrapping) {
+            Some(result) => {
+                self.0.truncate(len - 1);
+                self.0[len - 2] = result;
+                true
+            }
+            None => false,
         }
-        &self.0[len - (1 + dist)]
     }
 
-    /// Make a sneaky change to the top element on the stack.
-    pub fn peek_mut(&mut self, dist: usize) -> &mut T {
+    /// Push the result of `Slot::number_op` on the elements at `a` and `b`, if it has one.
+    pub fn number_op_at(&mut self, op: &Opcode, a: usize, b: usize, wrapping: bool) -> bool {
+        match T::number_op(op, &self.0[a], &self.0[b], wrapping) {
+            Some(result) => {
+                self.0.push(result);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take a sneaky look at the top element on the stack.
+    pub fn peek(&self, dist: usize) -> Cow<'_, Value> {
         let len = self.0.len();
         if len == 0 {
             panic!("Cannot peek into empty stack!")
         }
-        &mut self.0[len - (1 + dist)]
+        self.0[len - (1 + dist)].value()
     }
 
     /// Make a sneaky change to the top element on the stack.
@@ -89,20 +172,33 @@ impl<T: fmt::Debug> Stack<T> {
         self.0.clear();
     }
 
-    pub fn as_slice(&self) -> &[T] {
-        self.0.as_slice()
+    /// The elements from `start` up to the top, bottom first.
+    pub fn values(&self, start: usize) -> Cow<'_, [Value]> {
+        T::values(&self.0[start..])
     }
 
     pub fn truncate(&mut self, len: usize) {
         self.0.truncate(len);
     }
 
-    pub unsafe fn as_ptr(&mut self) -> *const T {
+    /// Remove the top `n` elements and return them, bottom first.
+    pub fn pop_vec(&mut self, n: usize) -> Vec<Value> {
         let len = self.0.len();
-        if len == 0 {
-            panic!("Cannot peek into empty stack!")
-        }
-        self.0.as_ptr().offset(len as isize)
+        self.0
+            .split_off(len - n)
+            .into_iter()
+            .map(Slot::into_value)
+            .collect()
+    }
+
+    /// Remove the elements from `start` up to the top `n`, which slide down to `start`.
+    pub fn remove_below(&mut self, start: usize, n: usize) {
+        let len = self.0.len();
+        self.0.drain(start..len - n);
+    }
+
+    pub fn extend(&mut self, values: Vec<Value>) {
+        self.0.extend(values.into_iter().map(T::from));
     }
 }
 
@@ -112,43 +208,43 @@ mod test {
 
     #[test]
     fn new() {
-        let stack: Stack<usize> = Stack::new();
+        let stack: Stack<StackValue> = Stack::new();
         assert!(stack.is_empty());
     }
 
     #[test]
     fn push() {
-        let mut stack: Stack<usize> = Stack::new();
-        stack.push(13);
+        let mut stack: Stack<StackValue> = Stack::new();
+        stack.push(Value::Int(13));
         assert!(!stack.is_empty());
     }
 
     #[test]
     fn pop() {
-        let mut stack: Stack<usize> = Stack::new();
-        stack.push(13);
+        let mut stack: Stack<StackValue> = Stack::new();
+        stack.push(Value::Int(13));
         let value = stack.pop();
-        assert_eq!(value, 13);
+        assert_eq!(value, Value::Int(13));
     }
 
     #[test]
     #[should_panic(expected = "empty stack")]
     fn empty_pop() {
-        let mut stack: Stack<usize> = Stack::new();
+        let mut stack: Stack<StackValue> = Stack::new();
         stack.pop();
     }
 
     #[test]
     fn peek() {
-        let mut stack: Stack<usize> = Stack::new();
-        stack.push(13);
-        assert_eq!(*stack.peek(0), 13)
+        let mut stack: Stack<StackValue> = Stack::new();
+        stack.push(Value::Int(13));
+        assert_eq!(*stack.peek(0), Value::Int(13))
     }
 
     #[test]
     #[should_panic(expected = "empty stack")]
     fn empty_peek() {
-        let stack: Stack<usize> = Stack::new();
+        let stack: Stack<StackValue> = Stack::new();
         stack.peek(0);
     }
 }
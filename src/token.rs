@@ -1,3 +1,5 @@
+use num_bigint::BigInt;
+use rust_decimal::Decimal;
 use std::hash::{Hash, Hasher};
 
 #[derive(Debug, Clone)]
@@ -14,6 +16,10 @@ pub enum TokenType {
     SemiColon,
     Slash,
     Star,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
 
     // One or two character tokens.
     Bang,
@@ -22,13 +28,36 @@ pub enum TokenType {
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
+    StarStar,
+    Tilde,
+    TildeSlash,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    PercentEqual,
+    PlusPlus,
+    MinusMinus,
+    Question,
+    QuestionQuestion,
+    QuestionDot,
+    Colon,
+    FatArrow,
+    Ellipsis,
+    DotDot,
+    DotDotEqual,
 
     // Literals.
     Identifier(String),
     String(String),
     Number(f64),
+    Integer(i64),
+    BigInteger(BigInt),
+    Decimal(Decimal),
 
     // Keywords.
     And,
@@ -47,8 +76,19 @@ pub enum TokenType {
     True,
     Var,
     While,
+    Throw,
+    Try,
+    Catch,
+    Finally,
+    Import,
+    From,
+    As,
+    Const,
+    Match,
+    Yield,
+    In,
 
-    Error,
+    Error(String),
 
     EOF,
 }
@@ -179,12 +219,126 @@ impl Hash for TokenType {
             TokenType::While => {
                 state.write_u8(38);
             }
-            TokenType::Error => {
+            TokenType::Error(_) => {
                 state.write_u8(39);
             }
             TokenType::EOF => {
                 state.write_u8(40);
             }
+            TokenType::Percent => {
+                state.write_u8(41);
+            }
+            TokenType::Ampersand => {
+                state.write_u8(42);
+            }
+            TokenType::Pipe => {
+                state.write_u8(43);
+            }
+            TokenType::Caret => {
+                state.write_u8(44);
+            }
+            TokenType::GreaterGreater => {
+                state.write_u8(45);
+            }
+            TokenType::LessLess => {
+                state.write_u8(46);
+            }
+            TokenType::StarStar => {
+                state.write_u8(47);
+            }
+            TokenType::Tilde => {
+                state.write_u8(48);
+            }
+            TokenType::TildeSlash => {
+                state.write_u8(49);
+            }
+            TokenType::PlusEqual => {
+                state.write_u8(50);
+            }
+            TokenType::MinusEqual => {
+                state.write_u8(51);
+            }
+            TokenType::StarEqual => {
+                state.write_u8(52);
+            }
+            TokenType::SlashEqual => {
+                state.write_u8(53);
+            }
+            TokenType::PercentEqual => {
+                state.write_u8(54);
+            }
+            TokenType::PlusPlus => {
+                state.write_u8(55);
+            }
+            TokenType::MinusMinus => {
+                state.write_u8(56);
+            }
+            TokenType::Question => {
+                state.write_u8(57);
+            }
+            TokenType::QuestionQuestion => {
+                state.write_u8(58);
+            }
+            TokenType::Colon => {
+                state.write_u8(59);
+            }
+            TokenType::FatArrow => {
+                state.write_u8(60);
+            }
+            TokenType::Ellipsis => {
+                state.write_u8(61);
+            }
+            TokenType::Throw => {
+                state.write_u8(62);
+            }
+            TokenType::Try => {
+                state.write_u8(63);
+            }
+            TokenType::Catch => {
+                state.write_u8(64);
+            }
+            TokenType::Finally => {
+                state.write_u8(65);
+            }
+            TokenType::Import => {
+                state.write_u8(66);
+            }
+            TokenType::From => {
+                state.write_u8(67);
+            }
+            TokenType::As => {
+                state.write_u8(68);
+            }
+            TokenType::Const => {
+                state.write_u8(69);
+            }
+            TokenType::Match => {
+                state.write_u8(70);
+            }
+            TokenType::DotDot => {
+                state.write_u8(71);
+            }
+            TokenType::DotDotEqual => {
+                state.write_u8(72);
+            }
+            TokenType::Yield => {
+                state.write_u8(73);
+            }
+            TokenType::In => {
+                state.write_u8(74);
+            }
+            TokenType::Integer(_) => {
+                state.write_u8(75);
+            }
+            TokenType::BigInteger(_) => {
+                state.write_u8(76);
+            }
+            TokenType::Decimal(_) => {
+                state.write_u8(77);
+            }
+            TokenType::QuestionDot => {
+                state.write_u8(78);
+            }
         }
     }
 }
@@ -195,6 +349,8 @@ pub struct Token {
     pub line: isize,
     pub start: usize,
     pub len: usize,
+    /// `///` doc comment lines preceding a `fun` or `class` keyword
+    pub doc: Option<String>,
 }
 
 impl Token {
@@ -204,6 +360,7 @@ impl Token {
             start,
             len,
             line,
+            doc: None,
         }
     }
 
@@ -213,6 +370,7 @@ impl Token {
             start: 0,
             len: 0,
             line: 0,
+            doc: None,
         }
     }
 }
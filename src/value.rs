@@ -1,16 +1,34 @@
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
 
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+use rust_decimal::Decimal;
+
+use crate::exception::ObjectException;
+use crate::fiber::FiberRef;
 use crate::function::ObjectFunction;
+use crate::generator::GeneratorRef;
+use crate::module::ObjectModule;
 use crate::native::ObjectNative;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Boolean(bool),
     Nil,
     Number(f64),
+    Int(i64),
+    BigInt(BigInt),
+    Decimal(Decimal),
     String(String),
-    Function(ObjectFunction),
+    Function(Arc<ObjectFunction>),
     NativeFunction(ObjectNative),
+    List(Vec<Value>),
+    Exception(ObjectException),
+    Module(ObjectModule),
+    Generator(GeneratorRef),
+    Fiber(FiberRef),
 }
 
 impl Value {
@@ -18,9 +36,17 @@ impl Value {
         Value::String(str.to_owned())
     }
 
+    /// true for both floats and integers
     pub fn is_number(&self) -> bool {
         match self {
-            Value::Number(_) => true,
+            Value::Number(_) | Value::Int(_) | Value::BigInt(_) | Value::Decimal(_) => true,
+            _ => false,
+        }
+    }
+    pub fn is_integral(&self) -> bool {
+        match self {
+            Value::Number(n) => n.fract() == 0.0,
+            Value::Int(_) | Value::BigInt(_) => true,
             _ => false,
         }
     }
@@ -42,10 +68,19 @@ impl Value {
         match self {
             Value::Function(_s) => true,
             Value::NativeFunction(_s) => true,
+            Value::List(_l) => true,
+            Value::Exception(_e) => true,
+            Value::Module(_m) => true,
+            Value::Generator(_g) => true,
+            Value::Fiber(_f) => true,
             _ => false,
         }
     }
 
+    pub fn is_list(&self) -> bool {
+        matches!(self, Value::List(_))
+    }
+
     pub fn is_function(&self) -> bool {
         match self {
             Value::Function(_s) => true,
@@ -67,6 +102,54 @@ impl Value {
         }
     }
 
+    ///
+    /// The value of a float or an integer as a float.
+    pub fn to_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Int(i) => Some(*i as f64),
+            Value::BigInt(b) => b.to_f64(),
+            Value::Decimal(d) => d.to_f64(),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The value of an integer, or of a float without a fractional part in the `i64` range.
+    pub fn to_i64(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            Value::Number(n)
+                if n.fract() == 0.0 && *n >= -(2f64.powi(63)) && *n < 2f64.powi(63) =>
+            {
+                Some(*n as i64)
+            }
+            Value::BigInt(b) => b.to_i64(),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The value of an integer of either size.
+    pub fn to_bigint(&self) -> Option<BigInt> {
+        match self {
+            Value::Int(i) => Some(BigInt::from(*i)),
+            Value::BigInt(b) => Some(b.clone()),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The exact value of an integer or a decimal, if it fits in a decimal.
+    pub fn to_decimal(&self) -> Option<Decimal> {
+        match self {
+            Value::Int(i) => Some(Decimal::from(*i)),
+            Value::BigInt(b) => Decimal::from_str(&b.to_string()).ok(),
+            Value::Decimal(d) => Some(*d),
+            _ => None,
+        }
+    }
+
     pub fn as_bool(&self) -> Result<&bool, &str> {
         match self {
             Value::Boolean(c) => Ok(c),
@@ -81,7 +164,7 @@ impl Value {
         }
     }
 
-    pub fn as_function(&self) -> Result<ObjectFunction, &str> {
+    pub fn as_function(&self) -> Result<Arc<ObjectFunction>, &str> {
         match self {
             Value::Function(obj_fn) => Ok(obj_fn.clone()),
             _ => Err("Must be a obj string"),
@@ -94,6 +177,45 @@ impl Value {
             _ => Err("Must be a obj string"),
         }
     }
+
+    pub fn as_list(&self) -> Result<&Vec<Value>, &str> {
+        match self {
+            Value::List(items) => Ok(items),
+            _ => Err("Must be a list"),
+        }
+    }
+}
+
+/// Integers are equal to floats with the same value and decimals to integers with the same value,
+/// other values only to their own kind.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Int(_) | Value::BigInt(_), Value::Number(_))
+            | (Value::Number(_), Value::Int(_) | Value::BigInt(_)) => {
+                self.to_i64().is_some() && self.to_i64() == other.to_i64()
+            }
+            (Value::BigInt(_), Value::Int(_) | Value::BigInt(_))
+            | (Value::Int(_), Value::BigInt(_)) => self.to_bigint() == other.to_bigint(),
+            (Value::Decimal(_), Value::Int(_) | Value::BigInt(_) | Value::Decimal(_))
+            | (Value::Int(_) | Value::BigInt(_), Value::Decimal(_)) => {
+                self.to_decimal().is_some() && self.to_decimal() == other.to_decimal()
+            }
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::NativeFunction(a), Value::NativeFunction(b)) => a == b,
+            (Value::List(a), Value::List(b)) => a == b,
+            (Value::Exception(a), Value::Exception(b)) => a == b,
+            (Value::Module(a), Value::Module(b)) => a == b,
+            (Value::Generator(a), Value::Generator(b)) => a == b,
+            (Value::Fiber(a), Value::Fiber(b)) => a == b,
+            _ => false,
+        }
+    }
 }
 
 impl Display for Value {
@@ -108,6 +230,15 @@ impl Display for Value {
             Value::Number(n) => {
                 write!(f, "{}", n)
             }
+            Value::Int(i) => {
+                write!(f, "{}", i)
+            }
+            Value::BigInt(b) => {
+                write!(f, "{}", b)
+            }
+            Value::Decimal(d) => {
+                write!(f, "{}", d)
+            }
 
             Value::String(s) => {
                 write!(f, "{}", s)
@@ -119,12 +250,36 @@ impl Display for Value {
             Value::NativeFunction(obj) => {
                 write!(f, "<native fn {}>", obj.name)
             }
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Exception(exception) => {
+                write!(f, "{}", exception)
+            }
+            Value::Module(module) => {
+                write!(f, "{}", module)
+            }
+            Value::Generator(generator) => {
+                write!(f, "{}", generator)
+            }
+            Value::Fiber(fiber) => {
+                write!(f, "{}", fiber)
+            }
         }
     }
 }
 #[cfg(test)]
 mod tests {
     use crate::value::Value;
+    use num_bigint::BigInt;
+    use rust_decimal::Decimal;
 
     #[test]
     fn assert_eqs() {
@@ -143,5 +298,19 @@ mod tests {
         assert_eq!(Value::Number(3.1), Value::Number(3.1),);
 
         assert_ne!(Value::Number(2.0), Value::Number(1f64));
+
+        assert_eq!(Value::Int(3), Value::Number(3.0));
+        assert_eq!(Value::Number(3.0), Value::Int(3));
+        assert_ne!(Value::Int(3), Value::Number(3.5));
+        assert_ne!(Value::Int(i64::MAX), Value::Number(i64::MAX as f64));
+        assert_ne!(Value::Int(1), Value::Boolean(true));
+
+        assert_eq!(Value::BigInt(BigInt::from(7)), Value::Int(7));
+        assert_eq!(
+            Value::Decimal(Decimal::new(110, 2)),
+            Value::Decimal(Decimal::new(11, 1))
+        );
+        assert_eq!(Value::Decimal(Decimal::new(200, 2)), Value::Int(2));
+        assert_ne!(Value::Decimal(Decimal::new(15, 1)), Value::Number(1.5));
     }
 }
@@ -1,33 +1,112 @@
 use crate::chunk::{Chunk, ChunkArena, ChunkOpCodeReader};
-use std::backtrace::Backtrace;
 
-use crate::error::{LoxCompileError, LoxRuntimeError};
+use crate::error::{LoxCompileError, LoxRuntimeError, LoxUncaughtError, ThrowSite};
+use crate::exception::ObjectException;
+use crate::fiber::{FiberOp, FiberRef, FiberState};
 use crate::function::ObjectFunction;
+use crate::generator::{GeneratorRef, GeneratorState, ObjectGenerator};
+#[cfg(feature = "jit")]
+use crate::jit::{self, Exit, Jit};
+use crate::module::{Module, ObjectModule};
 use crate::native::{NativeFn, ObjectNative};
+use crate::numeric;
 use crate::opcode::Opcode;
+use crate::operators;
 use crate::parser::Parser;
-use crate::stack::Stack;
+use crate::register::{Env, RegisterMachine};
+use crate::stack::{Stack, StackValue};
 use crate::value::Value;
 use crate::value::Value::Number;
-use crate::vm::CallResponse::{Native, Standard};
-use arrayvec::ArrayVec;
-use miette::{miette, Error, IntoDiagnostic, NamedSource, Result};
+use crate::vm::CallResponse::{Native, Resume, Standard};
+use crate::vm::ReturnResponse::{Caller, Finally, Finished};
+use miette::{IntoDiagnostic, NamedSource, Report, Result};
+use num_traits::ToPrimitive;
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub struct CallFrame {
-    function: ObjectFunction,
+    function: Arc<ObjectFunction>,
     //  The slots field points into the VM’s value stack at the first slot that this function can use
     value_stack_pos: usize,
     return_address_pos: usize,
+    handlers: Vec<Handler>,
+    /// the generator this frame runs, which is suspended again at each `yield`
+    generator: Option<GeneratorRef>,
+    /// for generators resumed by a `for` loop: where the loop continues once it is exhausted
+    exhausted_address_pos: Option<usize>,
+    /// the functions this frame ran before its tail calls, and the position after each call,
+    /// most recent last; older ones are only counted in `elided`
+    tail_calls: Vec<(Arc<ObjectFunction>, usize)>,
+    elided: usize,
+    /// where the exceptions pending in this frame's finally blocks were raised, by the
+    /// frame-relative stack position of the pending exception, innermost last
+    rethrows: Vec<(usize, ThrowSite)>,
 }
 
+/// An active `try` statement: where to resume when an exception reaches it, and the stack
+/// height to unwind to first.
+#[derive(Clone, Copy)]
+pub(crate) struct Handler {
+    catch_ip: Option<usize>,
+    finally_ip: Option<usize>,
+    stack_len: usize,
+}
+
+/// How a finally block was entered, stored below the pending value in its hidden locals.
+pub(crate) const COMPLETION_NORMAL: f64 = 0.0;
+const COMPLETION_THROW: f64 = 1.0;
+const COMPLETION_RETURN: f64 = 2.0;
+
+/// maximum call depth of each fiber
+const FRAMES_MAX: usize = 64;
+/// tail calls of a frame still listed in stack traces
+const TAIL_CALLS_TRACED: usize = 8;
+
 pub struct VM {
-    pub frames: ArrayVec<CallFrame, 64>,
+    /// call frames of the running fiber
+    pub frames: Vec<CallFrame>,
     pub frame_count: usize,
-    pub stack: Stack<Value>,
+    /// value stack of the running fiber
+    pub stack: Stack<StackValue>,
+    /// the running fiber, which owns `frames` and `stack` while it is suspended
+    fiber: FiberRef,
+    /// fibers queued by `spawn`, in the order `run` resumes them
+    ready: VecDeque<FiberRef>,
     pub globals: HashMap<String, Value>,
+    /// names of the main script's globals declared with `const`
+    constants: HashSet<String>,
+    /// built-in functions, visible from every module
+    pub natives: HashMap<String, Value>,
+    /// directories searched for imported modules, initially from `LOX_PATH`
+    pub search_path: Vec<PathBuf>,
+    /// state of each imported module of the running program, indexed like `ChunkArena::modules`
+    modules: Vec<ModuleState>,
+    /// position after the instruction being executed in the innermost frame
+    ip: usize,
+    /// wrap integer arithmetic around on overflow instead of raising a runtime error
+    pub wrapping_arithmetic: bool,
+    /// evaluate operators on constant operands at compile time
+    pub fold_constants: bool,
+    /// run the peephole optimizer over the compiled bytecode
+    pub optimize: bool,
+    /// let the optimizer fuse common instruction sequences into superinstructions
+    pub superinstructions: bool,
+    /// run `return f(x);` in the returning function's frame
+    pub tail_calls: bool,
+    /// read imported modules from the files `rowc` compiles them into while up to date, and
+    /// write those files for the others
+    pub cache_modules: bool,
+    /// run calls of functions without effects on the register machine, lowered to register code
+    pub register_vm: bool,
+    register_machine: RegisterMachine,
+    /// calls and loop back-edges after which a function is compiled to native code
+    #[cfg(feature = "jit")]
+    pub jit_threshold: u32,
+    #[cfg(feature = "jit")]
+    jit: Jit,
 }
 pub enum InterpretResult {
     Ok(Option<Value>),
@@ -35,32 +114,167 @@ pub enum InterpretResult {
     RuntimeError,
 }
 
+/// The globals of an imported module and, once its script has run, its module object.
+#[derive(Default)]
+struct ModuleState {
+    globals: HashMap<String, Value>,
+    constants: HashSet<String>,
+    object: Option<Value>,
+}
+
 pub enum CallResponse {
     Standard(bool),
     Native,
+    /// a generator was resumed, continue its frame at this position
+    Resume(usize),
+}
+
+pub enum ReturnResponse {
+    /// a finally block has to run first, at this position of the current frame
+    Finally(usize),
+    /// resume the caller at this position
+    Caller(usize),
+    /// the script itself returned
+    Finished(Value),
 }
 
 impl VM {
     pub fn new() -> Self {
         let mut vm = VM {
-            frames: ArrayVec::<CallFrame, 64>::new(),
+            frames: Vec::with_capacity(FRAMES_MAX),
             frame_count: 0,
             stack: Stack::with_capacity(256),
+            fiber: FiberRef::main(),
+            ready: VecDeque::new(),
             globals: HashMap::new(),
+            constants: HashSet::new(),
+            natives: HashMap::new(),
+            search_path: std::env::var_os("LOX_PATH")
+                .map(|paths| std::env::split_paths(&paths).collect())
+                .unwrap_or_default(),
+            modules: Vec::new(),
+            ip: 0,
+            wrapping_arithmetic: false,
+            fold_constants: true,
+            optimize: true,
+            superinstructions: true,
+            tail_calls: true,
+            cache_modules: false,
+            register_vm: false,
+            register_machine: RegisterMachine::default(),
+            #[cfg(feature = "jit")]
+            jit_threshold: std::env::var("ROX_JIT_THRESHOLD")
+                .ok()
+                .and_then(|threshold| threshold.parse().ok())
+                .unwrap_or(jit::HOT_THRESHOLD),
+            #[cfg(feature = "jit")]
+            jit: Jit::new(),
         };
         vm.define_native("clock".to_string(), |_a, _b| {
             let now = SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_millis();
-            return Number(now as f64);
+            Ok(Number(now as f64))
         });
 
-        vm.define_native("sin".to_string(), |_a, b| unsafe {
-            let arg = *b.as_ref().unwrap().as_number().unwrap();
-            return Number(arg.sin());
+        vm.define_native("sin".to_string(), |arg_count, args| unsafe {
+            if arg_count != 1 {
+                return Err(format!("Expected 1 arguments, but got {}", arg_count));
+            }
+            match args.as_ref().unwrap() {
+                arg if arg.is_number() => Ok(Number(arg.to_f64().unwrap().sin())),
+                other => Err(format!("sin expects a number, found {}", other)),
+            }
         });
 
+        vm.define_native("int".to_string(), |arg_count, args| unsafe {
+            if arg_count != 1 {
+                return Err(format!("Expected 1 arguments, but got {}", arg_count));
+            }
+            match args.as_ref().unwrap() {
+                Value::Int(i) => Ok(Value::Int(*i)),
+                Number(n) => match Number(n.trunc()).to_i64() {
+                    Some(i) => Ok(Value::Int(i)),
+                    None => Err(format!("Can't convert {} to an integer", n)),
+                },
+                Value::BigInt(b) => b
+                    .to_i64()
+                    .map(Value::Int)
+                    .ok_or_else(|| format!("Can't convert {} to an integer", b)),
+                Value::Decimal(d) => d
+                    .trunc()
+                    .to_i64()
+                    .map(Value::Int)
+                    .ok_or_else(|| format!("Can't convert {} to an integer", d)),
+                Value::String(s) => s
+                    .trim()
+                    .parse::<i64>()
+                    .map(Value::Int)
+                    .map_err(|_| format!("Can't convert \"{}\" to an integer", s)),
+                other => Err(format!("int expects a number or a string, found {}", other)),
+            }
+        });
+
+        vm.define_native("float".to_string(), |arg_count, args| unsafe {
+            if arg_count != 1 {
+                return Err(format!("Expected 1 arguments, but got {}", arg_count));
+            }
+            match args.as_ref().unwrap() {
+                arg if arg.is_number() => Ok(Number(arg.to_f64().unwrap())),
+                Value::String(s) => s
+                    .trim()
+                    .parse::<f64>()
+                    .map(Number)
+                    .map_err(|_| format!("Can't convert \"{}\" to a float", s)),
+                other => Err(format!(
+                    "float expects a number or a string, found {}",
+                    other
+                )),
+            }
+        });
+
+        vm.define_native("bigint".to_string(), |arg_count, args| unsafe {
+            if arg_count != 1 {
+                return Err(format!("Expected 1 arguments, but got {}", arg_count));
+            }
+            numeric::to_bigint(args.as_ref().unwrap())
+        });
+
+        vm.define_native("decimal".to_string(), |arg_count, args| unsafe {
+            if arg_count != 1 {
+                return Err(format!("Expected 1 arguments, but got {}", arg_count));
+            }
+            numeric::to_decimal(args.as_ref().unwrap())
+        });
+
+        vm.define_native("round".to_string(), |arg_count, args| unsafe {
+            numeric::round(std::slice::from_raw_parts(args, arg_count as usize))
+        });
+
+        vm.define_native("format".to_string(), |arg_count, args| unsafe {
+            numeric::format(std::slice::from_raw_parts(args, arg_count as usize))
+        });
+
+        vm.define_native("Fiber".to_string(), |arg_count, args| unsafe {
+            if arg_count != 1 {
+                return Err(format!("Expected 1 arguments, but got {}", arg_count));
+            }
+            match args.as_ref().unwrap() {
+                Value::Function(function) if function.generator => Err(format!(
+                    "{} is a generator, not a fiber function",
+                    function.name
+                )),
+                Value::Function(function) => {
+                    Ok(Value::Fiber(FiberRef::new(Some(function.clone()))))
+                }
+                other => Err(format!("Fiber expects a function, found {}", other)),
+            }
+        });
+        vm.define_fiber_native("suspend", FiberOp::Suspend);
+        vm.define_fiber_native("spawn", FiberOp::Spawn);
+        vm.define_fiber_native("run", FiberOp::Run);
+
         vm
     }
 
@@ -74,16 +288,44 @@ impl VM {
         //     name.clone(),
         //     function,
         // )));
-        self.globals.insert(
+        self.natives.insert(
             name.clone(),
             Value::NativeFunction(ObjectNative::new(name.clone(), function)),
         );
     }
 
+    fn define_fiber_native(&mut self, name: &str, op: FiberOp) {
+        let mut native = ObjectNative::new(name.to_string(), |_, _| {
+            unreachable!("the VM switches fibers itself")
+        });
+        native.fiber_op = Some(op);
+        self.natives
+            .insert(name.to_string(), Value::NativeFunction(native));
+    }
+
     pub fn interpret(&mut self, source: &str) -> Result<Option<Value>> {
+        self.interpret_module(source, None)
+    }
+
+    ///
+    /// Runs a script file; its imports are resolved relative to the file.
+    pub fn interpret_file(&mut self, path: &Path) -> Result<Option<Value>> {
+        let source = std::fs::read_to_string(path).into_diagnostic()?;
+        let path = path.canonicalize().into_diagnostic()?;
+        self.interpret_module(&source, Some(path))
+    }
+
+    fn interpret_module(&mut self, source: &str, path: Option<PathBuf>) -> Result<Option<Value>> {
         let mut array_array = ChunkArena::new();
+        array_array.modules[0] = Module::new(path, source.to_string());
 
         let mut parser = Parser::new(source, &mut array_array);
+        parser.search_path = self.search_path.clone();
+        parser.fold_constants = self.fold_constants;
+        parser.optimize = self.optimize;
+        parser.superinstructions = self.superinstructions;
+        parser.tail_calls = self.tail_calls;
+        parser.cache_modules = self.cache_modules;
 
         // let mut function = parser.compile()?;
         // self.frames.push(CallFrame {
@@ -113,8 +355,17 @@ impl VM {
                 // write!(stdout(), "CALLING FUNCTION {}\n", function.name);
                 // function.disassemble_chunk(&mut (Box::new(io::stdout()) as Box<dyn Write>));
 
+                self.fiber = FiberRef::main();
+                self.ready.clear();
+                let function = Arc::new(function.clone());
                 self.stack.push(Value::Function(function.clone()));
-                let _ = self.call(function, &0, 0);
+                let _ = self.call(&function, &0, 0);
+                self.modules = (0..parser.module_count())
+                    .map(|_| ModuleState::default())
+                    .collect();
+                self.register_machine.reset();
+                #[cfg(feature = "jit")]
+                self.jit.reset();
                 // self.frames.push(CallFrame {
                 //     function,
                 //     ip: 0,
@@ -161,11 +412,7 @@ impl VM {
         //     // unsafe { (*frame.function).chunk }
         // }
 
-        // unsafe {
-        Err(Error::msg(
-            format!("{}\n\n{}", msg.to_string(), Backtrace::force_capture()).to_string(),
-        ))?
-        // }
+        Err(LoxRuntimeError::new(msg))?
     }
 
     fn wrong_type_error<T>(&mut self, msg: &str) -> Result<T> {
@@ -176,9 +423,7 @@ impl VM {
         //     println!("Custom backtrace: {}", Backtrace::force_capture());
         // }
 
-        Err(Error::msg(
-            format!("{}\n\n{}", msg.to_string(), Backtrace::force_capture()).to_string(),
-        ))?
+        Err(LoxRuntimeError::new(msg))?
     }
 
     pub fn pop_operand_as_number(&mut self) -> Result<f64> {
@@ -187,16 +432,41 @@ impl VM {
             //  Err(LoxRuntimeError::new("Operand must be numbers"))?;
         }
         //let p = self.stack.peek(0).is_number();
-        Ok(*self.stack.pop().as_number().unwrap())
+        Ok(self.stack.pop().to_f64().unwrap())
     }
 
-    pub fn unchecked_pop_operand_as_numbers(&mut self) -> Result<(f64, f64)> {
-        let b = *self.stack.pop().as_number().unwrap();
-        let a = *self.stack.pop().as_number().unwrap();
+    pub fn pop_operand_as_integer(&mut self) -> Result<i64> {
+        if self.stack.peek(0).to_i64().is_none() {
+            return self.wrong_type_error(
+                format!("Operand must be an integer, found {}", self.stack.peek(0)).as_str(),
+            );
+        }
+        Ok(self.stack.pop().to_i64().unwrap())
+    }
+
+    pub fn pop_operand_as_integers(&mut self) -> Result<(i64, i64)> {
+        let op1 = self.stack.peek(0);
+        let op2 = self.stack.peek(1);
+        if op1.to_i64().is_none() || op2.to_i64().is_none() {
+            return self.wrong_type_error(
+                format!(
+                    "Operands must be integers, found operand #1 = {}, #2 = {}",
+                    op2, op1
+                )
+                .as_str(),
+            );
+        }
+        let b = self.stack.pop().to_i64().unwrap();
+        let a = self.stack.pop().to_i64().unwrap();
         Ok((a, b))
     }
 
     pub fn pop_operand_as_numbers(&mut self) -> Result<(f64, f64)> {
+        let (a, b) = self.pop_operand_as_values()?;
+        Ok((a.to_f64().unwrap(), b.to_f64().unwrap()))
+    }
+
+    fn pop_operand_as_values(&mut self) -> Result<(Value, Value)> {
         let op1 = self.stack.peek(0);
         let op2 = self.stack.peek(1);
         if !op1.is_number() || !op2.is_number() {
@@ -209,8 +479,8 @@ impl VM {
                 .as_str(),
             );
         }
-        let b = *self.stack.pop().as_number().unwrap();
-        let a = *self.stack.pop().as_number().unwrap();
+        let b = self.stack.pop();
+        let a = self.stack.pop();
         Ok((a, b))
     }
 
@@ -220,14 +490,14 @@ impl VM {
         Ok((a, b))
     }
 
-    pub fn unchecked_pop_operand_as_string_and_number(&mut self) -> Result<(f64, String)> {
+    pub fn unchecked_pop_operand_as_string_and_number(&mut self) -> Result<(Value, String)> {
         let b = self.stack.pop().as_string().unwrap().clone();
-        let a = self.stack.pop().as_number().unwrap().clone();
+        let a = self.stack.pop();
         Ok((a, b))
     }
 
-    pub fn unchecked_pop_operand_as_number_and_string(&mut self) -> Result<(String, f64)> {
-        let b = self.stack.pop().as_number().unwrap().clone();
+    pub fn unchecked_pop_operand_as_number_and_string(&mut self) -> Result<(String, Value)> {
+        let b = self.stack.pop();
         let a = self.stack.pop().as_string().unwrap().clone();
         Ok((a, b))
     }
@@ -265,7 +535,7 @@ impl VM {
 
     fn call_value(&mut self, arity: &u8, opcode_pos: usize) -> Result<CallResponse> {
         // let callee1 = self.stack.peek_mut(peek_pos - 1);
-        let callee = self.stack.peek_mut(*arity as usize);
+        let callee = self.stack.peek(*arity as usize);
 
         // println!(
         //     "peek_pos {}, arg_count {}, calle {}",
@@ -284,22 +554,57 @@ impl VM {
             //         todo!();
             //     }
             // }
-            if let Ok(mut func) = callee.as_function() {
-                return Ok(CallResponse::Standard(
-                    self.call(&mut func, arity, opcode_pos).unwrap(),
-                ));
+            if let Ok(func) = callee.as_function() {
+                return Ok(CallResponse::Standard(self.call(&func, arity, opcode_pos)?));
             }
-            if let Ok(native) = callee.as_native() {
-                unsafe {
-                    let fn_native = native.function;
-                    let _ptr0 = self.stack.as_ptr();
-                    let ptr = self.stack.as_ptr().offset(-((*arity) as isize));
-                    let result = fn_native(*arity, ptr);
-                    self.stack.pop_n(arity + 1);
-
-                    self.stack.push(result);
-                    return Ok(CallResponse::Native);
+            if let Value::Generator(generator) = &*callee {
+                let generator = generator.clone();
+                if *arity != 0 {
+                    return self.runtime_error(
+                        format!("Expected 0 arguments, but got {}", arity).as_str(),
+                    );
+                }
+                self.stack.pop();
+                return match self.resume(generator, opcode_pos, None)? {
+                    Some(ip) => Ok(Resume(ip)),
+                    None => {
+                        self.stack.push(Value::Nil);
+                        Ok(Native)
+                    }
+                };
+            }
+            if let Value::Fiber(fiber) = &*callee {
+                let fiber = fiber.clone();
+                if *arity > 1 {
+                    return self.runtime_error(
+                        format!("Expected 0 or 1 arguments, but got {}", arity).as_str(),
+                    );
                 }
+                let value = if *arity == 1 {
+                    self.stack.pop()
+                } else {
+                    Value::Nil
+                };
+                self.stack.pop();
+                let caller = self.fiber.clone();
+                return Ok(Resume(self.resume_fiber(fiber, value, caller, opcode_pos)?));
+            }
+            if let Ok(ObjectNative {
+                fiber_op: Some(op), ..
+            }) = callee.as_native()
+            {
+                return self.fiber_op(op, *arity, opcode_pos);
+            }
+            if let Ok(native) = callee.as_native() {
+                let result = {
+                    let args = self.stack.values(self.stack.len() - *arity as usize);
+                    (native.function)(*arity, args.as_ptr())
+                        .map_err(|msg| LoxRuntimeError::new(msg.as_str()))?
+                };
+                self.stack.pop_n(arity + 1);
+
+                self.stack.push(result);
+                return Ok(CallResponse::Native);
             }
         }
 
@@ -311,43 +616,535 @@ impl VM {
     ///
     fn call(
         &mut self,
-        function: &mut ObjectFunction,
+        function: &Arc<ObjectFunction>,
         arg_count: &u8,
         opcode_pos: usize,
     ) -> Result<bool> {
-        if *arg_count != function.arity {
-            return self.runtime_error(
-                format!(
-                    "Expected {} arguments, but got {}",
-                    function.arity, arg_count
-                )
-                .as_str(),
-            );
+        if self.frames.len() == FRAMES_MAX {
+            return self.runtime_error("Stack overflow");
+        }
+        let slot_count = self.adjust_arguments(function, *arg_count as usize)?;
+        if function.generator {
+            let slots = self.stack.pop_vec(slot_count + 1);
+            self.stack
+                .push(ObjectGenerator::new_value(function.clone(), slots));
+            return Ok(false);
         }
-        let p = self.stack.len() - *arg_count as usize - 1;
+        let p = self.stack.len() - slot_count - 1;
         //   println!("value_stack_pos {}", p);
         self.frames.push(CallFrame {
             function: function.clone(),
             value_stack_pos: p,
             return_address_pos: opcode_pos,
+            handlers: Vec::new(),
+            generator: None,
+            exhausted_address_pos: None,
+            tail_calls: Vec::new(),
+            elided: 0,
+            rethrows: Vec::new(),
         });
 
         Ok(true)
     }
+
+    ///
+    /// Calls the function below the `arg_count` arguments through its compiled code, once it
+    /// is hot. The result is left on the stack, or the function's frame is pushed to resume
+    /// where the compiled code deoptimized.
+    #[cfg(feature = "jit")]
+    fn call_compiled(
+        &mut self,
+        parser: &Parser,
+        arg_count: u8,
+        opcode_pos: usize,
+    ) -> Result<Option<CallResponse>> {
+        let function = match &*self.stack.peek(arg_count as usize) {
+            Value::Function(function) if !function.generator => function.clone(),
+            _ => return Ok(None),
+        };
+        let chunk = parser.chunk_at(function.chunk_index);
+        let code = match self.jit.hot(&function, chunk, self.jit_threshold) {
+            Some(code) if self.frames.len() < FRAMES_MAX => code,
+            _ => return Ok(None),
+        };
+        let slot_count = self.adjust_arguments(&function, arg_count as usize)?;
+        let value_stack_pos = self.stack.len() - slot_count - 1;
+        let exit = code.run(&self.stack.values(value_stack_pos), 0);
+        self.stack.truncate(value_stack_pos);
+        match exit {
+            Exit::Returned(result, _) => {
+                self.stack.push(result);
+                Ok(Some(Native))
+            }
+            Exit::Deoptimized(ip, frame) => {
+                self.jit.deoptimized(function.chunk_index);
+                self.stack.extend(frame);
+                self.frames.push(CallFrame {
+                    function,
+                    value_stack_pos,
+                    return_address_pos: opcode_pos,
+                    handlers: Vec::new(),
+                    generator: None,
+                    exhausted_address_pos: None,
+                    tail_calls: Vec::new(),
+                    elided: 0,
+                    rethrows: Vec::new(),
+                });
+                Ok(Some(Resume(ip)))
+            }
+        }
+    }
+
+    ///
+    /// Continues the current frame in its compiled code from the start of a loop, at `ip`,
+    /// once the function is hot. Returns where to continue interpreting: at the `OpReturn` the
+    /// compiled code returned at, with the result pushed, or where it deoptimized.
+    #[cfg(feature = "jit")]
+    fn loop_compiled(&mut self, parser: &Parser, ip: usize) -> Option<usize> {
+        let frame = self.frames.last().expect("no frame");
+        if !frame.handlers.is_empty() || frame.generator.is_some() {
+            return None;
+        }
+        let function = frame.function.clone();
+        let value_stack_pos = frame.value_stack_pos;
+        let chunk = parser.chunk_at(function.chunk_index);
+        let code = self.jit.hot(&function, chunk, self.jit_threshold)?;
+        let exit = {
+            let frame = self.stack.values(value_stack_pos);
+            if !code.enters_at(ip, frame.len()) {
+                return None;
+            }
+            code.run(&frame, ip)
+        };
+        match exit {
+            Exit::Returned(result, ip) => {
+                // the interpreter finishes the return, popping the frame
+                self.stack.push(result);
+                Some(ip)
+            }
+            Exit::Deoptimized(ip, frame) => {
+                self.jit.deoptimized(function.chunk_index);
+                self.stack.truncate(value_stack_pos);
+                self.stack.extend(frame);
+                Some(ip)
+            }
+        }
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn call_compiled(&mut self, _: &Parser, _: u8, _: usize) -> Result<Option<CallResponse>> {
+        Ok(None)
+    }
+
+    ///
+    /// Calls the function below the `arg_count` arguments on the register machine, when
+    /// `register_vm` is set, leaving the result on the stack. Returns false without calling when
+    /// the call has to be made by the stack VM.
+    fn call_registers(&mut self, parser: &Parser, arg_count: u8) -> bool {
+        if !self.register_vm {
+            return false;
+        }
+        let start = self.stack.len() - arg_count as usize - 1;
+        let mut machine = std::mem::take(&mut self.register_machine);
+        let env = Env {
+            globals: &|module, name| self.global(module, name).cloned(),
+            wrapping: self.wrapping_arithmetic,
+            frames: FRAMES_MAX - self.frames.len(),
+        };
+        let result = machine.call(parser, &self.stack.values(start), &env);
+        self.register_machine = machine;
+        match result {
+            Some(value) => {
+                self.stack.truncate(start);
+                self.stack.push(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    ///
+    /// Runs the function below the `arg_count` arguments in the current frame instead of a new
+    /// one, for `return f(x);`. Returns false without calling when the callee isn't a plain
+    /// function, or the frame still has a `try` statement or a generator to finish.
+    fn tail_call(&mut self, arg_count: u8, opcode_pos: usize) -> Result<bool> {
+        let function = match &*self.stack.peek(arg_count as usize) {
+            Value::Function(function) if !function.generator => function.clone(),
+            _ => return Ok(false),
+        };
+        let frame = self.frames.last().expect("no frame");
+        if !frame.handlers.is_empty() || frame.generator.is_some() {
+            return Ok(false);
+        }
+        let slot_count = self.adjust_arguments(&function, arg_count as usize)?;
+        let frame = self.frames.last_mut().expect("no frame");
+        self.stack
+            .remove_below(frame.value_stack_pos, slot_count + 1);
+
+        let caller = std::mem::replace(&mut frame.function, function);
+        if frame.tail_calls.len() == TAIL_CALLS_TRACED {
+            frame.tail_calls.remove(0);
+            frame.elided += 1;
+        }
+        frame.tail_calls.push((caller, opcode_pos));
+        Ok(true)
+    }
+
+    ///
+    /// Calls to `suspend`, `spawn` and `run`, with the callee and `arg_count` arguments on the
+    /// stack.
+    fn fiber_op(&mut self, op: FiberOp, arg_count: u8, opcode_pos: usize) -> Result<CallResponse> {
+        let expected = match op {
+            FiberOp::Suspend => 0..=1,
+            FiberOp::Spawn => 1..=1,
+            FiberOp::Run => 0..=0,
+        };
+        if !expected.contains(&arg_count) {
+            return self.runtime_error(
+                format!("Expected {:?} arguments, but got {}", expected, arg_count).as_str(),
+            );
+        }
+        let mut args = self.stack.pop_vec(arg_count as usize);
+        self.stack.pop();
+        match op {
+            FiberOp::Suspend => {
+                let value = args.pop().unwrap_or(Value::Nil);
+                Ok(Resume(self.suspend_fiber(value, opcode_pos)?))
+            }
+            FiberOp::Spawn => {
+                let fiber = match args.pop() {
+                    Some(Value::Function(function)) if !function.generator => {
+                        FiberRef::new(Some(function))
+                    }
+                    Some(Value::Fiber(fiber)) => fiber,
+                    other => {
+                        return self.runtime_error(
+                            format!("Can only spawn functions and fibers, found {:?}", other)
+                                .as_str(),
+                        )
+                    }
+                };
+                self.ready.push_back(fiber.clone());
+                self.stack.push(Value::Fiber(fiber));
+                Ok(Native)
+            }
+            FiberOp::Run => {
+                let caller = self.fiber.clone();
+                Ok(Resume(self.run_next(caller, opcode_pos)?))
+            }
+        }
+    }
+
+    ///
+    /// Makes `target` the running fiber. The running fiber keeps its stack and frames, and
+    /// `ip` as the position to continue at. Returns the position to continue `target` at.
+    fn switch_fiber(&mut self, target: FiberRef, ip: usize) -> usize {
+        if target == self.fiber {
+            return ip;
+        }
+        {
+            let mut current = self.fiber.lock();
+            current.stack = std::mem::replace(&mut self.stack, Stack::new());
+            current.frames = std::mem::take(&mut self.frames);
+            current.ip = ip;
+        }
+        let mut next = target.lock();
+        self.stack = std::mem::replace(&mut next.stack, Stack::new());
+        self.frames = std::mem::take(&mut next.frames);
+        let ip = next.ip;
+        drop(next);
+        self.fiber = target;
+        ip
+    }
+
+    ///
+    /// Switches to `fiber`, which returns control to `caller`. A new fiber starts by calling its
+    /// function with `value` as the argument if it takes one, a suspended fiber receives `value`
+    /// as the result of its `suspend` call. Returns the position to continue at.
+    fn resume_fiber(
+        &mut self,
+        fiber: FiberRef,
+        value: Value,
+        caller: FiberRef,
+        ip: usize,
+    ) -> Result<usize> {
+        let (state, function) = {
+            let fiber = fiber.lock();
+            (fiber.state, fiber.function.clone())
+        };
+        match state {
+            FiberState::Done => return self.runtime_error("Can't resume a finished fiber"),
+            FiberState::Running => return self.runtime_error("Fiber is already running"),
+            FiberState::New | FiberState::Suspended => {}
+        }
+        {
+            let mut fiber = fiber.lock();
+            fiber.caller = Some(caller);
+            fiber.state = FiberState::Running;
+        }
+        let ip = self.switch_fiber(fiber, ip);
+        if state == FiberState::Suspended {
+            self.stack.push(value);
+            return Ok(ip);
+        }
+
+        let function = function.expect("only the main fiber has no function");
+        self.stack.push(Value::Function(function.clone()));
+        let arg_count = if function.arity > 0 || function.variadic {
+            self.stack.push(value);
+            1
+        } else {
+            0
+        };
+        self.call(&function, &arg_count, 0)?;
+        Ok(0)
+    }
+
+    ///
+    /// Hands `value` to the fiber that resumed the running one, or lets the next ready fiber
+    /// run if the scheduler started it. Returns the position to continue at.
+    fn suspend_fiber(&mut self, value: Value, ip: usize) -> Result<usize> {
+        let current = self.fiber.clone();
+        let (caller, scheduled) = {
+            let mut fiber = current.lock();
+            (fiber.caller.take(), fiber.scheduled)
+        };
+        let caller = match caller {
+            Some(caller) => caller,
+            None => return self.runtime_error("Can't suspend the main fiber"),
+        };
+        current.lock().state = FiberState::Suspended;
+        if scheduled {
+            self.ready.push_back(current);
+            return self.run_next(caller, ip);
+        }
+        let ip = self.switch_fiber(caller, ip);
+        self.stack.push(value);
+        Ok(ip)
+    }
+
+    ///
+    /// The scheduler: resumes the next ready fiber, or returns to `caller`, the fiber that
+    /// called `run`, once all of them have finished.
+    fn run_next(&mut self, caller: FiberRef, ip: usize) -> Result<usize> {
+        while let Some(next) = self.ready.pop_front() {
+            let runnable = {
+                let mut fiber = next.lock();
+                fiber.scheduled = true;
+                matches!(fiber.state, FiberState::New | FiberState::Suspended)
+            };
+            if runnable {
+                return self.resume_fiber(next, Value::Nil, caller, ip);
+            }
+        }
+        let ip = self.switch_fiber(caller, ip);
+        self.stack.push(Value::Nil);
+        Ok(ip)
+    }
+
+    ///
+    /// Moves a suspended generator's frame back onto the call stack. The generator returns to
+    /// `return_address_pos`, or to `exhausted_address_pos` if given when it finishes.
+    /// Returns the position to continue the generator at, or None if it has already finished.
+    fn resume(
+        &mut self,
+        generator: GeneratorRef,
+        return_address_pos: usize,
+        exhausted_address_pos: Option<usize>,
+    ) -> Result<Option<usize>> {
+        let mut state = generator.lock();
+        match state.state {
+            GeneratorState::Done => return Ok(None),
+            GeneratorState::Running => return self.runtime_error("Generator is already running"),
+            GeneratorState::Suspended => {}
+        }
+        let value_stack_pos = self.stack.len();
+        self.stack.extend(std::mem::take(&mut state.slots));
+        let handlers = state
+            .handlers
+            .drain(..)
+            .map(|handler| Handler {
+                stack_len: handler.stack_len + value_stack_pos,
+                ..handler
+            })
+            .collect();
+        let rethrows = std::mem::take(&mut state.rethrows);
+        state.state = GeneratorState::Running;
+        let ip = state.ip;
+        let function = state.function.clone();
+        drop(state);
+
+        self.frames.push(CallFrame {
+            function,
+            value_stack_pos,
+            return_address_pos,
+            handlers,
+            generator: Some(generator),
+            exhausted_address_pos,
+            tail_calls: Vec::new(),
+            elided: 0,
+            rethrows,
+        });
+        Ok(Some(ip))
+    }
+
+    ///
+    /// Saves the running generator's frame and stack slots in the generator and pops the frame.
+    /// Returns the caller's position to continue at.
+    fn suspend(&mut self, ip: usize) -> usize {
+        let frame = self.frames.pop().expect("no frame");
+        let generator = frame
+            .generator
+            .expect("yield is only compiled into generator functions");
+        let mut state = generator.lock();
+        state.slots = self.stack.pop_vec(self.stack.len() - frame.value_stack_pos);
+        state.handlers = frame
+            .handlers
+            .iter()
+            .map(|handler| Handler {
+                stack_len: handler.stack_len - frame.value_stack_pos,
+                ..*handler
+            })
+            .collect();
+        state.rethrows = frame.rethrows;
+        state.ip = ip;
+        state.state = GeneratorState::Suspended;
+        frame.return_address_pos
+    }
+
+    ///
+    /// Lays the arguments out the way the callee expects them: nil for each missing optional
+    /// parameter and, for variadic functions, the extra arguments packed into a trailing list.
+    /// Returns the number of parameter slots now on the stack.
+    fn adjust_arguments(&mut self, function: &ObjectFunction, arg_count: usize) -> Result<usize> {
+        let arity = function.arity as usize;
+        let min_arity = function.min_arity as usize;
+        if arg_count < min_arity || (arg_count > arity && !function.variadic) {
+            let expected = if function.variadic {
+                format!("at least {}", min_arity)
+            } else if min_arity == arity {
+                format!("{}", arity)
+            } else {
+                format!("{} to {}", min_arity, arity)
+            };
+            return self.runtime_error(
+                format!("Expected {} arguments, but got {}", expected, arg_count).as_str(),
+            );
+        }
+
+        for _ in arg_count..arity {
+            self.stack.push(Value::Nil);
+        }
+        if function.variadic {
+            let rest = self.stack.pop_vec(arg_count.saturating_sub(arity));
+            self.stack.push(Value::List(rest));
+            return Ok(arity + 1);
+        }
+        Ok(arity)
+    }
+
+    ///
+    /// Reorders `name: value` arguments into parameter order. The last `names.len()` of the
+    /// `arg_count` arguments on the stack are named, the others positional.
+    /// Returns the resulting positional argument count.
+    fn bind_named_arguments(&mut self, arg_count: u8, names: &[Value]) -> Result<u8> {
+        let function = match self.stack.peek(arg_count as usize).as_function() {
+            Ok(function) => function,
+            Err(_) => return self.runtime_error("Only functions accept named arguments"),
+        };
+        let arity = function.arity as usize;
+        let named = self.stack.pop_vec(names.len());
+        let mut positional = self.stack.pop_vec(arg_count as usize - names.len());
+        let extra = positional.split_off(positional.len().min(arity));
+
+        let mut args: Vec<Option<Value>> = positional.into_iter().map(Some).collect();
+        args.resize(arity, None);
+        for (name, value) in names.iter().zip(named) {
+            let name = name.as_string().unwrap();
+            match function.params.iter().position(|p| p == name) {
+                None => {
+                    return self.runtime_error(
+                        format!("{} has no parameter named '{}'", function.name, name).as_str(),
+                    )
+                }
+                Some(i) if args[i].is_some() => {
+                    return self.runtime_error(
+                        format!("Argument '{}' given more than once", name).as_str(),
+                    )
+                }
+                Some(i) => args[i] = Some(value),
+            }
+        }
+        if let Some(i) = args[..function.min_arity as usize]
+            .iter()
+            .position(|arg| arg.is_none())
+        {
+            return self.runtime_error(
+                format!("Missing argument for parameter '{}'", function.params[i]).as_str(),
+            );
+        }
+
+        let count = args.len() + extra.len();
+        self.stack.extend(
+            args.into_iter()
+                .map(|arg| arg.unwrap_or(Value::Nil))
+                .collect(),
+        );
+        self.stack.extend(extra);
+        Ok(count as u8)
+    }
+
     ///
+    /// Flattens the `groups` argument lists on top of the stack into plain arguments.
+    /// Returns the resulting argument count.
+    fn spread_arguments(&mut self, groups: u8) -> Result<u8> {
+        let mut args = Vec::new();
+        for list in self.stack.pop_vec(groups as usize) {
+            match list {
+                Value::List(items) => args.extend(items),
+                other => {
+                    return self
+                        .runtime_error(format!("Can only spread lists, found {}", other).as_str())
+                }
+            }
+        }
+        if args.len() > 255 {
+            return self.runtime_error("Can't have more than 255 arguments");
+        }
+        let count = args.len() as u8;
+        self.stack.extend(args);
+        Ok(count)
+    }
+
     ///
+    /// Runs until the script returns, resuming at the matching handler each time an exception
+    /// is raised.
     pub fn run(&mut self, parser: Parser) -> Result<Option<Value>> {
+        let mut ip = 0;
+        loop {
+            match self.execute(&parser, ip) {
+                Ok(result) => return Ok(result),
+                Err(err) => ip = self.throw(&parser, err)?,
+            }
+        }
+    }
+
+    ///
+    ///
+    fn execute(&mut self, parser: &Parser, ip: usize) -> Result<Option<Value>> {
         // let mut frame = &mut self.frames[self.frame_count - 1];
         let mut frame = self.frames.last_mut().unwrap();
         let mut frame_slot = frame.value_stack_pos;
+        let mut module = frame.function.module;
         // let frame = frames_opt.last().unwrap();
         let mut chunk = parser.chunk_at(frame.function.chunk_index); //unsafe { (*frame.function).chunk.clone() }; // unsafe { &(*frame.function).chunk };
                                                                      // for c in &chunk.op_codes
-        let mut op_code_iter = ChunkOpCodeReader::new(chunk.op_codes.as_slice(), 0);
+        let mut op_code_iter = ChunkOpCodeReader::new(chunk.op_codes.as_slice(), ip);
 
         let _counter = 0;
         // let mut op_code_iter = chunk.op_codes.iter();
         while let Some((_ip, c)) = op_code_iter.next() {
+            self.ip = _ip;
             //  let _a = c.clone();
 
             //write!(stdout(), "OP CODE {:?}\n", a);
@@ -366,101 +1163,51 @@ impl VM {
                     // println!("const val {}", const_val);
                 }
 
-                Opcode::OpNegate => {
-                    let r = self.pop_operand_as_number().map(|f| {
-                        self.stack.push(Value::Number(-f));
-                        Some(Value::Number(-f))
-                    });
-                    if r.is_err() {
-                        return r;
+                Opcode::OpNegate | Opcode::OpNot | Opcode::OpBitNot => {
+                    let a = self.stack.pop();
+                    match operators::unary_op(c, a, self.wrapping_arithmetic) {
+                        Ok(value) => self.stack.push(value),
+                        Err(msg) => return self.runtime_error(msg.as_str()),
                     }
                 }
 
-                Opcode::OpAdd => {
-                    // println!("ADD ---------------------------------------");
-                    // println!("{:?}", self.stack);
-
-                    let op1 = self.stack.peek(0);
-                    let op2 = self.stack.peek(1);
-
-                    if op1.is_number() && op2.is_number() {
-                        match self.unchecked_pop_operand_as_numbers() {
-                            Ok((a, b)) => self.stack.push(Value::Number(a + b)),
-                            Err(err) => {
-                                return Err(err);
-                            }
-                        }
-                    } else if op1.is_string() && op2.is_string() {
-                        match self.unchecked_pop_operand_as_strings() {
-                            Ok((a, b)) => self.stack.push(Value::String(format!("{}{}", a, b))),
-                            Err(err) => {
-                                return Err(err);
-                            }
+                Opcode::OpAdd
+                | Opcode::OPSubtract
+                | Opcode::OPMultiply
+                | Opcode::OpDivide
+                | Opcode::OpModulo
+                | Opcode::OpPower
+                | Opcode::OpIntDivide
+                | Opcode::OpIncrement
+                | Opcode::OpBitAnd
+                | Opcode::OpBitOr
+                | Opcode::OpBitXor
+                | Opcode::OpShiftLeft
+                | Opcode::OpShiftRight
+                | Opcode::OpEqual
+                | Opcode::OpGreater
+                | Opcode::OpLess
+                | Opcode::OpGreaterEqual
+                | Opcode::OpLessEqual => {
+                    if !self.stack.number_op(c, self.wrapping_arithmetic) {
+                        let b = self.stack.pop();
+                        let a = self.stack.pop();
+                        match operators::binary_op(c, a, b, self.wrapping_arithmetic) {
+                            Ok(value) => self.stack.push(value),
+                            Err(msg) => return self.runtime_error(msg.as_str()),
                         }
-                    } else if op1.is_string() && op2.is_number() {
-                        match self.unchecked_pop_operand_as_string_and_number() {
-                            Ok((a, b)) => self.stack.push(Value::String(format!("{}{}", a, b))),
-                            Err(err) => {
-                                return Err(err);
-                            }
-                        }
-                    } else if op1.is_number() && op2.is_string() {
-                        match self.unchecked_pop_operand_as_number_and_string() {
-                            Ok((a, b)) => self.stack.push(Value::String(format!("{}{}", a, b))),
-                            Err(err) => {
-                                return Err(err);
-                            }
-                        }
-                    } else {
-                        return self.wrong_type_error(
-                            format!("Addition operation requires operands must be of same type, either number or string. Found operand #1 = {}, operand #2 = {}", op1, op2).as_str());
                     }
                 }
 
-                Opcode::OPSubtract => match self.pop_operand_as_numbers() {
-                    Ok((a, b)) => self.stack.push(Value::Number(a - b)),
-                    Err(result) => return Err(result),
-                },
-
-                Opcode::OPMultiply => match self.pop_operand_as_numbers() {
-                    Ok((a, b)) => self.stack.push(Value::Number(a * b)),
-                    Err(result) => return Err(result),
-                },
-
-                Opcode::OpDivide => match self.pop_operand_as_numbers() {
-                    Ok((a, b)) => self.stack.push(Value::Number(a / b)),
-                    Err(result) => return Err(result),
-                },
                 Opcode::OpFalse => self.stack.push(Value::Boolean(false)),
                 Opcode::OpNil => self.stack.push(Value::Nil),
                 Opcode::OpTrue => self.stack.push(Value::Boolean(true)),
 
-                Opcode::OpNot => match self.pop_operand_as_bool() {
-                    Ok(bool_operand) => self.stack.push(Value::Boolean(!bool_operand)),
-                    Err(result) => return Err(result),
-                },
-
-                Opcode::OpEqual => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    self.stack.push(Value::Boolean(a == b))
-                }
-
-                Opcode::OpGreater => match self.pop_operand_as_numbers() {
-                    Ok((a, b)) => self.stack.push(Value::Boolean(a > b)),
-                    Err(result) => return Err(result),
-                },
-
-                Opcode::OpLess => match self.pop_operand_as_numbers() {
-                    Ok((a, b)) => self.stack.push(Value::Boolean(a < b)),
-                    Err(result) => return Err(result),
-                },
-
                 Opcode::OpPrint => {
                     println!("{}", self.stack.pop());
                 }
 
-                Opcode::OpDefineGlobal(index) => {
+                Opcode::OpDefineGlobal(index) | Opcode::OpDefineGlobalConst(index) => {
                     let name = chunk
                         .read_constant(*index)
                         .unwrap()
@@ -468,9 +1215,15 @@ impl VM {
                         .ok()
                         .unwrap();
 
-                    self.globals
-                        .insert(name.to_string(), self.stack.peek(0).clone());
-                    self.stack.pop();
+                    if self.constants(module).contains(name) {
+                        return self
+                            .runtime_error(format!("Can't redefine constant {}", name).as_str());
+                    }
+                    if let Opcode::OpDefineGlobalConst(_) = c {
+                        self.constants(module).insert(name.to_string());
+                    }
+                    let value = self.stack.pop();
+                    self.namespace(module).insert(name.to_string(), value);
                 }
 
                 Opcode::OpGetGlobal(index) => {
@@ -481,7 +1234,7 @@ impl VM {
                         .ok()
                         .unwrap();
 
-                    match self.globals.get(name) {
+                    match self.global(module, name) {
                         Some(value) => {
                             self.stack.push(value.clone());
                         }
@@ -500,12 +1253,18 @@ impl VM {
                         .ok()
                         .unwrap();
 
-                    if !self.globals.contains_key(name) {
+                    if !self.namespace(module).contains_key(name) {
                         return self.runtime_error(format!("Undefined variable {}", name).as_str());
                         // return Err(LoxRuntimeError::new().into());
+                    } else if self.constants(module).contains(name) {
+                        return self
+                            .runtime_error(format!("Can't assign to constant {}", name).as_str());
                     } else {
-                        let v = self.stack.peek(0).clone();
-                        self.globals.insert(name.to_string(), v);
+                        let v = self.stack.peek(0).into_owned();
+                        // assigned in place, without allocating the name again
+                        if let Some(slot) = self.namespace(module).get_mut(name) {
+                            *slot = v;
+                        }
                     }
                 }
 
@@ -516,19 +1275,62 @@ impl VM {
                     //     self.stack.len()
                     // );
 
-                    let v = self.stack.get(*index + frame_slot).clone();
-                    // self.stack.push(v);
-                    self.stack.push(v);
+                    self.stack.push_copy(*index + frame_slot);
                 }
                 Opcode::OpSetLocal(index) => {
-                    self.stack
-                        .replace(*index + frame_slot, self.stack.peek(0).clone());
+                    self.stack.copy_top(*index + frame_slot);
+                }
+                Opcode::OpAddLocalConst(index, constant)
+                | Opcode::OpIncrementLocal(index, constant) => {
+                    let op = match c {
+                        Opcode::OpIncrementLocal(_, _) => &Opcode::OpIncrement,
+                        _ => &Opcode::OpAdd,
+                    };
+                    let slot = *index + frame_slot;
+                    let a = self.stack.get(slot);
+                    let b = chunk.read_constant(*constant).unwrap();
+                    let sum = match operators::number_op(op, &a, b, self.wrapping_arithmetic) {
+                        Some(sum) => Ok(sum),
+                        None => operators::binary_op(
+                            op,
+                            a.into_owned(),
+                            b.clone(),
+                            self.wrapping_arithmetic,
+                        ),
+                    };
+                    match sum {
+                        Ok(value) => self.stack.replace(slot, value),
+                        Err(msg) => return self.runtime_error(msg.as_str()),
+                    }
+                }
+                Opcode::OpJumpIfNotLessLocals(a, b, jump) => {
+                    let (a, b) = (*a + frame_slot, *b + frame_slot);
+                    if !self
+                        .stack
+                        .number_op_at(&Opcode::OpLess, a, b, self.wrapping_arithmetic)
+                    {
+                        match operators::binary_op(
+                            &Opcode::OpLess,
+                            self.stack.get(a).into_owned(),
+                            self.stack.get(b).into_owned(),
+                            self.wrapping_arithmetic,
+                        ) {
+                            Ok(less) => self.stack.push(less),
+                            Err(msg) => return self.runtime_error(msg.as_str()),
+                        }
+                    }
+                    if VM::is_falsey(&self.stack.peek(0)) {
+                        op_code_iter.jump(*jump);
+                    }
                 }
                 Opcode::OpPop => {
                     self.stack.pop();
                 }
+                Opcode::OpDup => {
+                    self.stack.push_copy(self.stack.len() - 1);
+                }
                 Opcode::OpJumpIfFalse(jump) => {
-                    if VM::is_falsey(self.stack.peek(0)) {
+                    if VM::is_falsey(&self.stack.peek(0)) {
                         op_code_iter.jump(*jump);
                     }
                 }
@@ -536,28 +1338,252 @@ impl VM {
                     op_code_iter.jump(*jump);
                 }
 
+                Opcode::OpJumpTable(index) => {
+                    let subject = self.stack.pop();
+                    op_code_iter.jump(chunk.jump_table(*index).target(&subject));
+                }
+
+                Opcode::OpInRange(inclusive) => {
+                    let upper = self.stack.pop();
+                    let lower = self.stack.pop();
+                    let value = self.stack.pop();
+                    let in_range = match (value, lower, upper) {
+                        (Value::Int(v), Value::Int(lo), Value::Int(hi)) => {
+                            lo <= v && (v < hi || (*inclusive && v <= hi))
+                        }
+                        (v, lo, hi) => match (v.to_f64(), lo.to_f64(), hi.to_f64()) {
+                            (Some(v), Some(lo), Some(hi)) => {
+                                lo <= v && (v < hi || (*inclusive && v <= hi))
+                            }
+                            _ => false,
+                        },
+                    };
+                    self.stack.push(Value::Boolean(in_range));
+                }
+
+                Opcode::OpForIter(jump) => {
+                    let exhausted_ip = _ip + *jump as usize;
+                    // the list stays borrowed on the stack, only the item is cloned
+                    let next = match (&*self.stack.peek(1), &*self.stack.peek(0)) {
+                        (Value::List(items), Value::Number(index)) => {
+                            Ok(items.get(*index as usize).cloned())
+                        }
+                        (Value::Generator(generator), _) => Err(Some(generator.clone())),
+                        _ => Err(None),
+                    };
+                    let resumed = match next {
+                        Ok(Some(item)) => {
+                            if let Value::Number(index) = *self.stack.peek(0) {
+                                let top = self.stack.len() - 1;
+                                self.stack.replace(top, Value::Number(index + 1.0));
+                            }
+                            self.stack.push(item);
+                            None
+                        }
+                        Ok(None) => {
+                            op_code_iter.jump(*jump);
+                            None
+                        }
+                        Err(Some(generator)) => {
+                            let resumed = self.resume(generator, _ip, Some(exhausted_ip))?;
+                            if resumed.is_none() {
+                                op_code_iter.jump(*jump);
+                            }
+                            resumed
+                        }
+                        Err(None) => {
+                            return self.runtime_error(
+                                format!(
+                                    "Can only iterate over lists and generators, found {}",
+                                    self.stack.peek(1)
+                                )
+                                .as_str(),
+                            )
+                        }
+                    };
+                    if let Some(ip) = resumed {
+                        frame = self.frames.last_mut().unwrap();
+                        chunk = parser.chunk_at(frame.function.chunk_index);
+                        frame_slot = frame.value_stack_pos;
+                        module = frame.function.module;
+                        op_code_iter = ChunkOpCodeReader::new(chunk.op_codes.as_slice(), ip);
+                    }
+                }
+
+                Opcode::OpYield => {
+                    let value = self.stack.pop();
+                    let return_address_pos = self.suspend(_ip);
+                    self.stack.push(value);
+                    frame = self.frames.last_mut().unwrap();
+                    chunk = parser.chunk_at(frame.function.chunk_index);
+                    frame_slot = frame.value_stack_pos;
+                    module = frame.function.module;
+                    op_code_iter =
+                        ChunkOpCodeReader::new(chunk.op_codes.as_slice(), return_address_pos);
+                }
+
                 Opcode::OpLoop(offset) => {
                     op_code_iter.prev(*offset);
+                    #[cfg(feature = "jit")]
+                    if let Some(ip) = self.loop_compiled(parser, _ip - *offset as usize) {
+                        op_code_iter = ChunkOpCodeReader::new(chunk.op_codes.as_slice(), ip);
+                    }
                 }
 
-                Opcode::OpCall(arity) => {
-                    // println!("OPCALL {}", _ip);
-                    // let mut v = self.stack.peek_mut((*num_args) as usize);
+                Opcode::OpTry(catch, finally) => {
+                    let handler = Handler {
+                        catch_ip: (*catch > 0).then(|| _ip + *catch as usize),
+                        finally_ip: (*finally > 0).then(|| _ip + *finally as usize),
+                        stack_len: self.stack.len(),
+                    };
+                    self.frames.last_mut().unwrap().handlers.push(handler);
+                }
+
+                Opcode::OpPopHandler => {
+                    self.frames.last_mut().unwrap().handlers.pop();
+                }
 
-                    match self.call_value(arity, _ip).unwrap() {
-                        Standard(success) => {
-                            if success {
-                                frame = self.frames.last_mut().unwrap();
-                                chunk = parser.chunk_at(frame.function.chunk_index);
-                                frame_slot = frame.value_stack_pos; // for c in &chunk.op_codes
-                                op_code_iter = ChunkOpCodeReader::new(chunk.op_codes.as_slice(), 0);
-                            } else {
-                                return self.runtime_error("Could not call function");
+                Opcode::OpThrow => {
+                    let value = self.stack.pop();
+                    return Err(LoxRuntimeError::thrown(value))?;
+                }
+
+                Opcode::OpImport(index) => {
+                    if let Some(object) = &self.modules[*index].object {
+                        self.stack.push(object.clone());
+                    } else {
+                        let function = Arc::new(
+                            parser
+                                .module_at(*index)
+                                .function
+                                .clone()
+                                .expect("imported module was compiled"),
+                        );
+                        self.stack.push(Value::Function(function.clone()));
+                        self.call(&function, &0, _ip)?;
+                        frame = self.frames.last_mut().unwrap();
+                        chunk = parser.chunk_at(frame.function.chunk_index);
+                        frame_slot = frame.value_stack_pos;
+                        module = frame.function.module;
+                        op_code_iter = ChunkOpCodeReader::new(chunk.op_codes.as_slice(), 0);
+                    }
+                }
+
+                Opcode::OpExportModule => {
+                    let object =
+                        Value::Module(ObjectModule::new(parser.module_at(module).name(), module));
+                    self.modules[module].object = Some(object.clone());
+                    self.stack.push(object);
+                }
+
+                Opcode::OpGetProperty(index) => {
+                    let name = chunk.read_constant(*index).unwrap().as_string().unwrap();
+                    match self.stack.pop() {
+                        // a live read, `from ... import` copies the value into a global instead
+                        Value::Module(object) => {
+                            match self.namespace(object.index).get(name).cloned() {
+                                Some(value) => self.stack.push(value),
+                                None => {
+                                    return self.runtime_error(
+                                        format!("Module {} has no export '{}'", object.name, name)
+                                            .as_str(),
+                                    )
+                                }
                             }
                         }
-                        Native => {
-                            // return Err(err);
+                        // `generator.next()` resumes the generator by calling it
+                        Value::Generator(generator) if name == "next" => {
+                            self.stack.push(Value::Generator(generator))
+                        }
+                        // `fiber.resume(value)` resumes the fiber by calling it
+                        Value::Fiber(fiber) if name == "resume" => {
+                            self.stack.push(Value::Fiber(fiber))
+                        }
+                        Value::Fiber(fiber) if name == "done" => {
+                            let done = fiber.lock().state == FiberState::Done;
+                            self.stack.push(Value::Boolean(done))
+                        }
+                        Value::Fiber(fiber) => {
+                            return self.runtime_error(
+                                format!("{} has no property '{}'", fiber, name).as_str(),
+                            )
+                        }
+                        Value::Generator(generator) => {
+                            return self.runtime_error(
+                                format!("{} has no property '{}'", generator, name).as_str(),
+                            )
+                        }
+                        Value::Exception(exception) if name == "message" => {
+                            self.stack.push(Value::String(exception.message))
+                        }
+                        // the calls active where the error was raised, innermost first
+                        Value::Exception(exception) if name == "trace" => self.stack.push(
+                            Value::List(exception.trace.into_iter().map(Value::String).collect()),
+                        ),
+                        Value::Exception(exception) => {
+                            return self.runtime_error(
+                                format!("{} has no property '{}'", exception, name).as_str(),
+                            )
+                        }
+                        other => {
+                            return self
+                                .runtime_error(format!("{} has no properties", other).as_str())
+                        }
+                    }
+                }
+
+                Opcode::OpBuildList(count) => {
+                    let items = self.stack.pop_vec(*count as usize);
+                    self.stack.push(Value::List(items));
+                }
+
+                Opcode::OpCall(_)
+                | Opcode::OpCallNamed(_, _)
+                | Opcode::OpCallSpread(_)
+                | Opcode::OpTailCall(_) => {
+                    // println!("OPCALL {}", _ip);
+                    // let mut v = self.stack.peek_mut((*num_args) as usize);
+                    let arity = match c {
+                        Opcode::OpCallNamed(arg_count, names) => {
+                            let names = chunk.read_constant(*names).unwrap().as_list().unwrap();
+                            self.bind_named_arguments(*arg_count, names)?
+                        }
+                        Opcode::OpCallSpread(groups) => self.spread_arguments(*groups)?,
+                        Opcode::OpCall(arg_count) | Opcode::OpTailCall(arg_count) => *arg_count,
+                        _ => unreachable!(),
+                    };
+
+                    let tail_call = matches!(c, Opcode::OpTailCall(_));
+                    let start_ip = if tail_call && self.tail_call(arity, _ip)? {
+                        Some(0)
+                    } else {
+                        let response = match self.call_compiled(parser, arity, _ip)? {
+                            Some(response) => response,
+                            None if self.call_registers(parser, arity) => Native,
+                            None => self.call_value(&arity, _ip)?,
+                        };
+                        match response {
+                            Standard(success) => {
+                                if success {
+                                    Some(0)
+                                } else {
+                                    // generator functions return the new generator without running
+                                    None
+                                }
+                            }
+                            Resume(ip) => Some(ip),
+                            Native => {
+                                // return Err(err);
+                                None
+                            }
                         }
+                    };
+                    if let Some(ip) = start_ip {
+                        frame = self.frames.last_mut().unwrap();
+                        chunk = parser.chunk_at(frame.function.chunk_index);
+                        frame_slot = frame.value_stack_pos; // for c in &chunk.op_codes
+                        module = frame.function.module;
+                        op_code_iter = ChunkOpCodeReader::new(chunk.op_codes.as_slice(), ip);
                     }
                 }
 
@@ -569,27 +1595,42 @@ impl VM {
                 //         InterpretResult::Ok(None)
                 //     }
                 // }
-                Opcode::OpReturn => {
+                Opcode::OpReturn | Opcode::OpEndFinally => {
                     // println!("RETURN ---------------------------------------");
                     // println!("{:?}", self.stack);
 
-                    let _result: Value = self.stack.pop();
-                    let last_frame = self.frames.pop().expect("no frame");
-
-                    self.stack.truncate(last_frame.value_stack_pos);
-
-                    if self.frames.is_empty() {
-                        // let _ = self.stack.pop();
-                        return Ok(Some(_result));
+                    let _result: Value = if *c == Opcode::OpEndFinally {
+                        let kind = self.stack.pop();
+                        let value = self.stack.pop();
+                        match kind {
+                            Number(kind) if kind == COMPLETION_THROW => {
+                                let site = self.rethrow_site();
+                                return Err(LoxRuntimeError::rethrown(value, site))?;
+                            }
+                            Number(kind) if kind == COMPLETION_RETURN => value,
+                            _ => continue,
+                        }
+                    } else {
+                        self.stack.pop()
+                    };
+
+                    match self.return_value(_result)? {
+                        Finished(result) => return Ok(Some(result)),
+                        Finally(finally_ip) => {
+                            op_code_iter =
+                                ChunkOpCodeReader::new(chunk.op_codes.as_slice(), finally_ip);
+                        }
+                        Caller(return_address_pos) => {
+                            frame = self.frames.last_mut().unwrap();
+                            chunk = parser.chunk_at(frame.function.chunk_index);
+                            frame_slot = frame.value_stack_pos;
+                            module = frame.function.module;
+                            op_code_iter = ChunkOpCodeReader::new(
+                                chunk.op_codes.as_slice(),
+                                return_address_pos,
+                            );
+                        }
                     }
-                    self.stack.push(_result);
-                    frame = self.frames.last_mut().unwrap();
-                    chunk = parser.chunk_at(frame.function.chunk_index);
-                    frame_slot = frame.value_stack_pos;
-                    op_code_iter = ChunkOpCodeReader::new(
-                        chunk.op_codes.as_slice(),
-                        last_frame.return_address_pos,
-                    );
                 }
             }
         }
@@ -597,6 +1638,215 @@ impl VM {
         return Err(LoxRuntimeError::new("end program"))?;
     }
 
+    ///
+    /// The globals of the main script (module 0) or of an imported module.
+    fn namespace(&mut self, module: usize) -> &mut HashMap<String, Value> {
+        if module == 0 {
+            &mut self.globals
+        } else {
+            &mut self.modules[module].globals
+        }
+    }
+
+    ///
+    /// The names declared with `const` in the main script or in an imported module.
+    fn constants(&mut self, module: usize) -> &mut HashSet<String> {
+        if module == 0 {
+            &mut self.constants
+        } else {
+            &mut self.modules[module].constants
+        }
+    }
+
+    ///
+    ///
+    fn global(&self, module: usize, name: &str) -> Option<&Value> {
+        let namespace = if module == 0 {
+            &self.globals
+        } else {
+            &self.modules[module].globals
+        };
+        namespace.get(name).or_else(|| self.natives.get(name))
+    }
+
+    ///
+    /// Pops the current frame, unless a finally block of one of its `try` statements has to
+    /// run first with the result as its pending completion. A fiber whose function returns
+    /// hands the result to the fiber that resumed it.
+    fn return_value(&mut self, result: Value) -> Result<ReturnResponse> {
+        let frame = self.frames.last_mut().expect("no frame");
+        while let Some(handler) = frame.handlers.pop() {
+            if let Some(finally_ip) = handler.finally_ip {
+                self.stack.truncate(handler.stack_len);
+                self.stack.push(result);
+                self.stack.push(Value::Number(COMPLETION_RETURN));
+                return Ok(Finally(finally_ip));
+            }
+        }
+
+        let last_frame = self.frames.pop().expect("no frame");
+        self.stack.truncate(last_frame.value_stack_pos);
+        if let Some(generator) = &last_frame.generator {
+            generator.lock().state = GeneratorState::Done;
+            if let Some(exhausted_address_pos) = last_frame.exhausted_address_pos {
+                return Ok(Caller(exhausted_address_pos));
+            }
+        }
+        if self.frames.is_empty() {
+            let (caller, scheduled) = {
+                let mut fiber = self.fiber.lock();
+                fiber.state = FiberState::Done;
+                (fiber.caller.take(), fiber.scheduled)
+            };
+            return match caller {
+                None => Ok(Finished(result)),
+                Some(caller) if scheduled => Ok(Caller(self.run_next(caller, 0)?)),
+                Some(caller) => {
+                    let ip = self.switch_fiber(caller, 0);
+                    self.stack.push(result);
+                    Ok(Caller(ip))
+                }
+            };
+        }
+        self.stack.push(result);
+        Ok(Caller(last_frame.return_address_pos))
+    }
+
+    ///
+    /// Unwinds `VM::frames` and `VM::stack` to the innermost handler and returns the position
+    /// to resume at. Errors raised by the VM itself are caught as exception objects.
+    /// Uncaught exceptions are reported at the instruction that raised them.
+    fn throw(&mut self, parser: &Parser, err: Report) -> Result<usize> {
+        let (message, thrown, site) = match err.downcast::<LoxRuntimeError>() {
+            Ok(err) => (err.message, err.thrown, err.site),
+            Err(err) => (err.to_string(), None, None),
+        };
+        let site = site.unwrap_or_else(|| self.throw_site(parser));
+        let exception = thrown
+            .unwrap_or_else(|| Value::Exception(ObjectException::new(message, site.trace.clone())));
+
+        // a fiber without a handler passes the exception on to the fiber that resumed it
+        let mut caller = Some(self.fiber.clone());
+        while let Some(fiber) = caller {
+            self.ip = self.switch_fiber(fiber, self.ip);
+            if let Some(catch_ip) = self.unwind(exception.clone(), &site) {
+                return Ok(catch_ip);
+            }
+            let mut fiber = self.fiber.lock();
+            caller = fiber.caller.take();
+            if caller.is_some() {
+                fiber.state = FiberState::Done;
+            }
+        }
+
+        self.stack.truncate(0);
+        let message = match exception {
+            Value::Exception(exception) => exception.to_string(),
+            value => format!("exception: {}", value),
+        };
+        Err(LoxUncaughtError {
+            src: NamedSource::new(
+                parser.module_at(site.module).source_name(),
+                parser.module_at(site.module).source.clone(),
+            ),
+            bad_bit: site.span.into(),
+            message,
+            trace: site.trace.join("\n"),
+        })?
+    }
+
+    ///
+    /// The instruction that just raised an exception.
+    fn throw_site(&self, parser: &Parser) -> ThrowSite {
+        let (span, module) = match self.frames.last() {
+            Some(frame) => (
+                parser
+                    .chunk_at(frame.function.chunk_index)
+                    .span_at(self.ip.saturating_sub(1)),
+                frame.function.module,
+            ),
+            None => ((0, 0), 0),
+        };
+        ThrowSite {
+            span,
+            module,
+            trace: self.stack_trace(parser),
+        }
+    }
+
+    ///
+    /// Where the exception that the finally block ending here rethrows was raised, which is
+    /// pending at the top of the stack. Sites left behind by finally blocks that were exited
+    /// some other way are dropped on the way.
+    fn rethrow_site(&mut self) -> Option<ThrowSite> {
+        let frame = self.frames.last_mut()?;
+        let position = self.stack.len() - frame.value_stack_pos;
+        while let Some((pending, site)) = frame.rethrows.pop() {
+            if pending <= position {
+                return (pending == position).then_some(site);
+            }
+        }
+        None
+    }
+
+    ///
+    /// Unwinds the running fiber to its innermost handler, which receives `exception` raised at
+    /// `site`. Returns the position of the handler's catch or finally code, or None if the fiber
+    /// has no handler left.
+    fn unwind(&mut self, exception: Value, site: &ThrowSite) -> Option<usize> {
+        while let Some(frame) = self.frames.last_mut() {
+            if let Some(handler) = frame.handlers.pop() {
+                self.stack.truncate(handler.stack_len);
+                self.stack.push(exception);
+                if let Some(catch_ip) = handler.catch_ip {
+                    // the finally block still runs if the catch block throws
+                    if handler.finally_ip.is_some() {
+                        frame.handlers.push(Handler {
+                            catch_ip: None,
+                            ..handler
+                        });
+                    }
+                    return Some(catch_ip);
+                }
+                self.stack.push(Value::Number(COMPLETION_THROW));
+                frame
+                    .rethrows
+                    .push((handler.stack_len - frame.value_stack_pos, site.clone()));
+                return handler.finally_ip;
+            }
+            if let Some(generator) = self.frames.pop().and_then(|frame| frame.generator) {
+                generator.lock().state = GeneratorState::Done;
+            }
+        }
+        None
+    }
+
+    ///
+    /// One line per active call, innermost first, followed by the calls a frame replaced through
+    /// tail calls.
+    fn stack_trace(&self, parser: &Parser) -> Vec<String> {
+        let mut ip = self.ip;
+        self.frames
+            .iter()
+            .rev()
+            .flat_map(|frame| {
+                let line = parser
+                    .chunk_at(frame.function.chunk_index)
+                    .line_at(ip.saturating_sub(1));
+                ip = frame.return_address_pos;
+                let tail_calls = frame.tail_calls.iter().rev().map(|(function, ip)| {
+                    let line = parser.chunk_at(function.chunk_index).line_at(ip - 1);
+                    format!("at {} (line {})", function.name, line + 1)
+                });
+                let elided = (frame.elided > 0)
+                    .then(|| format!("... {} earlier tail calls elided", frame.elided));
+                std::iter::once(format!("at {} (line {})", frame.function.name, line + 1))
+                    .chain(tail_calls)
+                    .chain(elided)
+            })
+            .collect()
+    }
+
     pub fn get_frame_slot(self) -> usize {
         self.frames.last().unwrap().value_stack_pos
     }
@@ -0,0 +1,87 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::assert_compile_error;
+    use rox::ast::{self, ArmBody, ExprKind, FunctionBody, Pattern, StmtKind, Target};
+    use rox::value::Value;
+    use rox::vm::VM;
+
+    ///
+    ///
+    ///
+    #[test]
+    fn functions_parse_into_declarations() {
+        let program = ast::parse("fun add(a, b = 1, ...rest) { return a + b; }").expect("parses");
+        let (name, function) = match &program.statements[..] {
+            [statement] => match &statement.kind {
+                StmtKind::Function { name, function } => (name, function),
+                kind => panic!("not a function declaration: {:?}", kind),
+            },
+            statements => panic!("expected one statement: {:?}", statements),
+        };
+        assert_eq!(name.name, "add");
+        // names are only bound to slots by the resolver
+        assert_eq!(name.target, Target::Global);
+        let params: Vec<_> = function
+            .params
+            .iter()
+            .map(|p| p.name.name.as_str())
+            .collect();
+        assert_eq!(params, ["a", "b"]);
+        assert!(function.params[0].default.is_none() && function.params[1].default.is_some());
+        assert_eq!(
+            function.rest.as_ref().map(|r| r.name.as_str()),
+            Some("rest")
+        );
+        assert!(matches!(&function.body, FunctionBody::Block(body) if body.len() == 1));
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn match_arms_keep_their_patterns() {
+        let program =
+            ast::parse("var x = match (2) { 1 | 2 => \"low\", 3..=9 => \"high\", _ => nil };")
+                .expect("parses");
+        let arms = match &program.statements[0].kind {
+            StmtKind::Var {
+                initializer: Some(initializer),
+                ..
+            } => match &initializer.kind {
+                ExprKind::Match(arms) => arms,
+                kind => panic!("not a match: {:?}", kind),
+            },
+            kind => panic!("not a var declaration: {:?}", kind),
+        };
+        let patterns: Vec<_> = arms.arms.iter().map(|arm| arm.patterns.clone()).collect();
+        assert_eq!(
+            patterns,
+            vec![
+                vec![
+                    Pattern::Literal(Value::Int(1)),
+                    Pattern::Literal(Value::Int(2))
+                ],
+                vec![Pattern::Range(Value::Int(3), Value::Int(9), true)],
+                vec![Pattern::Wildcard],
+            ]
+        );
+        assert!(arms
+            .arms
+            .iter()
+            .all(|arm| matches!(arm.body, ArmBody::Expression(_))));
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn resolution_errors_are_compile_errors() {
+        let mut vm = VM::new();
+        // parses fine, the misuse is found once names are resolved
+        assert!(ast::parse("{ var a = a; }").is_ok());
+        assert_compile_error(&mut vm, "{ var a = a; }").unwrap();
+        assert_compile_error(&mut vm, "const c = 1; c += 2;").unwrap();
+    }
+}
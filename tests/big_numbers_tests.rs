@@ -0,0 +1,155 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{assert_ok_equals, assert_runtime_error};
+    use miette::Result;
+    use rox::value::Value;
+    use rox::vm::VM;
+
+    fn assert_prints(vm: &mut VM, s: &str, expected: &str) -> Result<()> {
+        match vm.interpret(s)? {
+            Some(value) => {
+                assert_eq!(value.to_string(), expected);
+                Ok(())
+            }
+            None => panic!("Test did not return"),
+        }
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn big_integers() -> Result<()> {
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.interpret("return 123n;")?,
+            Some(Value::BigInt(_))
+        ));
+        assert_prints(
+            &mut vm,
+            "return 9223372036854775807n + 1;",
+            "9223372036854775808",
+        )?;
+        assert_prints(
+            &mut vm,
+            "return 2n ** 100;",
+            "1267650600228229401496703205376",
+        )?;
+        assert_prints(
+            &mut vm,
+            "var f = 1n; for (var i = 1; i <= 25; i++) f = f * i; return f;",
+            "15511210043330985984000000",
+        )?;
+        assert_prints(&mut vm, "return -7n ~/ 2;", "-4")?;
+        assert_prints(&mut vm, "return -7n % 2;", "-1")?;
+        assert_ok_equals(&mut vm, "return 5n == 5;", Value::Boolean(true))?;
+        assert_ok_equals(&mut vm, "return 2n ** 64 > 2 ** 62;", Value::Boolean(true))?;
+        assert_runtime_error(&mut vm, "return 1n ~/ 0;").unwrap();
+        assert_runtime_error(&mut vm, "return 10n ** -1;").unwrap();
+        assert_runtime_error(&mut vm, "return 2n ** 100000000000;").unwrap();
+        assert!(vm.interpret("return 1.5n;").is_err());
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn big_integer_division_is_exact() -> Result<()> {
+        let mut vm = VM::new();
+        assert_prints(&mut vm, "return 10n ** 30 / 10n ** 28;", "100")?;
+        assert!(matches!(
+            vm.interpret("return 10n ** 30 / 10n ** 28;")?,
+            Some(Value::BigInt(_))
+        ));
+        assert_prints(&mut vm, "return -12n / 4;", "-3")?;
+        assert_runtime_error(&mut vm, "return 3n / 2;").unwrap();
+        assert_runtime_error(&mut vm, "return -7n / 4;").unwrap();
+        assert_runtime_error(&mut vm, "return 10n / 3n;").unwrap();
+        assert_prints(&mut vm, "return decimal(3n) / 2;", "1.50")?;
+        assert_runtime_error(&mut vm, "return 10n / 0n;").unwrap();
+        assert_runtime_error(&mut vm, "return 10n / 0;").unwrap();
+        // the quotient is exact or an error, never a float
+        let err = vm
+            .interpret("return (10n ** 40 + 1) / 3;")
+            .expect_err("Expected the quotient not to be exact");
+        assert!(err.to_string().contains("use ~/"));
+        assert_prints(
+            &mut vm,
+            "return (10n ** 40 + 1) ~/ 3;",
+            &format!("3{}", "3".repeat(39)),
+        )?;
+
+        assert_runtime_error(&mut vm, "return 1n + 0.5;").unwrap();
+        assert_runtime_error(&mut vm, "return 123n * 1.5;").unwrap();
+        assert_prints(&mut vm, "return float(123n) + 1.5;", "124.5")?;
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn decimals() -> Result<()> {
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.interpret("return 1.10d;")?,
+            Some(Value::Decimal(_))
+        ));
+        assert_prints(&mut vm, "return 1.10d;", "1.10")?;
+        assert_prints(&mut vm, "return 0.1d + 0.2d;", "0.3")?;
+        assert_ok_equals(&mut vm, "return 0.1d + 0.2d == 0.3d;", Value::Boolean(true))?;
+        assert_prints(&mut vm, "return 19.99d * 3;", "59.97")?;
+        assert_ok_equals(&mut vm, "return 10d / 4 == 2.5d;", Value::Boolean(true))?;
+        assert_prints(&mut vm, "return -1.5d;", "-1.5")?;
+        assert_prints(&mut vm, "return 1.05d ** 2;", "1.1025")?;
+        assert_ok_equals(&mut vm, "return 2.50d > 2.5d;", Value::Boolean(false))?;
+        assert_ok_equals(&mut vm, "return 2.00d == 2;", Value::Boolean(true))?;
+        assert_runtime_error(&mut vm, "return 1.5d + 0.5;").unwrap();
+        assert_runtime_error(&mut vm, "return 1d / 0;").unwrap();
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn conversions_rounding_and_formatting() -> Result<()> {
+        let mut vm = VM::new();
+        assert_prints(&mut vm, "return decimal(0.1) + decimal(\"0.2\");", "0.3")?;
+        assert_prints(
+            &mut vm,
+            "return bigint(\"123456789012345678901234567890\");",
+            "123456789012345678901234567890",
+        )?;
+        assert_prints(&mut vm, "return bigint(12.7);", "12")?;
+        assert_ok_equals(&mut vm, "return int(12n) + 1;", Value::Int(13))?;
+        assert_ok_equals(&mut vm, "return float(1.25d);", Value::Number(1.25))?;
+
+        assert_prints(&mut vm, "return round(2.345d, 2);", "2.34")?;
+        assert_prints(&mut vm, "return round(2.345d, 2, \"half_up\");", "2.35")?;
+        assert_prints(&mut vm, "return round(-2.341d, 2, \"floor\");", "-2.35")?;
+        assert_prints(&mut vm, "return round(2.5d);", "2")?;
+        assert_ok_equals(
+            &mut vm,
+            "return round(2.675, 2, \"half_up\");",
+            Value::Number(2.68),
+        )?;
+
+        assert_prints(&mut vm, "return format(1.5d, 2);", "1.50")?;
+        assert_prints(&mut vm, "return format(2.345, 2, \"half_up\");", "2.35")?;
+        assert_prints(&mut vm, "return format(7, 1);", "7.0")?;
+        assert_prints(
+            &mut vm,
+            "return format(10n ** 30, 1);",
+            "1000000000000000000000000000000.0",
+        )?;
+
+        assert_runtime_error(&mut vm, "return round(1.5d, 1, \"sideways\");").unwrap();
+        assert_runtime_error(&mut vm, "return round(1.5d, -1);").unwrap();
+        assert_runtime_error(&mut vm, "return decimal(\"abc\");").unwrap();
+        Ok(())
+    }
+}
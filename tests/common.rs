@@ -15,7 +15,7 @@ pub fn assert_ok(vm: &mut VM, s: &'static str) -> Result<()> {
 
     // vm.interpret(s)
     // vm.interpret(s)?;
-    if let Err(err) = vm.interpret(s) {
+    if let Err(err) = interpret(vm, s) {
         let mut out = String::new();
         GraphicalReportHandler::new_themed(GraphicalTheme::ascii())
             .with_width(120)
@@ -41,7 +41,7 @@ pub fn assert_ok_return_value(vm: &mut VM, s: &str, expected_value: Value) -> Re
 ///
 ///
 pub fn assert_ok_equals(vm: &mut VM, s: &str, expected_value: Value) -> Result<()> {
-    match vm.interpret(s)? {
+    match interpret(vm, s)? {
         None => panic!("Test did not return"),
         Some(v) => {
             assert_eq!(expected_value, v);
@@ -51,15 +51,47 @@ pub fn assert_ok_equals(vm: &mut VM, s: &str, expected_value: Value) -> Result<(
 }
 
 pub fn assert_runtime_error(vm: &mut VM, s: &'static str) -> Result<(), &'static str> {
-    match vm.interpret(s) {
+    match interpret(vm, s) {
         Ok(_) => Err("Expected a runtime Error"),
         Err(_) => Ok(()),
     }
 }
 
 pub fn assert_compile_error(vm: &mut VM, s: &'static str) -> Result<(), &'static str> {
-    match vm.interpret(s) {
+    match interpret(vm, s) {
         Ok(_) => Err("Expected a compile Error"),
         Err(_) => Ok(()),
     }
 }
+
+///
+/// Interprets `s` on `vm`. With the `jit` feature, first runs it on two new VMs with the same
+/// settings, one only interpreting and one compiling every function it can from its first call,
+/// and checks the compiled code changes nothing.
+fn interpret(vm: &mut VM, s: &str) -> Result<Option<Value>> {
+    #[cfg(feature = "jit")]
+    {
+        let outcomes = [u32::MAX, 0].map(|threshold| {
+            let mut other = VM::new();
+            other.jit_threshold = threshold;
+            other.natives = vm.natives.clone();
+            other.search_path = vm.search_path.clone();
+            other.wrapping_arithmetic = vm.wrapping_arithmetic;
+            other.fold_constants = vm.fold_constants;
+            other.optimize = vm.optimize;
+            other.superinstructions = vm.superinstructions;
+            other.tail_calls = vm.tail_calls;
+            other.register_vm = vm.register_vm;
+            match other.interpret(s) {
+                Ok(value) => format!("{:?}", value),
+                Err(err) => err.to_string(),
+            }
+        });
+        assert_eq!(
+            outcomes[0], outcomes[1],
+            "compiled code changed the outcome of {}",
+            s
+        );
+    }
+    vm.interpret(s)
+}
@@ -0,0 +1,289 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{assert_ok_equals, assert_runtime_error};
+    use miette::Result;
+    use rox::value::Value;
+    use rox::vm::VM;
+
+    ///
+    ///
+    ///
+    #[test]
+    fn throw_and_catch() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+var result = "none";
+try {
+  throw "boom";
+  result = "not reached";
+} catch (e) {
+  result = e;
+}
+return result;
+"#,
+            Value::String("boom".to_string()),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun check(n) {
+  if (n > 10) throw n;
+  return n;
+}
+fun run(n) {
+  try {
+    return check(n);
+  } catch (e) {
+    return -e;
+  }
+}
+return run(3) + run(20);
+"#,
+            Value::Number(-17.0),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn catch_runtime_errors() -> Result<()> {
+        let mut vm = VM::new();
+        let caught = vm.interpret(
+            r#"
+fun inner() { return 1 + nil; }
+fun outer() { return inner(); }
+try { outer(); } catch (e) { return e; }
+"#,
+        )?;
+        match caught {
+            Some(Value::Exception(exception)) => {
+                assert!(exception.message.starts_with("Addition operation"));
+                assert_eq!(
+                    exception.trace,
+                    vec![
+                        "at inner (line 2)",
+                        "at outer (line 3)",
+                        "at script (line 4)"
+                    ]
+                );
+            }
+            other => panic!("Expected an exception, got {:?}", other),
+        }
+
+        match vm.interpret("try { print missing; } catch (e) { return e; }")? {
+            Some(Value::Exception(exception)) => {
+                assert_eq!(exception.message, "Undefined variable missing")
+            }
+            other => panic!("Expected an exception, got {:?}", other),
+        }
+
+        assert_ok_equals(
+            &mut vm,
+            r#"try { sin("a"); } catch (e) { return "caught"; }"#,
+            Value::String("caught".to_string()),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn scripts_read_error_fields() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"try { print missing; } catch (e) { return e.message; }"#,
+            Value::String("Undefined variable missing".to_string()),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun inner() { return 1 + nil; }
+fun outer() { var x = inner(); return x; }
+try { outer(); } catch (e) { return e.trace; }
+"#,
+            Value::List(vec![
+                Value::String("at inner (line 2)".to_string()),
+                Value::String("at outer (line 3)".to_string()),
+                Value::String("at script (line 4)".to_string()),
+            ]),
+        )?;
+        assert_runtime_error(
+            &mut vm,
+            r#"try { print missing; } catch (e) { return e.line; }"#,
+        )
+        .unwrap();
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn caught_errors_concatenate_with_strings() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"try { print missing; } catch (e) { return "caught: " + e; }"#,
+            Value::String("caught: Error: Undefined variable missing".to_string()),
+        )?;
+        assert_runtime_error(
+            &mut vm,
+            r#"try { print missing; } catch (e) { return e + 1; }"#,
+        )
+        .unwrap();
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn finally_runs_on_every_path() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+var log = "";
+try { log = log + "t"; } finally { log = log + "f"; }
+try { throw 1; } catch (e) { log = log + "c"; } finally { log = log + "f"; }
+try {
+  try { throw 2; } finally { log = log + "i"; }
+} catch (e) {
+  log = log + "o";
+}
+try {
+  try { throw 3; } catch (e) { throw e + 1; } finally { log = log + "r"; }
+} catch (e) {
+  log = log + e;
+}
+return log;
+"#,
+            Value::String("tfcfior4".to_string()),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            r#"
+var cleaned = false;
+fun f() {
+  var a = "kept";
+  try {
+    var b = "dropped";
+    return a;
+  } finally {
+    var c = "local";
+    cleaned = true;
+  }
+  return "not reached";
+}
+var result = f();
+if (cleaned) return result;
+return "not cleaned";
+"#,
+            Value::String("kept".to_string()),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            "fun g() { try { return 1; } finally { return 2; } } return g();",
+            Value::Number(2.0),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn unwinds_frames_and_stack() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun deep(n) {
+  var local = n;
+  if (n == 0) throw "bottom";
+  return deep(n - 1) + local;
+}
+var total = 0;
+for (var i = 0; i < 3; i = i + 1) {
+  try { total = total + deep(5); } catch (e) { total = total + 1; }
+}
+return total;
+"#,
+            Value::Number(3.0),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn uncaught_exceptions() -> Result<()> {
+        let mut vm = VM::new();
+        let err = vm
+            .interpret("fun f() { throw \"nope\"; }\nf();")
+            .expect_err("Expected an uncaught exception");
+        assert_eq!(err.to_string(), "Uncaught exception: nope");
+        let labels: Vec<_> = err.labels().expect("Expected a span").collect();
+        assert_eq!(labels[0].offset(), 16);
+
+        let err = vm
+            .interpret("try { throw 1; } finally { print \"cleanup\"; }")
+            .expect_err("Expected the exception to be rethrown");
+        assert_eq!(err.to_string(), "Uncaught exception: 1");
+
+        // the VM is reusable after an uncaught exception
+        assert_ok_equals(&mut vm, "return 1;", Value::Number(1.0))?;
+        assert_runtime_error(&mut vm, "throw nil;").unwrap();
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn uncaught_through_finally() {
+        let mut vm = VM::new();
+        let err = vm
+            .interpret("fun f() { try { throw 1; } finally { print \"f\"; } }\nf();")
+            .expect_err("Expected the exception to be rethrown");
+        assert_eq!(err.to_string(), "Uncaught exception: 1");
+        let labels: Vec<_> = err.labels().expect("Expected a span").collect();
+        assert_eq!(labels[0].offset(), 22);
+        assert_eq!(
+            err.help().expect("Expected a trace").to_string(),
+            "at f (line 1)\nat script (line 2)"
+        );
+
+        let err = vm
+            .interpret("try { var x = 1 + nil; } finally {}")
+            .expect_err("Expected the error to be rethrown");
+        let labels: Vec<_> = err.labels().expect("Expected a span").collect();
+        assert_eq!(labels[0].offset(), 18);
+
+        // nested finally blocks, the inner one exiting normally after catching its own exception
+        let err = vm
+            .interpret(
+                "try { throw 1; } finally { try { throw 2; } catch (e) {} finally { print 3; } }",
+            )
+            .expect_err("Expected the exception to be rethrown");
+        assert_eq!(err.to_string(), "Uncaught exception: 1");
+        let labels: Vec<_> = err.labels().expect("Expected a span").collect();
+        assert_eq!(labels[0].offset(), 12);
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn try_syntax_errors() {
+        let mut vm = VM::new();
+        assert!(vm.interpret("try { print 1; }").is_err());
+        assert!(vm.interpret("try { print 1; } catch { print 2; }").is_err());
+        assert!(vm.interpret("throw;").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{assert_ok_equals, assert_runtime_error};
+    use miette::Result;
+    use rox::value::Value;
+    use rox::vm::VM;
+
+    ///
+    ///
+    ///
+    #[test]
+    fn resume_and_suspend_transfer_values() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun accumulate(first) {
+  var total = first;
+  while (true) {
+    var next = suspend(total);
+    if (next == nil) return "total " + total;
+    total = total + next;
+  }
+}
+var fiber = Fiber(accumulate);
+var log = "";
+log = log + fiber.resume(10) + ",";
+log = log + fiber.resume(5) + ",";
+log = log + fiber.resume(1) + ",";
+log = log + fiber.resume();
+if (fiber.done) return log;
+"#,
+            Value::String("10,15,16,total 16".to_string()),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn suspend_from_nested_calls() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun visit(depth) {
+  if (depth == 0) return;
+  visit(depth - 1);
+  suspend(depth);
+  visit(depth - 1);
+}
+var walker = Fiber(() => visit(3));
+var order = "";
+var step = walker.resume();
+while (!walker.done) {
+  order = order + step;
+  step = walker();
+}
+return order;
+"#,
+            Value::String("1213121".to_string()),
+        )?;
+        // each fiber has its own stack: locals of the suspended fiber survive the caller's work
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun counter(start) {
+  var n = start;
+  while (true) { suspend(n); n = n + 1; }
+}
+var a = Fiber(counter);
+var b = Fiber(counter);
+a.resume(100);
+b.resume(200);
+var x = 1; var y = 2;
+return a.resume() + b.resume() + x + y;
+"#,
+            Value::Number(305.0),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn round_robin_scheduler() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+var log = "";
+fun task(name, steps) {
+  for (var i = 0; i < steps; i = i + 1) {
+    log = log + name + i + " ";
+    suspend();
+  }
+}
+spawn(() => task("a", 3));
+spawn(() => task("b", 1));
+spawn(() => task("c", 2));
+if (run() == nil) return log;
+"#,
+            Value::String("a0 b0 c0 a1 c1 a2 ".to_string()),
+        )?;
+        assert_ok_equals(&mut vm, "return run();", Value::Nil)
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn fiber_exceptions() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun risky() {
+  suspend(1);
+  throw "failed";
+}
+var fiber = Fiber(risky);
+fiber.resume();
+var caught = "";
+try { fiber.resume(); } catch (e) { caught = e; }
+if (fiber.done) return caught;
+"#,
+            Value::String("failed".to_string()),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun guarded() {
+  try { suspend("inside"); throw "oops"; } catch (e) { return "handled " + e; }
+}
+var fiber = Fiber(guarded);
+return fiber.resume() + " " + fiber.resume();
+"#,
+            Value::String("inside handled oops".to_string()),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn fiber_errors() {
+        let mut vm = VM::new();
+        assert_runtime_error(&mut vm, "suspend(1);").unwrap();
+        assert_runtime_error(&mut vm, "Fiber(1);").unwrap();
+        assert_runtime_error(&mut vm, "fun f() {} var x = Fiber(f); x(); x();").unwrap();
+        assert_runtime_error(&mut vm, "fun f() {} Fiber(f).resume(1, 2);").unwrap();
+        assert_runtime_error(
+            &mut vm,
+            "var me; fun f() { me.resume(); } me = Fiber(f); me.resume();",
+        )
+        .unwrap();
+        assert_runtime_error(&mut vm, "fun f() { return 1 + f(); } Fiber(f).resume();").unwrap();
+        // the VM is usable again after an error inside a fiber
+        assert_ok_equals(&mut vm, "return 1;", Value::Number(1.0)).unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::assert_runtime_error;
+    use miette::Result;
+    use rox::vm::VM;
+
+    const PROGRAMS: [&str; 10] = [
+        "return 1 + 2 * 3 - 4 ~/ 3;",
+        "return 7 / 2 + 2 ** 10 % 5;",
+        r#"return "n = " + 3 + 0.5;"#,
+        "return 1 >= 2 or 2 <= 2;",
+        "return 1 != 2 and !(1 == 1.0);",
+        "return -(3 & 6 | 8) << 2;",
+        "return 0.1d + 0.2d == 0.3d;",
+        "return 2n ** 70 > 2 ** 62;",
+        "var x = false; return (x or 1) + 2 * 3;",
+        r#"try { return 1 ~/ 0; } catch (e) { return "caught"; }"#,
+    ];
+
+    ///
+    ///
+    ///
+    #[test]
+    fn folding_preserves_results() -> Result<()> {
+        for program in PROGRAMS {
+            let mut folded = VM::new();
+            let mut unfolded = VM::new();
+            unfolded.fold_constants = false;
+            assert_eq!(
+                folded.interpret(program)?,
+                unfolded.interpret(program)?,
+                "{}",
+                program
+            );
+        }
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn folding_keeps_runtime_errors() {
+        for program in [
+            "return 1 ~/ 0;",
+            "return 1 % 0;",
+            r#"return "a" - 1;"#,
+            "return 9223372036854775807 + 1;",
+        ] {
+            for fold_constants in [true, false] {
+                let mut vm = VM::new();
+                vm.fold_constants = fold_constants;
+                assert_runtime_error(&mut vm, program).unwrap();
+            }
+        }
+    }
+}
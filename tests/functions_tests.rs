@@ -2,7 +2,7 @@ mod common;
 
 #[cfg(test)]
 mod tests {
-    use crate::common::{assert_ok, assert_ok_equals};
+    use crate::common::{assert_compile_error, assert_ok, assert_ok_equals};
     use miette::Result;
     use rox::value::Value;
     use rox::vm::VM;
@@ -306,7 +306,7 @@ return 1 + a(1);
             .interpret(
                 r#"
 fun a(x) {
-    return a(x+1);
+    return 1 + a(x+1);
 }
 print a(1);
         "#,
@@ -455,4 +455,207 @@ return sin(1.5709);
             Value::Number(1.5709_f64.sin()),
         )
     }
+
+    #[test]
+    fn vm_fn_anonymous() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+var add = fun (a, b) {
+    return a + b;
+};
+return add(1, 2);
+        "#,
+            Value::Number(3f64),
+        )
+    }
+
+    #[test]
+    fn vm_fn_anonymous_immediately_invoked() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+fun (a) { print a; }("statement");
+return fun () { return "expr"; }();
+        "#,
+            Value::String("expr".to_string()),
+        )
+    }
+
+    #[test]
+    fn vm_fn_arrow() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+fun apply(f, x) {
+    return f(x);
+}
+var twice = (x) => x * 2;
+var answer = () => 42;
+var sum = (a, b) => {
+    var s = a + b;
+    return s;
+};
+return apply(twice, 21) + answer() + sum(1, 2) + ((x) => x + 1)(0);
+        "#,
+            Value::Number(88f64),
+        )
+    }
+
+    #[test]
+    fn vm_fn_arrow_is_not_grouping() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+var a = 2;
+return (a) * 3 + (a);
+        "#,
+            Value::Number(8f64),
+        )
+    }
+
+    #[test]
+    fn vm_fn_lambda_name() -> Result<()> {
+        match VM::new().interpret("\nvar f = (x) => x;\nreturn f;")? {
+            Some(Value::Function(f)) => assert_eq!(f.name, "lambda:1"),
+            other => panic!("Expected a function, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vm_fn_default_parameters() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+fun f(a, b = 10, c = a + b) {
+    return a * 10000 + b * 100 + c;
+}
+return f(1) + f(1, 2) + f(1, 2, 3);
+        "#,
+            Value::Number(11011f64 + 10203f64 + 10203f64),
+        )
+    }
+
+    #[test]
+    fn vm_fn_default_arity_error() -> Result<()> {
+        let mut vm = VM::new();
+        let source = "fun f(a, b = 1) { return a; }\n";
+        assert!(vm.interpret(&format!("{}f();", source)).is_err());
+        assert!(vm.interpret(&format!("{}f(1, 2, 3);", source)).is_err());
+        assert!(vm.interpret("fun g(a = 1, b) { return b; }").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn vm_fn_named_arguments() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+fun f(a, b = 2, c = 3) {
+    return a * 100 + b * 10 + c;
+}
+return f(c: 9, a: 1) + f(4, c: 5) * 1000;
+        "#,
+            Value::Number(129f64 + 425f64 * 1000f64),
+        )
+    }
+
+    #[test]
+    fn vm_fn_named_arguments_errors() -> Result<()> {
+        let source = "fun f(a, b = 2) { return a + b; }\n";
+        for call in ["f(b: 1);", "f(1, a: 2);", "f(z: 1);", "clock(a: 1);"] {
+            assert!(
+                VM::new().interpret(&format!("{}{}", source, call)).is_err(),
+                "{} should fail",
+                call
+            );
+        }
+        assert!(VM::new()
+            .interpret(&format!("{}f(a: 1, 2);", source))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn vm_fn_variadic() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+fun f(a, ...rest) {
+    return rest;
+}
+print f(1);
+return f(1, 2, 3);
+        "#,
+            Value::List(vec![Value::Number(2f64), Value::Number(3f64)]),
+        )
+    }
+
+    #[test]
+    fn vm_fn_spread() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+fun list(...items) {
+    return items;
+}
+fun sum(a, b, c, d) {
+    return a * 1000 + b * 100 + c * 10 + d;
+}
+var xs = list(2, 3);
+return sum(1, ...xs, 4) + sum(...list(5, 6, 7, 8)) * 10000;
+        "#,
+            Value::Number(1234f64 + 56780000f64),
+        )
+    }
+
+    #[test]
+    fn vm_fn_spread_not_a_list() -> Result<()> {
+        assert!(VM::new()
+            .interpret("fun f(a) { return a; }\nf(...1);")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn vm_fn_spread_into_native() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+fun list(...items) {
+    return items;
+}
+return sin(...list(0));
+        "#,
+            Value::Number(0f64),
+        )
+    }
+
+    #[test]
+    fn vm_fn_arrow_default_and_rest() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+var f = (a, b = (1 + 1), ...rest) => a + b;
+return f(1) + f(1, 1, 5, 6);
+        "#,
+            Value::Number(5f64),
+        )
+    }
+
+    #[test]
+    fn vm_fn_lambda_capturing_a_local() {
+        // there are no closures, so this is rejected rather than read as a global
+        assert_compile_error(
+            &mut VM::new(),
+            "fun f() { var a = 1; var g = (x) => x + a; return g(1); } f();",
+        )
+        .unwrap();
+        assert_compile_error(
+            &mut VM::new(),
+            "fun f(a) { return fun () { return a; }; } f(1)();",
+        )
+        .unwrap();
+    }
 }
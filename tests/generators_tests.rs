@@ -0,0 +1,178 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{assert_compile_error, assert_ok_equals, assert_runtime_error};
+    use miette::Result;
+    use rox::value::Value;
+    use rox::vm::VM;
+
+    ///
+    ///
+    ///
+    #[test]
+    fn next_resumes_after_yield() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun count(start, stop) {
+  var i = start;
+  while (i < stop) {
+    yield i;
+    i = i + 1;
+  }
+  return "done";
+}
+var gen = count(1, 3);
+var log = "";
+log = log + gen.next() + ",";
+log = log + gen.next() + ",";
+log = log + gen.next();
+if (gen.next() == nil) log = log + ".";
+return log;
+"#,
+            Value::String("1,2,done.".to_string()),
+        )?;
+        // each call creates an independent generator
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun naturals() { var n = 0; while (true) { yield n; n = n + 1; } }
+var a = naturals();
+var b = naturals();
+a.next(); a.next();
+var next = b.next;
+return a.next() * 10 + next();
+"#,
+            Value::Number(20.0),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn for_in_loops() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun list(...items) { return items; }
+var total = 0;
+for (var x in list(1, 2, 3)) {
+  var doubled = x * 2;
+  total = total + doubled;
+}
+return total;
+"#,
+            Value::Number(12.0),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun squares(n) {
+  for (var i = 1; i <= n; i = i + 1) yield i * i;
+}
+fun sum(items) {
+  var total = 0;
+  for (var item in items) total = total + item;
+  return total;
+}
+fun list(...items) { return items; }
+return sum(squares(4)) + sum(list());
+"#,
+            Value::Number(30.0),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn lazy_pipelines() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun naturals() { var n = 1; while (true) { yield n; n = n + 1; } }
+fun filter(items, keep) { for (var item in items) if (keep(item)) yield item; }
+fun map(items, f) { for (var item in items) yield f(item); }
+fun take(items, n) {
+  if (n <= 0) return;
+  for (var item in items) {
+    yield item;
+    n = n - 1;
+    if (n == 0) return;
+  }
+}
+var result = "";
+for (var x in take(map(filter(naturals(), (n) => n % 2 == 0), (n) => n * n), 3)) {
+  result = result + x + " ";
+}
+return result;
+"#,
+            Value::String("4 16 36 ".to_string()),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn generators_and_exceptions() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+var log = "";
+fun guarded() {
+  try {
+    yield 1;
+    yield 2;
+    throw "stop";
+  } catch (e) {
+    log = log + e;
+    yield 3;
+  } finally {
+    log = log + "!";
+  }
+}
+var total = 0;
+for (var x in guarded()) total = total + x;
+return log + total;
+"#,
+            Value::String("stop!6".to_string()),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun failing() { yield 1; throw "broken"; }
+var gen = failing();
+gen.next();
+var caught = nil;
+try { gen.next(); } catch (e) { caught = e; }
+if (gen.next() == nil) return caught;
+"#,
+            Value::String("broken".to_string()),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn generator_errors() {
+        let mut vm = VM::new();
+        assert_compile_error(&mut vm, "yield 1;").unwrap();
+        assert_compile_error(&mut vm, "fun f() { yield 1 }").unwrap();
+        assert_runtime_error(&mut vm, "for (var x in 3) print x;").unwrap();
+        assert_runtime_error(&mut vm, "fun g() { yield 1; } g().next(1);").unwrap();
+        assert_runtime_error(&mut vm, "fun g() { yield 1; } g().send;").unwrap();
+        assert_runtime_error(
+            &mut vm,
+            "var gen; fun g() { yield gen.next(); } gen = g(); gen.next();",
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{assert_ok_equals, assert_runtime_error};
+    use miette::Result;
+    use rox::value::Value;
+    use rox::vm::VM;
+
+    ///
+    ///
+    ///
+    #[test]
+    fn literal_types() -> Result<()> {
+        let mut vm = VM::new();
+        assert!(matches!(vm.interpret("return 42;")?, Some(Value::Int(42))));
+        assert!(matches!(vm.interpret("return 42.0;")?, Some(Value::Number(n)) if n == 42.0));
+        assert!(matches!(vm.interpret("return -7;")?, Some(Value::Int(-7))));
+        assert!(vm.interpret("return 9223372036854775808;").is_err());
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn promotion() -> Result<()> {
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.interpret("return 2 + 3 * 4 - 1;")?,
+            Some(Value::Int(13))
+        ));
+        assert!(matches!(vm.interpret("return 2 + 0.5;")?, Some(Value::Number(n)) if n == 2.5));
+        assert!(matches!(vm.interpret("return 7 / 2;")?, Some(Value::Number(n)) if n == 3.5));
+        assert!(matches!(vm.interpret("return 6 / 3;")?, Some(Value::Number(n)) if n == 2.0));
+        assert!(matches!(
+            vm.interpret("return -7 ~/ 2;")?,
+            Some(Value::Int(-4))
+        ));
+        assert!(matches!(
+            vm.interpret("return 7 % 3;")?,
+            Some(Value::Int(1))
+        ));
+        assert!(matches!(
+            vm.interpret("return 2 ** 10;")?,
+            Some(Value::Int(1024))
+        ));
+        assert!(matches!(vm.interpret("return 2 ** -1;")?, Some(Value::Number(n)) if n == 0.5));
+        assert!(matches!(
+            vm.interpret("return 6 & 3;")?,
+            Some(Value::Int(2))
+        ));
+        assert!(matches!(
+            vm.interpret("return 1 < 1.5;")?,
+            Some(Value::Boolean(true))
+        ));
+        assert_ok_equals(&mut vm, "return 3 == 3.0;", Value::Boolean(true))?;
+        assert_ok_equals(
+            &mut vm,
+            r#"return "n = " + 3;"#,
+            Value::String("n = 3".to_string()),
+        )?;
+        assert_runtime_error(&mut vm, "return 1 ~/ 0;").unwrap();
+        assert_runtime_error(&mut vm, "return 1 % 0;").unwrap();
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn exact_past_two_to_the_fifty_three() -> Result<()> {
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.interpret("return 9007199254740993 + 2;")?,
+            Some(Value::Int(9007199254740995))
+        ));
+        assert!(matches!(
+            vm.interpret("var n = 1; for (var i = 0; i < 62; i++) n = n * 2; return n;")?,
+            Some(Value::Int(4611686018427387904))
+        ));
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn overflow() -> Result<()> {
+        let mut vm = VM::new();
+        let err = vm
+            .interpret("return 9223372036854775807 + 1;")
+            .expect_err("Expected an overflow");
+        assert!(err.to_string().contains("Integer overflow"));
+        assert_runtime_error(&mut vm, "return 3037000500 * 3037000500;").unwrap();
+        assert_runtime_error(&mut vm, "return 2 ** 64;").unwrap();
+        assert_runtime_error(&mut vm, "return -9223372036854775807 - 2;").unwrap();
+
+        vm.wrapping_arithmetic = true;
+        assert!(matches!(
+            vm.interpret("return 9223372036854775807 + 1;")?,
+            Some(Value::Int(i64::MIN))
+        ));
+        assert!(matches!(
+            vm.interpret("return 2 ** 64;")?,
+            Some(Value::Int(0))
+        ));
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn conversions() -> Result<()> {
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.interpret("return int(3.9);")?,
+            Some(Value::Int(3))
+        ));
+        assert!(matches!(
+            vm.interpret("return int(-3.9);")?,
+            Some(Value::Int(-3))
+        ));
+        assert!(matches!(
+            vm.interpret(r#"return int(" 12 ");"#)?,
+            Some(Value::Int(12))
+        ));
+        assert!(matches!(vm.interpret("return float(3);")?, Some(Value::Number(n)) if n == 3.0));
+        assert!(
+            matches!(vm.interpret(r#"return float("2.5");"#)?, Some(Value::Number(n)) if n == 2.5)
+        );
+        assert!(matches!(
+            vm.interpret("return int(float(5)) + 1;")?,
+            Some(Value::Int(6))
+        ));
+        assert_runtime_error(&mut vm, "return int(10.0 ** 300);").unwrap();
+        assert_runtime_error(&mut vm, r#"return int("1.5");"#).unwrap();
+        assert_runtime_error(&mut vm, "return float(nil);").unwrap();
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn literal_forms() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            "return 0xFF + 0b1010 + 0o17 + 1_000;",
+            Value::Int(1280),
+        )?;
+        assert_ok_equals(&mut vm, "return 1.5e-3 * 2e3;", Value::Number(3.0))?;
+        let err = vm
+            .interpret("var x = 12abc;")
+            .expect_err("Expected a malformed literal");
+        assert!(format!("{:?}", err).contains("Malformed number literal '12abc'"));
+        assert!(vm.interpret("return 0b;").is_err());
+        Ok(())
+    }
+}
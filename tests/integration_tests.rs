@@ -100,6 +100,13 @@ mod tests {
         assert_ok_return_value(&mut VM::new(), "2 == 2;", Value::Boolean(true))
     }
 
+    #[test]
+    fn vm_not_equal() -> Result<()> {
+        assert_ok_return_value(&mut VM::new(), "1 != 2;", Value::Boolean(true))?;
+        assert_ok_return_value(&mut VM::new(), "2 != 2;", Value::Boolean(false))?;
+        assert_ok_return_value(&mut VM::new(), r#""A" != "B";"#, Value::Boolean(true))
+    }
+
     #[test]
     fn vm_str_eval() -> Result<()> {
         assert_ok_return_value(&mut VM::new(), r#""A";"#, Value::String("A".to_string()))
@@ -308,4 +315,308 @@ print i;
         "#,
         )
     }
+
+    #[test]
+    fn vm_block_comment() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+/* a block comment
+   spanning /* nested */ lines */
+var a = 1 /* inline */ + 2;
+return a;
+        "#,
+            Value::Number(3f64),
+        )
+    }
+
+    #[test]
+    fn vm_unterminated_block_comment() -> Result<(), &'static str> {
+        assert_compile_error(&mut VM::new(), "var a = 1; /* never closed")
+    }
+
+    #[test]
+    fn vm_divide() -> Result<()> {
+        assert_ok_return_value(&mut VM::new(), "7 / 2;", Value::Number(3.5f64))
+    }
+
+    #[test]
+    fn vm_modulo() -> Result<()> {
+        assert_ok_return_value(&mut VM::new(), "7 % 3;", Value::Number(1f64))?;
+        assert_ok_return_value(&mut VM::new(), "1 + 7 % 3 * 2;", Value::Number(3f64))
+    }
+
+    #[test]
+    fn vm_power() -> Result<()> {
+        assert_ok_return_value(&mut VM::new(), "2 ** 10;", Value::Number(1024f64))?;
+        // right-associative: 2 ** (3 ** 2)
+        assert_ok_return_value(&mut VM::new(), "2 ** 3 ** 2;", Value::Number(512f64))?;
+        // binds tighter than unary minus: -(2 ** 2)
+        assert_ok_return_value(&mut VM::new(), "-2 ** 2;", Value::Number(-4f64))?;
+        assert_ok_return_value(&mut VM::new(), "2 * 3 ** 2;", Value::Number(18f64))
+    }
+
+    #[test]
+    fn vm_int_divide() -> Result<()> {
+        assert_ok_return_value(&mut VM::new(), "7 ~/ 2;", Value::Number(3f64))?;
+        assert_ok_return_value(&mut VM::new(), "-7 ~/ 2;", Value::Number(-4f64))
+    }
+
+    #[test]
+    fn vm_int_divide_by_zero() -> Result<(), &'static str> {
+        assert_runtime_error(&mut VM::new(), "1 ~/ 0;")
+    }
+
+    #[test]
+    fn vm_bitwise() -> Result<()> {
+        assert_ok_return_value(&mut VM::new(), "12 & 10;", Value::Number(8f64))?;
+        assert_ok_return_value(&mut VM::new(), "12 | 3;", Value::Number(15f64))?;
+        assert_ok_return_value(&mut VM::new(), "12 ^ 10;", Value::Number(6f64))?;
+        assert_ok_return_value(&mut VM::new(), "~5;", Value::Number(-6f64))?;
+        assert_ok_return_value(&mut VM::new(), "1 << 4;", Value::Number(16f64))?;
+        assert_ok_return_value(&mut VM::new(), "-16 >> 2;", Value::Number(-4f64))?;
+        // shifts bind tighter than '&', which binds tighter than '|'
+        assert_ok_return_value(&mut VM::new(), "1 | 1 << 2 & 6;", Value::Number(5f64))?;
+        assert_ok_return_value(&mut VM::new(), "1 << 2 == 4;", Value::Boolean(true))
+    }
+
+    #[test]
+    fn vm_bitwise_not_integral() -> Result<(), &'static str> {
+        assert_runtime_error(&mut VM::new(), "1.5 & 1;")?;
+        assert_runtime_error(&mut VM::new(), "~0.5;")?;
+        assert_runtime_error(&mut VM::new(), r#""a" | 1;"#)?;
+        assert_runtime_error(&mut VM::new(), "1 << 64;")
+    }
+
+    #[test]
+    fn vm_modulo_wrong_type() -> Result<(), &'static str> {
+        assert_runtime_error(&mut VM::new(), r#"1 % "a";"#)
+    }
+
+    #[test]
+    fn vm_compound_assignment_global() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+var a = 10;
+a += 5;
+a -= 3;
+a *= 2;
+a /= 4;
+a %= 4;
+return a;
+        "#,
+            Value::Number(2f64),
+        )
+    }
+
+    #[test]
+    fn vm_compound_assignment_local() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+var s = "";
+{
+    var a = 1;
+    var b = a += 2;
+    s += "x";
+    s += a + b;
+}
+return s;
+        "#,
+            Value::String("x6".to_string()),
+        )
+    }
+
+    #[test]
+    fn vm_increment() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+var a = 1;
+var b = a++;
+var c = ++a;
+{
+    var d = 5;
+    d--;
+    --d;
+    a = a * 10 + d;
+}
+return a * 100 + b * 10 + c;
+        "#,
+            Value::Number(3313f64),
+        )
+    }
+
+    #[test]
+    fn vm_increment_in_for() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+var total = 0;
+for (var i = 0; i < 5; i++) {
+    total += i;
+}
+return total;
+        "#,
+            Value::Number(10f64),
+        )
+    }
+
+    #[test]
+    fn vm_increment_non_number() -> Result<(), &'static str> {
+        assert_runtime_error(&mut VM::new(), "var s = \"a\"; s++;")?;
+        assert_runtime_error(&mut VM::new(), "var s = \"a\"; --s;")?;
+        assert_runtime_error(&mut VM::new(), "{ var s = \"a\"; s++; }")?;
+        assert_runtime_error(&mut VM::new(), "var b = true; b--;")?;
+        assert_runtime_error(
+            &mut VM::new(),
+            "fun f(s) { for (var i = 0; i < 3; i++) s++; } f(\"a\");",
+        )
+    }
+
+    #[test]
+    fn vm_invalid_compound_target() -> Result<(), &'static str> {
+        assert_compile_error(&mut VM::new(), "var a = 1; var b = 2; a + b += 1;")?;
+        assert_compile_error(&mut VM::new(), "1++;")?;
+        assert_compile_error(&mut VM::new(), "++1;")
+    }
+
+    #[test]
+    fn vm_conditional() -> Result<()> {
+        assert_ok_return_value(&mut VM::new(), "true ? 1 : 2;", Value::Number(1f64))?;
+        assert_ok_return_value(&mut VM::new(), "nil ? 1 : 2;", Value::Number(2f64))?;
+        assert_ok_return_value(
+            &mut VM::new(),
+            "false ? 1 : true ? 2 : 3;",
+            Value::Number(2f64),
+        )?;
+        assert_ok_return_value(&mut VM::new(), "1 < 2 ? 3 + 4 : 5;", Value::Number(7f64))?;
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+var a = 0;
+var b = a > 0 ? "pos" : a < 0 ? "neg" : "zero";
+return b;
+        "#,
+            Value::String("zero".to_string()),
+        )
+    }
+
+    #[test]
+    fn vm_conditional_missing_colon() -> Result<(), &'static str> {
+        assert_compile_error(&mut VM::new(), "var a = true ? 1;")
+    }
+
+    #[test]
+    fn vm_null_coalescing() -> Result<()> {
+        assert_ok_return_value(&mut VM::new(), "nil ?? 2;", Value::Number(2f64))?;
+        assert_ok_return_value(&mut VM::new(), "1 ?? 2;", Value::Number(1f64))?;
+        assert_ok_return_value(&mut VM::new(), "false ?? 2;", Value::Boolean(false))?;
+        assert_ok_return_value(&mut VM::new(), "nil ?? nil ?? 3;", Value::Number(3f64))?;
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+var port;
+{
+    var fallback = 8080;
+    port = port ?? fallback;
+}
+return port;
+        "#,
+            Value::Number(8080f64),
+        )
+    }
+
+    #[test]
+    fn vm_optional_chaining() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            "var config; return config?.port;",
+            Value::Nil,
+        )?;
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+var config;
+var port;
+{
+    var fallback = 8080;
+    port = config?.port ?? fallback;
+}
+return port;
+        "#,
+            Value::Number(8080f64),
+        )
+    }
+
+    #[test]
+    fn vm_optional_chaining_needs_property() -> Result<(), &'static str> {
+        assert_compile_error(&mut VM::new(), "var config; config?.;")?;
+        assert_runtime_error(&mut VM::new(), "var config; config.port;")
+    }
+
+    #[test]
+    fn vm_null_coalescing_short_circuit() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+var calls = 0;
+fun fallback() {
+    calls += 1;
+    return 0;
+}
+var a = 1 ?? fallback();
+return calls;
+        "#,
+            Value::Number(0f64),
+        )
+    }
+
+    #[test]
+    fn vm_const_declarations() -> Result<()> {
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+const PORT = 8080;
+const HOST = "localhost" + ":";
+fun url() {
+    const SCHEME = "http://";
+    return SCHEME + HOST + PORT;
+}
+return url();
+        "#,
+            Value::String("http://localhost:8080".to_string()),
+        )?;
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+const LIMIT = 3;
+var total = 0;
+for (var i = 0; i < LIMIT; i++) {
+    const STEP = i * 2;
+    total += STEP;
+}
+return total;
+        "#,
+            Value::Number(6f64),
+        )
+    }
+
+    #[test]
+    fn vm_const_reassignment() -> Result<(), &'static str> {
+        assert_compile_error(&mut VM::new(), "const A = 1; A = 2;")?;
+        assert_compile_error(&mut VM::new(), "const A = 1; A *= 2;")?;
+        assert_compile_error(&mut VM::new(), "const A = 1; A--;")?;
+        assert_compile_error(&mut VM::new(), "{ const B = 1; ++B; }")?;
+        assert_compile_error(&mut VM::new(), "const A = 1; var A = 2;")?;
+        assert_compile_error(&mut VM::new(), "const A;")?;
+        // not resolvable while compiling f, caught when it runs
+        assert_runtime_error(&mut VM::new(), "fun f() { C = 2; } const C = 1; f();")?;
+
+        let mut vm = VM::new();
+        assert_ok(&mut vm, "const D = 1;").unwrap();
+        assert_runtime_error(&mut vm, "D = 2;")?;
+        assert_runtime_error(&mut vm, "var D = 2;")
+    }
 }
@@ -0,0 +1,78 @@
+mod common;
+
+#[cfg(all(test, feature = "jit"))]
+mod tests {
+    use crate::common::assert_ok_equals;
+    use miette::Result;
+    use rox::value::Value;
+    use rox::vm::VM;
+
+    const PROGRAMS: [&str; 7] = [
+        "fun sum(n) { var s = 0; for (var i = 0; i < n; i++) s = s + i; return s; } return sum(1000);",
+        "fun f(x) { while (x >= 1.0) x = x / 2.0; return -x; } var s = 0.0; for (var i = 0; i < 50; i++) s = s + f(i * 1.5); return s;",
+        "fun f(n) { var x = 1; for (var i = 0; i < n; i++) x = x * 3; return x; } return f(30);",
+        "fun f(a, b) { return a < b or a >= b; } var nan = 0.0 / 0.0; return f(nan, 1.0) == f(1.0, 2.0);",
+        "fun f(a) { var s = 0; for (var i = 0; i < 10; i++) s = s + a; return s; } var s = \"\"; for (var i = 0; i < 40; i++) { s = f(i); s = f(i * 0.5); } return s;",
+        "fun f(a) { return a + 1; } var s = \"\"; for (var i = 0; i < 40; i++) s = s + f(i) + f(\"x\"); return s;",
+        "fun f() { var s = 0; for (var i = 0; i < 5000; i++) { if (i > 4000) s = s + 0.5; else s = s + i; } return s; } return f();",
+    ];
+
+    fn vm(threshold: u32) -> VM {
+        let mut vm = VM::new();
+        vm.jit_threshold = threshold;
+        vm
+    }
+
+    fn outcome(vm: &mut VM, program: &str) -> String {
+        match vm.interpret(program) {
+            Ok(value) => format!("{:?}", value),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn compiled_code_returns_the_same_results() {
+        for program in PROGRAMS {
+            let expected = outcome(&mut vm(u32::MAX), program);
+            for threshold in [0, 1, 10] {
+                assert_eq!(
+                    outcome(&mut vm(threshold), program),
+                    expected,
+                    "{} at {}",
+                    program,
+                    threshold
+                );
+            }
+        }
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn integer_overflow_is_left_to_the_interpreter() -> Result<()> {
+        let program = "fun f(n) { var x = 1; for (var i = 0; i < n; i++) x = x * 3; return x; } return f(50);";
+        let err = vm(0).interpret(program).expect_err("Expected an overflow");
+        assert!(err.to_string().contains("Integer overflow"));
+
+        let mut wrapping = vm(0);
+        wrapping.wrapping_arithmetic = true;
+        assert_ok_equals(&mut wrapping, program, Value::Int(3i64.wrapping_pow(50)))
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn hot_loops_are_entered_mid_function() -> Result<()> {
+        let mut vm = vm(100);
+        assert_ok_equals(
+            &mut vm,
+            "fun f() { var s = 0; for (var i = 0; i < 100000; i++) s = s + i; return s; } return f();",
+            Value::Int(4999950000),
+        )
+    }
+}
@@ -0,0 +1,144 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{assert_compile_error, assert_ok_equals};
+    use miette::Result;
+    use rox::value::Value;
+    use rox::vm::VM;
+
+    ///
+    ///
+    ///
+    #[test]
+    fn match_statement() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun describe(n) {
+  match (n) {
+    0 => return "zero";
+    1 | 2 | 3 => return "few";
+    4..10 => return "several";
+    10..=20 => { var many = "many"; return many; }
+    _ => return "lots";
+  }
+}
+return describe(0) + describe(2) + describe(7) + describe(10) + describe(21);
+"#,
+            Value::String("zerofewseveralmanylots".to_string()),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            r#"
+var seen = "none";
+match ("c") {
+  "a" => seen = "a";
+  "b" => seen = "b";
+}
+return seen;
+"#,
+            Value::String("none".to_string()),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn match_expression() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun sign(n) {
+  return match (n) {
+    0 => "zero",
+    -1000..0 => "negative",
+    _ => "positive"
+  };
+}
+return sign(-5) + sign(0) + sign(3);
+"#,
+            Value::String("negativezeropositive".to_string()),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            "var x = match (true) { false => 1, nil => 2, }; return x;",
+            Value::Nil,
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn guards() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun classify(n, strict) {
+  return match (n) {
+    0..100 if strict => "small",
+    0..100 => "small enough",
+    _ if n < 0 => "negative",
+    _ => "large",
+  };
+}
+return classify(5, true) + "," + classify(5, false) + "," + classify(-1, true) + "," + classify(500, true);
+"#,
+            Value::String("small,small enough,negative,large".to_string()),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn jump_tables() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun day(n) {
+  return match (n) {
+    1 => "mon", 2 => "tue", 3 => "wed", 4 => "thu", 5 => "fri",
+    6 | 7 => "weekend",
+    _ => "?"
+  };
+}
+return day(3) + day(7) + day(9) + day(2.5) + day("1");
+"#,
+            Value::String("wedweekend???".to_string()),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun code(s) {
+  return match (s) { "red" => 1, "green" => 2, "blue" => 3 };
+}
+return code("blue") * 10 + code("red");
+"#,
+            Value::Number(31.0),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            r#"var c = match ("pink") { "red" => 1, "green" => 2, "blue" => 3 }; return c;"#,
+            Value::Nil,
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn match_syntax_errors() {
+        let mut vm = VM::new();
+        assert_compile_error(&mut vm, "match (1) { 1 -> print 1; }").unwrap();
+        assert_compile_error(&mut vm, "match (1) { x => print 1; }").unwrap();
+        assert_compile_error(&mut vm, r#"match (1) { "a".."z" => print 1; }"#).unwrap();
+        assert_compile_error(&mut vm, "var x = match (1) { 1 => 2 3 => 4 };").unwrap();
+    }
+}
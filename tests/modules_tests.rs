@@ -0,0 +1,264 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::assert_ok_equals;
+    use miette::{IntoDiagnostic, Result};
+    use rox::module;
+    use rox::value::Value;
+    use rox::vm::VM;
+    use std::fs::{self, File};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    ///
+    ///
+    ///
+    #[test]
+    fn import_as() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+import "tests/modules/math.lox" as math;
+return math.area(2) + math.pi;
+"#,
+            Value::Number(15.0),
+        )?;
+        assert_ok_equals(
+            &mut vm,
+            r#"
+import "tests/modules/math.lox" as math;
+var square = math.square;
+return square(5);
+"#,
+            Value::Number(25.0),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn optional_chaining_on_module() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+import "tests/modules/math.lox" as math;
+return math?.square(3) + math?.pi;
+"#,
+            Value::Number(12.0),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn from_import() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+from "tests/modules/math.lox" import square, pi;
+return square(pi);
+"#,
+            Value::Number(9.0),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn modules_have_their_own_globals() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+var count = 100;
+import "tests/modules/counter.lox" as counter;
+counter.next();
+counter.next();
+return count + counter.next();
+"#,
+            Value::Number(103.0),
+        )?;
+        assert!(vm
+            .interpret(
+                r#"
+import "tests/modules/counter.lox" as counter;
+return counter.missing;
+"#
+            )
+            .is_err());
+        assert!(vm
+            .interpret("fun f() { return 1; } var x = f.name;")
+            .is_err());
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn module_properties_are_live() -> Result<()> {
+        let mut vm = VM::new();
+        assert_ok_equals(
+            &mut vm,
+            r#"
+import "tests/modules/counter.lox" as c;
+c.next();
+c.next();
+return c.count;
+"#,
+            Value::Number(2.0),
+        )?;
+        // `from ... import` copies the value at the time of the import
+        assert_ok_equals(
+            &mut VM::new(),
+            r#"
+from "tests/modules/counter.lox" import next, count;
+next();
+return count;
+"#,
+            Value::Number(0.0),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn modules_run_once() -> Result<()> {
+        let mut vm = VM::new();
+        // shapes.lox imports counter.lox relative to its own directory
+        assert_ok_equals(
+            &mut vm,
+            r#"
+import "tests/modules/counter.lox" as first;
+import "tests/modules/shapes.lox" as shapes;
+import "tests/modules/counter.lox" as second;
+shapes.circle(1);
+first.next();
+return second.next();
+"#,
+            Value::Number(3.0),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn search_path() -> Result<()> {
+        let mut vm = VM::new();
+        assert!(vm.interpret(r#"import "strings.lox" as s;"#).is_err());
+
+        vm.search_path.push(PathBuf::from("tests/modules/lib"));
+        assert_ok_equals(
+            &mut vm,
+            r#"
+from "strings.lox" import greet;
+return greet("lox");
+"#,
+            Value::String("hello lox".to_string()),
+        )
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn interpret_file() -> Result<()> {
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.interpret_file(Path::new("tests/modules/main.lox"))?,
+            Some(Value::Number(12.0))
+        );
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn compiled_modules() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("rox-compiled-modules-{}", std::process::id()));
+        fs::create_dir_all(&dir).into_diagnostic()?;
+        fs::write(dir.join("twice.lox"), "fun twice(x) { return x * 2; }").into_diagnostic()?;
+        let cached = dir.join("cached.lox");
+        fs::write(
+            &cached,
+            "from \"twice.lox\" import twice;\nvar value = twice(1);",
+        )
+        .into_diagnostic()?;
+        let modified = fs::metadata(&cached)
+            .and_then(|metadata| metadata.modified())
+            .into_diagnostic()?;
+        let set_modified = |time| {
+            File::options()
+                .write(true)
+                .open(&cached)
+                .and_then(|file| file.set_modified(time))
+                .into_diagnostic()
+        };
+        let script = format!("import \"{}\" as m; return m.value;", cached.display());
+
+        let mut vm = VM::new();
+        vm.cache_modules = true;
+        assert_ok_equals(&mut vm, &script, Value::Number(2.0))?;
+        assert!(dir.join("cached.loxc").is_file());
+        assert!(dir.join("twice.loxc").is_file());
+
+        // a source of the same size and modification time is taken to be unchanged
+        fs::write(
+            &cached,
+            "from \"twice.lox\" import twice;\nvar value = twice(2);",
+        )
+        .into_diagnostic()?;
+        set_modified(modified)?;
+        assert_ok_equals(&mut vm, &script, Value::Number(2.0))?;
+        assert_ok_equals(&mut VM::new(), &script, Value::Number(4.0))?;
+
+        set_modified(modified + Duration::from_secs(1))?;
+        assert_ok_equals(&mut vm, &script, Value::Number(4.0))?;
+
+        // rowc writes the same files
+        let output = module::compile_file(&cached, Some(&dir.join("out.loxc")), &[])?;
+        assert_eq!(
+            fs::read(output).into_diagnostic()?,
+            fs::read(dir.join("cached.loxc")).into_diagnostic()?
+        );
+        fs::remove_dir_all(&dir).into_diagnostic()
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn import_errors() {
+        let mut vm = VM::new();
+        let err = vm
+            .interpret(r#"import "tests/modules/cycle_a.lox" as a;"#)
+            .expect_err("Expected an import cycle");
+        assert!(err
+            .to_string()
+            .starts_with("Import cycle: cycle_a -> cycle_b -> cycle_a"));
+        // named once, by the import in the module where the cycle closes
+        assert_eq!(err.to_string().matches("(in ").count(), 1);
+        assert!(err.to_string().contains("cycle_b.lox, line 1"));
+
+        let err = vm
+            .interpret(r#"import "tests/modules/broken.lox" as broken;"#)
+            .expect_err("Expected a compile error in the module");
+        assert!(err.to_string().contains("broken.lox, line"));
+
+        assert!(vm.interpret(r#"import "missing.lox" as m;"#).is_err());
+        assert!(vm
+            .interpret(r#"{ import "tests/modules/math.lox" as math; }"#)
+            .is_err());
+        assert!(vm.interpret(r#"import "tests/modules/math.lox";"#).is_err());
+    }
+}
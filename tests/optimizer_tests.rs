@@ -0,0 +1,53 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::assert_runtime_error;
+    use miette::Result;
+    use rox::vm::VM;
+
+    const PROGRAMS: [&str; 10] = [
+        "var n = 0; for (var i = 0; i < 10; i++) { n; if (i >= 5) n = n + i; } return n;",
+        "fun f(a, b) { a; b; return a <= b and a >= 0 or b < 0; } return f(1, 2) and !f(3, 2);",
+        "var s = 0; var i = 0; while (i < 10) { i = i + 1; if (i != 3) s = s + i; } return s;",
+        "fun f(n) { return match (n) { 1 => \"one\", 2 => \"two\", 4 => \"four\", _ => \"many\" }; } return f(1) + f(2) + f(4) + f(7);",
+        "fun g() { try { return 1; } finally { return 2; } return 3; } return g();",
+        "var log = \"\"; try { throw 1; } catch (e) { log = log + \"c\"; } finally { log = log + \"f\"; } return log;",
+        "var nan = 0.0 / 0.0; return nan >= 1 or nan <= 1;",
+        "fun f(n) { var s = 0; for (var i = 0; i < n; i++) s += i; var j = 0; while (j < n) j = j + 1; return s + j; } return f(10);",
+        "fun f(n) { var s = \"\"; var i = 0.5; for (; i < n; i++) s = s + \"x\"; return s + i; } return f(3);",
+        "fun gen() { for (var i = 1; i <= 3; i = i + 1) yield i * i; } var s = 0; for (var x in gen()) s = s + x; return s;",
+    ];
+
+    ///
+    ///
+    ///
+    #[test]
+    fn optimized_code_behaves_the_same() -> Result<()> {
+        for program in PROGRAMS {
+            let mut optimized = VM::new();
+            let mut unfused = VM::new();
+            unfused.superinstructions = false;
+            let mut unoptimized = VM::new();
+            unoptimized.optimize = false;
+            let expected = unoptimized.interpret(program)?;
+            assert_eq!(optimized.interpret(program)?, expected, "{}", program);
+            assert_eq!(unfused.interpret(program)?, expected, "{}", program);
+        }
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn superinstructions_raise_the_same_errors() {
+        let mut vm = VM::new();
+        assert_runtime_error(&mut vm, "fun f() { var s = nil; s = s + 1; } f();").unwrap();
+        assert_runtime_error(&mut vm, "fun f(a, b) { while (a < b) a++; } f(1, \"x\");").unwrap();
+        let err = vm
+            .interpret("fun f() { var i = 9223372036854775807; i++; } f();")
+            .expect_err("Expected an overflow");
+        assert!(err.to_string().contains("Integer overflow"));
+    }
+}
@@ -0,0 +1,95 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::assert_ok_equals;
+    use miette::Result;
+    use rox::value::Value;
+    use rox::vm::VM;
+
+    const PROGRAMS: [&str; 8] = [
+        "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } return fib(15);",
+        "fun f(n) { var x = 0.5; for (var i = 0; i < n; i++) x = x * 1.5 / 1.25 - 0.1; return x; } return f(100);",
+        "fun f(a) { return a + (a = 2); } return f(40);",
+        "fun f(a, b) { return a and !b or a == b; } return f(1, nil) == f(nil, false);",
+        "fun greet(name) { return \"hello \" + name; } return greet(\"you\");",
+        "var base = 10; fun f(n) { return base * n; } var x = f(2); base = 3; return x + f(2);",
+        "fun f(n) { var x = 1; for (var i = 0; i < n; i++) x = x * 3; return x; } fun g(n) { return f(n) + 1; } return g(50);",
+        "fun f(n) { return clock() > n; } return f(0);",
+    ];
+
+    fn outcome(register_vm: bool, program: &str) -> String {
+        let mut vm = VM::new();
+        vm.register_vm = register_vm;
+        match vm.interpret(program) {
+            Ok(value) => format!("{:?}", value),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn register_machine_returns_the_same_results() {
+        for program in PROGRAMS {
+            assert_eq!(
+                outcome(true, program),
+                outcome(false, program),
+                "{}",
+                program
+            );
+        }
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn errors_are_raised_by_the_stack_vm() -> Result<()> {
+        let mut vm = VM::new();
+        vm.register_vm = true;
+        let caught = vm.interpret(
+            r#"
+fun f(n) { return n + nil; }
+fun g(n) { return f(n) * 2; }
+try { g(1); } catch (e) { return e; }
+"#,
+        )?;
+        match caught {
+            Some(Value::Exception(exception)) => {
+                assert_eq!(
+                    exception.trace,
+                    vec!["at f (line 2)", "at g (line 3)", "at script (line 4)"]
+                );
+            }
+            other => panic!("Expected an exception, found {:?}", other),
+        }
+
+        let err = vm
+            .interpret("fun down(n) { return 1 + down(n + 1); } return down(0);")
+            .expect_err("Expected a stack overflow");
+        assert!(err.to_string().contains("Stack overflow"));
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn register_machine_sees_reassigned_globals() -> Result<()> {
+        let mut vm = VM::new();
+        vm.register_vm = true;
+        assert_ok_equals(
+            &mut vm,
+            r#"
+fun double(n) { return n * 2; }
+fun apply(n) { return double(n); }
+var first = apply(4);
+fun double(n) { return n * 3; }
+return first + apply(4);
+"#,
+            Value::Int(20),
+        )
+    }
+}
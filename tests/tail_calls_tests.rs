@@ -0,0 +1,84 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use crate::common::assert_ok_equals;
+    use miette::Result;
+    use rox::value::Value;
+    use rox::vm::VM;
+
+    const PROGRAMS: [&str; 6] = [
+        "fun sum(n, acc) { if (n == 0) return acc; return sum(n - 1, acc + n); } return sum(50, 0);",
+        "fun even(n) { if (n == 0) return true; return odd(n - 1); } fun odd(n) { if (n == 0) return false; return even(n - 1); } return even(41);",
+        "fun f(a, b = 2, ...rest) { for (var x in rest) a = a + x; return a + b; } fun g(n) { return f(n, 3, 4, 5); } return g(1) + f(1);",
+        "fun f(n) { return n * 2; } fun g(n) { try { return f(n); } finally { n = 0; } } return g(4);",
+        "fun gen() { yield 1; return gen(); } var g = gen(); return g();",
+        "fun f() { return clock(); } return f() > 0;",
+    ];
+
+    ///
+    ///
+    ///
+    #[test]
+    fn tail_calls_return_the_same_results() -> Result<()> {
+        for program in PROGRAMS {
+            let mut plain = VM::new();
+            plain.tail_calls = false;
+            let expected = plain.interpret(program)?;
+            assert_eq!(VM::new().interpret(program)?, expected, "{}", program);
+        }
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn tail_recursion_runs_in_constant_frames() -> Result<()> {
+        let program = r#"
+fun count(n, acc) {
+  if (n == 0) return acc;
+  return count(n - 1, acc + 1);
+}
+return count(100000, 0);
+"#;
+        let mut vm = VM::new();
+        assert_ok_equals(&mut vm, program, Value::Int(100000))?;
+
+        let mut plain = VM::new();
+        plain.tail_calls = false;
+        let err = plain
+            .interpret(program)
+            .expect_err("Expected a stack overflow");
+        assert!(err.to_string().contains("Stack overflow"));
+        Ok(())
+    }
+
+    ///
+    ///
+    ///
+    #[test]
+    fn stack_traces_note_elided_tail_calls() -> Result<()> {
+        let mut vm = VM::new();
+        let caught = vm.interpret(
+            r#"
+fun fail() { return 1 + nil; }
+fun down(n) {
+  if (n == 0) return fail();
+  return down(n - 1);
+}
+try { down(20); } catch (e) { return e; }
+"#,
+        )?;
+        match caught {
+            Some(Value::Exception(exception)) => {
+                let mut expected = vec!["at fail (line 2)", "at down (line 4)"];
+                expected.extend(["at down (line 5)"; 7]);
+                expected.extend(["... 13 earlier tail calls elided", "at script (line 7)"]);
+                assert_eq!(exception.trace, expected);
+            }
+            other => panic!("Expected an exception, found {:?}", other),
+        }
+        Ok(())
+    }
+}